@@ -1,32 +1,89 @@
-use std::{cell::RefCell, collections::HashMap, mem, ptr};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    mem, ptr,
+};
 
 use comptr::ComPtr;
 
 use winapi::shared::d3d9caps::*;
 use winapi::shared::d3d9types::*;
 use winapi::shared::dxgi::*;
-use winapi::shared::dxgitype::DXGI_MODE_DESC;
+use winapi::shared::dxgi1_4::{
+    IDXGIAdapter3, DXGI_MEMORY_SEGMENT_GROUP_LOCAL, DXGI_QUERY_VIDEO_MEMORY_INFO,
+};
+use winapi::shared::dxgiformat::DXGI_FORMAT;
+use winapi::shared::dxgitype::*;
+use winapi::shared::guiddef::GUID;
+use winapi::shared::ntdef::LUID;
 use winapi::shared::windef::HMONITOR;
 use winapi::um::{d3d11::*, d3dcommon};
+use winapi::Interface;
 
 use super::{
-    fmt::{d3d_format_to_dxgi, is_display_mode_format},
+    fmt::{
+        d3d_format_to_dxgi, depth_format_to_dxgi_srv, is_depth_texture_format,
+        is_display_mode_format, is_null_format,
+    },
     *,
 };
 use crate::Error;
 
+const MIB: u64 = 1024 * 1024;
+
+/// Many games misbehave when told they have 4 GiB or more of VRAM.
+const DEFAULT_MEMORY_CAP_MB: u64 = 4095;
+
+/// Limits an amount of memory to the configured cap, and to 32 bits.
+fn cap_memory(mem: u64) -> u32 {
+    let cap = config::value("D3D9_MEMORY_CAP_MB").unwrap_or(DEFAULT_MEMORY_CAP_MB);
+    let cap = cap.saturating_mul(MIB);
+
+    // Need to clamp to 32-bits.
+    let mem = std::cmp::min(mem, cap);
+    std::cmp::min(mem, u64::from(std::u32::MAX)) as u32
+}
+
+/// A display attached to an adapter.
+struct Output {
+    output: ComPtr<IDXGIOutput>,
+    // Cache the display's properties.
+    desc: DXGI_OUTPUT_DESC,
+}
+
+impl Output {
+    fn new(output: ComPtr<IDXGIOutput>) -> Result<Self, Error> {
+        let desc = unsafe {
+            let mut desc = mem::zeroed();
+            let result = output.GetDesc(&mut desc);
+
+            if_not_success_err!(check_hresult(result, "Failed to get output description"));
+
+            desc
+        };
+
+        Ok(Self { output, desc })
+    }
+}
+
 /// This class represents a physical graphics adapter (GPU).
 pub struct Adapter {
     // Ordinal of this adapter in the list of GPUs.
     index: u32,
+    // DXGI interface representing the physical device.
+    adapter: ComPtr<IDXGIAdapter>,
     // Caches this adapter's description.
     adapter_desc: DXGI_ADAPTER_DESC,
-    // The display attached to this device.
-    output: Option<ComPtr<IDXGIOutput>>,
-    // Cache the display's properties.
-    output_desc: Option<DXGI_OUTPUT_DESC>,
-    // Caches the supported display modes compatible with a certain format.
-    mode_cache: RefCell<HashMap<D3DFORMAT, Box<[DXGI_MODE_DESC]>>>,
+    // The displays attached to this device.
+    outputs: Vec<Output>,
+    // D3D9 only supports one monitor per adapter,
+    // so all display queries are made on this output.
+    active_output: Cell<usize>,
+    // Caches the supported display modes of an output compatible with a certain format.
+    mode_cache: RefCell<HashMap<(D3DFORMAT, usize), Box<[DXGI_MODE_DESC]>>>,
+    // Caches the D3D11 support flags of the formats which were checked.
+    // These never change for a device, and games check the same formats many times.
+    format_cache: RefCell<HashMap<DXGI_FORMAT, u32>>,
     // With D3D11, obtaining a device's capabilities or checking for texture format support
     // requires us to create the device first.
     device: ComPtr<ID3D11Device>,
@@ -39,7 +96,7 @@ impl Adapter {
         let adapter = ComPtr::new(adapter);
 
         let adapter_desc = unsafe {
-            let mut desc = mem::uninitialized();
+            let mut desc = mem::zeroed();
             let result = adapter.GetDesc(&mut desc);
 
             if_not_success_err!(check_hresult(result, "Failed to get adapter description"));
@@ -47,34 +104,39 @@ impl Adapter {
             desc
         };
 
-        // D3D9 only supports one monitor per adapter.
-        // TODO: allow user to choose which monitor they want to use.
-        let output = unsafe {
-            let mut output = ptr::null_mut();
-            let result = adapter.EnumOutputs(0, &mut output);
-
-            match result {
-                0 => Some(ComPtr::new(output)),
-                _ => {
-                    // Some GPUs might have no outputs attached.
-                    warn!("No outputs detected for adapter {}", index);
-                    None
+        let mut outputs = Vec::new();
+
+        for i in 0.. {
+            let output = unsafe {
+                let mut output = ptr::null_mut();
+
+                // This fails once we run out of outputs.
+                if adapter.EnumOutputs(i, &mut output) != 0 {
+                    break;
                 }
-            }
-        };
 
-        let output_desc = output
-            .as_ref()
-            .ok_or(Error::NotFound)
-            .and_then(|output| unsafe {
-                let mut desc = mem::uninitialized();
-                let result = output.GetDesc(&mut desc);
+                ComPtr::new(output)
+            };
+
+            if let Ok(output) = Output::new(output) {
+                outputs.push(output);
+            }
+        }
 
-                if_not_success_err!(check_hresult(result, "Failed to get output description"));
+        // Some GPUs might have no outputs attached.
+        if outputs.is_empty() {
+            warn!("No outputs detected for adapter {}", index);
+        }
 
-                Ok(desc)
-            })
-            .ok();
+        // The user can choose which monitor they want to use.
+        let active_output = match config::value("D3D9_OUTPUT") {
+            Some(i) if i < outputs.len() => i,
+            Some(i) => {
+                warn!("Adapter {} has no output {}, using the first one", index, i);
+                0
+            }
+            None => 0,
+        };
 
         // We need to also create the D3D11 device now.;
         let mut feature_level = 0;
@@ -107,10 +169,12 @@ impl Adapter {
 
         let adapter = Self {
             index,
+            adapter,
             adapter_desc,
-            output,
-            output_desc,
+            outputs,
+            active_output: Cell::new(active_output),
             mode_cache: RefCell::new(HashMap::new()),
+            format_cache: RefCell::new(HashMap::new()),
             device,
         };
 
@@ -166,17 +230,117 @@ impl Adapter {
         id.Revision = desc.Revision;
 
         // D3D9 wants a 128-bit unique adapter identifier.
-        // We don't have anything like that available, so we combine a 64-bit LUID with the adapter's index.
-        // TODO: need to find a safer way to do this. Rust doesn't like raw pointer aliasing like C++ does.
+        // We don't have anything like that available, so we combine the 64-bit LUID with the adapter's index.
+        let luid = self.luid();
+        id.DeviceIdentifier = GUID {
+            Data1: luid.LowPart,
+            Data2: luid.HighPart as u16,
+            Data3: (luid.HighPart >> 16) as u16,
+            Data4: {
+                let mut data = [0; 8];
+                data[..4].copy_from_slice(&self.index.to_le_bytes());
+                data
+            },
+        };
 
         id.WHQLLevel = 1;
 
         id
     }
 
+    /// Returns all of the outputs attached to this adapter.
+    pub fn outputs(&self) -> impl Iterator<Item = &ComPtr<IDXGIOutput>> + '_ {
+        self.outputs.iter().map(|output| &output.output)
+    }
+
+    /// Chooses the output on which the display modes and the monitor are queried.
+    pub fn set_active_output(&self, index: usize) -> Result<(), Error> {
+        if index >= self.outputs.len() {
+            return Err(Error::InvalidCall);
+        }
+
+        if self.active_output.replace(index) != index {
+            // The output's modes might have changed since we last used it.
+            self.mode_cache
+                .borrow_mut()
+                .retain(|&(_, output), _| output != index);
+        }
+
+        Ok(())
+    }
+
+    /// Throws out the cached display modes.
+    ///
+    /// Should be called when the displays change, e.g. when a monitor is plugged in.
+    pub fn refresh_modes(&self) {
+        self.mode_cache.borrow_mut().clear();
+    }
+
+    // Returns the key of the cached modes of a format, for the active output.
+    fn mode_key(&self, fmt: D3DFORMAT) -> (D3DFORMAT, usize) {
+        (fmt, self.active_output.get())
+    }
+
+    // Retrieves the currently active output, if this adapter has any.
+    fn output(&self) -> Option<&Output> {
+        self.outputs.get(self.active_output.get())
+    }
+
+    /// Retrieves the output used by full screen swap chains, if this adapter has any.
+    pub fn active_output(&self) -> Option<ComPtr<IDXGIOutput>> {
+        self.output().map(|output| output.output.clone())
+    }
+
+    /// Finds the enumerated display mode matching a full screen back buffer.
+    ///
+    /// A refresh rate of 0 matches any mode of the right size.
+    pub fn find_mode(
+        &self,
+        fmt: D3DFORMAT,
+        width: u32,
+        height: u32,
+        refresh_rate: u32,
+    ) -> Option<DXGI_MODE_DESC> {
+        if self.output().is_none() || !is_display_mode_format(fmt) {
+            return None;
+        }
+
+        // Back buffers with or without alpha are displayed in the same modes.
+        let fmt = if fmt == D3DFMT_A2R10G10B10 {
+            fmt
+        } else {
+            D3DFMT_A8R8G8B8
+        };
+
+        self.cache_display_modes(fmt);
+
+        let mode_cache = self.mode_cache.borrow();
+        let modes = &mode_cache[&self.mode_key(fmt)];
+
+        modes
+            .iter()
+            .filter(|mode| mode.Width == width && mode.Height == height)
+            .find(|mode| {
+                let rf = mode.RefreshRate;
+                refresh_rate == 0
+                    || (rf.Denominator != 0 && rf.Numerator / rf.Denominator == refresh_rate)
+            })
+            .cloned()
+    }
+
+    /// Changes the ordinal of this adapter, after the adapters have been reordered.
+    pub fn set_index(&mut self, index: u32) {
+        self.index = index;
+    }
+
+    /// Returns the locally unique identifier of this adapter.
+    pub fn luid(&self) -> LUID {
+        self.adapter_desc.AdapterLuid
+    }
+
     /// Retrieves the number of display modes which match the requested format.
     pub fn mode_count(&self, fmt: D3DFORMAT) -> u32 {
-        if self.output.is_none() || !is_display_mode_format(fmt) {
+        if self.output().is_none() || !is_display_mode_format(fmt) {
             return 0;
         }
 
@@ -185,14 +349,14 @@ impl Adapter {
         self.cache_display_modes(fmt);
 
         let mode_cache = self.mode_cache.borrow();
-        let modes = &mode_cache[&fmt];
+        let modes = &mode_cache[&self.mode_key(fmt)];
 
         modes.len() as u32
     }
 
     /// Retrieves the display mode of a certain index.
     pub fn mode(&self, fmt: D3DFORMAT, index: u32) -> Option<D3DDISPLAYMODE> {
-        if self.output.is_none() || !is_display_mode_format(fmt) {
+        if self.output().is_none() || !is_display_mode_format(fmt) {
             return None;
         }
 
@@ -201,7 +365,7 @@ impl Adapter {
 
         // Cache should contain an empty vector even if a format is not supported.
         let mode_cache = self.mode_cache.borrow();
-        let modes = &mode_cache[&fmt];
+        let modes = &mode_cache[&self.mode_key(fmt)];
 
         modes
             .get(index as usize)
@@ -221,17 +385,70 @@ impl Adapter {
             })
     }
 
+    /// Retrieves the display modes which match a filter, including their scanline ordering.
+    pub fn modes_ex(&self, filter: &D3DDISPLAYMODEFILTER) -> Vec<D3DDISPLAYMODEEX> {
+        let fmt = filter.Format;
+
+        if self.output().is_none() || !is_display_mode_format(fmt) {
+            return Vec::new();
+        }
+
+        self.cache_display_modes(fmt);
+
+        let mode_cache = self.mode_cache.borrow();
+        let modes = &mode_cache[&self.mode_key(fmt)];
+
+        modes
+            .iter()
+            .map(|mode| D3DDISPLAYMODEEX {
+                Size: mem::size_of::<D3DDISPLAYMODEEX>() as u32,
+                Width: mode.Width,
+                Height: mode.Height,
+                RefreshRate: {
+                    let rf = mode.RefreshRate;
+                    if rf.Denominator == 0 {
+                        0
+                    } else {
+                        rf.Numerator / rf.Denominator
+                    }
+                },
+                Format: fmt,
+                ScanLineOrdering: match mode.ScanlineOrdering {
+                    DXGI_MODE_SCANLINE_ORDER_UPPER_FIELD_FIRST
+                    | DXGI_MODE_SCANLINE_ORDER_LOWER_FIELD_FIRST => D3DSCANLINEORDERING_INTERLACED,
+                    _ => D3DSCANLINEORDERING_PROGRESSIVE,
+                },
+            })
+            // Asking for interlaced modes returns progressive modes too.
+            .filter(|mode| {
+                filter.ScanLineOrdering == D3DSCANLINEORDERING_INTERLACED
+                    || mode.ScanLineOrdering == filter.ScanLineOrdering
+            })
+            .collect()
+    }
+
     /// Checks if a given format is supported for a specific resource usage.
     pub fn is_format_supported(&self, fmt: D3DFORMAT, rt: ResourceType, usage: UsageFlags) -> bool {
-        let fmt = d3d_format_to_dxgi(fmt);
+        // NULL render targets are never actually bound, so they're always supported.
+        if is_null_format(fmt) {
+            return rt == ResourceType::Surface && UsageFlags::RENDER_TARGET.contains(usage);
+        }
+
+        // Depth textures are sampled through a view with a different format,
+        // which must also be supported.
+        if is_depth_texture_format(fmt) && rt == ResourceType::Texture {
+            let sampling = D3D11_FORMAT_SUPPORT_TEXTURE2D | D3D11_FORMAT_SUPPORT_SHADER_SAMPLE;
+            let srv_support = self.format_support(depth_format_to_dxgi_srv(fmt));
 
-        let support = unsafe {
-            let mut sp = 0;
-            if self.device.CheckFormatSupport(fmt, &mut sp) != 0 {
+            if srv_support & sampling != sampling {
                 return false;
             }
-            sp
-        };
+        }
+
+        let support = self.format_support(d3d_format_to_dxgi(fmt));
+        if support == 0 {
+            return false;
+        }
 
         // Returns true if a resource type is _not_ supported.
         let check_rt = |d3d9_rt, sp| (rt == d3d9_rt) && ((support & sp) == 0);
@@ -256,6 +473,33 @@ impl Adapter {
         !lacks_support
     }
 
+    /// Checks if the GPU can generate the mip maps of textures with a given format.
+    ///
+    /// D3D11 renders into the mip levels, so the format must also be usable as a render target.
+    pub fn supports_mip_autogen(&self, fmt: D3DFORMAT) -> bool {
+        let needed = D3D11_FORMAT_SUPPORT_MIP_AUTOGEN | D3D11_FORMAT_SUPPORT_RENDER_TARGET;
+        self.format_support(d3d_format_to_dxgi(fmt)) & needed == needed
+    }
+
+    // Returns the D3D11 format support flags of a DXGI format, or 0 if it's not supported at all.
+    fn format_support(&self, fmt: DXGI_FORMAT) -> u32 {
+        *self
+            .format_cache
+            .borrow_mut()
+            .entry(fmt)
+            .or_insert_with(|| {
+                let mut support = 0;
+
+                unsafe {
+                    if self.device.CheckFormatSupport(fmt, &mut support) != 0 {
+                        return 0;
+                    }
+                }
+
+                support
+            })
+    }
+
     /// Checks if we support multisampling for a given format.
     /// Returns the maximum quality level supported for a given format.
     pub fn is_multisampling_supported(&self, fmt: D3DFORMAT, ms: D3DMULTISAMPLE_TYPE) -> u32 {
@@ -271,13 +515,58 @@ impl Adapter {
     }
 
     /// Returns the capabilities of this device.
-    pub fn caps(&self) -> D3DCAPS9 {
+    ///
+    /// Shader models and resource limits are trimmed to what the D3D11 device's feature level supports.
+    pub fn caps(&self) -> Result<D3DCAPS9, Error> {
+        // The device might have been removed since we created it.
+        let result = unsafe { self.device.GetDeviceRemovedReason() };
+        if_not_success_err!(check_hresult(result, "D3D11 device was removed"));
+
+        let feature_level = unsafe { self.device.GetFeatureLevel() };
+
+        let mut caps = self.max_caps();
+
+        if feature_level < d3dcommon::D3D_FEATURE_LEVEL_11_0 {
+            caps.MaxTextureWidth = 8192;
+            caps.MaxTextureHeight = 8192;
+            caps.MaxTextureAspectRatio = 8192;
+        }
+
+        if feature_level < d3dcommon::D3D_FEATURE_LEVEL_10_0 {
+            // Level 9 hardware is limited to shader model 2.
+            caps.VertexShaderVersion = 0xFFFE_0000 | (2 << 8);
+            caps.PixelShaderVersion = 0xFFFF_0000 | (2 << 8);
+            caps.MaxVertexShader30InstructionSlots = 0;
+            caps.MaxPixelShader30InstructionSlots = 0;
+            caps.VertexTextureFilterCaps = 0;
+            caps.MaxVolumeExtent = 256;
+
+            let (max_size, max_rts, max_anisotropy) = match feature_level {
+                d3dcommon::D3D_FEATURE_LEVEL_9_3 => (4096, 4, 16),
+                d3dcommon::D3D_FEATURE_LEVEL_9_2 => (2048, 1, 16),
+                _ => (2048, 1, 2),
+            };
+
+            caps.MaxTextureWidth = max_size;
+            caps.MaxTextureHeight = max_size;
+            caps.MaxTextureAspectRatio = max_size;
+            caps.NumSimultaneousRTs = max_rts;
+            caps.MaxAnisotropy = max_anisotropy;
+        }
+
+        Ok(caps)
+    }
+
+    // Returns the capabilities of a device supporting feature level 11.
+    fn max_caps(&self) -> D3DCAPS9 {
         D3DCAPS9 {
             DeviceType: D3DDEVTYPE_HAL,
             AdapterOrdinal: self.index,
-            Caps: 0,
-            // TODO: implement D3DCAPS2_CANSHARERESOURCE for D3D9Ex
-            Caps2: D3DCAPS2_CANAUTOGENMIPMAP
+            // The raster status is approximated by the swap chain.
+            Caps: D3DCAPS_READ_SCANLINE,
+            // Textures and render targets can be shared with D3D9Ex.
+            Caps2: D3DCAPS2_CANSHARERESOURCE
+                | D3DCAPS2_CANAUTOGENMIPMAP
                 | D3DCAPS2_CANCALIBRATEGAMMA
                 | D3DCAPS2_FULLSCREENGAMMA
                 | D3DCAPS2_CANMANAGERESOURCE
@@ -289,7 +578,11 @@ impl Adapter {
             // A lot of these features are bitflags, so we set all bits.
             PresentationIntervals: !0,
             CursorCaps: !0,
-            DevCaps: !0,
+            // Higher order primitives cannot be tessellated, so they are not advertised.
+            DevCaps: !(D3DDEVCAPS_NPATCHES
+                | D3DDEVCAPS_RTPATCHES
+                | D3DDEVCAPS_QUINTICRTPATCHES
+                | D3DDEVCAPS_RTPATCHHANDLEZERO),
             PrimitiveMiscCaps: !0,
             RasterCaps: !0,
             ZCmpCaps: !0,
@@ -334,18 +627,24 @@ impl Adapter {
             MaxUserClipPlanes: 8,
             MaxPrimitiveCount: std::u32::MAX,
             MaxVertexIndex: std::u32::MAX,
-            MaxVertexBlendMatrices: std::u32::MAX,
-            MaxVertexBlendMatrixIndex: std::u32::MAX,
+            MaxVertexBlendMatrices: 4,
+            MaxVertexBlendMatrixIndex: 255,
             VertexProcessingCaps: !0,
+            // Larger points are expanded into quads by a geometry shader.
             MaxPointSize: 2048.0,
             MaxStreams: 16,
-            MaxStreamStride: 1 << 31,
+            // D3D11 limits the stride of a vertex buffer.
+            MaxStreamStride: 2048,
             VertexShaderVersion: 0xFFFE_0000 | (3 << 8),
-            MaxVertexShaderConst: 1 << 16,
+            // Number of float constants available to vs_3_0.
+            MaxVertexShaderConst: 256,
             PixelShaderVersion: 0xFFFF_0000 | (3 << 8),
             PixelShader1xMaxValue: 8.0,
-            DevCaps2: !0,
-            MaxNpatchTessellationLevel: 256.0,
+            DevCaps2: !(D3DDEVCAPS2_ADAPTIVETESSRTPATCH
+                | D3DDEVCAPS2_ADAPTIVETESSNPATCH
+                | D3DDEVCAPS2_DMAPNPATCH
+                | D3DDEVCAPS2_PRESAMPLEDDMAPNPATCH),
+            MaxNpatchTessellationLevel: 0.0,
             Reserved5: 0,
             // TODO: multihead support
             MasterAdapterOrdinal: self.index,
@@ -354,20 +653,22 @@ impl Adapter {
             DeclTypes: !0,
             NumSimultaneousRTs: 8,
             StretchRectFilterCaps: !0,
+            // The shader model 2 limits are the maximums allowed by D3D9.
             VS20Caps: D3DVSHADERCAPS2_0 {
                 Caps: !0,
                 DynamicFlowControlDepth: 24,
-                NumTemps: 16384,
-                StaticFlowControlDepth: 1 << 31,
+                NumTemps: 32,
+                StaticFlowControlDepth: 4,
             },
             PS20Caps: D3DPSHADERCAPS2_0 {
                 Caps: !0,
                 DynamicFlowControlDepth: 24,
-                NumTemps: !16384,
-                StaticFlowControlDepth: 1 << 31,
-                NumInstructionSlots: 1 << 31,
+                NumTemps: 32,
+                StaticFlowControlDepth: 4,
+                NumInstructionSlots: 512,
             },
             VertexTextureFilterCaps: !0,
+            // This is D3DINFINITEINSTRUCTIONS, not a bitflag.
             MaxVShaderInstructionsExecuted: !0,
             MaxPShaderInstructionsExecuted: !0,
             MaxVertexShader30InstructionSlots: 32768,
@@ -375,10 +676,10 @@ impl Adapter {
         }
     }
 
-    /// Returns the (primary) monitor of this adapter.
+    /// Returns the monitor of the active output.
     pub fn monitor(&self) -> HMONITOR {
-        self.output_desc
-            .map(|desc| desc.Monitor)
+        self.output()
+            .map(|output| output.desc.Monitor)
             .unwrap_or(ptr::null_mut())
     }
 
@@ -387,25 +688,71 @@ impl Adapter {
         self.device.clone()
     }
 
-    /// Returns the amount of memory this adapter has.
+    /// Returns the amount of memory this adapter has, capped to a value old games can handle.
+    ///
+    /// The cap can be changed with the `D3D9_MEMORY_CAP_MB` environment variable.
     pub fn available_memory(&self) -> u32 {
+        cap_memory(self.available_memory_ex())
+    }
+
+    /// Returns the amount of video memory which can still be allocated, with the same cap
+    /// as `available_memory`.
+    ///
+    /// This uses the budget the OS gives us, if it is reported (Windows 10 and later).
+    /// Otherwise, it's the same as the total amount of memory.
+    pub fn free_memory(&self) -> u32 {
+        let adapter = unsafe {
+            let mut ptr: *mut IDXGIAdapter3 = ptr::null_mut();
+            let uuid = IDXGIAdapter3::uuidof();
+
+            let result = self
+                .adapter
+                .QueryInterface(&uuid, &mut ptr as *mut _ as *mut *mut _);
+            if result != 0 {
+                return self.available_memory();
+            }
+
+            ComPtr::new(ptr)
+        };
+
+        let info = unsafe {
+            let mut info: DXGI_QUERY_VIDEO_MEMORY_INFO = mem::zeroed();
+            let result =
+                adapter.QueryVideoMemoryInfo(0, DXGI_MEMORY_SEGMENT_GROUP_LOCAL, &mut info);
+            if result != 0 {
+                return self.available_memory();
+            }
+            info
+        };
+
+        let free = info.Budget.saturating_sub(info.CurrentUsage);
+
+        // Round down to a multiple of a MiB.
+        cap_memory((free / MIB) * MIB)
+    }
+
+    /// Returns the amount of memory this adapter has, without any limit.
+    pub fn available_memory_ex(&self) -> u64 {
         let desc = &self.adapter_desc;
 
         // We return the sum of the VRAM and the amount of shared RAM.
         // This is just a gross estimate.
-        let mem = desc.DedicatedVideoMemory + desc.DedicatedSystemMemory + desc.SharedSystemMemory;
-
-        // Round to the nearest MiB.
-        let mem = (mem / (1024 * 1024)) * (1024 * 1024);
-
-        // Need to clamp to 32-bits.
-        std::cmp::min(mem, std::u32::MAX as usize) as u32
+        let mem = [
+            desc.DedicatedVideoMemory,
+            desc.DedicatedSystemMemory,
+            desc.SharedSystemMemory,
+        ]
+        .iter()
+        .fold(0u64, |sum, &mem| sum.saturating_add(mem as u64));
+
+        // Round down to a multiple of a MiB.
+        (mem / MIB) * MIB
     }
 
     /// Retrieves the output's display modes and caches them.
     fn cache_display_modes(&self, fmt: D3DFORMAT) {
-        let output = match self.output {
-            Some(ref output) => output,
+        let output = match self.output() {
+            Some(output) => &output.output,
             None => return,
         };
 
@@ -413,7 +760,7 @@ impl Adapter {
             let mode_cache = self.mode_cache.borrow();
 
             // Nothing to do if already in cache.
-            if mode_cache.contains_key(&fmt) {
+            if mode_cache.contains_key(&self.mode_key(fmt)) {
                 return;
             }
         }
@@ -445,6 +792,6 @@ impl Adapter {
 
         // Even if the function calls fail, we still store the empty array
         // to determine if they're cached or not.
-        mode_cache.insert(fmt, mode_descs);
+        mode_cache.insert(self.mode_key(fmt), mode_descs);
     }
 }
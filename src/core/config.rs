@@ -0,0 +1,26 @@
+//! Runtime configuration, read from environment variables.
+
+use std::env;
+use std::str::FromStr;
+
+/// Parses the value of an environment variable.
+///
+/// Returns `None` if the variable is not set, or if it has an invalid value.
+pub fn value<T: FromStr>(var: &str) -> Option<T> {
+    let value = env::var(var).ok()?;
+
+    value.parse().ok().or_else(|| {
+        warn!("Invalid value for {}: {}", var, value);
+        None
+    })
+}
+
+/// Checks if a boolean option is enabled.
+///
+/// Any value other than `0` or `false` counts as enabled.
+pub fn flag(var: &str) -> bool {
+    match env::var(var) {
+        Ok(value) => value != "0" && !value.eq_ignore_ascii_case("false"),
+        Err(_) => false,
+    }
+}
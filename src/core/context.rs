@@ -6,35 +6,76 @@ use winapi::shared::d3d9::*;
 use winapi::shared::d3d9caps::D3DCAPS9;
 use winapi::shared::d3d9types::*;
 use winapi::shared::dxgi;
+use winapi::shared::dxgi1_4::IDXGIFactory4;
+use winapi::shared::ntdef::LUID;
 use winapi::shared::windef::{HMONITOR, HWND};
 use winapi::um::winuser;
 use winapi::Interface;
 use winapi::{
-    shared::d3d9::{IDirect3D9, IDirect3D9Vtbl},
+    shared::d3d9::{IDirect3D9, IDirect3D9Ex, IDirect3D9ExVtbl, IDirect3D9Vtbl},
     um::unknwnbase::{IUnknown, IUnknownVtbl},
 };
 
 use com_impl::{implementation, interface, ComInterface};
 
 use super::{
-    fmt::{is_depth_stencil_format, is_display_mode_format},
+    fmt::{
+        is_conversion_source_format, is_depth_stencil_format, is_display_mode_format,
+        DESKTOP_FORMAT,
+    },
     *,
 };
 use crate::{dev::Device, Error};
 
+/// Finds an adapter by its index, or by its LUID, written as a hexadecimal number prefixed with `0x`.
+fn find_adapter(adapters: &[Adapter], value: &str) -> Option<usize> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        let luid = u64::from_str_radix(hex, 16).ok()?;
+
+        adapters.iter().position(|adapter| {
+            let LUID {
+                LowPart: low,
+                HighPart: high,
+            } = adapter.luid();
+            (u64::from(high as u32) << 32 | u64::from(low)) == luid
+        })
+    } else {
+        value.parse().ok().filter(|&index| index < adapters.len())
+    }
+}
+
 /// D3D9 interface which stores all application context.
 ///
 /// Similar in role to a DXGI factory.
-#[interface(IDirect3D9)]
+/// It also implements the D3D9Ex extensions: the object always has the extended vtable,
+/// so apps can query for `IDirect3D9Ex` from a plain `IDirect3D9` (and back) at any time.
+#[interface(IDirect3D9Ex)]
 pub struct Context {
     refs: AtomicU32,
     factory: ComPtr<dxgi::IDXGIFactory>,
     adapters: Vec<Adapter>,
+    // Report the reference and software rasterizers as available,
+    // backed by the same hardware devices.
+    emulate_ref: bool,
 }
 
 impl Context {
     /// Creates a new D3D9 context.
+    ///
+    /// The `D3D9_WARP` environment variable makes it use the software rasterizer, like `new_warp`.
     pub fn new() -> Result<ComPtr<Context>, Error> {
+        Self::create(config::flag("D3D9_WARP"))
+    }
+
+    /// Creates a new D3D9 context, whose only adapter is D3D11's WARP software rasterizer.
+    ///
+    /// This doesn't require a GPU, so it can be used for testing on headless machines.
+    /// The adapter has no outputs, so only windowed mode is available.
+    pub fn new_warp() -> Result<ComPtr<Context>, Error> {
+        Self::create(true)
+    }
+
+    fn create(warp: bool) -> Result<ComPtr<Context>, Error> {
         // We first have to create a factory, which is the equivalent of this interface in DXGI terms.
         let factory = unsafe {
             let uuid = dxgi::IDXGIFactory::uuidof();
@@ -46,29 +87,96 @@ impl Context {
             ComPtr::new(factory)
         };
 
-        // Now we can enumerate all the graphics adapters on the system.
-        let adapters = (0..)
-            .scan(ptr::null_mut(), |adapter, id| unsafe {
-                let result = factory.EnumAdapters(id, adapter);
-                if result == 0 {
-                    Adapter::new(id, *adapter).ok()
-                } else {
-                    None
-                }
-            })
-            .fuse()
-            .collect();
+        let adapters = if warp {
+            vec![Self::warp_adapter(&factory)?]
+        } else {
+            Self::hardware_adapters(&factory)?
+        };
 
         let ctx = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
             factory,
             adapters,
+            emulate_ref: config::flag("D3D9_EMULATE_REF"),
         };
 
         Ok(unsafe { new_com_interface(ctx) })
     }
 
+    /// Enumerates all the graphics adapters on the system.
+    ///
+    /// Adapters which cannot be used are skipped. Returns an error if none of them can be used.
+    fn hardware_adapters(factory: &dxgi::IDXGIFactory) -> Result<Vec<Adapter>, Error> {
+        let mut adapters = Vec::new();
+
+        for id in 0.. {
+            let mut adapter = ptr::null_mut();
+
+            // Fails with `DXGI_ERROR_NOT_FOUND` once there are no more adapters.
+            if unsafe { factory.EnumAdapters(id, &mut adapter) } != 0 {
+                break;
+            }
+
+            // The skipped adapters are not counted in the D3D9 ordinals.
+            match Adapter::new(adapters.len() as u32, adapter) {
+                Ok(adapter) => adapters.push(adapter),
+                Err(err) => warn!("Skipping adapter {}, which cannot be used: {:?}", id, err),
+            }
+        }
+
+        if adapters.is_empty() {
+            error!("No usable graphics adapters were found");
+            return Err(Error::NotAvailable);
+        }
+
+        // On systems with multiple GPUs, users can choose the one games will use by default.
+        if let Some(value) = config::value::<String>("D3D9_ADAPTER") {
+            match find_adapter(&adapters, &value) {
+                Some(index) => {
+                    let adapter = adapters.remove(index);
+                    adapters.insert(0, adapter);
+
+                    for (i, adapter) in adapters.iter_mut().enumerate() {
+                        adapter.set_index(i as u32);
+                    }
+                }
+                None => warn!("Adapter {} not found, using the default order", value),
+            }
+        }
+
+        Ok(adapters)
+    }
+
+    /// Retrieves the adapter representing the WARP software rasterizer.
+    fn warp_adapter(factory: &dxgi::IDXGIFactory) -> Result<Adapter, Error> {
+        let adapter = unsafe {
+            let uuid = IDXGIFactory4::uuidof();
+            let mut factory4: *mut IDXGIFactory4 = ptr::null_mut();
+
+            let result = factory.QueryInterface(&uuid, &mut factory4 as *mut _ as *mut *mut _);
+            if_not_success_err!(check_hresult(result, "WARP requires DXGI 1.4"));
+            let factory4 = ComPtr::new(factory4);
+
+            let uuid = dxgi::IDXGIAdapter::uuidof();
+            let mut adapter: *mut dxgi::IDXGIAdapter = ptr::null_mut();
+
+            let result = factory4.EnumWarpAdapter(&uuid, &mut adapter as *mut _ as *mut *mut _);
+            if_not_success_err!(check_hresult(result, "Failed to get WARP adapter"));
+
+            adapter
+        };
+
+        info!("Using the WARP software rasterizer");
+
+        Adapter::new(0, adapter)
+    }
+
+    /// Retrieves an adapter by its ordinal.
+    pub fn adapter(&self, adapter: u32) -> Option<&Adapter> {
+        self.adapters.get(adapter as usize)
+    }
+
     fn check_adapter(&self, adapter: u32) -> Result<&Adapter, Error> {
         self.adapters
             .get(adapter as usize)
@@ -78,12 +186,120 @@ impl Context {
     fn check_devty(&self, dev_ty: D3DDEVTYPE) -> Result<(), Error> {
         match dev_ty {
             D3DDEVTYPE_HAL => Ok(()),
+            // Some games (especially debug builds) expect the reference rasterizer to exist.
+            D3DDEVTYPE_REF | D3DDEVTYPE_SW if self.emulate_ref => Ok(()),
             _ => Err(Error::InvalidCall),
         }
     }
+
+    /// Validates the presentation parameters of a new device.
+    ///
+    /// Like D3D9, the fields which were left at 0 are filled in with defaults,
+    /// and the corrected values are written back to the app's structure.
+    fn check_present_parameters(pp: &mut D3DPRESENT_PARAMETERS, focus: HWND) -> Result<(), Error> {
+        match pp.SwapEffect {
+            D3DSWAPEFFECT_DISCARD
+            | D3DSWAPEFFECT_FLIP
+            | D3DSWAPEFFECT_COPY
+            | D3DSWAPEFFECT_OVERLAY
+            | D3DSWAPEFFECT_FLIPEX => (),
+            _ => {
+                error!("Invalid swap effect: {}", pp.SwapEffect);
+                return Err(Error::InvalidCall);
+            }
+        }
+
+        // Multisampled back buffers cannot be presented, they are resolved and discarded.
+        if pp.MultiSampleType != D3DMULTISAMPLE_NONE && pp.SwapEffect != D3DSWAPEFFECT_DISCARD {
+            error!("Multisampling requires the discard swap effect");
+            return Err(Error::InvalidCall);
+        }
+
+        if pp.EnableAutoDepthStencil != 0 && !is_depth_stencil_format(pp.AutoDepthStencilFormat) {
+            error!(
+                "Invalid automatic depth / stencil format: {}",
+                pp.AutoDepthStencilFormat
+            );
+            return Err(Error::InvalidCall);
+        }
+
+        let valid_interval = match pp.PresentationInterval {
+            D3DPRESENT_INTERVAL_DEFAULT
+            | D3DPRESENT_INTERVAL_ONE
+            | D3DPRESENT_INTERVAL_IMMEDIATE => true,
+            // Waiting for multiple vertical blanks is only possible in full screen.
+            D3DPRESENT_INTERVAL_TWO | D3DPRESENT_INTERVAL_THREE | D3DPRESENT_INTERVAL_FOUR => {
+                pp.Windowed == 0
+            }
+            _ => false,
+        };
+
+        if !valid_interval {
+            error!(
+                "Invalid presentation interval: {:#x}",
+                pp.PresentationInterval
+            );
+            return Err(Error::InvalidCall);
+        }
+
+        pp.BackBufferCount = pp.BackBufferCount.max(1).min(D3DPRESENT_BACK_BUFFERS_MAX);
+
+        if pp.Windowed == 0 {
+            // In full screen, the parameters describe the exact display mode to use.
+            if pp.BackBufferWidth == 0 || pp.BackBufferHeight == 0 {
+                error!("Full screen devices require the size of the display mode");
+                return Err(Error::InvalidCall);
+            }
+
+            if !is_display_mode_format(pp.BackBufferFormat) {
+                error!("Invalid full screen format: {}", pp.BackBufferFormat);
+                return Err(Error::InvalidCall);
+            }
+
+            return Ok(());
+        }
+
+        // Windowed back buffers can have the desktop's format.
+        if pp.BackBufferFormat == D3DFMT_UNKNOWN {
+            pp.BackBufferFormat = DESKTOP_FORMAT;
+        }
+
+        // Back buffers default to the size of the window they present to.
+        if pp.BackBufferWidth == 0 || pp.BackBufferHeight == 0 {
+            let window = if pp.hDeviceWindow.is_null() {
+                focus
+            } else {
+                pp.hDeviceWindow
+            };
+
+            let mut rect = unsafe { mem::zeroed() };
+            if unsafe { winuser::GetClientRect(window, &mut rect) } == 0 {
+                error!("Cannot determine the back buffer size from the device window");
+                return Err(Error::InvalidCall);
+            }
+
+            // Minimized windows have an empty client area.
+            if pp.BackBufferWidth == 0 {
+                pp.BackBufferWidth = (rect.right - rect.left).max(1) as u32;
+            }
+
+            if pp.BackBufferHeight == 0 {
+                pp.BackBufferHeight = (rect.bottom - rect.top).max(1) as u32;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-impl_iunknown!(struct Context: IUnknown, IDirect3D9);
+impl Drop for Context {
+    fn drop(&mut self) {
+        // Apps release the context last, so anything still alive at this point was leaked.
+        leaks::report();
+    }
+}
+
+impl_iunknown!(struct Context: IUnknown, IDirect3D9, IDirect3D9Ex);
 
 #[implementation(IDirect3D9)]
 impl Context {
@@ -146,14 +362,32 @@ impl Context {
 
     /// Retrieve the current display mode of the GPU.
     fn get_adapter_display_mode(&self, adapter: u32, mode: *mut D3DDISPLAYMODE) -> Error {
-        let monitor = self.get_adapter_monitor(adapter);
+        let monitor = if_error!(self.check_adapter(adapter)).monitor();
         let mode = if_error!(check_mut_ref(mode));
 
+        // This format is usually what modern displays use internally.
+        mode.Format = DESKTOP_FORMAT;
+        // 0 indicates an adapter-default rate.
+        mode.RefreshRate = 0;
+
+        // Headless GPUs have no monitor, but games still need a mode for windowed rendering.
+        if monitor.is_null() {
+            run_once!(|| warn!(
+                "Adapter {} has no monitor, reporting a 1920x1080 mode",
+                adapter
+            ));
+            mode.Width = 1920;
+            mode.Height = 1080;
+            return Error::Success;
+        }
+
         let mi = unsafe {
-            let mut mi: winuser::MONITORINFO = mem::uninitialized();
+            let mut mi: winuser::MONITORINFO = mem::zeroed();
             mi.cbSize = mem::size_of_val(&mi) as u32;
-            let result = winuser::GetMonitorInfoW(monitor, &mut mi);
-            assert_ne!(result, 0, "Failed to retrieve monitor info");
+            if winuser::GetMonitorInfoW(monitor, &mut mi) == 0 {
+                error!("Failed to retrieve monitor info");
+                return Error::NotAvailable;
+            }
             mi
         };
 
@@ -161,10 +395,6 @@ impl Context {
 
         mode.Width = (rc.right - rc.left) as u32;
         mode.Height = (rc.bottom - rc.top) as u32;
-        // 0 indicates an adapter-default rate.
-        mode.RefreshRate = 0;
-        // This format is usually what modern displays use internally.
-        mode.Format = D3DFMT_X8R8G8B8;
 
         Error::Success
     }
@@ -175,14 +405,32 @@ impl Context {
         adapter: u32,
         ty: D3DDEVTYPE,
         adapter_fmt: D3DFORMAT,
-        _bb_fmt: D3DFORMAT,
-        _windowed: u32,
+        bb_fmt: D3DFORMAT,
+        windowed: u32,
     ) -> Error {
-        if_error!(self.check_adapter(adapter));
+        let adapter = if_error!(self.check_adapter(adapter));
         if_error!(self.check_devty(ty));
 
-        // We support hardware accel with all valid formats.
-        if is_display_mode_format(adapter_fmt) {
+        if !is_display_mode_format(adapter_fmt) {
+            return Error::NotAvailable;
+        }
+
+        let supported = if windowed != 0 {
+            // Windowed back buffers are converted to the desktop's format when presenting.
+            bb_fmt == D3DFMT_UNKNOWN || is_display_mode_format(bb_fmt)
+        } else {
+            // Full screen back buffers are scanned out directly, so they must have the
+            // display mode's format, optionally with an alpha channel.
+            let same_format = match (adapter_fmt, bb_fmt) {
+                (D3DFMT_X8R8G8B8, D3DFMT_A8R8G8B8) | (D3DFMT_X1R5G5B5, D3DFMT_A1R5G5B5) => true,
+                (adapter_fmt, bb_fmt) => adapter_fmt == bb_fmt,
+            };
+
+            // The monitor also has to support modes with this format.
+            same_format && adapter.mode_count(adapter_fmt) > 0
+        };
+
+        if supported {
             Error::Success
         } else {
             Error::NotAvailable
@@ -202,10 +450,15 @@ impl Context {
         let adapter = if_error!(self.check_adapter(adapter));
         if_error!(self.check_devty(ty));
 
-        if adapter.is_format_supported(check_fmt, rt, usage) {
-            Error::Success
-        } else {
+        // Textures can still be created if only mip map generation is unsupported.
+        let autogen = UsageFlags::AUTO_GEN_MIP_MAP;
+
+        if !adapter.is_format_supported(check_fmt, rt, usage - autogen) {
             Error::NotAvailable
+        } else if usage.intersects(autogen) && !adapter.supports_mip_autogen(check_fmt) {
+            Error::NoAutoGen
+        } else {
+            Error::Success
         }
     }
 
@@ -268,16 +521,30 @@ impl Context {
         &self,
         adapter: u32,
         ty: D3DDEVTYPE,
-        _src_fmt: D3DFORMAT,
-        _tgt_fmt: D3DFORMAT,
+        src_fmt: D3DFORMAT,
+        tgt_fmt: D3DFORMAT,
     ) -> Error {
-        if_error!(self.check_adapter(adapter));
+        let adapter = if_error!(self.check_adapter(adapter));
         if_error!(self.check_devty(ty));
 
-        // For most types we can simply convert them to the right format on-the-fly.
-        // TODO: we should at least validate the formats to make sure they are valid for back buffers.
+        // The conversion's result is always presented, so it has to be a back buffer format.
+        if !is_display_mode_format(tgt_fmt) {
+            return Error::NotAvailable;
+        }
+
+        // We can convert color formats on-the-fly, as long as we can render to the source format.
+        let can_convert = is_conversion_source_format(src_fmt)
+            && adapter.is_format_supported(
+                src_fmt,
+                ResourceType::Surface,
+                UsageFlags::RENDER_TARGET,
+            );
 
-        Error::Success
+        if can_convert {
+            Error::Success
+        } else {
+            Error::NotAvailable
+        }
     }
 
     /// Returns a structure describing the features and limits of an adapter.
@@ -286,7 +553,8 @@ impl Context {
         if_error!(self.check_devty(ty));
         let caps = if_error!(check_mut_ref(caps));
 
-        *caps = adapter.caps();
+        *caps = if_error!(adapter.caps());
+        caps.DeviceType = ty;
 
         Error::Success
     }
@@ -311,6 +579,17 @@ impl Context {
         if_error!(self.check_devty(ty));
         let ret = if_error!(check_mut_ref(device));
 
+        // Exactly one vertex processing mode must be requested.
+        let vertex_processing = flags
+            & (D3DCREATE_SOFTWARE_VERTEXPROCESSING
+                | D3DCREATE_HARDWARE_VERTEXPROCESSING
+                | D3DCREATE_MIXED_VERTEXPROCESSING);
+
+        if vertex_processing.count_ones() != 1 {
+            error!("Invalid vertex processing flags: {:#x}", flags);
+            return Error::InvalidCall;
+        }
+
         // TODO: support using multiple GPUs
         if flags & D3DCREATE_ADAPTERGROUP_DEVICE != 0 {
             warn!("Application requested the creation of a multi-GPU logical device");
@@ -338,7 +617,7 @@ impl Context {
         // This struct stores the original device creation parameters.
         let cp = D3DDEVICE_CREATION_PARAMETERS {
             AdapterOrdinal: adapter,
-            DeviceType: D3DDEVTYPE_HAL,
+            DeviceType: ty,
             hFocusWindow: focus,
             BehaviorFlags: flags,
         };
@@ -346,6 +625,7 @@ impl Context {
         // This structure describes some settings for the back buffer(s).
         // Since we don't support multiple adapters, we only use the first param in the array.
         let pp = if_error!(check_mut_ref(pp));
+        if_error!(Self::check_present_parameters(pp, focus));
 
         // Create the actual device.
         *ret = if_error!(crate::Device::new(
@@ -360,3 +640,95 @@ impl Context {
         Error::Success
     }
 }
+
+#[implementation(IDirect3D9Ex)]
+impl Context {
+    /// Returns the number of display modes which match a filter.
+    fn get_adapter_mode_count_ex(&self, adapter: u32, filter: *const D3DDISPLAYMODEFILTER) -> u32 {
+        let filter = match check_ref(filter) {
+            Ok(filter) => filter,
+            Err(_) => return 0,
+        };
+
+        self.adapters
+            .get(adapter as usize)
+            .map(|adapter| adapter.modes_ex(filter).len() as u32)
+            .unwrap_or_default()
+    }
+
+    /// Retrieves the list of display modes which match a filter.
+    fn enum_adapter_modes_ex(
+        &self,
+        adapter: u32,
+        filter: *const D3DDISPLAYMODEFILTER,
+        i: u32,
+        mode: *mut D3DDISPLAYMODEEX,
+    ) -> Error {
+        let adapter = if_error!(self.check_adapter(adapter));
+        let filter = if_error!(check_ref(filter));
+        let mode = if_error!(check_mut_ref(mode));
+
+        let modes = adapter.modes_ex(filter);
+        *mode = *if_error!(modes.get(i as usize).ok_or(Error::NotAvailable));
+
+        Error::Success
+    }
+
+    /// Retrieves the current display mode and rotation of the GPU.
+    fn get_adapter_display_mode_ex(
+        &self,
+        adapter: u32,
+        mode: *mut D3DDISPLAYMODEEX,
+        rotation: *mut D3DDISPLAYROTATION,
+    ) -> Error {
+        let mode_ex = if_error!(check_mut_ref(mode));
+
+        let mut mode = unsafe { mem::zeroed() };
+        if_not_success!(self.get_adapter_display_mode(adapter, &mut mode));
+
+        mode_ex.Width = mode.Width;
+        mode_ex.Height = mode.Height;
+        mode_ex.RefreshRate = mode.RefreshRate;
+        mode_ex.Format = mode.Format;
+        mode_ex.ScanLineOrdering = D3DSCANLINEORDERING_PROGRESSIVE;
+
+        // The rotation is optional.
+        if let Ok(rotation) = check_mut_ref(rotation) {
+            *rotation = D3DDISPLAYROTATION_IDENTITY;
+        }
+
+        Error::Success
+    }
+
+    /// Creates a logical device which implements the D3D9Ex extensions.
+    fn create_device_ex(
+        &self,
+        adapter: u32,
+        ty: D3DDEVTYPE,
+        focus: HWND,
+        flags: u32,
+        pp: *mut D3DPRESENT_PARAMETERS,
+        mode: *mut D3DDISPLAYMODEEX,
+        device: *mut *mut IDirect3DDevice9Ex,
+    ) -> Error {
+        // A full screen display mode is required iff the app wants a full screen device.
+        // The mode itself is already described by the presentation parameters.
+        let windowed = if_error!(check_ref(pp)).Windowed != 0;
+        if !windowed && mode.is_null() {
+            return Error::InvalidCall;
+        }
+
+        // Our devices always implement the Ex interface.
+        self.create_device(adapter, ty, focus, flags, pp, device as *mut *mut Device)
+    }
+
+    /// Retrieves the locally unique identifier of an adapter.
+    fn get_adapter_l_u_i_d(&self, adapter: u32, luid: *mut LUID) -> Error {
+        let adapter = if_error!(self.check_adapter(adapter));
+        let luid = if_error!(check_mut_ref(luid));
+
+        *luid = adapter.luid();
+
+        Error::Success
+    }
+}
@@ -7,10 +7,17 @@
 use winapi::shared::d3d9types::*;
 use winapi::shared::dxgiformat::*;
 
+const ATI1: u32 = 826889281;
 const ATI2: u32 = 843666497;
-const NULL: u32 = 0x4C4C554E;
+/// Dummy render target format, used by games for depth-only passes.
+pub const NULL: u32 = 0x4C4C554E;
 const RAWZ: u32 = 1515667794;
 const INTZ: u32 = 1515474505;
+const DF16: u32 = 0x3631_4644;
+const DF24: u32 = 0x3432_4644;
+
+/// Format of the desktop, which windowed back buffers use when they don't specify one.
+pub const DESKTOP_FORMAT: D3DFORMAT = D3DFMT_X8R8G8B8;
 
 /// Converts a display mode format to its corresponding DXGI format.
 pub fn d3d_display_format_to_dxgi(fmt: D3DFORMAT) -> DXGI_FORMAT {
@@ -18,7 +25,10 @@ pub fn d3d_display_format_to_dxgi(fmt: D3DFORMAT) -> DXGI_FORMAT {
         // We have to map all these formats to a format DXGI supports.
         D3DFMT_R8G8B8..=D3DFMT_A1R5G5B5 | D3DFMT_UNKNOWN => DXGI_FORMAT_B8G8R8A8_UNORM,
         D3DFMT_A2R10G10B10 => DXGI_FORMAT_R10G10B10A2_UNORM,
-        _ => panic!("Unknown D3D9 display format: {}", fmt),
+        _ => {
+            warn!("Unknown D3D9 display format: {}", fmt);
+            DXGI_FORMAT_B8G8R8A8_UNORM
+        }
     }
 }
 
@@ -37,6 +47,52 @@ pub fn is_display_mode_format(fmt: D3DFORMAT) -> bool {
     }
 }
 
+/// Swizzle value which selects a constant zero instead of a channel.
+pub const SWIZZLE_ZERO: u32 = 4;
+
+/// Swizzle value which selects a constant one instead of a channel.
+pub const SWIZZLE_ONE: u32 = 5;
+
+/// Swizzle value which selects a channel of the palette entry indexed by the sampled red channel.
+pub const SWIZZLE_PALETTE: u32 = 6;
+
+/// Maps the channels sampled from a texture to the channels a shader expects.
+///
+/// Every entry is the index of the channel (0 = R, ..., 3 = A) used for the
+/// corresponding output channel, or one of the `SWIZZLE_*` constants.
+pub type Swizzle = [u32; 4];
+
+/// The swizzle which returns the channels unchanged.
+pub const IDENTITY_SWIZZLE: Swizzle = [0, 1, 2, 3];
+
+/// Returns the swizzle needed to sample a texture of a given format as D3D9 would.
+///
+/// DXGI dropped the luminance formats, so they are stored in the red channel
+/// (and the green channel for alpha), and have to be expanded when sampled.
+pub fn format_swizzle(fmt: D3DFORMAT) -> Swizzle {
+    match fmt {
+        D3DFMT_L8 | D3DFMT_L16 => [0, 0, 0, SWIZZLE_ONE],
+        D3DFMT_A8L8 => [0, 0, 0, 1],
+        // Paletted formats store the index in the red channel.
+        D3DFMT_P8 => [SWIZZLE_PALETTE; 4],
+        _ => IDENTITY_SWIZZLE,
+    }
+}
+
+/// Checks if a given format can be converted to a back buffer format when presenting / stretching.
+///
+/// These are the color formats which can be rendered to, and then copied to a back buffer
+/// with a shader. Depth, compressed and video formats cannot be converted.
+pub fn is_conversion_source_format(fmt: D3DFORMAT) -> bool {
+    match fmt {
+        D3DFMT_A8R8G8B8..=D3DFMT_A1R5G5B5 => true,
+        D3DFMT_A2R10G10B10 | D3DFMT_A2B10G10R10 => true,
+        D3DFMT_X8B8G8R8 | D3DFMT_A8B8G8R8 => true,
+        D3DFMT_A16B16G16R16 | D3DFMT_A16B16G16R16F | D3DFMT_A32B32G32R32F => true,
+        _ => false,
+    }
+}
+
 /// Checks if a given format is a valid D/S buffer format.
 pub fn is_depth_stencil_format(fmt: D3DFORMAT) -> bool {
     match fmt {
@@ -44,12 +100,101 @@ pub fn is_depth_stencil_format(fmt: D3DFORMAT) -> bool {
         // Need to check all formats in this range to be valid.
         RAWZ => true,
         INTZ => true,
+        DF16 | DF24 => true,
         D3DFMT_D16_LOCKABLE..=D3DFMT_S8_LOCKABLE => true,
         _ => false,
     }
 }
 
-// This macro is used to generate bi-directional mapping between D3D and DXGI formats.
+/// Checks if a given format is the dummy `NULL` render target format.
+pub fn is_null_format(fmt: D3DFORMAT) -> bool {
+    fmt == NULL
+}
+
+/// Checks if a given format is one of the vendor-specific depth formats which can be sampled.
+///
+/// Games check for these to decide if they can read back the depth buffer in shaders.
+pub fn is_depth_texture_format(fmt: D3DFORMAT) -> bool {
+    match fmt {
+        INTZ | RAWZ | DF16 | DF24 => true,
+        _ => false,
+    }
+}
+
+/// Returns the formats of a depth / stencil texture's resource, depth / stencil view, and shader view.
+///
+/// Depth textures are created typeless, so they can be both rendered to and sampled from.
+fn depth_formats(fmt: D3DFORMAT) -> Option<(DXGI_FORMAT, DXGI_FORMAT, DXGI_FORMAT)> {
+    match fmt {
+        D3DFMT_D16 | D3DFMT_D16_LOCKABLE | DF16 => Some((
+            DXGI_FORMAT_R16_TYPELESS,
+            DXGI_FORMAT_D16_UNORM,
+            DXGI_FORMAT_R16_UNORM,
+        )),
+        D3DFMT_D24S8 | D3DFMT_D24X8 | D3DFMT_D24X4S4 | INTZ | RAWZ | DF24 => Some((
+            DXGI_FORMAT_R24G8_TYPELESS,
+            DXGI_FORMAT_D24_UNORM_S8_UINT,
+            DXGI_FORMAT_R24_UNORM_X8_TYPELESS,
+        )),
+        D3DFMT_D32 | D3DFMT_D32_LOCKABLE | D3DFMT_D32F_LOCKABLE => Some((
+            DXGI_FORMAT_R32_TYPELESS,
+            DXGI_FORMAT_D32_FLOAT,
+            DXGI_FORMAT_R32_FLOAT,
+        )),
+        D3DFMT_D24FS8 => Some((
+            DXGI_FORMAT_R32G8X24_TYPELESS,
+            DXGI_FORMAT_D32_FLOAT_S8X24_UINT,
+            DXGI_FORMAT_R32_FLOAT_X8X24_TYPELESS,
+        )),
+        _ => None,
+    }
+}
+
+/// Converts a depth / stencil format to the typeless format used to create the texture.
+pub fn depth_format_to_dxgi_typeless(fmt: D3DFORMAT) -> DXGI_FORMAT {
+    depth_formats(fmt).map_or(DXGI_FORMAT_UNKNOWN, |formats| formats.0)
+}
+
+/// Converts a depth / stencil format to the format used by depth / stencil views.
+pub fn depth_format_to_dxgi_typed(fmt: D3DFORMAT) -> DXGI_FORMAT {
+    depth_formats(fmt).map_or(DXGI_FORMAT_UNKNOWN, |formats| formats.1)
+}
+
+/// Converts a depth / stencil format to the format used for sampling the depth in shaders.
+pub fn depth_format_to_dxgi_srv(fmt: D3DFORMAT) -> DXGI_FORMAT {
+    depth_formats(fmt).map_or(DXGI_FORMAT_UNKNOWN, |formats| formats.2)
+}
+
+/// Returns the size in bytes of a block of pixels of a given format, and the width / height of the block.
+///
+/// Uncompressed formats use blocks of a single pixel,
+/// while block-compressed formats use blocks of 4x4 pixels.
+pub fn block_size(fmt: D3DFORMAT) -> (u32, u32) {
+    match fmt {
+        D3DFMT_A8 | D3DFMT_L8 | D3DFMT_P8 | D3DFMT_A4L4 | D3DFMT_R3G3B2 => (1, 1),
+
+        D3DFMT_R5G6B5 | D3DFMT_X1R5G5B5 | D3DFMT_A1R5G5B5 | D3DFMT_A4R4G4B4 | D3DFMT_X4R4G4B4
+        | D3DFMT_A8R3G3B2 | D3DFMT_A8L8 | D3DFMT_A8P8 | D3DFMT_L16 | D3DFMT_V8U8
+        | D3DFMT_L6V5U5 | D3DFMT_CxV8U8 | D3DFMT_D16 | D3DFMT_D16_LOCKABLE | D3DFMT_D15S1
+        | D3DFMT_R16F | D3DFMT_INDEX16 | D3DFMT_R8G8_B8G8 | D3DFMT_G8R8_G8B8 | D3DFMT_UYVY
+        | D3DFMT_YUY2 => (2, 1),
+
+        D3DFMT_R8G8B8 => (3, 1),
+
+        D3DFMT_A16B16G16R16 | D3DFMT_A16B16G16R16F | D3DFMT_G32R32F | D3DFMT_Q16W16V16U16 => (8, 1),
+
+        D3DFMT_A32B32G32R32F => (16, 1),
+
+        // Block compressed formats.
+        D3DFMT_DXT1 | ATI1 => (8, 4),
+        D3DFMT_DXT2 | D3DFMT_DXT3 | D3DFMT_DXT4 | D3DFMT_DXT5 | ATI2 => (16, 4),
+
+        // Most of the remaining formats are 32-bit.
+        _ => (4, 1),
+    }
+}
+
+// This macro is used to generate the mapping from D3D to DXGI formats.
 macro_rules! format_conv {
     ($($a:path => $b:path,)*) => {
         /// Converts a general resource format to a DXGI format.
@@ -58,18 +203,79 @@ macro_rules! format_conv {
             match fmt {
                 $($a => $b,)*
 
-                _ => panic!("Unknown D3D9 format: {}", fmt),
+                // Games often probe for vendor-specific FourCC formats, so we shouldn't crash here.
+                _ => {
+                    warn!("Unknown D3D9 format: {}", fmt);
+                    DXGI_FORMAT_UNKNOWN
+                }
             }
         }
+    }
+}
 
-        /// Converts a DXGI format back into a Direct3D format.
-        pub fn dxgi_format_to_d3d(fmt: DXGI_FORMAT) -> D3DFORMAT {
-            // Some DXGI formats map to multiple D3D9 formats.
-            #[allow(unreachable_patterns)]
-            match fmt {
-                $($b => $a,)*
-                _ => panic!("Unknown DXGI format: {}", fmt),
-            }
+/// Converts a DXGI format back into a Direct3D format.
+///
+/// Some DXGI formats are used for multiple D3D9 formats, so this returns the most common one.
+pub fn dxgi_format_to_d3d(fmt: DXGI_FORMAT) -> D3DFORMAT {
+    match fmt {
+        DXGI_FORMAT_A8_UNORM => D3DFMT_A8,
+        DXGI_FORMAT_R8_UNORM => D3DFMT_L8,
+
+        DXGI_FORMAT_B5G6R5_UNORM => D3DFMT_R5G6B5,
+        DXGI_FORMAT_B4G4R4A4_UNORM => D3DFMT_X4R4G4B4,
+        DXGI_FORMAT_B5G5R5A1_UNORM => D3DFMT_X1R5G5B5,
+        DXGI_FORMAT_R8G8_UNORM => D3DFMT_A8L8,
+        DXGI_FORMAT_R16_UNORM => D3DFMT_L16,
+
+        DXGI_FORMAT_R8G8B8A8_UNORM => D3DFMT_X8B8G8R8,
+        DXGI_FORMAT_B8G8R8X8_UNORM => D3DFMT_X8R8G8B8,
+        DXGI_FORMAT_B8G8R8A8_UNORM => D3DFMT_A8R8G8B8,
+        DXGI_FORMAT_R16G16_UNORM => D3DFMT_G16R16,
+
+        DXGI_FORMAT_R10G10B10A2_UNORM => D3DFMT_A2R10G10B10,
+
+        DXGI_FORMAT_D16_UNORM => D3DFMT_D16,
+        DXGI_FORMAT_D24_UNORM_S8_UINT => D3DFMT_D24S8,
+        DXGI_FORMAT_D32_FLOAT => D3DFMT_D32,
+        DXGI_FORMAT_D32_FLOAT_S8X24_UINT => D3DFMT_D24FS8,
+        // Depth textures are created typeless.
+        DXGI_FORMAT_R16_TYPELESS => D3DFMT_D16,
+        DXGI_FORMAT_R24G8_TYPELESS => D3DFMT_D24S8,
+        DXGI_FORMAT_R32_TYPELESS => D3DFMT_D32,
+        DXGI_FORMAT_R32G8X24_TYPELESS => D3DFMT_D24FS8,
+
+        // DXT2 and DXT4 only differ in whether the app premultiplied the alpha,
+        // so these map back to the formats apps use most.
+        DXGI_FORMAT_BC1_UNORM => D3DFMT_DXT1,
+        DXGI_FORMAT_BC2_UNORM => D3DFMT_DXT3,
+        DXGI_FORMAT_BC3_UNORM => D3DFMT_DXT5,
+        DXGI_FORMAT_BC4_UNORM => ATI1,
+        DXGI_FORMAT_BC5_UNORM => ATI2,
+
+        DXGI_FORMAT_G8R8_G8B8_UNORM => D3DFMT_R8G8_B8G8,
+        DXGI_FORMAT_R8G8_B8G8_UNORM => D3DFMT_G8R8_G8B8,
+
+        DXGI_FORMAT_R8G8_SNORM => D3DFMT_V8U8,
+        DXGI_FORMAT_R8G8B8A8_SNORM => D3DFMT_Q8W8V8U8,
+        DXGI_FORMAT_R16G16_SNORM => D3DFMT_V16U16,
+        DXGI_FORMAT_R16G16B16A16_SNORM => D3DFMT_Q16W16V16U16,
+
+        DXGI_FORMAT_R16_UINT => D3DFMT_INDEX16,
+        DXGI_FORMAT_R32_UINT => D3DFMT_INDEX32,
+
+        DXGI_FORMAT_R16_FLOAT => D3DFMT_R16F,
+        DXGI_FORMAT_R16G16_FLOAT => D3DFMT_G16R16F,
+        DXGI_FORMAT_R16G16B16A16_UNORM => D3DFMT_A16B16G16R16,
+        DXGI_FORMAT_R16G16B16A16_FLOAT => D3DFMT_A16B16G16R16F,
+        DXGI_FORMAT_R32_FLOAT => D3DFMT_R32F,
+        DXGI_FORMAT_R32G32_FLOAT => D3DFMT_G32R32F,
+        DXGI_FORMAT_R32G32B32A32_FLOAT => D3DFMT_A32B32G32R32F,
+
+        DXGI_FORMAT_UNKNOWN => D3DFMT_UNKNOWN,
+
+        _ => {
+            warn!("Unknown DXGI format: {}", fmt);
+            D3DFMT_UNKNOWN
         }
     }
 }
@@ -79,7 +285,9 @@ macro_rules! format_conv {
 format_conv! {
     // 8 bit formats
     D3DFMT_A8 => DXGI_FORMAT_A8_UNORM,
+    // Luminance and paletted formats have to be swizzled, see `format_swizzle`.
     D3DFMT_L8 => DXGI_FORMAT_R8_UNORM,
+    D3DFMT_P8 => DXGI_FORMAT_R8_UNORM,
 
     // 16 bit formats
     D3DFMT_R5G6B5 => DXGI_FORMAT_B5G6R5_UNORM,
@@ -108,23 +316,32 @@ format_conv! {
     D3DFMT_D24X8 => DXGI_FORMAT_D24_UNORM_S8_UINT,
     D3DFMT_D32 => DXGI_FORMAT_D32_FLOAT,
     D3DFMT_D32F_LOCKABLE => DXGI_FORMAT_D32_FLOAT,
+    D3DFMT_D32_LOCKABLE => DXGI_FORMAT_D32_FLOAT,
+    // There are no 4-bit stencil or float 24-bit depth formats, so we use bigger ones.
+    D3DFMT_D24X4S4 => DXGI_FORMAT_D24_UNORM_S8_UINT,
+    D3DFMT_D24FS8 => DXGI_FORMAT_D32_FLOAT_S8X24_UINT,
 
     // Compressed formats
     D3DFMT_DXT1 => DXGI_FORMAT_BC1_UNORM,
+    // DXT2 and DXT4 are the premultiplied alpha variants of DXT3 and DXT5.
     D3DFMT_DXT2 => DXGI_FORMAT_BC2_UNORM,
-    D3DFMT_DXT3 => DXGI_FORMAT_BC3_UNORM,
-    D3DFMT_DXT4 => DXGI_FORMAT_BC4_UNORM,
-    D3DFMT_DXT5 => DXGI_FORMAT_BC5_UNORM,
+    D3DFMT_DXT3 => DXGI_FORMAT_BC2_UNORM,
+    D3DFMT_DXT4 => DXGI_FORMAT_BC3_UNORM,
+    D3DFMT_DXT5 => DXGI_FORMAT_BC3_UNORM,
+
+    // ATI1 is a single channel format, equivalent to BC4.
+    ATI1 => DXGI_FORMAT_BC4_UNORM,
 
     // ATI2, TODO: https://aras-p.info/texts/D3D9GPUHacks.html red and green channel swap
     ATI2 => DXGI_FORMAT_BC5_UNORM,
-    // TODO: What is NULL even?
-    NULL => NULL,
     // Depth Stencil (https://forum.beyond3d.com/threads/multisample-depth-buffer-resolve.38711/)
     // claiming that RAWZ is different in terms of compression, maybe we should make RAWZ fail
     // and only support INTZ
     RAWZ => DXGI_FORMAT_D24_UNORM_S8_UINT,
     INTZ => DXGI_FORMAT_D24_UNORM_S8_UINT,
+    // AMD's depth texture formats, without stencil.
+    DF16 => DXGI_FORMAT_D16_UNORM,
+    DF24 => DXGI_FORMAT_D24_UNORM_S8_UINT,
 
     // Special formats: mostly used for hardware video.
     D3DFMT_R8G8_B8G8 => DXGI_FORMAT_G8R8_G8B8_UNORM,
@@ -134,6 +351,13 @@ format_conv! {
     D3DFMT_V8U8 => DXGI_FORMAT_R8G8_SNORM,
     D3DFMT_Q8W8V8U8 => DXGI_FORMAT_R8G8B8A8_SNORM,
     D3DFMT_V16U16 => DXGI_FORMAT_R16G16_SNORM,
+    D3DFMT_Q16W16V16U16 => DXGI_FORMAT_R16G16B16A16_SNORM,
+    // The third component of this format is computed in the shader.
+    D3DFMT_CxV8U8 => DXGI_FORMAT_R8G8_SNORM,
+
+    // Index buffer formats
+    D3DFMT_INDEX16 => DXGI_FORMAT_R16_UINT,
+    D3DFMT_INDEX32 => DXGI_FORMAT_R32_UINT,
 
     // Buffer formats
     D3DFMT_R16F => DXGI_FORMAT_R16_FLOAT,
@@ -146,25 +370,84 @@ format_conv! {
 
     // Unknown format
     D3DFMT_UNKNOWN => DXGI_FORMAT_UNKNOWN,
+    // Nothing gets written to a NULL render target, so it has no backing format.
+    NULL => DXGI_FORMAT_UNKNOWN,
 
-    // Unsupported formats
-    // TODO: some formats have no support in modern DXGI.
-    // We might still be able to approximate them with some other formats though.
-    D3DFMT_P8 => DXGI_FORMAT_UNKNOWN,
+    // Unsupported formats: these have no DXGI equivalent,
+    // and would have to be converted on the CPU when uploaded.
     D3DFMT_A8P8 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_A4L4 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_R3G3B2 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_A8R3G3B2 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_R8G8B8 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_D15S1 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_D24FS8 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_D24X4S4 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_S8_LOCKABLE => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_CxV8U8 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_L6V5U5 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_X8L8V8U8 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_A2W10V10U10 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_Q16W16V16U16 => DXGI_FORMAT_UNKNOWN,
     D3DFMT_UYVY => DXGI_FORMAT_UNKNOWN,
     D3DFMT_YUY2 => DXGI_FORMAT_UNKNOWN,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dxgi_formats_map_back_to_their_source() {
+        let formats = [
+            D3DFMT_A8,
+            D3DFMT_L8,
+            D3DFMT_R5G6B5,
+            D3DFMT_A8L8,
+            D3DFMT_X8R8G8B8,
+            D3DFMT_A8R8G8B8,
+            D3DFMT_A2R10G10B10,
+            D3DFMT_D16,
+            D3DFMT_D24S8,
+            D3DFMT_DXT1,
+            D3DFMT_DXT3,
+            D3DFMT_DXT5,
+            ATI1,
+            ATI2,
+            D3DFMT_V8U8,
+            D3DFMT_INDEX16,
+            D3DFMT_INDEX32,
+            D3DFMT_A16B16G16R16F,
+            D3DFMT_A32B32G32R32F,
+        ];
+
+        for &fmt in &formats {
+            assert_eq!(dxgi_format_to_d3d(d3d_format_to_dxgi(fmt)), fmt);
+        }
+    }
+
+    #[test]
+    fn premultiplied_formats_share_a_dxgi_format() {
+        assert_eq!(
+            dxgi_format_to_d3d(d3d_format_to_dxgi(D3DFMT_DXT2)),
+            D3DFMT_DXT3
+        );
+        assert_eq!(
+            dxgi_format_to_d3d(d3d_format_to_dxgi(D3DFMT_DXT4)),
+            D3DFMT_DXT5
+        );
+    }
+
+    #[test]
+    fn unknown_formats_do_not_panic() {
+        assert_eq!(dxgi_format_to_d3d(DXGI_FORMAT_BC7_UNORM), D3DFMT_UNKNOWN);
+        assert_eq!(
+            d3d_display_format_to_dxgi(D3DFMT_DXT1),
+            DXGI_FORMAT_B8G8R8A8_UNORM
+        );
+    }
+
+    #[test]
+    fn luminance_formats_are_expanded_when_sampled() {
+        assert_eq!(format_swizzle(D3DFMT_L8), [0, 0, 0, SWIZZLE_ONE]);
+        assert_eq!(format_swizzle(D3DFMT_A8L8), [0, 0, 0, 1]);
+        assert_eq!(format_swizzle(D3DFMT_P8), [SWIZZLE_PALETTE; 4]);
+        assert_eq!(format_swizzle(D3DFMT_A8R8G8B8), IDENTITY_SWIZZLE);
+    }
+}
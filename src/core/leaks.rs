@@ -0,0 +1,82 @@
+//! Detection of COM objects which are never released.
+//!
+//! When the `leak-check` feature is enabled, every object created by the library
+//! is registered here, and unregistered when its last reference is released.
+//! The objects which are still alive when the D3D9 context is destroyed
+//! are then logged, which helps with tracking down mismatched `AddRef` / `Release` calls.
+//!
+//! Without the feature, all of these functions do nothing.
+
+#[cfg(feature = "leak-check")]
+mod registry {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use winapi::um::unknwnbase::IUnknown;
+
+    /// Objects which are currently alive, with their type names.
+    ///
+    /// The map is still usable after a panic while it was locked,
+    /// so a poisoned lock must not take the whole process down with it.
+    static OBJECTS: Mutex<Option<HashMap<usize, &'static str>>> = Mutex::new(None);
+
+    pub fn register(object: usize, name: &'static str) {
+        let mut objects = OBJECTS.lock().unwrap_or_else(|e| e.into_inner());
+        objects
+            .get_or_insert_with(HashMap::new)
+            .insert(object, name);
+    }
+
+    pub fn unregister(object: usize) {
+        let mut objects = OBJECTS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(objects) = objects.as_mut() {
+            objects.remove(&object);
+        }
+    }
+
+    pub fn report() {
+        // Copy the list, since querying the reference counts calls back into the objects.
+        let alive: Vec<_> = match OBJECTS.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            Some(objects) => objects.iter().map(|(&obj, &name)| (obj, name)).collect(),
+            None => return,
+        };
+
+        if alive.is_empty() {
+            return;
+        }
+
+        warn!("{} objects are still alive:", alive.len());
+
+        for (object, name) in alive {
+            // The only way to read the count is through the return values of these methods.
+            let refs = unsafe {
+                let unknwn = &*(object as *const IUnknown);
+                unknwn.AddRef();
+                unknwn.Release()
+            };
+
+            warn!("{} at {:#x}, with {} references", name, object, refs);
+        }
+    }
+}
+
+/// Starts tracking a newly created object.
+#[inline]
+pub fn register<T>(_object: *const T) {
+    #[cfg(feature = "leak-check")]
+    registry::register(_object as usize, std::any::type_name::<T>());
+}
+
+/// Stops tracking an object, once its last reference is released.
+#[inline]
+pub fn unregister<T>(_object: *const T) {
+    #[cfg(feature = "leak-check")]
+    registry::unregister(_object as usize);
+}
+
+/// Logs every object which is still alive.
+#[inline]
+pub fn report() {
+    #[cfg(feature = "leak-check")]
+    registry::report();
+}
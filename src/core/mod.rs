@@ -6,6 +6,10 @@
 mod enums;
 pub use self::enums::*;
 
+pub mod config;
+
+pub mod leaks;
+
 pub mod fmt;
 
 pub mod msample;
@@ -38,7 +42,7 @@ pub fn check_hresult(hr: i32, msg: &'static str) -> Error {
     if hr != 0 {
         let err = std::io::Error::from_raw_os_error(hr);
         error!("{}: {}", msg, err);
-        Error::DriverInternalError
+        Error::from_hresult(hr)
     } else {
         Error::Success
     }
@@ -59,16 +63,35 @@ pub fn check_mut_ref<'a, T>(ptr: *mut T) -> Result<&'a mut T, Error> {
 /// Unsafe because there is no way of checking if `this` implements the desired interface.
 pub unsafe fn new_com_interface<T, I>(this: T) -> ComPtr<I> {
     // Danger right here.
-    ComPtr::new(Box::into_raw(Box::new(this)) as *mut _)
+    let ptr = Box::into_raw(Box::new(this));
+    leaks::register(ptr);
+    ComPtr::new(ptr as *mut _)
 }
 
 /// Creates a new reference to a COM interface.
+///
+/// Null pointers are returned as they are, since getters return them for unset state.
 pub fn com_ref<T>(iface: *const T) -> *mut T {
     let unknwn = iface as *const winapi::um::unknwnbase::IUnknown;
 
-    unsafe {
-        (*unknwn).AddRef();
+    if let Some(unknwn) = unsafe { unknwn.as_ref() } {
+        unsafe {
+            unknwn.AddRef();
+        }
     }
 
     iface as *mut _
 }
+
+/// Queries a COM object for an interface, storing a new reference to it in `ret`.
+pub fn query_interface<T>(
+    obj: *const T,
+    riid: &winapi::shared::guiddef::GUID,
+    ret: &mut usize,
+) -> Error {
+    let unknwn = obj as *const winapi::um::unknwnbase::IUnknown;
+
+    let result = unsafe { (*unknwn).QueryInterface(riid, ret as *mut usize as *mut _) };
+
+    Error::from_hresult(result)
+}
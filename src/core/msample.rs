@@ -1,13 +1,23 @@
 //! Implements multisampling-related conversion functions.
 
 use std::cmp;
-use winapi::shared::{d3d9types::D3DMULTISAMPLE_TYPE, dxgitype::DXGI_SAMPLE_DESC};
+use winapi::shared::{
+    d3d9types::{D3DMULTISAMPLE_NONMASKABLE, D3DMULTISAMPLE_TYPE},
+    dxgitype::DXGI_SAMPLE_DESC,
+};
 
 /// Converts a D3D9 multisample type / quality pair to a DXGI_SAMPLE_DESC structure.
 pub fn d3d9_to_dxgi_samples(ty: D3DMULTISAMPLE_TYPE, qlt: u32) -> DXGI_SAMPLE_DESC {
-    // TODO: see how to handle non-maskable multisampling.
+    // Non-maskable multisampling is not supported, it becomes a single sample
+    // (whose only quality level is 0).
+    if ty <= D3DMULTISAMPLE_NONMASKABLE {
+        return DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        };
+    }
 
-    let count = cmp::min(cmp::max(1, ty), 16);
+    let count = cmp::min(ty, 16);
 
     DXGI_SAMPLE_DESC {
         Count: count,
@@ -1,4 +1,4 @@
-use std::{mem, ptr};
+use std::{mem, ptr, slice};
 
 use winapi::um::d3d11::*;
 
@@ -6,7 +6,7 @@ use comptr::ComPtr;
 
 use crate::core::*;
 
-use super::util::d3d_usage_to_d3d11;
+use super::util::d3d_buffer_usage_to_d3d11;
 use crate::Error;
 
 /// Wrapper for a vertex/index buffer.
@@ -24,7 +24,14 @@ impl Buffer {
         pool: MemoryPool,
         bind_flags: u32,
     ) -> Result<Self, Error> {
-        let (usage, _, cpu_flags) = d3d_usage_to_d3d11(usage, pool)?;
+        let (usage, cpu_flags) = d3d_buffer_usage_to_d3d11(usage, pool)?;
+
+        // Staging buffers cannot be bound, they are copied to a GPU buffer first.
+        let bind_flags = if usage == D3D11_USAGE_STAGING {
+            0
+        } else {
+            bind_flags
+        };
 
         let desc = D3D11_BUFFER_DESC {
             ByteWidth: len,
@@ -47,6 +54,53 @@ impl Buffer {
         Ok(Self { buffer })
     }
 
+    /// Creates a buffer of the same size which only the GPU can access.
+    ///
+    /// Used to upload the contents of buffers which live in system memory.
+    pub fn new_gpu_copy(&self, device: &ID3D11Device, bind_flags: u32) -> Result<Self, Error> {
+        let desc = D3D11_BUFFER_DESC {
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: bind_flags,
+            CPUAccessFlags: 0,
+            ..self.desc()
+        };
+
+        let buffer = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateBuffer(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create GPU buffer"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { buffer })
+    }
+
+    /// Creates a buffer of the same size which the CPU can read from and write to.
+    ///
+    /// Used to lock buffers which live in VRAM: the app locks the copy,
+    /// which is then copied back to the GPU buffer when it's unlocked.
+    pub fn new_staging_copy(&self, device: &ID3D11Device) -> Result<Self, Error> {
+        let desc = D3D11_BUFFER_DESC {
+            Usage: D3D11_USAGE_STAGING,
+            BindFlags: 0,
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ | D3D11_CPU_ACCESS_WRITE,
+            ..self.desc()
+        };
+
+        let buffer = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateBuffer(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create staging buffer"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { buffer })
+    }
+
     /// Retrieves this buffer as a resource.
     pub fn as_resource(&self) -> *mut ID3D11Resource {
         self.buffer.upcast().as_mut()
@@ -55,7 +109,7 @@ impl Buffer {
     /// Retrieves the description of this buffer.
     pub fn desc(&self) -> D3D11_BUFFER_DESC {
         unsafe {
-            let mut buf = mem::uninitialized();
+            let mut buf = mem::zeroed();
             self.buffer.GetDesc(&mut buf);
             buf
         }
@@ -64,4 +118,117 @@ impl Buffer {
     pub fn as_buffer(&self) -> *mut ID3D11Buffer {
         self.buffer.as_mut()
     }
+
+    /// Reads back the whole contents of this buffer.
+    ///
+    /// Buffers which cannot be read by the CPU are first copied to a staging buffer.
+    pub fn read(&self, device: &ID3D11Device, ctx: &ID3D11DeviceContext) -> Result<Vec<u8>, Error> {
+        let desc = self.desc();
+
+        let staging = if desc.CPUAccessFlags & D3D11_CPU_ACCESS_READ != 0 {
+            self.buffer.clone()
+        } else {
+            let staging_desc = D3D11_BUFFER_DESC {
+                Usage: D3D11_USAGE_STAGING,
+                BindFlags: 0,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+                ..desc
+            };
+
+            unsafe {
+                let mut ptr = ptr::null_mut();
+
+                let result = device.CreateBuffer(&staging_desc, ptr::null(), &mut ptr);
+                if_not_success_err!(check_hresult(result, "Failed to create staging buffer"));
+
+                let staging = ComPtr::new(ptr);
+                ctx.CopyResource(staging.upcast().as_mut(), self.as_resource());
+                staging
+            }
+        };
+
+        let resource = staging.upcast().as_mut();
+
+        unsafe {
+            let mut mapped = mem::zeroed();
+
+            let result = ctx.Map(resource, 0, D3D11_MAP_READ, 0, &mut mapped);
+            if_not_success_err!(check_hresult(result, "Failed to map buffer for reading"));
+
+            let data = slice::from_raw_parts(mapped.pData as *const u8, desc.ByteWidth as usize);
+            let data = data.to_vec();
+
+            ctx.Unmap(resource, 0);
+
+            Ok(data)
+        }
+    }
+
+    /// Overwrites part of this buffer's contents, starting at `offset`.
+    pub fn write(
+        &self,
+        device: &ID3D11Device,
+        ctx: &ID3D11DeviceContext,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let desc = self.desc();
+
+        if offset as usize + data.len() > desc.ByteWidth as usize {
+            return Err(Error::InvalidCall);
+        }
+
+        let resource = self.as_resource();
+
+        // Dynamic buffers can only be written to by discarding their contents,
+        // since the GPU might still be using the old ones. The rest has to be written back.
+        if desc.Usage == D3D11_USAGE_DYNAMIC {
+            let mut contents = self.read(device, ctx)?;
+            contents[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+
+            unsafe {
+                let mut mapped = mem::zeroed();
+
+                let result = ctx.Map(resource, 0, D3D11_MAP_WRITE_DISCARD, 0, &mut mapped);
+                if_not_success_err!(check_hresult(result, "Failed to map buffer for writing"));
+
+                ptr::copy_nonoverlapping(
+                    contents.as_ptr(),
+                    mapped.pData as *mut u8,
+                    contents.len(),
+                );
+
+                ctx.Unmap(resource, 0);
+            }
+
+            return Ok(());
+        }
+
+        unsafe {
+            if desc.CPUAccessFlags & D3D11_CPU_ACCESS_WRITE == 0 {
+                let dest_box = D3D11_BOX {
+                    left: offset,
+                    right: offset + data.len() as u32,
+                    top: 0,
+                    bottom: 1,
+                    front: 0,
+                    back: 1,
+                };
+
+                ctx.UpdateSubresource(resource, 0, &dest_box, data.as_ptr() as *const _, 0, 0);
+            } else {
+                let mut mapped = mem::zeroed();
+
+                let result = ctx.Map(resource, 0, D3D11_MAP_WRITE, 0, &mut mapped);
+                if_not_success_err!(check_hresult(result, "Failed to map buffer for writing"));
+
+                let dest = (mapped.pData as *mut u8).offset(offset as isize);
+                ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
+
+                ctx.Unmap(resource, 0);
+            }
+        }
+
+        Ok(())
+    }
 }
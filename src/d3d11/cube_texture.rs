@@ -0,0 +1,148 @@
+use std::ptr;
+
+use winapi::shared::d3d9types::*;
+use winapi::um::{d3d11::*, d3dcommon::D3D11_SRV_DIMENSION_TEXTURECUBE};
+
+use comptr::ComPtr;
+
+use crate::core::{fmt::d3d_format_to_dxgi, msample::d3d9_to_dxgi_samples, *};
+
+use super::util::d3d_usage_to_d3d11;
+use super::Texture2D;
+use crate::Error;
+
+/// Converts a cube map face to the index of the array slice storing it.
+///
+/// Returns `None` if the face is invalid.
+pub fn cube_face_to_array_slice(face: D3DCUBEMAP_FACES) -> Option<u32> {
+    // D3D11 stores the faces in the same order as D3D9 enumerates them.
+    match face {
+        D3DCUBEMAP_FACE_POSITIVE_X => Some(0),
+        D3DCUBEMAP_FACE_NEGATIVE_X => Some(1),
+        D3DCUBEMAP_FACE_POSITIVE_Y => Some(2),
+        D3DCUBEMAP_FACE_NEGATIVE_Y => Some(3),
+        D3DCUBEMAP_FACE_POSITIVE_Z => Some(4),
+        D3DCUBEMAP_FACE_NEGATIVE_Z => Some(5),
+        _ => None,
+    }
+}
+
+/// Wrapper for a D3D11 2D texture array, used as a cube map.
+#[derive(Clone)]
+pub struct CubeTexture {
+    texture: Texture2D,
+    // Only available if the texture can be bound to shaders.
+    view: Option<ComPtr<ID3D11ShaderResourceView>>,
+}
+
+impl CubeTexture {
+    /// Creates a new cube texture.
+    pub fn new(
+        device: &ID3D11Device,
+        edge_len: u32,
+        levels: u32,
+        uflags: UsageFlags,
+        fmt: D3DFORMAT,
+        pool: MemoryPool,
+    ) -> Result<Self, Error> {
+        let (usage, bind_flags, cpu_flags) = d3d_usage_to_d3d11(uflags, pool)?;
+        let fmt = d3d_format_to_dxgi(fmt);
+
+        let mut misc_flags = D3D11_RESOURCE_MISC_TEXTURECUBE;
+
+        if uflags.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+            misc_flags |= D3D11_RESOURCE_MISC_GENERATE_MIPS;
+        }
+
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: edge_len,
+            Height: edge_len,
+            MipLevels: levels,
+            ArraySize: 6,
+            Format: fmt,
+            SampleDesc: d3d9_to_dxgi_samples(0, 0),
+            Usage: usage,
+            BindFlags: bind_flags,
+            CPUAccessFlags: cpu_flags,
+            MiscFlags: misc_flags,
+        };
+
+        let texture: Texture2D = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create cube texture"));
+
+            ComPtr::new(ptr).into()
+        };
+
+        let view = if bind_flags & D3D11_BIND_SHADER_RESOURCE != 0 {
+            Some(Self::create_cube_view(device, &texture, 0)?)
+        } else {
+            None
+        };
+
+        Ok(Self { texture, view })
+    }
+
+    /// Creates a view which samples a texture array as a cube map,
+    /// starting with the `most_detailed_mip` level.
+    pub fn create_cube_view(
+        device: &ID3D11Device,
+        texture: &Texture2D,
+        most_detailed_mip: u32,
+    ) -> Result<ComPtr<ID3D11ShaderResourceView>, Error> {
+        let desc = texture.desc();
+
+        let mut view_desc = D3D11_SHADER_RESOURCE_VIEW_DESC {
+            Format: desc.Format,
+            ViewDimension: D3D11_SRV_DIMENSION_TEXTURECUBE,
+            u: unsafe { std::mem::zeroed() },
+        };
+
+        unsafe {
+            *view_desc.u.TextureCube_mut() = D3D11_TEXCUBE_SRV {
+                MostDetailedMip: most_detailed_mip,
+                MipLevels: desc.MipLevels - most_detailed_mip,
+            };
+        }
+
+        let view = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result =
+                device.CreateShaderResourceView(texture.as_resource(), &view_desc, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create cube texture view"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(view)
+    }
+
+    /// Retrieves the underlying texture array.
+    pub fn texture(&self) -> &Texture2D {
+        &self.texture
+    }
+
+    /// Retrieves the cube map view of this texture, if it can be used in shaders.
+    pub fn shader_view(&self) -> Option<&ComPtr<ID3D11ShaderResourceView>> {
+        self.view.as_ref()
+    }
+
+    /// Calculates the index of the subresource storing a face's mip level.
+    pub fn calc_subresource(
+        &self,
+        face: D3DCUBEMAP_FACES,
+        level: u32,
+        num_levels: u32,
+    ) -> Option<u32> {
+        cube_face_to_array_slice(face)
+            .map(|slice| self.texture.calc_subresource(level, slice, num_levels))
+    }
+
+    /// Retrieves this texture as a resource.
+    pub fn as_resource(&self) -> *mut ID3D11Resource {
+        self.texture.as_resource()
+    }
+}
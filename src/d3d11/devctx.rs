@@ -1,3 +1,5 @@
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::{mem, ops, ptr};
 
 use winapi::shared::{d3d9types::*, winerror};
@@ -8,22 +10,97 @@ use comptr::ComPtr;
 use crate::core::*;
 use crate::Error;
 
+use super::{CommandSink, DeferredSink, ImmediateSink};
+
 /// Wrapper for a D3D11 immediate context.
+///
+/// The commands are sent through a `CommandSink`, which might record them
+/// to be submitted to the immediate context later.
+///
+/// The immediate context is not thread safe, so if the device is used
+/// from multiple threads, every access to it has to go through a lock.
 #[derive(Clone)]
 pub struct DeviceContext {
     ctx: ComPtr<ID3D11DeviceContext>,
+    sink: Rc<dyn CommandSink>,
+    lock: Option<Arc<Mutex<()>>>,
+}
+
+/// Grants access to a device context, for as long as it's alive.
+pub struct ContextGuard<'a> {
+    ctx: &'a ID3D11DeviceContext,
+    _lock: Option<MutexGuard<'a, ()>>,
 }
 
 impl DeviceContext {
     /// Retrieve's a device's immediate context.
-    pub fn new(device: &ID3D11Device) -> Self {
-        let ctx = unsafe {
+    ///
+    /// If `multithreaded` is set, all accesses to the context are serialized.
+    /// If `deferred` is set, commands are recorded into a deferred context,
+    /// and only submitted when `submit` is called or data is read back.
+    pub fn new(device: &ID3D11Device, multithreaded: bool, deferred: bool) -> Self {
+        let ctx: ComPtr<ID3D11DeviceContext> = unsafe {
             let mut ptr = ptr::null_mut();
             device.GetImmediateContext(&mut ptr);
             ComPtr::new(ptr)
         };
 
-        Self { ctx }
+        let deferred = if deferred {
+            DeferredSink::new(device)
+                .map_err(|_| warn!("Falling back to recording commands into the immediate context"))
+                .ok()
+        } else {
+            None
+        };
+
+        let sink: Rc<dyn CommandSink> = match deferred {
+            Some(sink) => Rc::new(sink),
+            None => Rc::new(ImmediateSink::new(ctx.clone())),
+        };
+
+        let lock = if multithreaded {
+            Some(Arc::new(Mutex::new(())))
+        } else {
+            None
+        };
+
+        Self { ctx, sink, lock }
+    }
+
+    /// Acquires exclusive access to the context into which commands are recorded.
+    ///
+    /// The lock is not reentrant: the guard must be dropped before calling
+    /// any other function which uses the context.
+    pub fn lock(&self) -> ContextGuard<'_> {
+        self.guard(self.sink.context())
+    }
+
+    /// Acquires exclusive access to the immediate context, after submitting
+    /// all of the recorded commands.
+    ///
+    /// Required for anything which reads data back from the GPU.
+    pub fn immediate(&self) -> ContextGuard<'_> {
+        let guard = self.guard(&self.ctx);
+
+        // Errors are logged when they happen, and the immediate context is still usable.
+        let _ = self.sink.submit(&self.ctx);
+
+        guard
+    }
+
+    /// Submits all of the recorded commands to the immediate context.
+    pub fn submit(&self) -> Result<(), Error> {
+        let _guard = self.guard(&self.ctx);
+        self.sink.submit(&self.ctx)
+    }
+
+    fn guard<'a>(&'a self, ctx: &'a ID3D11DeviceContext) -> ContextGuard<'a> {
+        let lock = self.lock.as_ref().map(|lock| {
+            // A panic on another thread doesn't leave the context in an invalid state.
+            lock.lock().unwrap_or_else(|err| err.into_inner())
+        });
+
+        ContextGuard { ctx, _lock: lock }
     }
 
     /// Maps a resource.
@@ -34,6 +111,46 @@ impl DeviceContext {
         flags: LockFlags,
         usage: UsageFlags,
     ) -> Result<D3DLOCKED_RECT, Error> {
+        let mapped = self.map_subresource(res, subres, flags, usage)?;
+
+        // For block compressed formats, D3D11's row pitch is already the size of
+        // a row of blocks, which is also what D3D9 expects.
+
+        let mapped = D3DLOCKED_RECT {
+            Pitch: mapped.RowPitch as i32,
+            pBits: mapped.pData,
+        };
+
+        Ok(mapped)
+    }
+
+    /// Maps a 3D resource.
+    pub fn map_box(
+        &self,
+        res: *mut ID3D11Resource,
+        subres: u32,
+        flags: LockFlags,
+        usage: UsageFlags,
+    ) -> Result<D3DLOCKED_BOX, Error> {
+        let mapped = self.map_subresource(res, subres, flags, usage)?;
+
+        let mapped = D3DLOCKED_BOX {
+            RowPitch: mapped.RowPitch as i32,
+            SlicePitch: mapped.DepthPitch as i32,
+            pBits: mapped.pData,
+        };
+
+        Ok(mapped)
+    }
+
+    /// Maps a subresource, converting D3D9's lock flags to D3D11's map flags.
+    fn map_subresource(
+        &self,
+        res: *mut ID3D11Resource,
+        subres: u32,
+        flags: LockFlags,
+        usage: UsageFlags,
+    ) -> Result<D3D11_MAPPED_SUBRESOURCE, Error> {
         let map_flags = if usage.intersects(UsageFlags::WRITE_ONLY) {
             // NOOVERWRITE must come first, since in D3D11 it's a superset of discard.
             if flags.intersects(LockFlags::NO_OVERWRITE) {
@@ -70,8 +187,10 @@ impl DeviceContext {
 
         // Try to map the subresource.
         let mapped = unsafe {
-            let mut buf = mem::uninitialized();
-            let result = self.Map(res, subres, map_flags, gpu_flags, &mut buf);
+            let mut buf = mem::zeroed();
+            let result = self
+                .immediate()
+                .Map(res, subres, map_flags, gpu_flags, &mut buf);
 
             match result {
                 0 => Ok(buf),
@@ -81,27 +200,20 @@ impl DeviceContext {
             }
         }?;
 
-        // TODO: we need special handling for pitch with DXT texture formats.
-
-        let mapped = D3DLOCKED_RECT {
-            Pitch: mapped.RowPitch as i32,
-            pBits: mapped.pData,
-        };
-
         Ok(mapped)
     }
 
     /// Unmaps a resource.
     pub fn unmap(&self, res: *mut ID3D11Resource, subres: u32) {
         unsafe {
-            self.Unmap(res, subres);
+            self.immediate().Unmap(res, subres);
         }
     }
 }
 
-impl ops::Deref for DeviceContext {
+impl<'a> ops::Deref for ContextGuard<'a> {
     type Target = ID3D11DeviceContext;
     fn deref(&self) -> &ID3D11DeviceContext {
-        &self.ctx
+        self.ctx
     }
 }
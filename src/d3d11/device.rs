@@ -1,9 +1,14 @@
-use std::ops;
+use std::{mem, ops, ptr};
 
-use winapi::um::d3d11::ID3D11Device;
+use winapi::shared::dxgi::IDXGIDevice1;
+use winapi::um::{d3d11::*, d3dcommon};
+use winapi::Interface;
 
 use comptr::ComPtr;
 
+use crate::core::*;
+use crate::Error;
+
 /// Wraps a D3D11 device.
 #[derive(Clone)]
 pub struct Device {
@@ -15,6 +20,50 @@ impl Device {
     pub fn new(device: ComPtr<ID3D11Device>) -> Self {
         Self { device }
     }
+
+    /// Retrieves the DXGI interface of this device.
+    pub fn dxgi_device(&self) -> Result<ComPtr<IDXGIDevice1>, Error> {
+        let device = unsafe {
+            let mut ptr: *mut IDXGIDevice1 = ptr::null_mut();
+            let uuid = IDXGIDevice1::uuidof();
+
+            let result = self
+                .device
+                .QueryInterface(&uuid, &mut ptr as *mut _ as *mut *mut _);
+            if_not_success_err!(check_hresult(result, "Failed to get DXGI device"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(device)
+    }
+
+    /// Checks if draws can be predicated on the result of an occlusion query.
+    ///
+    /// Occlusion predicates are not available on feature level 9 hardware.
+    pub fn supports_predication(&self) -> bool {
+        let feature_level = unsafe { self.device.GetFeatureLevel() };
+        feature_level >= d3dcommon::D3D_FEATURE_LEVEL_10_0
+    }
+
+    /// Checks if constant buffers can be bound at an offset, and written to without discarding them.
+    ///
+    /// Both of these require a D3D11.1 runtime and driver.
+    pub fn supports_constant_offsets(&self) -> bool {
+        unsafe {
+            let mut options: D3D11_FEATURE_DATA_D3D11_OPTIONS = mem::zeroed();
+
+            let result = self.device.CheckFeatureSupport(
+                D3D11_FEATURE_D3D11_OPTIONS,
+                &mut options as *mut _ as *mut _,
+                mem::size_of_val(&options) as u32,
+            );
+
+            result == 0
+                && options.ConstantBufferOffsetting != 0
+                && options.MapNoOverwriteOnDynamicConstantBuffer != 0
+        }
+    }
 }
 
 impl ops::Deref for Device {
@@ -0,0 +1,241 @@
+use std::cell::{Cell, RefCell};
+use std::{cmp, ptr};
+
+use winapi::um::d3d11::*;
+use winapi::um::d3d11_1::ID3D11DeviceContext1;
+use winapi::Interface;
+
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::Error;
+
+use super::{Buffer, Device, DeviceContext};
+
+/// Size of a dynamic buffer when it's first created.
+pub const DEFAULT_DYNAMIC_BUFFER_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Allocations start at multiples of this, so that any kind of data is properly aligned.
+const ALIGNMENT: u32 = 16;
+
+/// Constant buffers can only be bound at offsets which are multiples of 16 constants.
+const CONSTANT_ALIGNMENT: u32 = 16 * CONSTANT_SIZE;
+
+/// Size of a shader constant, which is a vector of four 32-bit values.
+const CONSTANT_SIZE: u32 = 16;
+
+/// Size of the largest constant buffer a shader can use.
+const MAX_CONSTANT_BUFFER_SIZE: u32 = D3D11_REQ_CONSTANT_BUFFER_ELEMENT_COUNT * CONSTANT_SIZE;
+
+/// Ring allocator for data which is streamed to the GPU, such as the vertices of user pointer draws.
+///
+/// Every allocation is written after the previous one with `MAP_WRITE_NO_OVERWRITE`,
+/// so the GPU can keep reading the older data. Once the buffer is full,
+/// it's mapped with `MAP_WRITE_DISCARD` and the allocations start over from the beginning.
+///
+/// Constant buffers can only be bound at an offset since D3D11.1. On older runtimes,
+/// every upload of constants discards the buffer instead, which gives each draw a copy of its own.
+pub struct DynamicBuffer {
+    device: Device,
+    bind_flags: u32,
+    buffer: RefCell<Buffer>,
+    size: Cell<u32>,
+    // Offset of the first free byte in the buffer.
+    offset: Cell<u32>,
+    // Whether allocations are placed after each other, or always start at the beginning.
+    sub_allocate: bool,
+}
+
+impl DynamicBuffer {
+    /// Creates a new buffer of a certain size, which can be bound with certain flags.
+    pub fn new(device: &Device, size: u32, bind_flags: u32) -> Result<Self, Error> {
+        Self::with_mode(device, size, bind_flags, true)
+    }
+
+    /// Creates a new buffer for shader constants, which are uploaded with `upload_constants`.
+    pub fn new_constants(device: &Device) -> Result<Self, Error> {
+        if device.supports_constant_offsets() {
+            Self::with_mode(
+                device,
+                DEFAULT_DYNAMIC_BUFFER_SIZE,
+                D3D11_BIND_CONSTANT_BUFFER,
+                true,
+            )
+        } else {
+            info!("Constant buffers cannot be bound at an offset, they will be discarded on every upload");
+            Self::with_mode(
+                device,
+                MAX_CONSTANT_BUFFER_SIZE,
+                D3D11_BIND_CONSTANT_BUFFER,
+                false,
+            )
+        }
+    }
+
+    fn with_mode(
+        device: &Device,
+        size: u32,
+        bind_flags: u32,
+        sub_allocate: bool,
+    ) -> Result<Self, Error> {
+        let buffer = Self::create(device, size, bind_flags)?;
+
+        Ok(Self {
+            device: device.clone(),
+            bind_flags,
+            buffer: RefCell::new(buffer),
+            size: Cell::new(size),
+            // The first allocation discards the buffer's initial contents.
+            offset: Cell::new(size),
+            sub_allocate,
+        })
+    }
+
+    fn create(device: &Device, size: u32, bind_flags: u32) -> Result<Buffer, Error> {
+        Buffer::new(
+            device,
+            size,
+            UsageFlags::DYNAMIC | UsageFlags::WRITE_ONLY,
+            MemoryPool::Default,
+            bind_flags,
+        )
+    }
+
+    /// Copies some data into the buffer.
+    ///
+    /// Returns the buffer the data was written to, and the offset in bytes at which it starts.
+    /// The buffer is replaced by a larger one if the data doesn't fit,
+    /// so the returned buffer must be bound again after every upload.
+    pub fn upload(&self, ctx: &DeviceContext, data: &[u8]) -> Result<(Buffer, u32), Error> {
+        let len = data.len() as u32;
+
+        if len > self.size.get() {
+            let size = len
+                .checked_next_power_of_two()
+                .ok_or(Error::OutOfVideoMemory)?;
+            let size = cmp::max(size, self.size.get().saturating_mul(2));
+            debug!("Growing dynamic buffer to {} bytes", size);
+
+            *self.buffer.borrow_mut() = Self::create(&self.device, size, self.bind_flags)?;
+            self.size.set(size);
+            self.offset.set(size);
+        }
+
+        let alignment = if self.bind_flags & D3D11_BIND_CONSTANT_BUFFER != 0 {
+            CONSTANT_ALIGNMENT
+        } else {
+            ALIGNMENT
+        };
+
+        let aligned = (self.offset.get() + alignment - 1) & !(alignment - 1);
+
+        let (offset, flags) = match aligned.checked_add(len) {
+            Some(end) if self.sub_allocate && end <= self.size.get() => {
+                (aligned, LockFlags::NO_OVERWRITE)
+            }
+            _ => (0, LockFlags::DISCARD),
+        };
+
+        let buffer = self.buffer.borrow().clone();
+        let resource = buffer.as_resource();
+        let mapped = ctx.map(resource, 0, flags, UsageFlags::WRITE_ONLY)?;
+
+        unsafe {
+            let dest = (mapped.pBits as *mut u8).add(offset as usize);
+            ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
+        }
+
+        ctx.unmap(resource, 0);
+
+        self.offset.set(offset + len);
+
+        Ok((buffer, offset))
+    }
+
+    /// Copies some shader constants into the buffer.
+    ///
+    /// Must only be used on buffers created with `new_constants`.
+    pub fn upload_constants(
+        &self,
+        ctx: &DeviceContext,
+        data: &[u8],
+    ) -> Result<ConstantRange, Error> {
+        if data.len() as u32 > MAX_CONSTANT_BUFFER_SIZE {
+            return Err(Error::InvalidCall);
+        }
+
+        let (buffer, offset) = self.upload(ctx, data)?;
+
+        // The bound range must also be a multiple of 16 constants. The next allocation
+        // starts at the following multiple, so the range never overlaps with it.
+        let len = (data.len() as u32 + CONSTANT_ALIGNMENT - 1) & !(CONSTANT_ALIGNMENT - 1);
+
+        Ok(ConstantRange {
+            buffer,
+            first: offset / CONSTANT_SIZE,
+            count: len / CONSTANT_SIZE,
+            at_offset: self.sub_allocate,
+        })
+    }
+}
+
+/// The shader stages constants can be bound to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShaderStage {
+    Vertex,
+    Geometry,
+    Pixel,
+}
+
+/// Shader constants which were uploaded to a dynamic buffer.
+#[derive(Clone)]
+pub struct ConstantRange {
+    buffer: Buffer,
+    // The range of the buffer which contains the constants, in units of 16 bytes.
+    first: u32,
+    count: u32,
+    // Whether the range has to be bound at an offset, which requires D3D11.1.
+    at_offset: bool,
+}
+
+impl ConstantRange {
+    /// Binds these constants to a slot of a shader stage.
+    pub fn bind(&self, ctx: &ID3D11DeviceContext, stage: ShaderStage, slot: u32) {
+        let buffer = self.buffer.as_buffer();
+
+        if !self.at_offset {
+            unsafe {
+                match stage {
+                    ShaderStage::Vertex => ctx.VSSetConstantBuffers(slot, 1, &buffer),
+                    ShaderStage::Geometry => ctx.GSSetConstantBuffers(slot, 1, &buffer),
+                    ShaderStage::Pixel => ctx.PSSetConstantBuffers(slot, 1, &buffer),
+                }
+            }
+            return;
+        }
+
+        let ctx1 = unsafe {
+            let mut ptr: *mut ID3D11DeviceContext1 = ptr::null_mut();
+            let uuid = ID3D11DeviceContext1::uuidof();
+
+            let result = ctx.QueryInterface(&uuid, &mut ptr as *mut _ as *mut *mut _);
+            if result != 0 {
+                // The runtime told us it supports D3D11.1 when the buffer was created.
+                run_once!(|| error!("Failed to get the D3D11.1 device context"));
+                return;
+            }
+
+            ComPtr::new(ptr)
+        };
+
+        let (first, count) = (&self.first, &self.count);
+
+        unsafe {
+            match stage {
+                ShaderStage::Vertex => ctx1.VSSetConstantBuffers1(slot, 1, &buffer, first, count),
+                ShaderStage::Geometry => ctx1.GSSetConstantBuffers1(slot, 1, &buffer, first, count),
+                ShaderStage::Pixel => ctx1.PSSetConstantBuffers1(slot, 1, &buffer, first, count),
+            }
+        }
+    }
+}
@@ -6,10 +6,33 @@ mod device;
 pub use self::device::Device;
 
 mod devctx;
-pub use self::devctx::DeviceContext;
+pub use self::devctx::{ContextGuard, DeviceContext};
+
+mod sink;
+pub use self::sink::{CommandSink, DeferredSink, ImmediateSink};
 
 mod buffer;
 pub use self::buffer::Buffer;
 
+mod dynamic_buffer;
+pub use self::dynamic_buffer::{
+    ConstantRange, DynamicBuffer, ShaderStage, DEFAULT_DYNAMIC_BUFFER_SIZE,
+};
+
+mod shader;
+pub use self::shader::compile_shader;
+
 mod texture;
 pub use self::texture::Texture2D;
+
+mod cube_texture;
+pub use self::cube_texture::CubeTexture;
+
+mod volume_texture;
+pub use self::volume_texture::VolumeTexture;
+
+mod swap_chain;
+pub use self::swap_chain::SwapChain;
+
+mod query;
+pub use self::query::Query;
@@ -0,0 +1,92 @@
+use std::{mem, ptr};
+
+use winapi::um::d3d11::*;
+
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::Error;
+
+/// Wrapper for a D3D11 asynchronous query.
+#[derive(Clone)]
+pub struct Query {
+    query: ComPtr<ID3D11Query>,
+}
+
+impl Query {
+    /// Creates a new query of a certain type.
+    pub fn new(device: &ID3D11Device, ty: D3D11_QUERY) -> Result<Self, Error> {
+        let desc = D3D11_QUERY_DESC {
+            Query: ty,
+            MiscFlags: 0,
+        };
+
+        let query = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateQuery(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create query"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { query })
+    }
+
+    /// Creates a predicate, which is set when any pixels are drawn between its beginning and end.
+    ///
+    /// Predicates can be used to skip draw calls on the GPU.
+    pub fn new_predicate(device: &ID3D11Device) -> Result<Self, Error> {
+        let desc = D3D11_QUERY_DESC {
+            Query: D3D11_QUERY_OCCLUSION_PREDICATE,
+            MiscFlags: 0,
+        };
+
+        let query = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreatePredicate(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create predicate"));
+
+            // Predicates are queries, with no extra methods.
+            ComPtr::new(ptr as *mut ID3D11Query)
+        };
+
+        Ok(Self { query })
+    }
+
+    /// Retrieves this query as an asynchronous object.
+    pub fn as_async(&self) -> *mut ID3D11Asynchronous {
+        self.query.upcast().as_mut()
+    }
+
+    /// Retrieves this query as a predicate.
+    ///
+    /// Must only be called on queries created with `new_predicate`.
+    pub fn as_predicate(&self) -> *mut ID3D11Predicate {
+        self.query.as_mut() as *mut ID3D11Query as *mut ID3D11Predicate
+    }
+
+    /// Tries to read the query's result.
+    ///
+    /// Returns `None` if the data is not yet available.
+    pub fn read<T>(&self, ctx: &ID3D11DeviceContext, flags: u32) -> Result<Option<T>, Error> {
+        unsafe {
+            let mut data: T = mem::zeroed();
+
+            let result = ctx.GetData(
+                self.as_async(),
+                &mut data as *mut T as *mut _,
+                mem::size_of::<T>() as u32,
+                flags,
+            );
+
+            match result {
+                0 => Ok(Some(data)),
+                // S_FALSE indicates the data is not yet ready.
+                1 => Ok(None),
+                hr => Err(check_hresult(hr, "Failed to retrieve query data")),
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Runtime compilation of HLSL shaders.
+
+use std::ffi::CString;
+use std::sync::Arc;
+use std::{ptr, slice};
+
+use winapi::um::d3dcommon::ID3DBlob;
+use winapi::um::d3dcompiler::{D3DCompile, D3DCOMPILE_OPTIMIZATION_LEVEL3};
+
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::Error;
+
+/// Retrieves the contents of a blob returned by the compiler.
+fn blob_bytes(blob: &ID3DBlob) -> &[u8] {
+    unsafe { slice::from_raw_parts(blob.GetBufferPointer() as *const u8, blob.GetBufferSize()) }
+}
+
+/// Compiles an HLSL shader, whose entry point is called `main`,
+/// for a certain target profile (such as `vs_4_0`).
+///
+/// Returns the shader's byte code.
+pub fn compile_shader(source: &str, target: &str) -> Result<Arc<[u32]>, Error> {
+    let target = CString::new(target).map_err(|_| Error::InvalidCall)?;
+
+    let mut code = ptr::null_mut();
+    let mut messages = ptr::null_mut();
+
+    let result = unsafe {
+        D3DCompile(
+            source.as_ptr() as *const _,
+            source.len(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null_mut(),
+            b"main\0".as_ptr() as *const _,
+            target.as_ptr(),
+            D3DCOMPILE_OPTIMIZATION_LEVEL3,
+            0,
+            &mut code,
+            &mut messages,
+        )
+    };
+
+    // The compiler also reports warnings for shaders which compiled successfully.
+    if !messages.is_null() {
+        let messages = unsafe { ComPtr::new(messages) };
+        let messages = String::from_utf8_lossy(blob_bytes(&messages));
+        debug!(
+            "Shader compiler output:\n{}",
+            messages.trim_end_matches('\0')
+        );
+    }
+
+    if result != 0 {
+        error!("Failed to compile shader:\n{}", source);
+    }
+    if_not_success_err!(check_hresult(result, "Failed to compile shader"));
+
+    let code = unsafe { ComPtr::new(code) };
+
+    // Byte code is always made of 32-bit tokens.
+    let code = blob_bytes(&code)
+        .chunks_exact(4)
+        .map(|token| u32::from_ne_bytes([token[0], token[1], token[2], token[3]]))
+        .collect();
+
+    Ok(code)
+}
@@ -0,0 +1,96 @@
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use winapi::um::d3d11::*;
+
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::Error;
+
+/// Destination of the state and draw commands issued by the device.
+pub trait CommandSink {
+    /// Retrieves the context into which commands are recorded.
+    fn context(&self) -> &ID3D11DeviceContext;
+
+    /// Makes sure all of the recorded commands were submitted to the immediate context.
+    fn submit(&self, immediate: &ID3D11DeviceContext) -> Result<(), Error>;
+}
+
+/// Sends every command straight to the immediate context.
+pub struct ImmediateSink {
+    ctx: ComPtr<ID3D11DeviceContext>,
+}
+
+impl ImmediateSink {
+    /// Creates a sink which uses the immediate context.
+    pub fn new(ctx: ComPtr<ID3D11DeviceContext>) -> Self {
+        Self { ctx }
+    }
+}
+
+impl CommandSink for ImmediateSink {
+    fn context(&self) -> &ID3D11DeviceContext {
+        &self.ctx
+    }
+
+    fn submit(&self, _immediate: &ID3D11DeviceContext) -> Result<(), Error> {
+        // Nothing is ever held back.
+        Ok(())
+    }
+}
+
+/// Records commands into a deferred context, and replays them
+/// as a single command list when they are submitted.
+///
+/// This avoids the driver overhead of the immediate context,
+/// for games which make lots of small state changes.
+pub struct DeferredSink {
+    ctx: ComPtr<ID3D11DeviceContext>,
+    // Whether the deferred context was used since the last submission.
+    recorded: AtomicBool,
+}
+
+impl DeferredSink {
+    /// Creates a new deferred context to record commands into.
+    pub fn new(device: &ID3D11Device) -> Result<Self, Error> {
+        let ctx = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateDeferredContext(0, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create deferred context"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self {
+            ctx,
+            recorded: AtomicBool::new(false),
+        })
+    }
+}
+
+impl CommandSink for DeferredSink {
+    fn context(&self) -> &ID3D11DeviceContext {
+        self.recorded.store(true, Ordering::Relaxed);
+        &self.ctx
+    }
+
+    fn submit(&self, immediate: &ID3D11DeviceContext) -> Result<(), Error> {
+        if !self.recorded.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        unsafe {
+            // The state set on the deferred context has to carry over to the next command list,
+            // since the device only binds it again when it changes.
+            let mut list = ptr::null_mut();
+            let result = self.ctx.FinishCommandList(1, &mut list);
+            if_not_success_err!(check_hresult(result, "Failed to finish command list"));
+            let list: ComPtr<ID3D11CommandList> = ComPtr::new(list);
+
+            // The immediate context's state is only used for reading back data, so it can be cleared.
+            immediate.ExecuteCommandList(list.as_mut(), 0);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use std::{mem, ops, ptr};
+
+use winapi::shared::{
+    d3d9types::*,
+    dxgi::*,
+    dxgiformat::*,
+    dxgitype::*,
+    windef::{HWND, RECT},
+    winerror,
+};
+use winapi::um::{d3d11::*, unknwnbase::IUnknown, wingdi, winuser};
+use winapi::Interface;
+
+use comptr::ComPtr;
+
+use crate::core::{
+    fmt::{d3d_display_format_to_dxgi, DESKTOP_FORMAT},
+    msample::d3d9_to_dxgi_samples,
+    *,
+};
+use crate::Error;
+
+use super::Texture2D;
+
+/// Wrapper for a DXGI swap chain.
+pub struct SwapChain {
+    swap_chain: ComPtr<IDXGISwapChain>,
+}
+
+/// Describes the back buffers needed for some presentation parameters.
+///
+/// The parameters are updated with the values which will actually be used.
+fn buffer_desc(pp: &mut D3DPRESENT_PARAMETERS, window: HWND) -> Result<DXGI_MODE_DESC, Error> {
+    // Determine the dimensions of the back buffer.
+    let (width, height) = {
+        if pp.BackBufferWidth == 0 || pp.BackBufferHeight == 0 {
+            // In full-screen modes, we need to be told the exact resolution.
+            if pp.Windowed == 0 {
+                return Err(Error::InvalidCall);
+            }
+
+            // If either of these was 0, the app requested us to use the window's size.
+            unsafe {
+                let mut r = mem::zeroed();
+
+                if winuser::GetClientRect(window, &mut r) == 0 {
+                    error!("Cannot determine the back buffer size from the device window");
+                    return Err(Error::InvalidCall);
+                }
+
+                // We have to update the elements of the structure which were 0.
+                if pp.BackBufferWidth == 0 {
+                    pp.BackBufferWidth = (r.right - r.left) as u32;
+                }
+
+                if pp.BackBufferHeight == 0 {
+                    pp.BackBufferHeight = (r.bottom - r.top) as u32;
+                }
+
+                (pp.BackBufferWidth, pp.BackBufferHeight)
+            }
+        } else {
+            (pp.BackBufferWidth, pp.BackBufferHeight)
+        }
+    };
+
+    let refresh_rate = DXGI_RATIONAL {
+        Numerator: pp.FullScreen_RefreshRateInHz,
+        Denominator: 1,
+    };
+
+    // Determine the back buffer format.
+    // We take a mut reference because we have to update the format
+    // with the actual format we will be using.
+    let fmt = &mut pp.BackBufferFormat;
+
+    // If it's unknown, use the display's one.
+    if *fmt == D3DFMT_UNKNOWN {
+        *fmt = DESKTOP_FORMAT;
+    }
+
+    Ok(DXGI_MODE_DESC {
+        Width: width,
+        Height: height,
+        RefreshRate: refresh_rate,
+        Format: d3d_display_format_to_dxgi(*fmt),
+        ScanlineOrdering: DXGI_MODE_SCANLINE_ORDER_UNSPECIFIED,
+        Scaling: DXGI_MODE_SCALING_UNSPECIFIED,
+    })
+}
+
+/// Determines the number of back buffers to create.
+///
+/// The presentation parameters are updated with the number which will actually be used,
+/// so that apps can read it back with `GetPresentParameters`.
+fn buffer_count(pp: &mut D3DPRESENT_PARAMETERS) -> u32 {
+    if pp.SwapEffect == D3DSWAPEFFECT_COPY && pp.BackBufferCount > 1 {
+        error!("Application requested multiple back buffers, even though swap effect is COPY");
+        error!("Limiting to one back buffer");
+        pp.BackBufferCount = 1;
+    }
+
+    // We have to clamp this to be between 1 and 3.
+    // TODO: with D3D9Ex the limit goes up to 30.
+    pp.BackBufferCount = std::cmp::min(std::cmp::max(pp.BackBufferCount, 1), 3);
+    pp.BackBufferCount
+}
+
+impl SwapChain {
+    /// Creates a new swap chain with the given parameters, which presents into a window.
+    ///
+    /// The presentation parameters are updated with the values which were actually used.
+    pub fn new(
+        device: &ID3D11Device,
+        factory: &IDXGIFactory,
+        pp: &mut D3DPRESENT_PARAMETERS,
+        window: HWND,
+    ) -> Result<Self, Error> {
+        // First we need to set up the description of this swap chain.
+        let mut sc_desc = {
+            // Fill in the description of the back buffer.
+            let buffer_desc = buffer_desc(pp, window)?;
+
+            let sample_desc = {
+                let count = if pp.SwapEffect != D3DSWAPEFFECT_DISCARD {
+                    error!("Multisampling is only supported with the DISCARD swap effect");
+                    error!("Disabling multisample antialiasing");
+                    1
+                } else {
+                    pp.MultiSampleType
+                };
+
+                d3d9_to_dxgi_samples(count, pp.MultiSampleQuality)
+            };
+
+            let buffer_usage = DXGI_USAGE_BACK_BUFFER | DXGI_USAGE_RENDER_TARGET_OUTPUT;
+
+            let buffer_count = buffer_count(pp);
+
+            let swap_effect = match pp.SwapEffect {
+                D3DSWAPEFFECT_DISCARD => DXGI_SWAP_EFFECT_DISCARD,
+                se => {
+                    error!("Unsupported swap effect: {}", se);
+                    error!("Falling back to DISCARD");
+                    DXGI_SWAP_EFFECT_DISCARD
+                }
+            };
+
+            // TODO: we currently ignore the pp.Flags
+            if pp.Flags != 0 {
+                warn!("Unsupported presentation flags: {}", pp.Flags);
+            }
+
+            // GDI is used for the kinds of presents DXGI doesn't support,
+            // like presenting part of the back buffer or to another window.
+            // Full screen apps which want to show dialogs on top of the game
+            // have to ask for a lockable back buffer, like in D3D9.
+            let gdi = pp.Windowed != 0 || pp.Flags & D3DPRESENTFLAG_LOCKABLE_BACKBUFFER != 0;
+
+            let flags = if gdi
+                && sample_desc.Count == 1
+                && buffer_desc.Format == DXGI_FORMAT_B8G8R8A8_UNORM
+            {
+                DXGI_SWAP_CHAIN_FLAG_GDI_COMPATIBLE
+            } else {
+                0
+            };
+
+            DXGI_SWAP_CHAIN_DESC {
+                BufferDesc: buffer_desc,
+                SampleDesc: sample_desc,
+                BufferUsage: buffer_usage,
+                BufferCount: buffer_count,
+                OutputWindow: window,
+                // Swap chains are always created windowed, and made full screen afterwards.
+                Windowed: 1,
+                SwapEffect: swap_effect,
+                Flags: flags,
+            }
+        };
+
+        let swap_chain = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = factory.CreateSwapChain(
+                device as *const _ as *mut IUnknown,
+                &mut sc_desc,
+                &mut ptr,
+            );
+            if_not_success_err!(check_hresult(result, "Failed to create swap chain"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { swap_chain })
+    }
+
+    /// Resizes the buffers of this swap chain to match new presentation parameters.
+    ///
+    /// All references to the old buffers must have been released.
+    pub fn resize(&self, pp: &mut D3DPRESENT_PARAMETERS) -> Result<(), Error> {
+        let desc = self.desc()?;
+
+        let buffer_desc = buffer_desc(pp, desc.OutputWindow)?;
+        let buffer_count = buffer_count(pp);
+
+        let result = unsafe {
+            self.swap_chain.ResizeBuffers(
+                buffer_count,
+                buffer_desc.Width,
+                buffer_desc.Height,
+                buffer_desc.Format,
+                desc.Flags,
+            )
+        };
+        if_not_success_err!(check_hresult(result, "Failed to resize swap chain"));
+
+        Ok(())
+    }
+
+    /// Makes this swap chain full screen on an output, switching it to a display mode.
+    ///
+    /// Returns `false` if full screen mode is currently not available,
+    /// for example because another app is using the output.
+    pub fn set_fullscreen(
+        &self,
+        output: &IDXGIOutput,
+        mode: &DXGI_MODE_DESC,
+    ) -> Result<bool, Error> {
+        unsafe {
+            let result = self.swap_chain.ResizeTarget(mode);
+            if_not_success_err!(check_hresult(result, "Failed to resize swap chain target"));
+
+            let output = output as *const _ as *mut _;
+            match self.swap_chain.SetFullscreenState(1, output) {
+                0 => (),
+                winerror::DXGI_ERROR_NOT_CURRENTLY_AVAILABLE
+                | winerror::DXGI_STATUS_MODE_CHANGE_IN_PROGRESS => return Ok(false),
+                hr => return Err(check_hresult(hr, "Failed to enter full screen mode")),
+            }
+
+            // The buffers have to match the new mode.
+            let result =
+                self.swap_chain
+                    .ResizeBuffers(0, mode.Width, mode.Height, DXGI_FORMAT_UNKNOWN, 0);
+            if_not_success_err!(check_hresult(result, "Failed to resize swap chain"));
+        }
+
+        Ok(true)
+    }
+
+    /// Switches this swap chain back to windowed mode.
+    pub fn set_windowed(&self) -> Result<(), Error> {
+        let result = unsafe { self.swap_chain.SetFullscreenState(0, ptr::null_mut()) };
+        if_not_success_err!(check_hresult(result, "Failed to leave full screen mode"));
+        Ok(())
+    }
+
+    /// Checks if the buffers of this swap chain can be accessed with GDI.
+    pub fn is_gdi_compatible(&self) -> Result<bool, Error> {
+        Ok(self.desc()?.Flags & DXGI_SWAP_CHAIN_FLAG_GDI_COMPATIBLE != 0)
+    }
+
+    /// Copies part of the back buffer into a window, stretching it if necessary.
+    ///
+    /// Only supported by swap chains whose buffers are compatible with GDI.
+    pub fn blit_to_window(&self, src: &RECT, window: HWND, dest: &RECT) -> Result<(), Error> {
+        if !self.is_gdi_compatible()? {
+            error!("Swap chain's back buffer cannot be copied with GDI");
+            return Err(Error::NotAvailable);
+        }
+
+        let mut ptr: *mut IDXGISurface1 = ptr::null_mut();
+        let uuid = IDXGISurface1::uuidof();
+
+        let result = unsafe {
+            self.swap_chain
+                .GetBuffer(0, &uuid, &mut ptr as *mut _ as *mut *mut _)
+        };
+        if_not_success_err!(check_hresult(
+            result,
+            "Failed to retrieve swap chain buffer"
+        ));
+
+        let surface = ComPtr::new(ptr);
+
+        unsafe {
+            let mut src_dc = ptr::null_mut();
+            let result = surface.GetDC(0, &mut src_dc);
+            if_not_success_err!(check_hresult(result, "Failed to get back buffer's DC"));
+
+            let dest_dc = winuser::GetDC(window);
+
+            wingdi::StretchBlt(
+                dest_dc,
+                dest.left,
+                dest.top,
+                dest.right - dest.left,
+                dest.bottom - dest.top,
+                src_dc,
+                src.left,
+                src.top,
+                src.right - src.left,
+                src.bottom - src.top,
+                wingdi::SRCCOPY,
+            );
+
+            winuser::ReleaseDC(window, dest_dc);
+
+            let result = surface.ReleaseDC(ptr::null_mut());
+            if_not_success_err!(check_hresult(result, "Failed to release back buffer's DC"));
+        }
+
+        Ok(())
+    }
+
+    /// Retrieves a buffer in this swap chain.
+    pub fn buffer(&self, id: u32) -> Result<Texture2D, Error> {
+        let mut ptr: *mut ID3D11Texture2D = ptr::null_mut();
+        let uuid = ID3D11Texture2D::uuidof();
+
+        let ret = &mut ptr as *mut _ as *mut *mut _;
+
+        let result = unsafe { self.swap_chain.GetBuffer(id, &uuid, ret) };
+
+        if_not_success_err!(check_hresult(
+            result,
+            "Failed to retrieve swap chain buffer"
+        ));
+
+        Ok(ComPtr::new(ptr).into())
+    }
+
+    /// Retrieves the output which contains most of this swap chain's window.
+    pub fn output(&self) -> Result<ComPtr<IDXGIOutput>, Error> {
+        let output = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.swap_chain.GetContainingOutput(&mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to get swap chain's output"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(output)
+    }
+
+    /// Retrieves the description of this swap chain.
+    pub fn desc(&self) -> Result<DXGI_SWAP_CHAIN_DESC, Error> {
+        unsafe {
+            let mut desc = mem::zeroed();
+            let result = self.swap_chain.GetDesc(&mut desc);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to get swap chain description"
+            ));
+            Ok(desc)
+        }
+    }
+}
+
+impl Drop for SwapChain {
+    fn drop(&mut self) {
+        unsafe {
+            // According to the DXGI docs, if the swap chain was created as full-screen
+            // we need to make it windowed right before destroying it.
+            self.swap_chain.SetFullscreenState(0, ptr::null_mut());
+        }
+    }
+}
+
+impl ops::Deref for SwapChain {
+    type Target = IDXGISwapChain;
+    fn deref(&self) -> &IDXGISwapChain {
+        &self.swap_chain
+    }
+}
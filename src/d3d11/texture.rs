@@ -1,11 +1,21 @@
-use std::{mem, ptr};
+use std::{cmp, mem, ptr};
 
 use winapi::shared::d3d9types::*;
+use winapi::shared::dxgi::IDXGIResource;
 use winapi::um::d3d11::*;
+use winapi::um::winnt::HANDLE;
+use winapi::Interface;
 
 use comptr::ComPtr;
 
-use crate::core::{fmt::d3d_format_to_dxgi, msample::d3d9_to_dxgi_samples, *};
+use crate::core::{
+    fmt::{
+        d3d_format_to_dxgi, depth_format_to_dxgi_srv, depth_format_to_dxgi_typed,
+        depth_format_to_dxgi_typeless,
+    },
+    msample::d3d9_to_dxgi_samples,
+    *,
+};
 
 use super::util::d3d_usage_to_d3d11;
 use crate::Error;
@@ -30,6 +40,12 @@ impl Texture2D {
 
         let fmt = d3d_format_to_dxgi(fmt);
 
+        let misc_flags = if uflags.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+            D3D11_RESOURCE_MISC_GENERATE_MIPS
+        } else {
+            0
+        };
+
         let desc = D3D11_TEXTURE2D_DESC {
             Width: width,
             Height: height,
@@ -40,7 +56,7 @@ impl Texture2D {
             Usage: usage,
             BindFlags: bind_flags,
             CPUAccessFlags: cpu_flags,
-            MiscFlags: 0,
+            MiscFlags: misc_flags,
         };
 
         let texture = unsafe {
@@ -55,28 +71,34 @@ impl Texture2D {
         Ok(Self { texture })
     }
 
-    /// Creates a new cube map texture.
-    pub fn new_cube_texture(
+    /// Creates a new render target.
+    pub fn new_rt(
         device: &ID3D11Device,
-        dimension: u32,
-        levels: u32,
-        uflags: UsageFlags,
+        (width, height): (u32, u32),
         fmt: D3DFORMAT,
-        pool: MemoryPool,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
     ) -> Result<Self, Error> {
-        let (usage, bind_flags, cpu_flags) = d3d_usage_to_d3d11(uflags, pool)?;
         let fmt = d3d_format_to_dxgi(fmt);
+        let sample_desc = d3d9_to_dxgi_samples(ms_ty, ms_qlt);
+
+        // Non-multisampled render targets could later be used as textures.
+        let bind_flags = if sample_desc.Count == 1 {
+            D3D11_BIND_RENDER_TARGET | D3D11_BIND_SHADER_RESOURCE
+        } else {
+            D3D11_BIND_RENDER_TARGET
+        };
 
         let desc = D3D11_TEXTURE2D_DESC {
-            Width: dimension,
-            Height: dimension,
-            MipLevels: levels,
-            ArraySize: 6,
+            Width: width,
+            Height: height,
+            MipLevels: 1,
+            ArraySize: 1,
             Format: fmt,
-            SampleDesc: d3d9_to_dxgi_samples(0, 0),
-            Usage: usage,
+            SampleDesc: sample_desc,
+            Usage: D3D11_USAGE_DEFAULT,
             BindFlags: bind_flags,
-            CPUAccessFlags: cpu_flags,
+            CPUAccessFlags: 0,
             MiscFlags: 0,
         };
 
@@ -84,7 +106,10 @@ impl Texture2D {
             let mut ptr = ptr::null_mut();
 
             let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
-            if_not_success_err!(check_hresult(result, "Failed to create cube texture"));
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create render target texture"
+            ));
 
             ComPtr::new(ptr)
         };
@@ -92,42 +117,214 @@ impl Texture2D {
         Ok(Self { texture })
     }
 
-    /// Creates a new render target.
-    pub fn new_rt(
-        device: &ID3D11Device,
-        (width, height): (u32, u32),
-        fmt: D3DFORMAT,
-        ms_ty: D3DMULTISAMPLE_TYPE,
-        ms_qlt: u32,
-    ) -> Result<Self, Error> {
-        let fmt = d3d_format_to_dxgi(fmt);
+    /// Creates a CPU-accessible copy of this texture's layout.
+    ///
+    /// Used for locking resources which the GPU writes to, since D3D11
+    /// does not allow mapping those directly.
+    pub fn new_staging(&self, device: &ID3D11Device) -> Result<Self, Error> {
+        let desc = D3D11_TEXTURE2D_DESC {
+            SampleDesc: d3d9_to_dxgi_samples(0, 0),
+            Usage: D3D11_USAGE_STAGING,
+            BindFlags: 0,
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ | D3D11_CPU_ACCESS_WRITE,
+            MiscFlags: 0,
+            ..self.desc()
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create staging texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a copy of this texture's layout which can only be accessed by the GPU,
+    /// and can be bound to shaders.
+    ///
+    /// Used to upload the contents of textures which live in system memory.
+    pub fn new_gpu_copy(&self, device: &ID3D11Device) -> Result<Self, Error> {
+        let desc = self.desc();
 
         let desc = D3D11_TEXTURE2D_DESC {
-            Width: width,
-            Height: height,
-            MipLevels: 1,
-            ArraySize: 1,
-            Format: fmt,
-            SampleDesc: d3d9_to_dxgi_samples(ms_ty, ms_qlt),
             Usage: D3D11_USAGE_DEFAULT,
-            BindFlags: D3D11_BIND_RENDER_TARGET,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE,
+            CPUAccessFlags: 0,
+            MiscFlags: desc.MiscFlags & D3D11_RESOURCE_MISC_TEXTURECUBE,
+            ..desc
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create GPU texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a GPU-only, single-sampled copy of this texture's layout.
+    ///
+    /// Multisampled textures have to be resolved into one of these before being read back.
+    pub fn new_resolve_target(&self, device: &ID3D11Device) -> Result<Self, Error> {
+        let desc = D3D11_TEXTURE2D_DESC {
+            SampleDesc: d3d9_to_dxgi_samples(0, 0),
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: 0,
             CPUAccessFlags: 0,
             MiscFlags: 0,
+            ..self.desc()
         };
 
         let texture = unsafe {
             let mut ptr = ptr::null_mut();
 
             let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create resolve texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a copy of this texture's layout which other devices can open.
+    pub fn new_shared(&self, device: &ID3D11Device) -> Result<Self, Error> {
+        let desc = self.desc();
+
+        let desc = D3D11_TEXTURE2D_DESC {
+            MiscFlags: desc.MiscFlags | D3D11_RESOURCE_MISC_SHARED,
+            ..desc
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create shared texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Opens a texture shared by another device.
+    pub fn open_shared(device: &ID3D11Device, handle: HANDLE) -> Result<Self, Error> {
+        let texture = unsafe {
+            let uuid = ID3D11Texture2D::uuidof();
+            let mut ptr: *mut ID3D11Texture2D = ptr::null_mut();
+
+            let result =
+                device.OpenSharedResource(handle, &uuid, &mut ptr as *mut _ as *mut *mut _);
+            if_not_success_err!(check_hresult(result, "Failed to open shared texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Retrieves the handle which other devices can use to open this texture.
+    ///
+    /// The texture must have been created with `new_shared`.
+    pub fn shared_handle(&self) -> Result<HANDLE, Error> {
+        unsafe {
+            let uuid = IDXGIResource::uuidof();
+            let mut resource: *mut IDXGIResource = ptr::null_mut();
+
+            let result = self
+                .texture
+                .QueryInterface(&uuid, &mut resource as *mut _ as *mut *mut _);
+            if_not_success_err!(check_hresult(result, "Texture is not a DXGI resource"));
+            let resource = ComPtr::new(resource);
+
+            let mut handle = ptr::null_mut();
+            let result = resource.GetSharedHandle(&mut handle);
+            if_not_success_err!(check_hresult(result, "Failed to get shared handle"));
+
+            Ok(handle)
+        }
+    }
+
+    /// Copies the contents of this texture to another one, resolving it if it's multisampled.
+    pub fn copy_to(&self, ctx: &ID3D11DeviceContext, dest: &Texture2D) {
+        let desc = self.desc();
+
+        unsafe {
+            if desc.SampleDesc.Count > 1 {
+                ctx.ResolveSubresource(dest.as_resource(), 0, self.as_resource(), 0, desc.Format);
+            } else {
+                ctx.CopyResource(dest.as_resource(), self.as_resource());
+            }
+        }
+    }
+
+    /// Creates a shader resource view of the whole texture.
+    ///
+    /// Returns `None` if this texture cannot be bound to shaders.
+    pub fn create_shader_view(
+        &self,
+        device: &ID3D11Device,
+    ) -> Result<Option<ComPtr<ID3D11ShaderResourceView>>, Error> {
+        if self.desc().BindFlags & D3D11_BIND_SHADER_RESOURCE == 0 {
+            return Ok(None);
+        }
+
+        let resource = self.as_resource();
+
+        let view = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateShaderResourceView(resource, ptr::null(), &mut ptr);
             if_not_success_err!(check_hresult(
                 result,
-                "Failed to create render target texture"
+                "Failed to create shader resource view"
             ));
 
             ComPtr::new(ptr)
         };
 
-        Ok(Self { texture })
+        Ok(Some(view))
+    }
+
+    /// Creates a shader resource view which skips the mip levels before `most_detailed_mip`.
+    pub fn create_mip_view(
+        &self,
+        device: &ID3D11Device,
+        most_detailed_mip: u32,
+    ) -> Result<ComPtr<ID3D11ShaderResourceView>, Error> {
+        let desc = self.desc();
+
+        let mut view_desc: D3D11_SHADER_RESOURCE_VIEW_DESC = unsafe { mem::zeroed() };
+        view_desc.Format = desc.Format;
+        view_desc.ViewDimension = D3D11_SRV_DIMENSION_TEXTURE2D;
+
+        let view = unsafe {
+            *view_desc.u.Texture2D_mut() = D3D11_TEX2D_SRV {
+                MostDetailedMip: most_detailed_mip,
+                MipLevels: desc.MipLevels - most_detailed_mip,
+            };
+
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateShaderResourceView(self.as_resource(), &view_desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create shader resource view"
+            ));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(view)
     }
 
     /// Creates a render target view from this texture.
@@ -150,12 +347,24 @@ impl Texture2D {
     }
 
     /// Creates a new depth/stencil buffer.
+    ///
+    /// The texture is typeless, and non-multisampled buffers can also be sampled by shaders.
     pub fn new_ds(
         device: &ID3D11Device,
         (width, height): (u32, u32),
         fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
     ) -> Result<Self, Error> {
-        let fmt = d3d_format_to_dxgi(fmt);
+        let fmt = depth_format_to_dxgi_typeless(fmt);
+        let sample_desc = d3d9_to_dxgi_samples(ms_ty, ms_qlt);
+
+        // Games commonly sample depth buffers for shadow maps or post processing.
+        let bind_flags = if sample_desc.Count == 1 {
+            D3D11_BIND_DEPTH_STENCIL | D3D11_BIND_SHADER_RESOURCE
+        } else {
+            D3D11_BIND_DEPTH_STENCIL
+        };
 
         let desc = D3D11_TEXTURE2D_DESC {
             Width: width,
@@ -163,9 +372,9 @@ impl Texture2D {
             MipLevels: 1,
             ArraySize: 1,
             Format: fmt,
-            SampleDesc: d3d9_to_dxgi_samples(0, 0),
+            SampleDesc: sample_desc,
             Usage: D3D11_USAGE_DEFAULT,
-            BindFlags: D3D11_BIND_DEPTH_STENCIL,
+            BindFlags: bind_flags,
             CPUAccessFlags: 0,
             MiscFlags: 0,
         };
@@ -186,16 +395,27 @@ impl Texture2D {
     }
 
     /// Creates a depth / stencil view from this texture.
+    ///
+    /// Since depth textures are typeless, the view's format is derived from the D3D9 format.
     pub fn create_ds_view(
         &self,
         device: &ID3D11Device,
+        fmt: D3DFORMAT,
     ) -> Result<ComPtr<ID3D11DepthStencilView>, Error> {
         let resource = self.as_resource();
 
+        let mut desc: D3D11_DEPTH_STENCIL_VIEW_DESC = unsafe { mem::zeroed() };
+        desc.Format = depth_format_to_dxgi_typed(fmt);
+        desc.ViewDimension = if self.desc().SampleDesc.Count > 1 {
+            D3D11_DSV_DIMENSION_TEXTURE2DMS
+        } else {
+            D3D11_DSV_DIMENSION_TEXTURE2D
+        };
+
         let view = unsafe {
             let mut ptr = ptr::null_mut();
 
-            let result = device.CreateDepthStencilView(resource, ptr::null(), &mut ptr);
+            let result = device.CreateDepthStencilView(resource, &desc, &mut ptr);
             if_not_success_err!(check_hresult(
                 result,
                 "Failed to create depth / stencil view"
@@ -207,6 +427,41 @@ impl Texture2D {
         Ok(view)
     }
 
+    /// Creates a shader resource view which samples the depth of a depth / stencil texture.
+    ///
+    /// Returns `None` if this texture cannot be bound to shaders.
+    pub fn create_depth_shader_view(
+        &self,
+        device: &ID3D11Device,
+        fmt: D3DFORMAT,
+    ) -> Result<Option<ComPtr<ID3D11ShaderResourceView>>, Error> {
+        if self.desc().BindFlags & D3D11_BIND_SHADER_RESOURCE == 0 {
+            return Ok(None);
+        }
+
+        let resource = self.as_resource();
+
+        let mut desc: D3D11_SHADER_RESOURCE_VIEW_DESC = unsafe { mem::zeroed() };
+        desc.Format = depth_format_to_dxgi_srv(fmt);
+        desc.ViewDimension = D3D11_SRV_DIMENSION_TEXTURE2D;
+
+        let view = unsafe {
+            desc.u.Texture2D_mut().MipLevels = 1;
+
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateShaderResourceView(resource, &desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create depth shader resource view"
+            ));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Some(view))
+    }
+
     /// Retrieves this texture as a resource.
     pub fn as_resource(&self) -> *mut ID3D11Resource {
         self.texture.upcast().as_mut()
@@ -217,10 +472,79 @@ impl Texture2D {
         D3D11CalcSubresource(levels, array_slice, num_levels)
     }
 
+    /// Reads back a subresource of a texture with 32 bits per pixel.
+    ///
+    /// Returns the width and height of the subresource, and its pixels one row after the other.
+    pub fn read_pixels(
+        &self,
+        device: &ID3D11Device,
+        ctx: &ID3D11DeviceContext,
+        subres: u32,
+    ) -> Result<(u32, u32, Vec<u32>), Error> {
+        let desc = self.desc();
+
+        let level = subres % desc.MipLevels;
+        let width = cmp::max(desc.Width >> level, 1);
+        let height = cmp::max(desc.Height >> level, 1);
+
+        let staging_desc = D3D11_TEXTURE2D_DESC {
+            Width: width,
+            Height: height,
+            MipLevels: 1,
+            ArraySize: 1,
+            SampleDesc: d3d9_to_dxgi_samples(0, 0),
+            Usage: D3D11_USAGE_STAGING,
+            BindFlags: 0,
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+            MiscFlags: 0,
+            ..desc
+        };
+
+        unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&staging_desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create staging texture"));
+
+            let staging: ComPtr<ID3D11Texture2D> = ComPtr::new(ptr);
+            let resource = staging.upcast().as_mut();
+
+            ctx.CopySubresourceRegion(
+                resource,
+                0,
+                0,
+                0,
+                0,
+                self.as_resource(),
+                subres,
+                ptr::null(),
+            );
+
+            let mut mapped = mem::zeroed();
+            let result = ctx.Map(resource, 0, D3D11_MAP_READ, 0, &mut mapped);
+            if_not_success_err!(check_hresult(result, "Failed to map texture for reading"));
+
+            let mut pixels = Vec::with_capacity((width * height) as usize);
+
+            for row in 0..height as usize {
+                let row =
+                    (mapped.pData as *const u8).add(row * mapped.RowPitch as usize) as *const u32;
+
+                for col in 0..width as usize {
+                    pixels.push(ptr::read_unaligned(row.add(col)));
+                }
+            }
+
+            ctx.Unmap(resource, 0);
+
+            Ok((width, height, pixels))
+        }
+    }
+
     /// Retrieves the description of this texture.
     pub fn desc(&self) -> D3D11_TEXTURE2D_DESC {
         unsafe {
-            let mut desc = mem::uninitialized();
+            let mut desc = mem::zeroed();
             self.texture.GetDesc(&mut desc);
             desc
         }
@@ -5,43 +5,70 @@ use crate::Error;
 
 /// Converts D3D9's buffer/texture usage and pool flags to corresponding D3D11 flags.
 ///
+/// | Pool        | Usage                       | D3D11 usage | CPU access   |
+/// |-------------|-----------------------------|-------------|--------------|
+/// | Default     | render target, auto mip map | Default     | none         |
+/// | Default     | dynamic                     | Dynamic     | write        |
+/// | Default     | static                      | Default     | none         |
+/// | Managed     | static                      | Staging     | read / write |
+/// | System mem. | dynamic or write only       | Dynamic     | write        |
+/// | System mem. | other                       | Staging     | read / write |
+/// | Scratch     | any                         | Staging     | read / write |
+///
+/// Managed resources are the copy in system memory, which is uploaded to VRAM
+/// when the resource is used, so they cannot be bound directly.
+/// Staging resources have no bind flags, the other ones can be read in shaders.
+/// Buffers are converted with `d3d_buffer_usage_to_d3d11`, since they have their own bind flags.
+///
 /// Returns an error if a certain combination is invalid.
 pub fn d3d_usage_to_d3d11(
     uflags: UsageFlags,
     pool: MemoryPool,
 ) -> Result<(D3D11_USAGE, D3D11_BIND_FLAG, D3D11_CPU_ACCESS_FLAG), Error> {
-    let mut usage = D3D11_USAGE_DEFAULT;
-    let mut cpu_flags = 0;
+    let gpu_only = UsageFlags::RENDER_TARGET | UsageFlags::DEPTH_STENCIL;
+
+    let invalid = match pool {
+        // Resources the CPU writes to cannot be rendered to.
+        MemoryPool::Default => uflags.contains(UsageFlags::DYNAMIC | UsageFlags::RENDER_TARGET),
+        // Managed resources are uploaded by the runtime, so they cannot be dynamic.
+        MemoryPool::Managed => uflags.intersects(gpu_only | UsageFlags::DYNAMIC),
+        // The GPU cannot write to resources which are not in VRAM.
+        MemoryPool::SystemMem | MemoryPool::Scratch => {
+            uflags.intersects(gpu_only | UsageFlags::AUTO_GEN_MIP_MAP)
+        }
+    };
+
+    if invalid {
+        error!(
+            "Invalid usage {:?} for a resource in {:?} pool",
+            uflags, pool
+        );
+        return Err(Error::InvalidCall);
+    }
+
+    if uflags.intersects(UsageFlags::RENDER_TARGET | UsageFlags::AUTO_GEN_MIP_MAP) {
+        // Generating mip maps requires the GPU to render into the texture,
+        // so it can only live in VRAM.
+        let bind_flags = D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET;
+        return Ok((D3D11_USAGE_DEFAULT, bind_flags, 0));
+    }
 
     let write_to = UsageFlags::DYNAMIC | UsageFlags::WRITE_ONLY;
 
-    match pool {
-        MemoryPool::Default => {
-            if uflags.intersects(write_to) {
-                usage = D3D11_USAGE_DYNAMIC;
-                cpu_flags = D3D11_CPU_ACCESS_WRITE;
-            }
+    let (usage, cpu_flags) = match pool {
+        MemoryPool::Default if uflags.intersects(UsageFlags::DYNAMIC) => {
+            (D3D11_USAGE_DYNAMIC, D3D11_CPU_ACCESS_WRITE)
         }
-        MemoryPool::Managed => {
-            if uflags.intersects(write_to) {
-                usage = D3D11_USAGE_DYNAMIC;
-                cpu_flags = D3D11_CPU_ACCESS_WRITE;
-            } else {
-                usage = D3D11_USAGE_STAGING;
-                cpu_flags = D3D11_CPU_ACCESS_WRITE | D3D11_CPU_ACCESS_READ;
-            }
-        }
-        MemoryPool::SystemMem => {
-            if uflags.intersects(write_to) {
-                usage = D3D11_USAGE_DYNAMIC;
-                cpu_flags = D3D11_CPU_ACCESS_WRITE;
-            } else {
-                usage = D3D11_USAGE_STAGING;
-                cpu_flags = D3D11_CPU_ACCESS_WRITE | D3D11_CPU_ACCESS_READ;
-            }
+        // Static resources are only written by the GPU, or with `UpdateSubresource`.
+        MemoryPool::Default => (D3D11_USAGE_DEFAULT, 0),
+        MemoryPool::SystemMem if uflags.intersects(write_to) => {
+            (D3D11_USAGE_DYNAMIC, D3D11_CPU_ACCESS_WRITE)
         }
-        _ => error!("Unsupported memory pool: {:?}", pool),
-    }
+        MemoryPool::Managed | MemoryPool::SystemMem | MemoryPool::Scratch => (
+            D3D11_USAGE_STAGING,
+            D3D11_CPU_ACCESS_WRITE | D3D11_CPU_ACCESS_READ,
+        ),
+    };
 
     let bind_flags = if usage != D3D11_USAGE_STAGING {
         // Even if the app doesn't end up using this in a shader,
@@ -53,3 +80,137 @@ pub fn d3d_usage_to_d3d11(
 
     Ok((usage, bind_flags, cpu_flags))
 }
+
+/// Converts D3D9's buffer usage and pool flags to the D3D11 usage and CPU access flags.
+///
+/// Unlike textures, static buffers in the default pool can still be locked by the app.
+/// They stay in VRAM, and are locked through a staging copy instead.
+pub fn d3d_buffer_usage_to_d3d11(
+    uflags: UsageFlags,
+    pool: MemoryPool,
+) -> Result<(D3D11_USAGE, D3D11_CPU_ACCESS_FLAG), Error> {
+    let (usage, _, cpu_flags) = d3d_usage_to_d3d11(uflags, pool)?;
+    Ok((usage, cpu_flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRV: D3D11_BIND_FLAG = D3D11_BIND_SHADER_RESOURCE;
+    const RTV: D3D11_BIND_FLAG = D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET;
+    const READ_WRITE: D3D11_CPU_ACCESS_FLAG = D3D11_CPU_ACCESS_READ | D3D11_CPU_ACCESS_WRITE;
+
+    /// Every usage which matters for the conversion, in the same order as the expected results.
+    fn usages() -> [UsageFlags; 8] {
+        [
+            UsageFlags::empty(),
+            UsageFlags::DYNAMIC,
+            UsageFlags::WRITE_ONLY,
+            UsageFlags::DYNAMIC | UsageFlags::WRITE_ONLY,
+            UsageFlags::RENDER_TARGET,
+            UsageFlags::DEPTH_STENCIL,
+            UsageFlags::AUTO_GEN_MIP_MAP,
+            UsageFlags::DYNAMIC | UsageFlags::RENDER_TARGET,
+        ]
+    }
+
+    #[test]
+    fn usage_and_pool_map_to_d3d11() {
+        let static_vram = Some((D3D11_USAGE_DEFAULT, SRV, 0));
+        let dynamic = Some((D3D11_USAGE_DYNAMIC, SRV, D3D11_CPU_ACCESS_WRITE));
+        let render_target = Some((D3D11_USAGE_DEFAULT, RTV, 0));
+        let staging = Some((D3D11_USAGE_STAGING, 0, READ_WRITE));
+
+        let table = [
+            (
+                MemoryPool::Default,
+                [
+                    static_vram,
+                    dynamic,
+                    static_vram,
+                    dynamic,
+                    render_target,
+                    static_vram,
+                    render_target,
+                    None,
+                ],
+            ),
+            (
+                MemoryPool::Managed,
+                [
+                    staging,
+                    None,
+                    staging,
+                    None,
+                    None,
+                    None,
+                    render_target,
+                    None,
+                ],
+            ),
+            (
+                MemoryPool::SystemMem,
+                [staging, dynamic, dynamic, dynamic, None, None, None, None],
+            ),
+            (
+                MemoryPool::Scratch,
+                [staging, staging, staging, staging, None, None, None, None],
+            ),
+        ];
+
+        for &(pool, ref expected) in &table {
+            for (&uflags, &expected) in usages().iter().zip(expected) {
+                let result = d3d_usage_to_d3d11(uflags, pool);
+
+                match expected {
+                    Some(expected) => {
+                        assert_eq!(result.ok(), Some(expected), "{:?} {:?}", pool, uflags)
+                    }
+                    None => assert!(
+                        matches!(result, Err(Error::InvalidCall)),
+                        "{:?} {:?}",
+                        pool,
+                        uflags
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn buffer_usage_and_pool_map_to_d3d11() {
+        let static_vram = Some((D3D11_USAGE_DEFAULT, 0));
+        let dynamic = Some((D3D11_USAGE_DYNAMIC, D3D11_CPU_ACCESS_WRITE));
+        let staging = Some((D3D11_USAGE_STAGING, READ_WRITE));
+
+        // Buffers can only be dynamic or write only.
+        let table = [
+            (
+                MemoryPool::Default,
+                [static_vram, dynamic, static_vram, dynamic],
+            ),
+            (MemoryPool::Managed, [staging, None, staging, None]),
+            (MemoryPool::SystemMem, [staging, dynamic, dynamic, dynamic]),
+            (MemoryPool::Scratch, [staging, staging, staging, staging]),
+        ];
+
+        for &(pool, ref expected) in &table {
+            for (&uflags, &expected) in usages()[..4].iter().zip(expected) {
+                let result = d3d_buffer_usage_to_d3d11(uflags, pool);
+
+                match expected {
+                    Some(expected) => {
+                        assert_eq!(result.ok(), Some(expected), "{:?} {:?}", pool, uflags)
+                    }
+                    None => assert!(
+                        matches!(result, Err(Error::InvalidCall)),
+                        "{:?} {:?}",
+                        pool,
+                        uflags
+                    ),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::{mem, ptr};
+
+use winapi::shared::d3d9types::*;
+use winapi::um::{d3d11::*, d3dcommon::D3D11_SRV_DIMENSION_TEXTURE3D};
+
+use comptr::ComPtr;
+
+use crate::core::{fmt::d3d_format_to_dxgi, *};
+
+use super::util::d3d_usage_to_d3d11;
+use crate::Error;
+
+/// Wrapper for a D3D11 3D texture.
+#[derive(Clone)]
+pub struct VolumeTexture {
+    texture: ComPtr<ID3D11Texture3D>,
+}
+
+impl VolumeTexture {
+    /// Creates a new volume texture.
+    pub fn new(
+        device: &ID3D11Device,
+        (width, height, depth): (u32, u32, u32),
+        levels: u32,
+        uflags: UsageFlags,
+        fmt: D3DFORMAT,
+        pool: MemoryPool,
+    ) -> Result<Self, Error> {
+        let (usage, bind_flags, cpu_flags) = d3d_usage_to_d3d11(uflags, pool)?;
+
+        let fmt = d3d_format_to_dxgi(fmt);
+
+        let desc = D3D11_TEXTURE3D_DESC {
+            Width: width,
+            Height: height,
+            Depth: depth,
+            MipLevels: levels,
+            Format: fmt,
+            Usage: usage,
+            BindFlags: bind_flags,
+            CPUAccessFlags: cpu_flags,
+            MiscFlags: 0,
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture3D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create volume texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a copy of this texture's layout which can only be accessed by the GPU,
+    /// and can be bound to shaders.
+    pub fn new_gpu_copy(&self, device: &ID3D11Device) -> Result<Self, Error> {
+        let desc = D3D11_TEXTURE3D_DESC {
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE,
+            CPUAccessFlags: 0,
+            MiscFlags: 0,
+            ..self.desc()
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture3D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create GPU volume texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a shader resource view which skips the mip levels before `most_detailed_mip`.
+    pub fn create_shader_view(
+        &self,
+        device: &ID3D11Device,
+        most_detailed_mip: u32,
+    ) -> Result<ComPtr<ID3D11ShaderResourceView>, Error> {
+        let desc = self.desc();
+
+        let mut view_desc: D3D11_SHADER_RESOURCE_VIEW_DESC = unsafe { mem::zeroed() };
+        view_desc.Format = desc.Format;
+        view_desc.ViewDimension = D3D11_SRV_DIMENSION_TEXTURE3D;
+
+        let view = unsafe {
+            *view_desc.u.Texture3D_mut() = D3D11_TEX3D_SRV {
+                MostDetailedMip: most_detailed_mip,
+                MipLevels: desc.MipLevels - most_detailed_mip,
+            };
+
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateShaderResourceView(self.as_resource(), &view_desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create volume texture view"
+            ));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(view)
+    }
+
+    /// Retrieves this texture as a resource.
+    pub fn as_resource(&self) -> *mut ID3D11Resource {
+        self.texture.upcast().as_mut()
+    }
+
+    /// Retrieves the description of this texture.
+    pub fn desc(&self) -> D3D11_TEXTURE3D_DESC {
+        unsafe {
+            let mut desc = mem::zeroed();
+            self.texture.GetDesc(&mut desc);
+            desc
+        }
+    }
+
+    /// Retrieves the dimensions of a certain mip level.
+    pub fn level_size(&self, level: u32) -> (u32, u32, u32) {
+        let desc = self.desc();
+        let mip = |dim: u32| std::cmp::max(dim >> level, 1);
+
+        (mip(desc.Width), mip(desc.Height), mip(desc.Depth))
+    }
+}
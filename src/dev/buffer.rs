@@ -1,6 +1,13 @@
+use std::cell::{Cell, RefCell};
+use std::ptr;
 use std::sync::atomic::{AtomicU32, Ordering};
 
+use winapi::shared::dxgiformat::*;
 use winapi::shared::{d3d9::*, d3d9types::*};
+use winapi::um::d3d11::{
+    D3D11_BIND_INDEX_BUFFER, D3D11_BIND_VERTEX_BUFFER, D3D11_BOX, D3D11_USAGE_DEFAULT,
+    D3D11_USAGE_DYNAMIC,
+};
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
 
 use com_impl::{implementation, interface, ComInterface};
@@ -10,7 +17,194 @@ use crate::core::*;
 use crate::d3d11;
 use crate::Error;
 
-use super::{Device, Resource};
+use super::{Device, ManagedStorage, Resident, Resource};
+
+/// A lock of a buffer in VRAM, which the app accesses through a staging copy.
+#[derive(Copy, Clone)]
+struct ShadowLock {
+    // The locked range, in bytes.
+    start: u32,
+    end: u32,
+    // Read only locks don't have to be copied back.
+    read_only: bool,
+}
+
+/// Locks the memory of vertex and index buffers.
+///
+/// The CPU can't access static buffers in the default pool, since they live in VRAM.
+/// They are locked through a staging copy, which is created on their first lock,
+/// and the locked range is copied back to VRAM when they are unlocked.
+struct BufferLock {
+    shadow: RefCell<Option<d3d11::Buffer>>,
+    locked: Cell<Option<ShadowLock>>,
+}
+
+impl BufferLock {
+    fn new() -> Self {
+        Self {
+            shadow: RefCell::new(None),
+            locked: Cell::new(None),
+        }
+    }
+
+    /// Locks a range of a buffer, and returns a pointer to its first byte.
+    ///
+    /// A size of 0 locks everything from `offset` to the end of the buffer.
+    fn lock(
+        &self,
+        resource: &Resource,
+        buffer: &d3d11::Buffer,
+        offset: u32,
+        size: u32,
+        flags: LockFlags,
+    ) -> Result<*mut u8, Error> {
+        let desc = buffer.desc();
+
+        let end = if size == 0 {
+            desc.ByteWidth
+        } else {
+            offset.saturating_add(size)
+        };
+
+        if offset > end || end > desc.ByteWidth {
+            return Err(Error::InvalidCall);
+        }
+
+        let device = resource.device().dx11_device();
+        let ctx = resource.device_context();
+        let usage = resource.usage();
+        // Only dynamic buffers in VRAM can be discarded.
+        let write_flags = LockFlags::DISCARD | LockFlags::NO_OVERWRITE;
+
+        let data = match desc.Usage {
+            D3D11_USAGE_DEFAULT => {
+                let existing = self.shadow.borrow().clone();
+                let shadow = match existing {
+                    Some(shadow) => shadow,
+                    None => {
+                        let shadow = buffer.new_staging_copy(device)?;
+                        self.shadow.replace(Some(shadow.clone()));
+                        shadow
+                    }
+                };
+
+                // Only the locked range is copied back, so write only locks
+                // don't need the current contents.
+                let reads = flags.intersects(LockFlags::READ_ONLY)
+                    || !usage.intersects(UsageFlags::WRITE_ONLY);
+
+                if reads && !flags.intersects(LockFlags::DISCARD) {
+                    unsafe {
+                        ctx.lock()
+                            .CopyResource(shadow.as_resource(), buffer.as_resource());
+                    }
+                }
+
+                let map_usage = if reads {
+                    usage - UsageFlags::WRITE_ONLY
+                } else {
+                    usage | UsageFlags::WRITE_ONLY
+                };
+
+                let mapped = ctx.map(shadow.as_resource(), 0, flags - write_flags, map_usage)?;
+
+                self.locked.set(Some(ShadowLock {
+                    start: offset,
+                    end,
+                    read_only: flags.intersects(LockFlags::READ_ONLY),
+                }));
+
+                mapped.pBits
+            }
+            D3D11_USAGE_DYNAMIC if flags.intersects(write_flags) => {
+                let usage = usage | UsageFlags::WRITE_ONLY;
+                ctx.map(buffer.as_resource(), 0, flags, usage)?.pBits
+            }
+            D3D11_USAGE_DYNAMIC => {
+                // The old contents have to be kept, but the GPU might still be using them.
+                // D3D9 waits for the GPU in this case, so we read them back,
+                // then write them into a newly discarded buffer.
+                run_once!(|| warn!(
+                    "Dynamic buffers locked without DISCARD or NOOVERWRITE are read back"
+                ));
+
+                let contents = buffer.read(device, &ctx.immediate())?;
+
+                let flags = flags | LockFlags::DISCARD;
+                let usage = usage | UsageFlags::WRITE_ONLY;
+                let mapped = ctx.map(buffer.as_resource(), 0, flags, usage)?;
+
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        contents.as_ptr(),
+                        mapped.pBits as *mut u8,
+                        contents.len(),
+                    );
+                }
+
+                mapped.pBits
+            }
+            // Buffers in system memory are never used by the GPU directly.
+            _ => {
+                let mapped = ctx.map(
+                    buffer.as_resource(),
+                    0,
+                    flags - write_flags,
+                    resource.map_usage(),
+                )?;
+                mapped.pBits
+            }
+        };
+
+        Ok(unsafe { (data as *mut u8).add(offset as usize) })
+    }
+
+    /// Unlocks a buffer, copying the locked range back to VRAM if needed.
+    fn unlock(&self, resource: &Resource, buffer: &d3d11::Buffer) -> Result<(), Error> {
+        let ctx = resource.device_context();
+
+        let lock = match self.locked.take() {
+            Some(lock) => lock,
+            None if buffer.desc().Usage == D3D11_USAGE_DEFAULT => return Err(Error::InvalidCall),
+            None => {
+                ctx.unmap(buffer.as_resource(), 0);
+                resource.mark_dirty(buffer.as_resource());
+                return Ok(());
+            }
+        };
+
+        let shadow = self.shadow.borrow();
+        let shadow = shadow.as_ref().ok_or(Error::InvalidCall)?;
+
+        ctx.unmap(shadow.as_resource(), 0);
+
+        if !lock.read_only {
+            let range = D3D11_BOX {
+                left: lock.start,
+                right: lock.end,
+                top: 0,
+                bottom: 1,
+                front: 0,
+                back: 1,
+            };
+
+            unsafe {
+                ctx.lock().CopySubresourceRegion(
+                    buffer.as_resource(),
+                    0,
+                    lock.start,
+                    0,
+                    0,
+                    shadow.as_resource(),
+                    0,
+                    &range,
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
 
 /// Buffer holding vertex data.
 #[interface(IDirect3DVertexBuffer9)]
@@ -19,6 +213,7 @@ pub struct VertexBuffer {
     refs: AtomicU32,
     fvf: u32,
     buffer: d3d11::Buffer,
+    lock_state: BufferLock,
 }
 
 impl VertexBuffer {
@@ -30,12 +225,20 @@ impl VertexBuffer {
         buffer: d3d11::Buffer,
         usage: UsageFlags,
     ) -> ComPtr<Self> {
+        let mut resource = Resource::new(device, usage, pool, ResourceType::VertexBuffer);
+
+        if pool == MemoryPool::Managed {
+            let storage = ManagedStorage::Buffer(buffer.clone(), D3D11_BIND_VERTEX_BUFFER);
+            resource.manage(storage);
+        }
+
         let vb = Self {
             __vtable: Box::new(Self::create_vtable()),
-            resource: Resource::new(device, usage, pool, ResourceType::VertexBuffer),
+            resource,
             refs: AtomicU32::new(1),
             fvf,
             buffer,
+            lock_state: BufferLock::new(),
         };
 
         unsafe { new_com_interface(vb) }
@@ -44,6 +247,35 @@ impl VertexBuffer {
     pub fn get_dx11(&self) -> &d3d11::Buffer {
         &self.buffer
     }
+
+    /// Retrieves the flexible vertex format of the vertices in this buffer.
+    pub fn fvf(&self) -> u32 {
+        self.fvf
+    }
+
+    /// Describes this buffer, with the parameters it was created with.
+    ///
+    /// D3D11 doesn't know about the pool, usage and FVF, so they are taken from the D3D9 resource.
+    pub fn desc(&self) -> D3DVERTEXBUFFER_DESC {
+        D3DVERTEXBUFFER_DESC {
+            Format: D3DFMT_VERTEXDATA,
+            Type: ResourceType::VertexBuffer as u32,
+            Usage: self.usage().bits(),
+            Pool: self.pool() as u32,
+            Size: self.buffer.desc().ByteWidth,
+            FVF: self.fvf,
+        }
+    }
+
+    /// Retrieves the buffer to bind to the pipeline.
+    ///
+    /// Managed buffers are uploaded to VRAM first.
+    pub fn gpu_buffer(&self) -> Result<d3d11::Buffer, Error> {
+        match self.resident()? {
+            Some(Resident::Buffer(buffer)) => Ok(buffer),
+            _ => Ok(self.buffer.clone()),
+        }
+    }
 }
 
 impl std::ops::Deref for VertexBuffer {
@@ -67,40 +299,26 @@ impl ComInterface<IDirect3DResource9Vtbl> for VertexBuffer {
 impl VertexBuffer {
     fn get_desc(&self, ret: *mut D3DVERTEXBUFFER_DESC) -> Error {
         let ret = if_error!(check_mut_ref(ret));
-
-        let desc = self.buffer.desc();
-
-        ret.Type = ResourceType::VertexBuffer as u32;
-        ret.Size = desc.ByteWidth;
-        ret.Format = D3DFMT_R32F;
-        ret.FVF = self.fvf;
-        ret.Pool = self.pool() as u32;
-        ret.Usage = self.usage().bits();
-
+        *ret = self.desc();
         Error::Success
     }
 
-    fn lock(&self, offset: u32, _size: u32, ret: *mut *mut u8, flags: LockFlags) -> Error {
+    fn lock(&self, offset: u32, size: u32, ret: *mut *mut u8, flags: LockFlags) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        let resource = self.buffer.as_resource();
-        let ctx = self.device_context();
-        let mapped = if_error!(ctx.map(resource, 0, flags, self.usage()));
-
         // TODO: allow buffers to be mapped multiple times.
         info!("Mapped vertex buffer");
-        *ret = unsafe {
-            let addr = mapped.pBits as *mut u8;
-            addr.offset(offset as isize)
-        };
+        *ret = if_error!(self
+            .lock_state
+            .lock(self, &self.buffer, offset, size, flags));
 
         Error::Success
     }
 
     fn unlock(&self) -> Error {
-        let resource = self.buffer.as_resource();
-        let ctx = self.device_context();
-        ctx.unmap(resource, 0);
+        let _lock = self.device().lock();
+        if_error!(self.lock_state.unlock(self, &self.buffer));
         Error::Success
     }
 }
@@ -112,6 +330,7 @@ pub struct IndexBuffer {
     refs: AtomicU32,
     fmt: D3DFORMAT,
     buffer: d3d11::Buffer,
+    lock_state: BufferLock,
 }
 
 impl IndexBuffer {
@@ -123,16 +342,62 @@ impl IndexBuffer {
         buffer: d3d11::Buffer,
         usage: UsageFlags,
     ) -> ComPtr<Self> {
+        let mut resource = Resource::new(device, usage, pool, ResourceType::IndexBuffer);
+
+        if pool == MemoryPool::Managed {
+            let storage = ManagedStorage::Buffer(buffer.clone(), D3D11_BIND_INDEX_BUFFER);
+            resource.manage(storage);
+        }
+
         let vb = Self {
             __vtable: Box::new(Self::create_vtable()),
-            resource: Resource::new(device, usage, pool, ResourceType::IndexBuffer),
+            resource,
             refs: AtomicU32::new(1),
             fmt,
             buffer,
+            lock_state: BufferLock::new(),
         };
 
         unsafe { new_com_interface(vb) }
     }
+
+    pub fn get_dx11(&self) -> &d3d11::Buffer {
+        &self.buffer
+    }
+
+    /// Retrieves the format of the indices in this buffer.
+    pub fn format(&self) -> D3DFORMAT {
+        self.fmt
+    }
+
+    /// Describes this buffer, with the parameters it was created with.
+    pub fn desc(&self) -> D3DINDEXBUFFER_DESC {
+        D3DINDEXBUFFER_DESC {
+            Format: self.fmt,
+            Type: ResourceType::IndexBuffer as u32,
+            Usage: self.usage().bits(),
+            Pool: self.pool() as u32,
+            Size: self.buffer.desc().ByteWidth,
+        }
+    }
+
+    /// Retrieves the format to use when binding this buffer.
+    pub fn dxgi_format(&self) -> DXGI_FORMAT {
+        match self.fmt {
+            D3DFMT_INDEX32 => DXGI_FORMAT_R32_UINT,
+            _ => DXGI_FORMAT_R16_UINT,
+        }
+    }
+
+    /// Retrieves the buffer to bind to the pipeline.
+    ///
+    /// Managed buffers are uploaded to VRAM first.
+    pub fn gpu_buffer(&self) -> Result<d3d11::Buffer, Error> {
+        match self.resident()? {
+            Some(Resident::Buffer(buffer)) => Ok(buffer),
+            _ => Ok(self.buffer.clone()),
+        }
+    }
 }
 
 impl std::ops::Deref for IndexBuffer {
@@ -156,39 +421,26 @@ impl ComInterface<IDirect3DResource9Vtbl> for IndexBuffer {
 impl IndexBuffer {
     fn get_desc(&self, ret: *mut D3DINDEXBUFFER_DESC) -> Error {
         let ret = if_error!(check_mut_ref(ret));
-
-        let desc = self.buffer.desc();
-
-        ret.Type = ResourceType::IndexBuffer as u32;
-        ret.Size = desc.ByteWidth;
-        ret.Format = self.fmt;
-        ret.Pool = self.pool() as u32;
-        ret.Usage = self.usage().bits();
-
+        *ret = self.desc();
         Error::Success
     }
 
-    fn lock(&self, offset: u32, _size: u32, ret: *mut *mut u8, flags: LockFlags) -> Error {
+    fn lock(&self, offset: u32, size: u32, ret: *mut *mut u8, flags: LockFlags) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        let resource = self.buffer.as_resource();
-        let ctx = self.device_context();
-        let mapped = if_error!(ctx.map(resource, 0, flags, self.usage()));
-
         // TODO: allow buffers to be mapped multiple times.
         info!("Mapped index buffer");
-        *ret = unsafe {
-            let addr = mapped.pBits as *mut u8;
-            addr.offset(offset as isize)
-        };
+        *ret = if_error!(self
+            .lock_state
+            .lock(self, &self.buffer, offset, size, flags));
 
         Error::Success
     }
 
     fn unlock(&self) -> Error {
-        let resource = self.buffer.as_resource();
-        let ctx = self.device_context();
-        ctx.unmap(resource, 0);
+        let _lock = self.device().lock();
+        if_error!(self.lock_state.unlock(self, &self.buffer));
         Error::Success
     }
 }
@@ -0,0 +1,147 @@
+//! Bounded caches for objects which are expensive to recreate.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use winapi::um::d3d11::ID3D11Device;
+
+use comptr::ComPtr;
+
+use crate::core::config;
+use crate::Error;
+
+/// Statistics about the usage of a cache.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CacheStats {
+    /// Number of entries currently stored.
+    pub len: usize,
+    /// Maximum number of entries the cache can hold.
+    pub capacity: usize,
+    /// Number of lookups which found an entry.
+    pub hits: u64,
+    /// Number of lookups which had to create a new entry.
+    pub misses: u64,
+    /// Number of entries which were thrown out to make room for new ones.
+    pub evictions: u64,
+}
+
+/// A cache which holds at most a fixed number of entries,
+/// evicting the least recently used ones when it is full.
+pub struct LruCache<K, V> {
+    // Every entry remembers the last time it was used.
+    entries: HashMap<K, (V, u64)>,
+    // Monotonic counter, incremented on every access.
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    /// Creates a new cache which can hold up to `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = std::cmp::max(capacity, 1);
+
+        Self {
+            entries: HashMap::with_capacity(capacity),
+            clock: 0,
+            stats: CacheStats {
+                capacity,
+                ..CacheStats::default()
+            },
+        }
+    }
+
+    /// Creates a new cache whose capacity can be overridden by an environment variable.
+    pub fn from_env(var: &str, default: usize) -> Self {
+        Self::new(config::value(var).unwrap_or(default))
+    }
+
+    /// Retrieves an entry from the cache, creating it with `create` if it's missing.
+    pub fn get_or_insert_with<E>(
+        &mut self,
+        key: K,
+        create: impl FnOnce() -> Result<V, E>,
+    ) -> Result<V, E> {
+        self.clock += 1;
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.1 = self.clock;
+            self.stats.hits += 1;
+            return Ok(entry.0.clone());
+        }
+
+        self.stats.misses += 1;
+
+        let value = create()?;
+
+        if self.entries.len() >= self.stats.capacity {
+            self.evict();
+        }
+
+        self.entries.insert(key, (value.clone(), self.clock));
+
+        Ok(value)
+    }
+
+    /// Removes all entries from the cache.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the usage statistics of this cache.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            len: self.entries.len(),
+            ..self.stats
+        }
+    }
+
+    // Removes the least recently used entry.
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, used))| *used)
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+/// Description of a D3D11 state object, used to look it up in a `StateCache`.
+pub trait StateDesc: Hash + Eq + Clone {
+    /// The type of the state object.
+    type State;
+
+    /// Creates a new state object matching this description.
+    fn create(&self, device: &ID3D11Device) -> Result<ComPtr<Self::State>, Error>;
+}
+
+/// Bounded cache of D3D11 state objects, keyed by their description.
+///
+/// Evicted objects are only destroyed once they are no longer bound to the pipeline.
+pub struct StateCache<K, V> {
+    cache: LruCache<K, ComPtr<V>>,
+}
+
+impl<K: StateDesc<State = V>, V> StateCache<K, V> {
+    /// Creates a new cache which can hold up to `capacity` state objects.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: LruCache::new(capacity),
+        }
+    }
+
+    /// Retrieves the state object matching a description, creating it if necessary.
+    pub fn get(&mut self, device: &ID3D11Device, key: K) -> Result<ComPtr<V>, Error> {
+        let desc = key.clone();
+        self.cache.get_or_insert_with(key, || desc.create(device))
+    }
+
+    /// Returns the usage statistics of this cache.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
@@ -0,0 +1,137 @@
+//! Hardware cursor emulation.
+//!
+//! D3D9's hardware cursor is implemented with a Win32 cursor, which the system
+//! draws on top of the window, with the same hotspot and alpha blending.
+
+use std::cell::Cell;
+use std::ptr;
+
+use winapi::shared::minwindef::FALSE;
+use winapi::shared::windef::{HCURSOR, POINT};
+use winapi::um::{wingdi, winuser};
+
+use crate::Error;
+
+/// The cursor an app set on a device.
+pub struct Cursor {
+    // Null until the app sets an image.
+    handle: Cell<HCURSOR>,
+    visible: Cell<bool>,
+}
+
+impl Cursor {
+    /// Creates a new, hidden cursor without an image.
+    pub fn new() -> Self {
+        Self {
+            handle: Cell::new(ptr::null_mut()),
+            visible: Cell::new(false),
+        }
+    }
+
+    /// Replaces the cursor's image.
+    ///
+    /// `pixels` contains the image's rows, in the `A8R8G8B8` format.
+    pub fn set_image(
+        &self,
+        width: u32,
+        height: u32,
+        pixels: &[u32],
+        hotspot: (u32, u32),
+    ) -> Result<(), Error> {
+        // The mask is ignored for images with an alpha channel, but it's still required.
+        // Its rows are aligned to 16 bits.
+        let mask_pitch = ((width + 15) / 16 * 2) as usize;
+        let mask = vec![0u8; mask_pitch * height as usize];
+
+        let handle = unsafe {
+            let color = wingdi::CreateBitmap(
+                width as i32,
+                height as i32,
+                1,
+                32,
+                pixels.as_ptr() as *const _,
+            );
+            let mask =
+                wingdi::CreateBitmap(width as i32, height as i32, 1, 1, mask.as_ptr() as *const _);
+
+            let mut info = winuser::ICONINFO {
+                fIcon: FALSE,
+                xHotspot: hotspot.0,
+                yHotspot: hotspot.1,
+                hbmMask: mask,
+                hbmColor: color,
+            };
+
+            let handle = if color.is_null() || mask.is_null() {
+                ptr::null_mut()
+            } else {
+                winuser::CreateIconIndirect(&mut info)
+            };
+
+            // The cursor keeps its own copy of the bitmaps.
+            wingdi::DeleteObject(color as *mut _);
+            wingdi::DeleteObject(mask as *mut _);
+
+            handle
+        };
+
+        if handle.is_null() {
+            error!("Failed to create a {}x{} cursor", width, height);
+            return Err(Error::InvalidCall);
+        }
+
+        let old = self.handle.replace(handle);
+
+        unsafe {
+            if self.visible.get() {
+                winuser::SetCursor(handle);
+            }
+
+            if !old.is_null() {
+                winuser::DestroyCursor(old);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the cursor to a certain position on the desktop.
+    pub fn set_position(&self, x: i32, y: i32) {
+        unsafe {
+            let mut pos = POINT { x: 0, y: 0 };
+            winuser::GetCursorPos(&mut pos);
+
+            // Moving the cursor generates mouse events, which could make the app set it again.
+            if pos.x != x || pos.y != y {
+                winuser::SetCursorPos(x, y);
+            }
+        }
+    }
+
+    /// Shows or hides the cursor, and returns whether it was previously visible.
+    pub fn show(&self, visible: bool) -> bool {
+        let handle = if visible {
+            self.handle.get()
+        } else {
+            ptr::null_mut()
+        };
+
+        unsafe {
+            winuser::SetCursor(handle);
+        }
+
+        self.visible.replace(visible)
+    }
+}
+
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        let handle = self.handle.get();
+
+        if !handle.is_null() {
+            unsafe {
+                winuser::DestroyCursor(handle);
+            }
+        }
+    }
+}
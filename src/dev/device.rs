@@ -1,25 +1,58 @@
-use std::sync::atomic::AtomicU32;
-use std::{cmp, mem, ptr};
-
-use winapi::shared::{d3d9::*, d3d9caps::D3DCAPS9, d3d9types::*, dxgi::IDXGIFactory, windef::*};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::{cmp, mem, ptr, slice};
+
+use winapi::ctypes::c_void;
+use winapi::shared::{
+    d3d9::*,
+    d3d9caps::D3DCAPS9,
+    d3d9types::*,
+    dxgi::{IDXGIFactory, DXGI_MWA_NO_ALT_ENTER, DXGI_MWA_NO_WINDOW_CHANGES},
+    windef::*,
+};
 use winapi::um::{
     d3d11::*,
+    d3dcommon::D3D11_PRIMITIVE_TOPOLOGY_POINTLIST,
     unknwnbase::{IUnknown, IUnknownVtbl},
+    wingdi::PALETTEENTRY,
+    winnt::HANDLE,
+    winuser,
 };
 
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
 
-use super::state::{DeviceState, StateBlock};
+use super::ffp::{
+    self, d3dcolor_to_vec, FixedFunction, GeometryShaderKey, PixelShaderKey, VertexShaderKey,
+    PALETTE_SIZE,
+};
+use super::state::{
+    Bound, DeviceState, StateBlock, StateChange, StreamSource, MAX_ACTIVE_LIGHTS, MAX_CLIP_PLANES,
+    MAX_STREAMS, MAX_TEXTURE_STAGES,
+};
 use super::*;
 
 use crate::core::*;
 use crate::d3d11;
 use crate::Error;
-use winapi::shared::minwindef::{DWORD, UINT};
+use winapi::shared::dxgiformat::*;
+use winapi::shared::minwindef::{BOOL, DWORD, UINT};
+
+/// Apps can define up to this many texture palettes.
+const MAX_PALETTES: u32 = 1 << 16;
+
+/// Number of samplers pixel shaders can read textures from.
+const MAX_PIXEL_SAMPLERS: u32 = 16;
+
+/// Number of vertex shader constants available with software vertex processing.
+const MAX_SOFTWARE_VERTEX_SHADER_CONSTANTS: u32 = 8192;
 
 /// Structure representing a logical graphics device.
-#[interface(IDirect3DDevice9)]
+///
+/// It also implements the D3D9Ex extensions.
+#[interface(IDirect3DDevice9Ex)]
 pub struct Device {
     refs: AtomicU32,
     // Interface which created this device.
@@ -33,6 +66,8 @@ pub struct Device {
     device: d3d11::Device,
     // The context in which commands are run.
     ctx: d3d11::DeviceContext,
+    // Held during every API call, if the app uses the device from multiple threads.
+    lock: Option<DeviceLock>,
     // Store the creation params, since the app might request them later.
     creation_params: D3DDEVICE_CREATION_PARAMETERS,
     // The DXGI factory which was used to create this device.
@@ -43,10 +78,62 @@ pub struct Device {
     // The implicit swap chain for the back buffer.
     // There is one for each device in an adapter group.
     swap_chains: Vec<ComPtr<SwapChain>>,
+    // Swap chains created by the app, which are not kept alive by the device.
+    additional_swap_chains: RefCell<Vec<*const SwapChain>>,
     // The device's currently set render targets.
     render_targets: Vec<Option<ComPtr<Surface>>>,
     // The device's current depth / stencil buffer.
     depth_stencil: Option<ComPtr<Surface>>,
+    // Input layouts which were created for declaration / shader pairs.
+    input_layouts: RefCell<InputLayoutCache>,
+    // State objects which were created for the render states.
+    pipeline: RefCell<PipelineStates>,
+    // Shaders generated for the fixed function pipeline, and the constants they read.
+    ffp: RefCell<FixedFunction>,
+    // Vertex declarations created for the flexible vertex formats the app used.
+    fvf_decls: HashMap<u32, ComPtr<VertexDeclaration>>,
+    // State which changed since the last draw, and has to be bound again.
+    dirty: DirtyState,
+    // Number of resources allocated in the default pool which are still alive.
+    default_resources: AtomicU32,
+    // Number of objects which point back to this device and are still alive.
+    // The device is only freed once all of them were destroyed.
+    children: AtomicU32,
+    // Keeps track of the resources in the managed pool, and uploads them to VRAM.
+    managed: RefCell<ResidencyManager>,
+    // Set when the device can no longer render, e.g. because the window was minimized.
+    // The app has to call `Reset` to get out of this state.
+    lost: Cell<bool>,
+    // Whether we are between a BeginScene / EndScene pair.
+    in_scene: bool,
+    // Reject draw calls issued outside of a scene, like the debug runtime does.
+    strict_scenes: bool,
+    // Flush the pending commands at the end of every scene.
+    flush_on_end_scene: bool,
+    // Caps the frame rate, if the user requested it.
+    frame_limiter: Option<FrameLimiter>,
+    // Measures the frames presented by this device.
+    profiler: Profiler,
+    // Buffers which the data of user pointer draws is copied into.
+    user_vertices: d3d11::DynamicBuffer,
+    user_indices: d3d11::DynamicBuffer,
+    // The hardware cursor.
+    cursor: Cursor,
+    // Texture palettes defined by the app, and the one used by paletted textures.
+    palettes: HashMap<u32, Box<[PALETTEENTRY]>>,
+    current_palette: u32,
+    // Number of segments N-patches would be tessellated into.
+    // Only stored for the app, since N-patches are drawn as ordinary triangles.
+    n_patch_segments: f32,
+    // Whether vertices are currently processed in software.
+    // Can only be changed on devices created with mixed vertex processing.
+    software_vertex_processing: bool,
+    // Clip status set by the app. D3D11 clips primitives itself,
+    // so this is only stored to be returned to the app.
+    clip_status: D3DCLIPSTATUS9,
+
+    // State changes made while recording a state block, if one is being recorded.
+    recording: Option<Vec<StateChange>>,
 
     // The current internal state of this device,
     // as it was last set by calling state functions.
@@ -63,7 +150,10 @@ impl Device {
         factory: ComPtr<IDXGIFactory>,
     ) -> Result<ComPtr<Device>, Error> {
         let device = d3d11::Device::new(adapter.device());
-        let ctx = d3d11::DeviceContext::new(&device);
+        // Apps which use the device from multiple threads expect D3D9 to serialize the calls.
+        let multithreaded = cp.BehaviorFlags & D3DCREATE_MULTITHREADED != 0;
+        let deferred = config::flag("D3D9_DEFERRED_CONTEXT");
+        let ctx = d3d11::DeviceContext::new(&device, multithreaded, deferred);
 
         // Determine which window to render to.
         // TODO: track the focus window and use it to disable rendering
@@ -77,7 +167,20 @@ impl Device {
                 .ok_or(Error::InvalidCall)?
         };
 
-        let istate = DeviceState::default();
+        let istate = DeviceState::default_state(pp);
+
+        let profiler = Profiler::new(&device);
+        let user_vertices = d3d11::DynamicBuffer::new(
+            &device,
+            d3d11::DEFAULT_DYNAMIC_BUFFER_SIZE,
+            D3D11_BIND_VERTEX_BUFFER,
+        )?;
+        let user_indices = d3d11::DynamicBuffer::new(
+            &device,
+            d3d11::DEFAULT_DYNAMIC_BUFFER_SIZE,
+            D3D11_BIND_INDEX_BUFFER,
+        )?;
+        let ffp = FixedFunction::new(&device)?;
 
         let device = Self {
             __vtable: Box::new(Self::create_vtable()),
@@ -86,12 +189,45 @@ impl Device {
             adapter,
             device,
             ctx,
+            lock: if multithreaded {
+                Some(DeviceLock::new())
+            } else {
+                None
+            },
             creation_params: cp,
             factory,
             window,
             swap_chains: Vec::new(),
+            additional_swap_chains: RefCell::new(Vec::new()),
             render_targets: Vec::new(),
             depth_stencil: None,
+            input_layouts: RefCell::new(InputLayoutCache::new()),
+            pipeline: RefCell::new(PipelineStates::new()),
+            ffp: RefCell::new(ffp),
+            fvf_decls: HashMap::new(),
+            dirty: DirtyState::new(),
+            default_resources: AtomicU32::new(0),
+            children: AtomicU32::new(0),
+            managed: RefCell::new(ResidencyManager::new(u64::from(adapter.available_memory()))),
+            lost: Cell::new(false),
+            in_scene: false,
+            strict_scenes: config::flag("D3D9_STRICT_SCENES"),
+            flush_on_end_scene: config::flag("D3D9_FLUSH_ON_END_SCENE"),
+            frame_limiter: FrameLimiter::from_env(),
+            profiler,
+            user_vertices,
+            user_indices,
+            cursor: Cursor::new(),
+            palettes: HashMap::new(),
+            current_palette: 0,
+            n_patch_segments: 0.0,
+            software_vertex_processing: cp.BehaviorFlags & D3DCREATE_SOFTWARE_VERTEXPROCESSING != 0,
+            // Nothing has been clipped yet.
+            clip_status: D3DCLIPSTATUS9 {
+                ClipUnion: 0,
+                ClipIntersection: !0,
+            },
+            recording: None,
             istate,
         };
 
@@ -100,38 +236,28 @@ impl Device {
         // Create the default swap chain for the adapter.
         device.create_default_swap_chain(pp)?;
 
-        // Create the default render target for the swap chain.
-        device.create_default_render_target()?;
+        // The app is responsible for switching between windowed and full screen modes.
+        if !config::flag("D3D9_DXGI_WINDOW_CHANGES") {
+            let focus = if cp.hFocusWindow.is_null() {
+                device.window
+            } else {
+                cp.hFocusWindow
+            };
 
-        // If the application requested it, we can automatically create
-        // a depth/stencil buffer for it.
-        if pp.EnableAutoDepthStencil != 0 {
-            device.depth_stencil = {
-                let width = pp.BackBufferWidth;
-                let height = pp.BackBufferHeight;
-                let fmt = pp.AutoDepthStencilFormat;
-                let discard = pp.Flags & D3DPRESENTFLAG_DISCARD_DEPTHSTENCIL;
-                let ms_ty = 0;
-                let ms_qlt = 0;
-                let shared_handle = 0;
-
-                let mut ptr = ptr::null_mut();
-
-                if_not_success_err!(device.create_depth_stencil_surface(
-                    width,
-                    height,
-                    fmt,
-                    ms_ty,
-                    ms_qlt,
-                    discard,
-                    &mut ptr,
-                    shared_handle,
-                ));
-
-                Some(ComPtr::new(ptr))
+            let result = unsafe {
+                device.factory.MakeWindowAssociation(
+                    focus,
+                    DXGI_MWA_NO_WINDOW_CHANGES | DXGI_MWA_NO_ALT_ENTER,
+                )
             };
+            if_not_success_err!(check_hresult(result, "Failed to associate window"));
         }
 
+        // Create the default render target for the swap chain.
+        device.create_default_render_target()?;
+
+        device.create_auto_depth_stencil(pp)?;
+
         // Now that we have an initial RT / DS buffer, we need to set D3D11's state.
         device.update_render_targets();
 
@@ -152,342 +278,1364 @@ impl Device {
         &self.device
     }
 
-    /// Creates the default swap chain for this device.
-    fn create_default_swap_chain(&mut self, pp: &mut D3DPRESENT_PARAMETERS) -> Result<(), Error> {
-        // Note: this function is usually used for non-implicit swap chains,
-        // but it's a good idea to reuse it.
-        let swap_chain = {
-            let mut ret = ptr::null_mut();
-            match self.create_additional_swap_chain(pp, &mut ret) {
-                Error::Success => (),
-                err => return Err(err),
-            };
-
-            ComPtr::new(ret)
+    /// Retrieves the input layout to use with a vertex declaration and a vertex shader's byte code.
+    pub fn input_layout(
+        &self,
+        decl: &VertexDeclaration,
+        code: &Arc<[u32]>,
+    ) -> Result<ComPtr<ID3D11InputLayout>, Error> {
+        self.input_layouts
+            .borrow_mut()
+            .get(&self.device, decl.elements(), code)
+    }
+
+    /// Returns statistics about the input layout cache, for debugging purposes.
+    pub fn input_layout_stats(&self) -> CacheStats {
+        self.input_layouts.borrow().stats()
+    }
+
+    /// Skips the following draw calls on the GPU, depending on the result of an occlusion query.
+    ///
+    /// This is an extension to D3D9, for engines which would otherwise wait for the result
+    /// on the CPU. Draws are skipped when whether any pixels passed the query equals `value`.
+    /// Passing `None` draws everything again.
+    pub fn set_predication(&self, query: Option<&Query>, value: bool) -> Result<(), Error> {
+        let _lock = self.lock();
+
+        let predicate = match query {
+            Some(query) => query
+                .predicate()
+                .map(d3d11::Query::as_predicate)
+                .ok_or(Error::NotAvailable)?,
+            None => ptr::null_mut(),
         };
 
-        // Now put it in the list of implicit swap chains, which should be empty.
-        let scs = &mut self.swap_chains;
-
-        assert!(
-            scs.is_empty(),
-            "Cannot create default swap chain if it already exists"
-        );
-
-        scs.push(swap_chain);
+        unsafe {
+            self.ctx.lock().SetPredication(predicate, value as BOOL);
+        }
 
         Ok(())
     }
 
-    /// Tries to retrieve a swap chain based on the index.
-    fn check_swap_chain(&self, sc: u32) -> Result<&ComPtr<SwapChain>, Error> {
-        self.swap_chains.get(sc as usize).ok_or(Error::InvalidCall)
+    /// Retrieves the profiler which measures this device's frames.
+    pub fn profiler(&self) -> &Profiler {
+        &self.profiler
     }
 
-    /// Helper function for creating render targets.
-    fn create_render_target_helper(
-        &self,
-        texture: d3d11::Texture2D,
-    ) -> Result<ComPtr<Surface>, Error> {
-        // Create a render target view into the texture.
-        let rt_view = texture.create_rt_view(&self.device)?;
-
-        let data = SurfaceData::RenderTarget(rt_view);
-        let surface = Surface::new(
-            self,
-            texture,
-            UsageFlags::RENDER_TARGET,
-            MemoryPool::Default,
-            data,
-        );
-
-        Ok(surface)
+    /// Returns statistics about the state object caches, for debugging purposes.
+    pub fn pipeline_stats(&self) -> PipelineStats {
+        self.pipeline.borrow().stats()
     }
 
-    /// Creates the default render target for this device.
-    fn create_default_render_target(&mut self) -> Result<(), Error> {
-        let sc = &self.swap_chains[0];
-        let bbuf = sc.buffer(0)?;
-
-        let rt = self.create_render_target_helper(bbuf)?;
-
-        self.render_targets.push(Some(rt));
+    /// Creates a new swap chain for this device.
+    fn new_swap_chain(&self, pp: &mut D3DPRESENT_PARAMETERS) -> Result<ComPtr<SwapChain>, Error> {
+        // Additional swap chains usually present to a different window than the device's.
+        let window = self.swap_chain_window(pp);
 
-        Ok(())
+        SwapChain::new(self, &self.device, &self.factory, pp, window)
     }
 
-    /// Retrieves a handle to a render target.
-    fn check_render_target(&self, i: u32) -> Result<&ComPtr<Surface>, Error> {
-        if let Some(rt) = self.render_targets.get(i as usize) {
-            if let Some(rt) = rt {
-                Ok(rt)
-            } else {
-                Err(Error::NotFound)
-            }
-        } else {
-            Err(Error::NotFound)
+    /// Creates the default swap chain for this device.
+    fn create_default_swap_chain(&mut self, pp: &mut D3DPRESENT_PARAMETERS) -> Result<(), Error> {
+        // The list of implicit swap chains should be empty.
+        if !self.swap_chains.is_empty() {
+            error!("Cannot create default swap chain if it already exists");
+            return Err(Error::InvalidCall);
         }
-    }
-
-    /// Synchronises D3D9's render target views and depth / stencil view with D3D11.
-    fn update_render_targets(&mut self) {
-        let num = self.render_targets.len() as u32;
 
-        let mut rt_views = [ptr::null_mut(); 8];
-        for (i, rt) in self.render_targets.iter().enumerate() {
-            if let Some(rt) = rt {
-                rt_views[i] = rt.render_target_view().unwrap() as *mut _;
-            }
-        }
+        let swap_chain = self.new_swap_chain(pp)?;
+        self.swap_chains.push(swap_chain);
 
-        let ds_view = self
-            .depth_stencil
-            .as_ref()
-            .map(|ds| ds.depth_stencil_view().unwrap() as *mut _)
-            .unwrap_or(ptr::null_mut());
+        Ok(())
+    }
 
-        unsafe {
-            self.ctx.OMSetRenderTargets(num, rt_views.as_ptr(), ds_view);
+    /// Creates the depth / stencil buffer, if the application requested us to.
+    ///
+    /// It has the size of the back buffer, and is bound until the app sets another one.
+    fn create_auto_depth_stencil(&mut self, pp: &D3DPRESENT_PARAMETERS) -> Result<(), Error> {
+        if pp.EnableAutoDepthStencil == 0 {
+            return Ok(());
         }
 
-        // We also need to update the viewport.
-        let (width, height) = unsafe {
-            let rt = self.render_targets[0].as_ref().unwrap();
-            let mut desc = mem::uninitialized();
-            rt.get_desc(&mut desc);
-            (desc.Width, desc.Height)
-        };
-
-        let vp = D3DVIEWPORT9 {
-            X: 0,
-            Y: 0,
-            Width: width,
-            Height: height,
-            MinZ: 0.0,
-            MaxZ: 1.0,
+        self.depth_stencil = {
+            let width = pp.BackBufferWidth;
+            let height = pp.BackBufferHeight;
+            let fmt = pp.AutoDepthStencilFormat;
+            let discard = pp.Flags & D3DPRESENTFLAG_DISCARD_DEPTHSTENCIL;
+            // D3D11 requires it to be multisampled like the back buffer.
+            let ms_ty = pp.MultiSampleType;
+            let ms_qlt = pp.MultiSampleQuality;
+            let shared_handle = 0;
+
+            let mut ptr = ptr::null_mut();
+
+            if_not_success_err!(self.create_depth_stencil_surface(
+                width,
+                height,
+                fmt,
+                ms_ty,
+                ms_qlt,
+                discard,
+                &mut ptr,
+                shared_handle,
+            ));
+
+            Some(ComPtr::new(ptr))
         };
 
-        self.set_viewport(&vp);
+        Ok(())
     }
-}
 
-impl_iunknown!(struct Device: IUnknown, IDirect3DDevice9);
+    /// Registers a new resource allocated in the default pool.
+    pub fn add_default_resource(&self) {
+        self.default_resources.fetch_add(1, Ordering::SeqCst);
+    }
 
-#[implementation(IDirect3DDevice9)]
-impl Device {
-    // -- Device status functions --
+    /// Unregisters a resource allocated in the default pool, once it is destroyed.
+    pub fn remove_default_resource(&self) {
+        self.default_resources.fetch_sub(1, Ordering::SeqCst);
+    }
 
-    /// Resets the device, recreating all its state.
-    fn reset(&mut self) {
-        unimplemented!()
+    /// Locks the device for the rest of an API call, if the app uses it from multiple threads.
+    pub fn lock(&self) -> Option<DeviceLockGuard> {
+        self.lock.as_ref().map(DeviceLock::lock)
     }
 
-    /// Checks that the device has not yet been lost / reset.
-    fn test_cooperative_level(&self) -> Error {
-        // Even if the device were lost, we wouldn't be able to do much.
-        Error::Success
+    /// Registers an object which points back to this device, keeping it alive.
+    pub fn add_child(&self) {
+        self.children.fetch_add(1, Ordering::SeqCst);
     }
 
-    /// Determines how much graphics memory is available.
-    fn get_available_texture_mem(&self) -> u32 {
-        self.adapter().available_memory()
+    /// Unregisters an object which pointed back to this device, once it is destroyed.
+    ///
+    /// Frees the device if the app already released it, and this was its last child.
+    /// The device must not be used after calling this.
+    pub fn remove_child(&self) {
+        let prev = self.children.fetch_sub(1, Ordering::SeqCst);
+        if prev == 1 && self.refs.load(Ordering::SeqCst) == 0 {
+            leaks::unregister(self as *const _);
+            let _box = unsafe { Box::from_raw(self as *const _ as *mut Self) };
+        }
     }
 
-    /// Asks the driver to evict all managed resources from VRAM.
-    fn evict_managed_resources(&self) -> Error {
-        // Do nothing. The D3D11 driver handles everything.
-        Error::Success
+    /// Releases the objects this device keeps alive, once the app released the device.
+    fn release_objects(&mut self) {
+        self.swap_chains.clear();
+        self.render_targets.clear();
+        self.depth_stencil = None;
+        self.fvf_decls.clear();
+        self.istate = DeviceState::default();
+        self.recording = None;
     }
 
-    // -- Creation parameters functions --
+    // The residency manager is shared by all resources, so the functions below
+    // must only be called while the device is locked.
 
-    /// Returns a reference to the parent interface.
-    fn get_direct_3_d(&self, ptr: *mut *mut Context) -> Error {
-        let ptr = if_error!(check_mut_ref(ptr));
-        *ptr = com_ref(self.parent);
-        Error::Success
+    /// Starts tracking a resource allocated in the managed pool.
+    pub fn register_managed(&self, storage: ManagedStorage) -> ManagedHandle {
+        self.managed.borrow_mut().register(storage)
     }
 
-    /// Returns the caps of this device.
-    fn get_device_caps(&self, caps: *mut D3DCAPS9) -> Error {
-        let caps = if_error!(check_mut_ref(caps));
-        *caps = self.adapter().caps();
-        Error::Success
+    /// Stops tracking a managed resource, once it is destroyed.
+    pub fn unregister_managed(&self, handle: ManagedHandle) {
+        self.managed.borrow_mut().unregister(handle);
     }
 
-    /// Returns the creation parameters of this device.
-    fn get_creation_parameters(&self, params: *mut D3DDEVICE_CREATION_PARAMETERS) -> Error {
-        let params = if_error!(check_mut_ref(params));
-        *params = self.creation_params;
-        Error::Success
+    /// Changes the order in which a managed resource gets evicted from VRAM.
+    pub fn set_managed_priority(&self, handle: ManagedHandle, priority: u32) {
+        self.managed.borrow_mut().set_priority(handle, priority);
     }
 
-    // -- Swap chain functions --
-
-    /// Creates new swap chains for this device.
-    fn create_additional_swap_chain(
-        &mut self,
-        pp: *mut D3DPRESENT_PARAMETERS,
-        ret: *mut *mut SwapChain,
-    ) -> Error {
-        let factory = self.factory.as_mut();
-        let pp = if_error!(check_mut_ref(pp));
-        let window = self.window;
-
-        let ret = if_error!(check_mut_ref(ret));
-
-        *ret = if_error!(SwapChain::new(self, &self.device, factory, pp, window)).into();
-        Error::Success
+    /// Changes the most detailed mip level used by a managed texture.
+    pub fn set_managed_lod(&self, handle: ManagedHandle, lod: u32) -> Result<(), Error> {
+        self.managed.borrow_mut().set_lod(&self.device, handle, lod)
     }
 
-    /// Returns an implicit swap chain.
-    fn get_swap_chain(&self, sc: u32, ret: *mut *mut SwapChain) -> Error {
-        let sc = if_error!(self.check_swap_chain(sc));
-        let ret = if_error!(check_mut_ref(ret));
+    /// Schedules a managed resource to be uploaded again the next time it's used.
+    pub fn mark_managed_dirty(&self, handle: ManagedHandle) {
+        self.managed.borrow_mut().mark_dirty(handle);
+    }
 
-        *ret = sc.clone().into();
-        Error::Success
+    /// Retrieves the VRAM copy of a managed resource, uploading it if necessary.
+    pub fn make_resident(&self, handle: ManagedHandle) -> Result<Resident, Error> {
+        self.managed
+            .borrow_mut()
+            .make_resident(&self.device, &self.ctx.immediate(), handle)
     }
 
-    /// Returns the number of implicit swap chains.
-    fn get_number_of_swap_chains(&self) -> u32 {
-        // TODO: to have more than one implicit SC, we need multi-GPU support.
-        1
+    /// Returns statistics about the managed resources, for debugging purposes.
+    pub fn residency_stats(&self) -> ResidencyStats {
+        self.managed.borrow().stats()
     }
 
-    // The functions below all operate on the implicit swap chains.
+    /// Recreates the swap chain and the default render targets from new presentation parameters.
+    ///
+    /// All of the state is set back to its default value.
+    fn reset_helper(&mut self, pp: &mut D3DPRESENT_PARAMETERS) -> Result<(), Error> {
+        // The render targets might be referencing the old swap chain's buffers,
+        // so they have to be released first.
+        self.render_targets.clear();
+        self.depth_stencil = None;
+
+        // The bound resources are kept alive by the state, which is reset anyway.
+        self.istate = DeviceState::default();
+        self.recording = None;
 
-    fn present(&self, src: usize, dest: usize, wnd: HWND, dirty: usize) -> Error {
         for sc in &self.swap_chains {
-            match sc.present(src, dest, wnd, dirty, 0) {
-                Error::Success => (),
-                err => return err,
-            }
+            sc.as_mut().release_back_buffer();
         }
-        Error::Success
-    }
 
-    fn get_front_buffer_data(&self, sc: u32, fb: *mut Surface) -> Error {
-        if_error!(self.check_swap_chain(sc)).get_front_buffer_data(fb)
+        // The buffers cannot be resized while they're still bound.
+        unsafe {
+            self.ctx
+                .lock()
+                .OMSetRenderTargets(0, ptr::null(), ptr::null_mut());
+        }
+        self.ctx.submit()?;
+
+        // If anything fails below, the app will have to try again.
+        self.lost.set(true);
+
+        // All the resources in the default pool are lost,
+        // and the app is required to release them before calling reset.
+        let outstanding = self.default_resources.load(Ordering::SeqCst);
+        if outstanding != 0 {
+            error!(
+                "Cannot reset device while {} default pool resources still exist",
+                outstanding
+            );
+            return Err(Error::InvalidCall);
+        }
+
+        // The implicit swap chain is reused, in order to keep the same window association.
+        let window = self.swap_chain_window(pp);
+        self.swap_chains[0]
+            .as_mut()
+            .reset(&self.factory, pp, window)?;
+        self.create_default_render_target()?;
+        self.create_auto_depth_stencil(pp)?;
+
+        self.in_scene = false;
+        self.lost.set(false);
+
+        let state = DeviceState::default_state(pp);
+        self.restore_state(&state, D3DSBT_ALL);
+
+        self.update_render_targets();
+
+        Ok(())
     }
 
-    fn get_back_buffer(
-        &self,
-        sc: u32,
-        bi: u32,
-        ty: D3DBACKBUFFER_TYPE,
-        ret: *mut *mut Surface,
-    ) -> Error {
-        if_error!(self.check_swap_chain(sc)).get_back_buffer(bi, ty, ret)
+    /// Determines the window a swap chain with certain parameters presents to.
+    fn swap_chain_window(&self, pp: &D3DPRESENT_PARAMETERS) -> HWND {
+        if pp.hDeviceWindow.is_null() {
+            self.window
+        } else {
+            pp.hDeviceWindow
+        }
     }
 
-    fn get_raster_status(&self, sc: u32, rs: *mut D3DRASTER_STATUS) -> Error {
-        if_error!(self.check_swap_chain(sc)).get_raster_status(rs)
+    /// Tries to retrieve an implicit swap chain based on the index.
+    fn check_swap_chain(&self, sc: u32) -> Result<&ComPtr<SwapChain>, Error> {
+        self.swap_chains.get(sc as usize).ok_or(Error::InvalidCall)
     }
 
-    fn get_display_mode(&self, sc: u32, dm: *mut D3DDISPLAYMODE) -> Error {
-        if_error!(self.check_swap_chain(sc)).get_display_mode(dm)
+    /// Stops keeping track of an additional swap chain, once it's destroyed.
+    pub fn forget_swap_chain(&self, swap_chain: *const SwapChain) {
+        self.additional_swap_chains
+            .borrow_mut()
+            .retain(|&sc| sc != swap_chain);
     }
 
-    // -- Gamma control functions --
+    /// Creates the default render target for this device.
+    fn create_default_render_target(&mut self) -> Result<(), Error> {
+        // The default render target is the implicit swap chain's back buffer.
+        let rt = self.swap_chains[0]
+            .back_buffer()
+            .cloned()
+            .ok_or(Error::InvalidCall)?;
 
-    /// Sets the current gamma ramp.
-    fn set_gamma_ramp(&mut self, sc: u32, flags: u32, ramp: *const D3DGAMMARAMP) {
-        self.check_swap_chain(sc)
-            .and_then(|sc| check_ref(ramp).and_then(|ramp| sc.set_gamma_ramp(flags, ramp)))
-            .unwrap_or_else(|_| error!("Failed to set gamma ramp"));
-    }
+        self.render_targets.push(Some(rt));
 
-    /// Retrieves the monitor's gamma ramp.
-    fn get_gamma_ramp(&self, sc: u32, ret: *mut D3DGAMMARAMP) {
-        self.check_swap_chain(sc)
-            .and_then(|sc| check_mut_ref(ret).and_then(|ret| sc.get_gamma_ramp(ret)))
-            .unwrap_or_else(|_| error!("Failed to retrieve gamma ramp"));
+        Ok(())
     }
 
-    // -- Render target functions --
-
-    /// Creates a new render target.
-    fn create_render_target(
-        &mut self,
-        width: u32,
-        height: u32,
-        fmt: D3DFORMAT,
-        ms_ty: D3DMULTISAMPLE_TYPE,
-        ms_qlt: u32,
-        lockable: u32,
-        ret: *mut *mut Surface,
+    /// Shares a texture with other devices, using the handle passed to a D3D9Ex creation function.
+    ///
+    /// If the handle is null, the texture is recreated as a shared resource, and its handle is
+    /// returned to the app. Otherwise, the texture shared through the handle is opened instead,
+    /// and must have the same layout.
+    fn share_texture(
+        &self,
+        texture: d3d11::Texture2D,
+        pool: MemoryPool,
         shared_handle: usize,
-    ) -> Error {
-        let ret = if_error!(check_mut_ref(ret));
+    ) -> Result<d3d11::Texture2D, Error> {
+        let handle = match unsafe { (shared_handle as *mut HANDLE).as_mut() } {
+            Some(handle) => handle,
+            None => return Ok(texture),
+        };
 
-        if lockable != 0 {
-            error!("Lockable render targets are not supported");
+        if pool != MemoryPool::Default {
+            error!("Only resources in the default pool can be shared");
+            return Err(Error::InvalidCall);
         }
 
-        if shared_handle != 0 {
-            error!("Shared resources are not supported");
-            return Error::InvalidCall;
+        if handle.is_null() {
+            let shared = texture.new_shared(&self.device)?;
+            *handle = shared.shared_handle()?;
+            return Ok(shared);
         }
 
-        // First we need to create a texture we will render to.
-        let texture = if_error!(d3d11::Texture2D::new_rt(
-            &self.device,
-            (width, height),
-            fmt,
-            ms_ty,
-            ms_qlt
-        ));
+        let shared = d3d11::Texture2D::open_shared(&self.device, *handle)?;
 
-        *ret = if_error!(self.create_render_target_helper(texture)).into();
+        let (desc, shared_desc) = (texture.desc(), shared.desc());
+        if (desc.Width, desc.Height, desc.Format)
+            != (shared_desc.Width, shared_desc.Height, shared_desc.Format)
+        {
+            error!("Shared texture's size or format doesn't match the requested texture");
+            return Err(Error::InvalidCall);
+        }
 
-        Error::Success
+        Ok(shared)
     }
 
-    /// Sets a new render target on this device.
-    fn set_render_target(&mut self, i: u32, rt: *mut Surface) -> Error {
-        if i >= D3D11_SIMULTANEOUS_RENDER_TARGET_COUNT {
-            return Error::InvalidCall;
+    /// Checks that the adapter supports a certain multisampling type / quality pair for a format.
+    fn check_multisample(
+        &self,
+        fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
+    ) -> Result<(), Error> {
+        if ms_ty == D3DMULTISAMPLE_NONMASKABLE {
+            run_once!(|| warn!(
+                "Non-maskable multisampling is not supported, using a single sample instead"
+            ));
         }
 
-        // The default render target is never allowed to be null.
-        if i == 0 && rt.is_null() {
-            return Error::InvalidCall;
+        if ms_ty <= D3DMULTISAMPLE_NONMASKABLE {
+            return Ok(());
         }
 
-        let i = i as usize;
-
-        // Ensure the RT vector contains at least as many entries as we need.
-        self.render_targets.resize(i + 1, None);
-
-        self.render_targets[i] = if let Some(rt) = unsafe { rt.as_mut() } {
-            // Ensure this surface is indeed a render target.
-            if rt.render_target_view().is_none() {
-                return Error::InvalidCall;
-            }
+        // The adapter returns the number of quality levels it supports.
+        let levels = self.adapter().is_multisampling_supported(fmt, ms_ty);
 
-            Some(ComPtr::new(rt))
+        if ms_qlt < levels {
+            Ok(())
         } else {
-            None
-        };
-
-        self.update_render_targets();
-
-        Error::Success
+            error!(
+                "Unsupported multisampling type {} (quality {}) for format {}",
+                ms_ty, ms_qlt, fmt
+            );
+            Err(Error::InvalidCall)
+        }
     }
 
-    /// Retrieves a reference to a bound render target.
-    fn get_render_target(&self, i: u32, ret: *mut *mut Surface) -> Error {
-        let rt = if_error!(self.check_render_target(i));
-        let ret = if_error!(check_mut_ref(ret));
+    /// Removes the automatic mip map generation flag if the format doesn't support it.
+    ///
+    /// Like D3D9, the texture is still created, but the app has to fill in its mip levels.
+    fn check_autogen(&self, usage: UsageFlags, fmt: D3DFORMAT) -> UsageFlags {
+        if usage.intersects(UsageFlags::AUTO_GEN_MIP_MAP)
+            && !self.adapter().supports_mip_autogen(fmt)
+        {
+            warn!("Mip maps cannot be generated for format {}", fmt);
+            usage - UsageFlags::AUTO_GEN_MIP_MAP
+        } else {
+            usage
+        }
+    }
 
-        *ret = rt.clone().into();
+    /// Retrieves a handle to a render target.
+    fn check_render_target(&self, i: u32) -> Result<&ComPtr<Surface>, Error> {
+        if let Some(rt) = self.render_targets.get(i as usize) {
+            if let Some(rt) = rt {
+                Ok(rt)
+            } else {
+                Err(Error::NotFound)
+            }
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+
+    /// Returns the current state of this device.
+    pub fn state(&self) -> &DeviceState {
+        &self.istate
+    }
+
+    /// Retrieves the value of a render state.
+    pub fn render_state(&self, state: D3DRENDERSTATETYPE) -> u32 {
+        self.istate.get_render_state(state)
+    }
+
+    /// Retrieves the value of a sampler's state.
+    pub fn sampler_state(&self, sampler: u32, ty: D3DSAMPLERSTATETYPE) -> u32 {
+        self.istate.get_sampler_state(sampler, ty)
+    }
+
+    /// Retrieves the value of a texture stage's state.
+    pub fn texture_stage_state(&self, stage: u32, ty: D3DTEXTURESTAGESTATETYPE) -> u32 {
+        self.istate.get_texture_stage_state(stage, ty)
+    }
+
+    /// Retrieves the texture bound to a stage, if any.
+    pub fn texture(&self, stage: u32) -> Option<&BaseTexture> {
+        unsafe { BaseTexture::from_com(self.istate.get_texture(stage)) }
+    }
+
+    /// Checks if a texture stage's operations read from the stage's texture.
+    fn stage_reads_texture(&self, stage: u32) -> bool {
+        let reads_texture = |op, args: [D3DTEXTURESTAGESTATETYPE; 3]| {
+            let used: &[_] = match op {
+                D3DTOP_DISABLE => return false,
+                // These blend using the texture's alpha.
+                D3DTOP_BLENDTEXTUREALPHA | D3DTOP_BLENDTEXTUREALPHAPM => return true,
+                D3DTOP_SELECTARG1 => &args[1..2],
+                D3DTOP_SELECTARG2 => &args[2..3],
+                D3DTOP_MULTIPLYADD | D3DTOP_LERP => &args[..],
+                _ => &args[1..],
+            };
+
+            used.iter().any(|&arg| {
+                self.texture_stage_state(stage, arg) & D3DTA_SELECTMASK == D3DTA_TEXTURE
+            })
+        };
+
+        let color_op = self.texture_stage_state(stage, D3DTSS_COLOROP);
+        let alpha_op = self.texture_stage_state(stage, D3DTSS_ALPHAOP);
+
+        reads_texture(
+            color_op,
+            [D3DTSS_COLORARG0, D3DTSS_COLORARG1, D3DTSS_COLORARG2],
+        ) || reads_texture(
+            alpha_op,
+            [D3DTSS_ALPHAARG0, D3DTSS_ALPHAARG1, D3DTSS_ALPHAARG2],
+        )
+    }
+
+    /// Restores (part of) the state of this device from a saved copy.
+    pub fn restore_state(&mut self, state: &DeviceState, ty: D3DSTATEBLOCKTYPE) {
+        self.istate.copy_from(state, ty);
+
+        // Some of the state is also tracked by D3D11, so we need to bind it again.
+        if ty != D3DSBT_PIXELSTATE {
+            self.dirty
+                .mark(DirtyFlags::VERTEX_SHADER | DirtyFlags::INPUT_LAYOUT);
+        }
+
+        // These are made of both vertex and pixel states.
+        self.dirty.mark(
+            DirtyFlags::BLEND
+                | DirtyFlags::RASTERIZER
+                | DirtyFlags::TEXTURES
+                | DirtyFlags::FIXED_FUNCTION,
+        );
+
+        if ty == D3DSBT_ALL {
+            for stream in 0..MAX_STREAMS as u32 {
+                self.dirty.mark_stream(stream);
+            }
+
+            self.dirty.mark(DirtyFlags::INDICES | DirtyFlags::VIEWPORT);
+        }
+    }
+
+    /// Binds all of the state which changed since the last draw.
+    ///
+    /// Must be called before every draw, with the topology it uses.
+    pub fn flush_state(&mut self, topology: D3D11_PRIMITIVE_TOPOLOGY) {
+        self.update_fixed_function(topology);
+
+        let (dirty, streams) = self.dirty.take();
+
+        if dirty.contains(DirtyFlags::BLEND) {
+            self.bind_blend_state();
+        }
+        if dirty.contains(DirtyFlags::RASTERIZER) {
+            self.bind_rasterizer_state();
+        }
+        if dirty.contains(DirtyFlags::VIEWPORT) {
+            self.bind_viewport();
+        }
+        if dirty.contains(DirtyFlags::VERTEX_SHADER) {
+            self.bind_vertex_shader();
+        }
+        if dirty.contains(DirtyFlags::INPUT_LAYOUT) {
+            self.bind_input_layout();
+        }
+        if dirty.contains(DirtyFlags::GEOMETRY_SHADER) {
+            self.bind_geometry_shader();
+        }
+        if dirty.contains(DirtyFlags::PIXEL_SHADER) {
+            self.bind_pixel_shader();
+        }
+        // Pre-transformed vertices are converted back using the viewport.
+        if dirty.intersects(DirtyFlags::FIXED_FUNCTION | DirtyFlags::VIEWPORT) {
+            self.bind_fixed_function_constants();
+        }
+        if dirty.contains(DirtyFlags::INDICES) {
+            self.bind_indices();
+        }
+
+        self.bind_textures(dirty.contains(DirtyFlags::TEXTURES));
+
+        for stream in 0..MAX_STREAMS as u32 {
+            if streams & (1 << stream) != 0 {
+                self.bind_stream_source(stream);
+            }
+        }
+    }
+
+    /// Determines which generated shaders the next draw uses,
+    /// and marks the ones which changed since the previous draw to be bound again.
+    fn update_fixed_function(&mut self, topology: D3D11_PRIMITIVE_TOPOLOGY) {
+        let decl = unsafe { self.istate.get_vertex_declaration().as_ref() };
+
+        // Only draws without a vertex shader use a generated one.
+        let vs_key = match decl {
+            Some(decl) if self.istate.get_vertex_shader().is_null() => {
+                Some(VertexShaderKey::new(decl.elements(), &self.istate))
+            }
+            _ => None,
+        };
+        // Points can only be expanded when the vertex shader outputs their size.
+        let gs_key = match &vs_key {
+            Some(vs_key) if topology == D3D11_PRIMITIVE_TOPOLOGY_POINTLIST => {
+                GeometryShaderKey::new(&self.istate, vs_key)
+            }
+            _ => None,
+        };
+        let ps_key = PixelShaderKey::new(&self.istate);
+
+        let dirty = self.ffp.get_mut().update_keys(vs_key, gs_key, ps_key);
+        if !dirty.is_empty() {
+            self.dirty.mark(dirty);
+        }
+    }
+
+    /// Retrieves the counters measuring how many state changes were batched together.
+    pub fn flush_stats(&self) -> FlushStats {
+        self.dirty.stats()
+    }
+
+    /// Records a state change into the state block being recorded, if any.
+    ///
+    /// Returns `true` if the change was recorded, in which case it must not be applied.
+    fn record(&mut self, change: StateChange) -> bool {
+        match &mut self.recording {
+            Some(changes) => {
+                changes.push(change);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks if the device was lost. While lost, all draw calls should be ignored.
+    pub fn is_lost(&self) -> bool {
+        self.lost.get()
+    }
+
+    // Checks if the window we are rendering to is minimized.
+    fn is_window_minimized(&self) -> bool {
+        unsafe { winuser::IsIconic(self.window) != 0 }
+    }
+
+    /// Checks if the app is allowed to draw right now.
+    fn check_scene(&self) -> Result<(), Error> {
+        if self.strict_scenes && !self.in_scene {
+            error!("Draw call issued outside of a scene");
+            Err(Error::InvalidCall)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Draws primitives from data which was copied into the user pointer buffers.
+    ///
+    /// Like D3D9, this unbinds the vertex buffer of stream 0 and the index buffer afterwards.
+    fn draw_user_primitives(
+        &mut self,
+        topology: D3D11_PRIMITIVE_TOPOLOGY,
+        vertices: &[u8],
+        stride: u32,
+        indices: Option<(&[u8], DXGI_FORMAT)>,
+        base_vertex: i32,
+    ) -> Result<(), Error> {
+        let (vertex_buffer, vertex_offset) = self.user_vertices.upload(&self.ctx, vertices)?;
+        let index_buffer = match indices {
+            Some((indices, _)) => Some(self.user_indices.upload(&self.ctx, indices)?),
+            None => None,
+        };
+
+        self.flush_state(topology);
+
+        let ctx = self.ctx.lock();
+
+        unsafe {
+            ctx.IASetVertexBuffers(0, 1, &vertex_buffer.as_buffer(), &stride, &vertex_offset);
+            ctx.IASetPrimitiveTopology(topology);
+
+            match (indices, index_buffer) {
+                (Some((indices, format)), Some((index_buffer, index_offset))) => {
+                    let index_size = if format == DXGI_FORMAT_R32_UINT { 4 } else { 2 };
+                    let count = (indices.len() / index_size) as u32;
+
+                    ctx.IASetIndexBuffer(index_buffer.as_buffer(), format, index_offset);
+                    ctx.DrawIndexed(count, 0, base_vertex);
+                }
+                _ => ctx.Draw(vertices.len() as u32 / stride, 0),
+            }
+        }
+
+        drop(ctx);
+        self.profiler.record_draw();
+
+        self.istate.set_stream_source(0, StreamSource::default());
+        self.dirty.mark_stream(0);
+
+        if indices.is_some() {
+            self.istate.set_indices(ptr::null_mut());
+            self.dirty.mark(DirtyFlags::INDICES);
+        }
+
+        Ok(())
+    }
+
+    /// Reads back the data of every stream used by a vertex declaration.
+    fn read_streams(&self, elems: &[D3DVERTEXELEMENT9]) -> Result<Vec<Option<StreamData>>, Error> {
+        let mut streams: Vec<Option<StreamData>> = (0..MAX_STREAMS).map(|_| None).collect();
+
+        for e in elems {
+            let index = e.Stream as usize;
+            if index >= MAX_STREAMS || streams[index].is_some() {
+                continue;
+            }
+
+            let source = self
+                .istate
+                .get_stream_source(index as u32)
+                .unwrap_or_default();
+            if let Some(buffer) = unsafe { source.buffer.as_ptr().as_ref() } {
+                let data = buffer
+                    .get_dx11()
+                    .read(&self.device, &self.ctx.immediate())?;
+                streams[index] = Some(StreamData {
+                    data,
+                    offset: source.offset as usize,
+                    stride: source.stride as usize,
+                });
+            }
+        }
+
+        Ok(streams)
+    }
+
+    /// Binds the blend state matching the current render states to the pipeline.
+    ///
+    /// The constant blend factor and the sample mask are not part of the state object,
+    /// so they have to be set again every time the state is bound.
+    fn bind_blend_state(&self) {
+        let key = BlendKey::new(&self.istate);
+        let blend_factor = d3dcolor_to_vec(self.istate.get_render_state(D3DRS_BLENDFACTOR));
+        let sample_mask = self.istate.get_render_state(D3DRS_MULTISAMPLEMASK);
+
+        let state = match self.pipeline.borrow_mut().blend_state(&self.device, key) {
+            Ok(state) => state,
+            Err(err) => {
+                error!("Failed to create blend state: {:?}", err);
+                return;
+            }
+        };
+
+        unsafe {
+            self.ctx
+                .lock()
+                .OMSetBlendState(state.as_mut(), &blend_factor, sample_mask);
+        }
+    }
+
+    /// Binds the rasterizer state matching the current render states to the pipeline.
+    fn bind_rasterizer_state(&self) {
+        let depth_format = self
+            .depth_stencil
+            .as_ref()
+            .map(|ds| unsafe {
+                let mut desc = mem::zeroed();
+                ds.get_desc(&mut desc);
+                desc.Format
+            })
+            .unwrap_or(D3DFMT_UNKNOWN);
+
+        let key = RasterizerKey::new(&self.istate, depth_format);
+
+        let state = match self
+            .pipeline
+            .borrow_mut()
+            .rasterizer_state(&self.device, key)
+        {
+            Ok(state) => state,
+            Err(err) => {
+                error!("Failed to create rasterizer state: {:?}", err);
+                return;
+            }
+        };
+
+        unsafe {
+            self.ctx.lock().RSSetState(state.as_mut());
+        }
+    }
+
+    /// Binds the current viewport to the pipeline.
+    fn bind_viewport(&self) {
+        let vp = self.istate.get_viewport();
+
+        let viewport = D3D11_VIEWPORT {
+            TopLeftX: vp.X as f32,
+            TopLeftY: vp.Y as f32,
+            Width: vp.Width as f32,
+            Height: vp.Height as f32,
+            MinDepth: vp.MinZ,
+            MaxDepth: vp.MaxZ,
+        };
+
+        unsafe {
+            self.ctx.lock().RSSetViewports(1, &viewport);
+        }
+    }
+
+    /// Binds the textures used for drawing to the pipeline.
+    ///
+    /// Managed textures are made resident before every draw, since they might have been
+    /// evicted or modified since the last one. The other ones are only bound when they change.
+    fn bind_textures(&self, changed: bool) {
+        let pixel_samplers = 0..MAX_PIXEL_SAMPLERS;
+        let vertex_samplers = D3DVERTEXTEXTURESAMPLER0..=D3DVERTEXTEXTURESAMPLER3;
+
+        for stage in pixel_samplers.chain(vertex_samplers) {
+            let texture = self.texture(stage);
+
+            let managed = texture.map_or(false, |texture| texture.pool() == MemoryPool::Managed);
+            if !changed && !managed {
+                continue;
+            }
+
+            let view = match texture.map(BaseTexture::bind_view) {
+                Some(Ok(view)) => view,
+                Some(Err(err)) => {
+                    error!("Failed to make texture resident: {:?}", err);
+                    None
+                }
+                None => None,
+            };
+
+            let view = view.as_ref().map_or(ptr::null_mut(), |view| view.as_mut());
+
+            unsafe {
+                let ctx = self.ctx.lock();
+
+                if stage < MAX_PIXEL_SAMPLERS {
+                    ctx.PSSetShaderResources(stage, 1, &view);
+                } else {
+                    ctx.VSSetShaderResources(stage - D3DVERTEXTEXTURESAMPLER0, 1, &view);
+                }
+            }
+        }
+    }
+
+    /// Retrieves the vertex shader generated for the fixed function state, if the next draw uses one.
+    fn generated_vertex_shader(&self) -> Option<ffp::GeneratedVertexShader> {
+        match self.ffp.borrow_mut().vertex_shader(&self.device) {
+            Ok(vs) => vs,
+            Err(err) => {
+                error!("Failed to generate vertex shader: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Binds the current vertex shader to the pipeline,
+    /// or the one generated for the fixed function state if there is none.
+    fn bind_vertex_shader(&self) {
+        let vs = match unsafe { self.istate.get_vertex_shader().as_ref() } {
+            Some(vs) => Some(vs.get_dx11().clone()),
+            None => self.generated_vertex_shader().map(|vs| vs.shader),
+        };
+        let vs = vs
+            .as_ref()
+            .map(|vs| vs.as_mut() as *mut _)
+            .unwrap_or(ptr::null_mut());
+
+        unsafe {
+            self.ctx.lock().VSSetShader(vs, ptr::null(), 0);
+        }
+    }
+
+    /// Binds the input layout matching the current vertex declaration and vertex shader.
+    fn bind_input_layout(&self) {
+        let decl = unsafe { self.istate.get_vertex_declaration().as_ref() };
+        let code = match unsafe { self.istate.get_vertex_shader().as_ref() } {
+            Some(vs) => Some(Arc::clone(vs.code())),
+            None => self.generated_vertex_shader().map(|vs| vs.code),
+        };
+
+        let layout = match (decl, code) {
+            (Some(decl), Some(code)) => match self.input_layout(decl, &code) {
+                Ok(layout) => Some(layout),
+                Err(err) => {
+                    error!("Failed to create input layout: {:?}", err);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let layout = layout
+            .as_ref()
+            .map(|layout| layout.as_mut() as *mut _)
+            .unwrap_or(ptr::null_mut());
+
+        unsafe {
+            self.ctx.lock().IASetInputLayout(layout);
+        }
+    }
+
+    /// Binds the pixel shader generated for the texture stages to the pipeline.
+    ///
+    /// The app's pixel shaders are not translated yet, so the texture stages are used instead.
+    fn bind_pixel_shader(&self) {
+        if !self.istate.get_pixel_shader().is_null() {
+            run_once!(|| warn!("Pixel shaders are not supported yet, using the texture stages"));
+        }
+
+        let ps = match self.ffp.borrow_mut().pixel_shader(&self.device) {
+            Ok(ps) => Some(ps),
+            Err(err) => {
+                error!("Failed to generate pixel shader: {:?}", err);
+                None
+            }
+        };
+        let ps = ps
+            .as_ref()
+            .map(|ps| ps.as_mut() as *mut _)
+            .unwrap_or(ptr::null_mut());
+
+        unsafe {
+            self.ctx.lock().PSSetShader(ps, ptr::null(), 0);
+        }
+    }
+
+    /// Binds the geometry shader which expands points, or unbinds it if the next draw doesn't need one.
+    fn bind_geometry_shader(&self) {
+        let gs = match self.ffp.borrow_mut().geometry_shader(&self.device) {
+            Ok(gs) => gs,
+            Err(err) => {
+                error!("Failed to generate geometry shader: {:?}", err);
+                None
+            }
+        };
+        let gs = gs
+            .as_ref()
+            .map(|gs| gs.as_mut() as *mut _)
+            .unwrap_or(ptr::null_mut());
+
+        unsafe {
+            self.ctx.lock().GSSetShader(gs, ptr::null(), 0);
+        }
+    }
+
+    /// Uploads the fixed function state read by the generated shaders, and binds it to the pipeline.
+    fn bind_fixed_function_constants(&self) {
+        let constants = match self.ffp.borrow().upload_constants(&self.ctx, &self.istate) {
+            Ok(constants) => constants,
+            Err(err) => {
+                error!("Failed to upload fixed function constants: {:?}", err);
+                return;
+            }
+        };
+
+        let palette = self.ffp.borrow().palette_view().as_mut();
+
+        let ctx = self.ctx.lock();
+        constants.bind(&ctx, d3d11::ShaderStage::Vertex, ffp::CONSTANTS_SLOT);
+        constants.bind(&ctx, d3d11::ShaderStage::Geometry, ffp::CONSTANTS_SLOT);
+        constants.bind(&ctx, d3d11::ShaderStage::Pixel, ffp::CONSTANTS_SLOT);
+
+        unsafe {
+            ctx.PSSetShaderResources(ffp::PALETTE_SLOT, 1, &palette);
+        }
+    }
+
+    /// Binds the vertex buffer of an input stream to the pipeline.
+    fn bind_stream_source(&self, stream: u32) {
+        let source = match self.istate.get_stream_source(stream) {
+            Some(source) => source,
+            None => return,
+        };
+
+        let buffer = match unsafe { source.buffer.as_ptr().as_ref() }.map(VertexBuffer::gpu_buffer)
+        {
+            Some(Ok(buffer)) => Some(buffer),
+            Some(Err(err)) => {
+                error!("Failed to upload vertex buffer: {:?}", err);
+                None
+            }
+            None => None,
+        };
+        let buffer = buffer
+            .as_ref()
+            .map(d3d11::Buffer::as_buffer)
+            .unwrap_or(ptr::null_mut());
+
+        unsafe {
+            self.ctx
+                .lock()
+                .IASetVertexBuffers(stream, 1, &buffer, &source.stride, &source.offset);
+        }
+    }
+
+    /// Binds the current index buffer to the pipeline.
+    fn bind_indices(&self) {
+        let ib = unsafe { self.istate.get_indices().as_ref() };
+
+        let buffer = match ib.map(IndexBuffer::gpu_buffer) {
+            Some(Ok(buffer)) => Some(buffer),
+            Some(Err(err)) => {
+                error!("Failed to upload index buffer: {:?}", err);
+                None
+            }
+            None => None,
+        };
+
+        let (buffer, fmt) = match (ib, buffer.as_ref()) {
+            (Some(ib), Some(buffer)) => (buffer.as_buffer(), ib.dxgi_format()),
+            _ => (ptr::null_mut(), DXGI_FORMAT_UNKNOWN),
+        };
+
+        unsafe {
+            self.ctx.lock().IASetIndexBuffer(buffer, fmt, 0);
+        }
+    }
+
+    /// Synchronises D3D9's render target views and depth / stencil view with D3D11,
+    /// and resets the viewport to cover the first render target.
+    fn update_render_targets(&mut self) {
+        self.bind_render_targets();
+
+        // We also need to update the viewport.
+        let (width, height) = match self.render_targets.first().and_then(Option::as_ref) {
+            Some(rt) => rt.size(),
+            None => {
+                error!("No render target is bound, keeping the current viewport");
+                return;
+            }
+        };
+
+        let vp = D3DVIEWPORT9 {
+            X: 0,
+            Y: 0,
+            Width: width,
+            Height: height,
+            MinZ: 0.0,
+            MaxZ: 1.0,
+        };
+
+        // This happens even while a state block is being recorded.
+        self.istate.set_viewport(&vp);
+        self.dirty.mark(DirtyFlags::VIEWPORT);
+    }
+
+    /// Binds D3D9's render target views and depth / stencil view to the pipeline.
+    fn bind_render_targets(&self) {
+        let num = self.render_targets.len() as u32;
+
+        let mut rt_views = [ptr::null_mut(); 8];
+        for (i, rt) in self.render_targets.iter().enumerate() {
+            // NULL render targets don't have a view.
+            if let Some(view) = rt.as_ref().and_then(|rt| rt.render_target_view()) {
+                rt_views[i] = view as *mut _;
+            }
+        }
+
+        let ds_view = match self.depth_stencil.as_ref() {
+            Some(ds) => match ds.depth_stencil_view() {
+                Some(view) => view as *mut _,
+                None => {
+                    error!("Depth / stencil surface has no view, unbinding it");
+                    ptr::null_mut()
+                }
+            },
+            None => ptr::null_mut(),
+        };
+
+        unsafe {
+            self.ctx
+                .lock()
+                .OMSetRenderTargets(num, rt_views.as_ptr(), ds_view);
+        }
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        let stats = self.dirty.stats();
+        debug!(
+            "{} state changes were bound as {} states, in {} flushes",
+            stats.changes, stats.binds, stats.flushes
+        );
+    }
+}
+
+// Resources and swap chains point back to the device, so it cannot use `impl_iunknown`:
+// the device has to stay alive until all of them were destroyed.
+#[implementation(IUnknown)]
+impl Device {
+    fn query_interface(&mut self, riid: &winapi::shared::guiddef::GUID, obj: &mut usize) -> i32 {
+        use winapi::shared::{
+            guiddef::IsEqualGUID,
+            winerror::{E_NOINTERFACE, S_OK},
+        };
+        use winapi::Interface;
+
+        *obj = 0;
+
+        if IsEqualGUID(riid, &IUnknown::uuidof())
+            || IsEqualGUID(riid, &IDirect3DDevice9::uuidof())
+            || IsEqualGUID(riid, &IDirect3DDevice9Ex::uuidof())
+        {
+            *obj = self as *mut _ as usize;
+            self.add_ref();
+            S_OK
+        } else {
+            E_NOINTERFACE
+        }
+    }
+
+    fn add_ref(&mut self) -> u32 {
+        let prev = self.refs.fetch_add(1, Ordering::SeqCst);
+        prev + 1
+    }
+
+    fn release(&mut self) -> u32 {
+        let prev = self.refs.fetch_sub(1, Ordering::SeqCst);
+        if prev == 1 {
+            // Our own objects are children too, so hold on to ourselves while releasing them.
+            self.add_child();
+            self.release_objects();
+            self.remove_child();
+        }
+        prev - 1
+    }
+}
+
+#[implementation(IDirect3DDevice9)]
+impl Device {
+    // -- Device status functions --
+
+    /// Resets the device, recreating all its state.
+    fn reset(&mut self, pp: *mut D3DPRESENT_PARAMETERS) -> Error {
+        let _lock = self.lock();
+        let pp = if_error!(check_mut_ref(pp));
+        to_error_success!(self.reset_helper(pp))
+    }
+
+    /// Checks that the device has not yet been lost / reset.
+    fn test_cooperative_level(&self) -> Error {
+        let _lock = self.lock();
+        if !self.lost.get() {
+            Error::Success
+        } else if self.is_window_minimized() {
+            // The device cannot be reset until the window is restored.
+            Error::DeviceLost
+        } else {
+            Error::DeviceNotReset
+        }
+    }
+
+    /// Determines how much graphics memory is available.
+    fn get_available_texture_mem(&self) -> u32 {
+        let _lock = self.lock();
+        self.adapter().free_memory()
+    }
+
+    /// Asks the driver to evict all managed resources from VRAM.
+    fn evict_managed_resources(&self) -> Error {
+        let _lock = self.lock();
+        // They will be uploaded again the next time they're used.
+        self.managed.borrow_mut().evict_all();
+        Error::Success
+    }
+
+    // -- Creation parameters functions --
+
+    /// Returns a reference to the parent interface.
+    fn get_direct_3_d(&self, ptr: *mut *mut Context) -> Error {
+        let _lock = self.lock();
+        let ptr = if_error!(check_mut_ref(ptr));
+        *ptr = com_ref(self.parent);
+        Error::Success
+    }
+
+    /// Returns the caps of this device.
+    ///
+    /// These are the same as the ones reported by the context for this adapter and device type,
+    /// except for the limits which are raised while vertices are processed in software.
+    /// Devices created with mixed vertex processing report the limits of the current mode.
+    fn get_device_caps(&self, caps: *mut D3DCAPS9) -> Error {
+        let _lock = self.lock();
+        let caps = if_error!(check_mut_ref(caps));
+        *caps = if_error!(self.adapter().caps());
+        caps.DeviceType = self.creation_params.DeviceType;
+
+        if self.software_vertex_processing {
+            caps.MaxVertexShaderConst = MAX_SOFTWARE_VERTEX_SHADER_CONSTANTS;
+        }
+
+        Error::Success
+    }
+
+    /// Returns the creation parameters of this device.
+    fn get_creation_parameters(&self, params: *mut D3DDEVICE_CREATION_PARAMETERS) -> Error {
+        let _lock = self.lock();
+        let params = if_error!(check_mut_ref(params));
+        *params = self.creation_params;
+        Error::Success
+    }
+
+    // -- Swap chain functions --
+
+    /// Creates new swap chains for this device.
+    fn create_additional_swap_chain(
+        &mut self,
+        pp: *mut D3DPRESENT_PARAMETERS,
+        ret: *mut *mut SwapChain,
+    ) -> Error {
+        let _lock = self.lock();
+        let pp = if_error!(check_mut_ref(pp));
+        let ret = if_error!(check_mut_ref(ret));
+
+        let swap_chain = if_error!(self.new_swap_chain(pp));
+        swap_chain.as_mut().set_additional();
+
+        self.additional_swap_chains
+            .borrow_mut()
+            .push(&*swap_chain as *const SwapChain);
+
+        *ret = swap_chain.into();
+        Error::Success
+    }
+
+    /// Returns a swap chain of this device.
+    ///
+    /// The implicit swap chains come first, followed by the ones created by the app.
+    fn get_swap_chain(&self, sc: u32, ret: *mut *mut SwapChain) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let index = sc as usize;
+
+        *ret = match self.swap_chains.get(index) {
+            Some(sc) => sc.clone().into(),
+            None => {
+                let additional = self.additional_swap_chains.borrow();
+                let sc = if_error!(additional
+                    .get(index - self.swap_chains.len())
+                    .ok_or(Error::InvalidCall));
+                com_ref(*sc)
+            }
+        };
+
+        Error::Success
+    }
+
+    /// Returns the number of swap chains, both implicit and created by the app.
+    fn get_number_of_swap_chains(&self) -> u32 {
+        let _lock = self.lock();
+        // TODO: to have more than one implicit SC, we need multi-GPU support.
+        (self.swap_chains.len() + self.additional_swap_chains.borrow().len()) as u32
+    }
+
+    // The functions below all operate on the implicit swap chains.
+
+    fn present(&self, src: usize, dest: usize, wnd: HWND, dirty: usize) -> Error {
+        let _lock = self.lock();
+        self.present_ex(src, dest, wnd, dirty, 0)
+    }
+
+    fn get_front_buffer_data(&self, sc: u32, fb: *mut Surface) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_swap_chain(sc)).get_front_buffer_data(fb)
+    }
+
+    fn get_back_buffer(
+        &self,
+        sc: u32,
+        bi: u32,
+        ty: D3DBACKBUFFER_TYPE,
+        ret: *mut *mut Surface,
+    ) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_swap_chain(sc)).get_back_buffer(bi, ty, ret)
+    }
+
+    fn get_raster_status(&self, sc: u32, rs: *mut D3DRASTER_STATUS) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_swap_chain(sc)).get_raster_status(rs)
+    }
+
+    fn get_display_mode(&self, sc: u32, dm: *mut D3DDISPLAYMODE) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_swap_chain(sc)).get_display_mode(dm)
+    }
+
+    // -- Gamma control functions --
+
+    /// Sets the current gamma ramp.
+    fn set_gamma_ramp(&mut self, sc: u32, flags: u32, ramp: *const D3DGAMMARAMP) {
+        let _lock = self.lock();
+        self.check_swap_chain(sc)
+            .and_then(|sc| check_ref(ramp).and_then(|ramp| sc.set_gamma_ramp(flags, ramp)))
+            .unwrap_or_else(|_| error!("Failed to set gamma ramp"));
+    }
+
+    /// Retrieves the monitor's gamma ramp.
+    fn get_gamma_ramp(&self, sc: u32, ret: *mut D3DGAMMARAMP) {
+        let _lock = self.lock();
+        self.check_swap_chain(sc)
+            .and_then(|sc| check_mut_ref(ret).and_then(|ret| sc.get_gamma_ramp(ret)))
+            .unwrap_or_else(|_| error!("Failed to retrieve gamma ramp"));
+    }
+
+    // -- Render target functions --
+
+    /// Creates a new render target.
+    fn create_render_target(
+        &mut self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
+        lockable: u32,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+    ) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        // Games use these to do depth-only rendering, without a color buffer.
+        if fmt::is_null_format(fmt) {
+            if shared_handle != 0 {
+                error!("Null render targets cannot be shared");
+                return Error::InvalidCall;
+            }
+
+            // We still need a texture for the surface, but it is never bound.
+            let texture = if_error!(d3d11::Texture2D::new_rt(
+                &self.device,
+                (1, 1),
+                D3DFMT_A8,
+                0,
+                0
+            ));
+
+            *ret = Surface::new(
+                self,
+                texture,
+                UsageFlags::RENDER_TARGET,
+                MemoryPool::Default,
+                SurfaceData::NullRenderTarget(width, height),
+            )
+            .into();
+
+            return Error::Success;
+        }
+
+        if_error!(self.check_multisample(fmt, ms_ty, ms_qlt));
+
+        let multisampled = ms_ty > D3DMULTISAMPLE_NONMASKABLE;
+
+        if lockable != 0 && multisampled {
+            error!("Multisampled render targets cannot be lockable");
+        }
+
+        // First we need to create a texture we will render to.
+        let texture = if_error!(d3d11::Texture2D::new_rt(
+            &self.device,
+            (width, height),
+            fmt,
+            ms_ty,
+            ms_qlt
+        ));
+        let texture = if_error!(self.share_texture(texture, MemoryPool::Default, shared_handle));
+
+        // D3D11 doesn't allow mapping render targets, so we go through a staging texture.
+        let staging = if lockable != 0 && !multisampled {
+            Some(if_error!(texture.new_staging(&self.device)))
+        } else {
+            None
+        };
+
+        let rt_view = if_error!(texture.create_rt_view(&self.device));
+
+        *ret = Surface::with_staging(
+            self,
+            texture,
+            staging,
+            UsageFlags::RENDER_TARGET,
+            MemoryPool::Default,
+            SurfaceData::RenderTarget(rt_view),
+        )
+        .into();
+
+        Error::Success
+    }
+
+    /// Sets a new render target on this device.
+    fn set_render_target(&mut self, i: u32, rt: *mut Surface) -> Error {
+        let _lock = self.lock();
+        if i >= D3D11_SIMULTANEOUS_RENDER_TARGET_COUNT {
+            return Error::InvalidCall;
+        }
+
+        // The default render target is never allowed to be null.
+        if i == 0 && rt.is_null() {
+            return Error::InvalidCall;
+        }
+
+        let i = i as usize;
+
+        // Ensure the RT vector contains at least as many entries as we need.
+        self.render_targets.resize(i + 1, None);
+
+        self.render_targets[i] = if let Some(rt) = unsafe { rt.as_mut() } {
+            // Ensure this surface is indeed a render target.
+            if !rt.is_render_target() {
+                return Error::InvalidCall;
+            }
+
+            Some(ComPtr::new(rt))
+        } else {
+            None
+        };
+
+        self.update_render_targets();
+
+        Error::Success
+    }
+
+    /// Retrieves a reference to a bound render target.
+    fn get_render_target(&self, i: u32, ret: *mut *mut Surface) -> Error {
+        let _lock = self.lock();
+        let rt = if_error!(self.check_render_target(i));
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = rt.clone().into();
 
         Error::Success
     }
 
     /// Copies a render target's data into a surface.
     fn get_render_target_data(&self, _rt: *mut Surface, _dest: *mut Surface) {
+        let _lock = self.lock();
         unimplemented!()
     }
 
@@ -499,12 +1647,13 @@ impl Device {
         width: u32,
         height: u32,
         fmt: D3DFORMAT,
-        _ms_ty: D3DMULTISAMPLE_TYPE,
-        _ms_qlt: u32,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
         discard: u32,
         ret: *mut *mut Surface,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -512,15 +1661,29 @@ impl Device {
             return Error::InvalidCall;
         }
 
+        if !fmt::is_depth_stencil_format(fmt) {
+            error!("Invalid depth / stencil format: {}", fmt);
+            return Error::InvalidCall;
+        }
+
+        if_error!(self.check_multisample(fmt, ms_ty, ms_qlt));
+
         if discard != 0 {
             error!("Discarding depth/stencil buffer not supported");
         }
 
-        let texture = if_error!(d3d11::Texture2D::new_ds(&self.device, (width, height), fmt));
+        let texture = if_error!(d3d11::Texture2D::new_ds(
+            &self.device,
+            (width, height),
+            fmt,
+            ms_ty,
+            ms_qlt
+        ));
 
-        let ds_view = if_error!(texture.create_ds_view(&self.device));
+        let ds_view = if_error!(texture.create_ds_view(&self.device, fmt));
+        let shader_view = if_error!(texture.create_depth_shader_view(&self.device, fmt));
 
-        let data = SurfaceData::DepthStencil(ds_view);
+        let data = SurfaceData::DepthStencil(ds_view, shader_view);
 
         *ret = Surface::new(
             self,
@@ -536,6 +1699,7 @@ impl Device {
 
     /// Sets the current depth / stencil buffer.
     fn set_depth_stencil_surface(&mut self, ds: *mut Surface) -> Error {
+        let _lock = self.lock();
         self.depth_stencil = if let Some(ds) = unsafe { ds.as_mut() } {
             if ds.depth_stencil_view().is_none() {
                 return Error::InvalidCall;
@@ -546,20 +1710,32 @@ impl Device {
             None
         };
 
+        // Unlike changing the render targets, this doesn't reset the viewport.
+        self.bind_render_targets();
+
+        // The depth bias depends on the depth buffer's format.
+        self.dirty.mark(DirtyFlags::RASTERIZER);
+
         Error::Success
     }
 
     /// Retrieves the bound depth / stencil buffer.
+    ///
+    /// This is the automatic depth / stencil buffer, unless the app set another one.
     fn get_depth_stencil_surface(&self, ret: *mut *mut Surface) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        *ret = self
-            .depth_stencil
-            .as_ref()
-            .map(|ds| ds.clone().into())
-            .unwrap_or(ptr::null_mut());
-
-        Error::Success
+        match self.depth_stencil.as_ref() {
+            Some(ds) => {
+                *ret = ds.clone().into();
+                Error::Success
+            }
+            None => {
+                *ret = ptr::null_mut();
+                Error::NotFound
+            }
+        }
     }
 
     /// Creates an off-screen surface.
@@ -572,6 +1748,7 @@ impl Device {
         ret: *mut *mut Surface,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -608,6 +1785,7 @@ impl Device {
         dest: *mut Surface,
         dp: *const POINT,
     ) -> Error {
+        let _lock = self.lock();
         let src = if_error!(check_mut_ref(src));
         let dest = if_error!(check_mut_ref(dest));
         let dp = if_error!(check_ref(dp));
@@ -631,7 +1809,7 @@ impl Device {
 
             let src_box = src_box.map(|b| &b as *const _).unwrap_or(ptr::null());
 
-            self.ctx.CopySubresourceRegion(
+            self.ctx.lock().CopySubresourceRegion(
                 dest_res,
                 dest_subres,
                 dp.x as u32,
@@ -653,6 +1831,7 @@ impl Device {
         unimplemented!()
     }
     fn color_fill(&self, surface: *mut Surface, rect: *const RECT, color: D3DCOLOR) -> Error {
+        let _lock = self.lock();
         warn!(
             "unimplemented: color_fill({:?}, {:?}, {})",
             surface, rect, color
@@ -682,21 +1861,41 @@ impl Device {
         ret: *mut *mut Texture,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        if shared_handle != 0 {
-            error!("Shared resources are not supported");
-            return Error::InvalidCall;
-        }
-
         if levels == 0 {
             levels = 32 - cmp::max(width, height).leading_zeros();
         }
 
-        if usage.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
-            warn!("Autom mip-map generation not yet supported");
+        // Depth textures (e.g. INTZ) are used both as depth buffers and as shader inputs.
+        if usage.intersects(UsageFlags::DEPTH_STENCIL) {
+            if !fmt::is_depth_stencil_format(fmt) || pool != MemoryPool::Default {
+                return Error::InvalidCall;
+            }
+
+            if shared_handle != 0 {
+                error!("Shared depth textures are not supported");
+                return Error::InvalidCall;
+            }
+
+            let texture = if_error!(d3d11::Texture2D::new_ds(
+                &self.device,
+                (width, height),
+                fmt,
+                0,
+                0
+            ));
+
+            let view = if_error!(texture.create_depth_shader_view(&self.device, fmt));
+
+            *ret = Texture::new(self, pool, texture, None, view, fmt, 1, usage).into();
+
+            return Error::Success;
         }
 
+        let usage = self.check_autogen(usage, fmt);
+
         let texture = if_error!(d3d11::Texture2D::new(
             &self.device,
             (width, height),
@@ -705,8 +1904,18 @@ impl Device {
             fmt,
             pool
         ));
+        let texture = if_error!(self.share_texture(texture, pool, shared_handle));
+
+        // Textures with automatically generated mip maps cannot be mapped directly.
+        let staging = if usage.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+            Some(if_error!(texture.new_staging(&self.device)))
+        } else {
+            None
+        };
 
-        *ret = Texture::new(self, pool, texture, levels, usage).into();
+        let view = if_error!(texture.create_shader_view(&self.device));
+
+        *ret = Texture::new(self, pool, texture, staging, view, fmt, levels, usage).into();
 
         Error::Success
     }
@@ -722,6 +1931,7 @@ impl Device {
         ret: *mut *mut CubeTexture,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -733,11 +1943,9 @@ impl Device {
             levels = 32 - edge_len.leading_zeros();
         }
 
-        if usage.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
-            warn!("Autom mip-map generation not yet supported");
-        }
+        let usage = self.check_autogen(usage, fmt);
 
-        let texture = if_error!(d3d11::Texture2D::new_cube_texture(
+        let texture = if_error!(d3d11::CubeTexture::new(
             &self.device,
             edge_len,
             levels,
@@ -746,13 +1954,50 @@ impl Device {
             pool
         ));
 
-        *ret = CubeTexture::new(self, texture, levels, usage, pool).into();
+        *ret = CubeTexture::new(self, texture, fmt, levels, usage, pool).into();
 
         Error::Success
     }
 
-    fn create_volume_texture() {
-        unimplemented!()
+    /// Creates a new volume texture.
+    fn create_volume_texture(
+        &self,
+        width: u32,
+        height: u32,
+        depth: u32,
+        mut levels: u32,
+        usage: UsageFlags,
+        fmt: D3DFORMAT,
+        pool: MemoryPool,
+        ret: *mut *mut VolumeTexture,
+        shared_handle: usize,
+    ) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if shared_handle != 0 {
+            error!("Shared resources are not supported");
+            return Error::InvalidCall;
+        }
+
+        if levels == 0 {
+            levels = 32 - cmp::max(cmp::max(width, height), depth).leading_zeros();
+        }
+
+        let usage = self.check_autogen(usage, fmt);
+
+        let texture = if_error!(d3d11::VolumeTexture::new(
+            &self.device,
+            (width, height, depth),
+            levels,
+            usage,
+            fmt,
+            pool
+        ));
+
+        *ret = VolumeTexture::new(self, pool, texture, fmt, levels, usage).into();
+
+        Error::Success
     }
 
     // -- Drawing functions --
@@ -766,37 +2011,148 @@ impl Device {
         z: f32,
         stencil: DWORD,
     ) -> Error {
+        let _lock = self.lock();
         warn!("Unimplemented clear");
         Error::Success
     }
 
-    fn begin_scene(&self) -> Error {
-        // TODO: BeginScene should handle if EndScene wasn't properly called.
-        // It should potentially also clear some stuff
+    /// Begins a new scene. Draw calls should only be issued inside of scenes.
+    fn begin_scene(&mut self) -> Error {
+        let _lock = self.lock();
+        if self.in_scene {
+            return Error::InvalidCall;
+        }
+
+        self.in_scene = true;
 
         Error::Success
     }
-    fn end_scene() {
-        unimplemented!()
+
+    /// Ends the current scene.
+    fn end_scene(&mut self) -> Error {
+        let _lock = self.lock();
+        if !self.in_scene {
+            return Error::InvalidCall;
+        }
+
+        self.in_scene = false;
+
+        // D3D11 doesn't need scenes, but some apps expect the commands
+        // to be submitted to the GPU when a scene ends.
+        if self.flush_on_end_scene {
+            unsafe {
+                self.ctx.immediate().Flush();
+            }
+        }
+
+        Error::Success
     }
 
     fn draw_indexed_primitive() {
         unimplemented!()
     }
-    fn draw_indexed_primitive_u_p() {
-        unimplemented!()
+    /// Draws indexed primitives, with the vertices and indices read from the app's memory.
+    fn draw_indexed_primitive_u_p(
+        &mut self,
+        ty: D3DPRIMITIVETYPE,
+        min_vertex_index: u32,
+        num_vertices: u32,
+        prim_count: u32,
+        index_data: *const c_void,
+        index_format: D3DFORMAT,
+        vertex_data: *const c_void,
+        stride: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_scene());
+
+        if index_data.is_null() || vertex_data.is_null() || stride == 0 {
+            return Error::InvalidCall;
+        }
+
+        let (format, index_size) = match index_format {
+            D3DFMT_INDEX16 => (DXGI_FORMAT_R16_UINT, 2),
+            D3DFMT_INDEX32 => (DXGI_FORMAT_R32_UINT, 4),
+            _ => return Error::InvalidCall,
+        };
+
+        let (topology, count) =
+            if_error!(primitive_topology(ty, prim_count).ok_or(Error::InvalidCall));
+
+        // Draw calls are silently dropped while the device is lost.
+        if count == 0 || num_vertices == 0 || self.is_lost() {
+            return Error::Success;
+        }
+
+        // Only the vertices which are referenced by the indices have to be copied.
+        let vertices = unsafe {
+            let start = (vertex_data as *const u8).add(min_vertex_index as usize * stride as usize);
+            slice::from_raw_parts(start, num_vertices as usize * stride as usize)
+        };
+        let indices =
+            unsafe { slice::from_raw_parts(index_data as *const u8, count as usize * index_size) };
+
+        let fan;
+        let indices = if ty == D3DPT_TRIANGLEFAN {
+            fan = triangulate_fan(indices, index_size, prim_count);
+            &fan[..]
+        } else {
+            indices
+        };
+
+        to_error_success!(self.draw_user_primitives(
+            topology,
+            vertices,
+            stride,
+            Some((indices, format)),
+            -(min_vertex_index as i32),
+        ))
     }
     fn draw_primitive() {
         unimplemented!()
     }
-    fn draw_primitive_u_p() {
-        unimplemented!()
+    /// Draws primitives, with the vertices read from the app's memory.
+    fn draw_primitive_u_p(
+        &mut self,
+        ty: D3DPRIMITIVETYPE,
+        prim_count: u32,
+        data: *const c_void,
+        stride: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_scene());
+
+        if data.is_null() || stride == 0 {
+            return Error::InvalidCall;
+        }
+
+        let (topology, count) =
+            if_error!(primitive_topology(ty, prim_count).ok_or(Error::InvalidCall));
+
+        // Draw calls are silently dropped while the device is lost.
+        if count == 0 || self.is_lost() {
+            return Error::Success;
+        }
+
+        let vertices =
+            unsafe { slice::from_raw_parts(data as *const u8, count as usize * stride as usize) };
+
+        let fan;
+        let vertices = if ty == D3DPT_TRIANGLEFAN {
+            fan = triangulate_fan(vertices, stride as usize, prim_count);
+            &fan[..]
+        } else {
+            vertices
+        };
+
+        to_error_success!(self.draw_user_primitives(topology, vertices, stride, None, 0))
     }
 
     // -- State block functions --
 
     /// Creates a new state block which can capture commands.
     fn create_state_block(&mut self, ty: D3DSTATEBLOCKTYPE, ret: *mut *mut StateBlock) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         *ret = if_error!(StateBlock::new(self, ty)).into();
@@ -806,20 +2162,52 @@ impl Device {
 
     /// Begins recording a new state block.
     fn begin_state_block(&mut self) -> Error {
-        unimplemented!()
+        let _lock = self.lock();
+        // State blocks cannot be nested.
+        if self.recording.is_some() {
+            return Error::InvalidCall;
+        }
+
+        self.recording = Some(Vec::new());
+
+        Error::Success
     }
 
     /// Ends recording a state block, and returns a pointer to it.
     fn end_state_block(&mut self, ret: *mut *mut StateBlock) -> Error {
-        let _ret = if_error!(check_mut_ref(ret));
-        unimplemented!()
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        let changes = match self.recording.take() {
+            Some(changes) => changes,
+            None => return Error::InvalidCall,
+        };
+
+        *ret = StateBlock::recorded(self, changes).into();
+
+        Error::Success
     }
 
     /// Validates the current state of the device, or the state of the
     /// currently recording state block, if any.
     fn validate_device(&self, passes: *mut u32) -> Error {
+        let _lock = self.lock();
         let passes = if_error!(check_mut_ref(passes));
 
+        // Pixel shaders replace the texture stages entirely.
+        if self.istate.get_pixel_shader().is_null() {
+            for stage in 0..MAX_TEXTURE_STAGES as u32 {
+                // The first disabled stage ends the cascade.
+                if self.texture_stage_state(stage, D3DTSS_COLOROP) == D3DTOP_DISABLE {
+                    break;
+                }
+
+                if self.stage_reads_texture(stage) && self.istate.get_texture(stage).is_null() {
+                    return Error::ConflictingRenderState;
+                }
+            }
+        }
+
         // We do not emulate anything using multiple passes.
         *passes = 1;
 
@@ -828,14 +2216,35 @@ impl Device {
 
     // -- Hardware cursor functions --
 
-    fn set_cursor_position() {
-        unimplemented!()
+    /// Moves the cursor to a certain position on the desktop.
+    fn set_cursor_position(&self, x: i32, y: i32, _flags: u32) {
+        let _lock = self.lock();
+        // The system cursor is always updated immediately.
+        self.cursor.set_position(x, y);
     }
-    fn set_cursor_properties() {
-        unimplemented!()
+
+    /// Changes the cursor's image and hotspot.
+    fn set_cursor_properties(&self, x: u32, y: u32, bitmap: *mut Surface) -> Error {
+        let _lock = self.lock();
+        let bitmap = if_error!(check_ref(bitmap));
+
+        let mut desc = unsafe { mem::zeroed() };
+        if_not_success!(bitmap.get_desc(&mut desc));
+
+        // The hotspot has to be inside of the image.
+        if desc.Format != D3DFMT_A8R8G8B8 || x >= desc.Width || y >= desc.Height {
+            return Error::InvalidCall;
+        }
+
+        let (width, height, pixels) = if_error!(bitmap.read_pixels());
+
+        to_error_success!(self.cursor.set_image(width, height, &pixels, (x, y)))
     }
-    fn show_cursor() {
-        unimplemented!()
+
+    /// Shows or hides the cursor, and returns whether it was previously visible.
+    fn show_cursor(&self, show: BOOL) -> BOOL {
+        let _lock = self.lock();
+        self.cursor.show(show != 0) as BOOL
     }
 
     // -- Pipeline state functions --
@@ -845,12 +2254,45 @@ impl Device {
 
     /// Sets the render state.
     fn set_render_state(&mut self, state: D3DRENDERSTATETYPE, value: u32) -> Error {
+        let _lock = self.lock();
+        if self.record(StateChange::RenderState(state, value)) {
+            return Error::Success;
+        }
+
         self.istate.set_render_state(state, value);
+
+        let dirty = match state {
+            D3DRS_CLIPPLANEENABLE
+            | D3DRS_CLIPPING
+            | D3DRS_TEXTUREFACTOR
+            | D3DRS_AMBIENT
+            | D3DRS_FOGCOLOR
+            | D3DRS_FOGSTART
+            | D3DRS_FOGEND
+            | D3DRS_FOGDENSITY
+            | D3DRS_ALPHAREF
+            | D3DRS_POINTSIZE
+            | D3DRS_POINTSIZE_MIN
+            | D3DRS_POINTSIZE_MAX
+            | D3DRS_POINTSCALE_A
+            | D3DRS_POINTSCALE_B
+            | D3DRS_POINTSCALE_C => DirtyFlags::FIXED_FUNCTION,
+            D3DRS_BLENDFACTOR | D3DRS_MULTISAMPLEMASK => DirtyFlags::BLEND,
+            state if is_blend_state(state) => DirtyFlags::BLEND,
+            state if is_rasterizer_state(state) => DirtyFlags::RASTERIZER,
+            _ => DirtyFlags::empty(),
+        };
+
+        if !dirty.is_empty() {
+            self.dirty.mark(dirty);
+        }
+
         Error::Success
     }
 
     /// Retrieves the value of the current render state.
     fn get_render_state(&self, state: D3DRENDERSTATETYPE, ret: *mut u32) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         *ret = self.istate.get_render_state(state);
@@ -866,6 +2308,7 @@ impl Device {
         elems: *const D3DVERTEXELEMENT9,
         ret: *mut *mut VertexDeclaration,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         *ret = VertexDeclaration::new(self, elems).into();
@@ -875,12 +2318,19 @@ impl Device {
 
     /// Sets the current vertex declaration.
     fn set_vertex_declaration(&mut self, decl: *const VertexDeclaration) -> Error {
+        let _lock = self.lock();
+        if self.record(StateChange::VertexDeclaration(Bound::new(decl))) {
+            return Error::Success;
+        }
+
         self.istate.set_vertex_declaration(decl);
+        self.dirty.mark(DirtyFlags::INPUT_LAYOUT);
         Error::Success
     }
 
     /// Gets the current vertex declaration.
     fn get_vertex_declaration(&self, ret: *mut *const VertexDeclaration) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = com_ref(self.istate.get_vertex_declaration());
         Error::Success
@@ -888,6 +2338,7 @@ impl Device {
 
     /// Creates a vertex shader from its bytecode.
     fn create_vertex_shader(&self, func: *const u32, ret: *mut *mut VertexShader) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = if_error!(VertexShader::new(self, func)).into();
         Error::Success
@@ -895,22 +2346,24 @@ impl Device {
 
     /// Sets the current vertex shader.
     fn set_vertex_shader(&mut self, vs_raw_ptr: *const VertexShader) -> Error {
+        let _lock = self.lock();
         trace!("set_vertex_shader");
-        let vs = if_error!(check_ref(vs_raw_ptr));
-        self.istate.set_vertex_shader(vs);
-        unsafe {
-            self.device_context()
-                .VSSetShader(vs.get_dx11().as_mut(), ptr::null_mut(), 0);
+
+        if self.record(StateChange::VertexShader(Bound::new(vs_raw_ptr))) {
+            return Error::Success;
         }
+
+        self.istate.set_vertex_shader(vs_raw_ptr);
+        self.dirty
+            .mark(DirtyFlags::VERTEX_SHADER | DirtyFlags::INPUT_LAYOUT);
         Error::Success
     }
 
     /// Retrieves the current vertex shader;
     fn get_vertex_shader(&self, ret: *mut *const VertexShader) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
-        *ret = self.istate.get_vertex_shader();
-        // TODO: somehow use DX11 GetVertexShader and wrap it, so _we_ aren't responsible for the state?
-        // But then how to look it up properly.
+        *ret = com_ref(self.istate.get_vertex_shader());
         Error::Success
     }
 
@@ -926,6 +2379,7 @@ impl Device {
         constant_data: *const f32,
         vector4f_count: UINT,
     ) -> Error {
+        let _lock = self.lock();
         warn!(
             "TODO: Implement set_vertex_shader_constant_f({}, {:?}, {})",
             start_register, constant_data, vector4f_count
@@ -962,6 +2416,7 @@ impl Device {
         ret: *mut *mut VertexBuffer,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -969,6 +2424,22 @@ impl Device {
             return Error::InvalidCall;
         }
 
+        // The FVF is 0 if the app uses vertex declarations,
+        // otherwise the buffer must be able to hold at least one vertex.
+        let min_len = if fvf != 0 {
+            vertex_size(&fvf_to_elements(fvf), 0) as u32
+        } else {
+            1
+        };
+
+        if len < min_len {
+            error!(
+                "Vertex buffer of {} bytes is too small for FVF {:#x}",
+                len, fvf
+            );
+            return Error::InvalidCall;
+        }
+
         let buffer = if_error!(d3d11::Buffer::new(
             &self.device,
             len,
@@ -992,6 +2463,7 @@ impl Device {
         ret: *mut *mut IndexBuffer,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -999,6 +2471,20 @@ impl Device {
             return Error::InvalidCall;
         }
 
+        let index_size = match fmt {
+            D3DFMT_INDEX16 => 2,
+            D3DFMT_INDEX32 => 4,
+            _ => {
+                error!("Invalid index buffer format: {}", fmt);
+                return Error::InvalidCall;
+            }
+        };
+
+        // The buffer must be able to hold at least one index.
+        if len < index_size {
+            return Error::InvalidCall;
+        }
+
         let buffer = if_error!(d3d11::Buffer::new(
             &self.device,
             len,
@@ -1012,31 +2498,62 @@ impl Device {
         Error::Success
     }
 
+    /// Binds a vertex buffer to an input stream.
     fn set_stream_source(
-        &self,
+        &mut self,
         stream_number: UINT,
         stream_data: *mut VertexBuffer,
         offset_in_bytes: UINT,
         stride: UINT,
     ) -> Error {
+        let _lock = self.lock();
         // TODO: When grouping buffers together, we can reduce API calls, but for this we need a
         // reliable way of knowing when we've reached the last s_s_s call, introducing state bookkeeping and related issues.
         trace!("SetStreamSource");
-        let vb = if_error!(check_ref(stream_data));
 
-        unsafe {
-            self.device_context().IASetVertexBuffers(
-                stream_number,
-                1,
-                &vb.get_dx11().as_buffer(),
-                &stride,
-                &offset_in_bytes,
-            );
+        if stream_number as usize >= MAX_STREAMS {
+            return Error::InvalidCall;
+        }
+
+        let source = StreamSource {
+            buffer: Bound::new(stream_data),
+            offset: offset_in_bytes,
+            stride,
+        };
+
+        if self.record(StateChange::StreamSource(stream_number, source.clone())) {
+            return Error::Success;
         }
+
+        self.istate.set_stream_source(stream_number, source);
+        self.dirty.mark_stream(stream_number);
+
         Error::Success
     }
-    fn get_stream_source() {
-        unimplemented!()
+
+    /// Retrieves the vertex buffer bound to an input stream.
+    fn get_stream_source(
+        &self,
+        stream_number: UINT,
+        ret: *mut *mut VertexBuffer,
+        offset: *mut UINT,
+        stride: *mut UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let offset = if_error!(check_mut_ref(offset));
+        let stride = if_error!(check_mut_ref(stride));
+
+        let source = if_error!(self
+            .istate
+            .get_stream_source(stream_number)
+            .ok_or(Error::InvalidCall));
+
+        *ret = com_ref(source.buffer.as_ptr());
+        *offset = source.offset;
+        *stride = source.stride;
+
+        Error::Success
     }
 
     fn set_stream_source_freq() {
@@ -1050,6 +2567,11 @@ impl Device {
 
     /// Sets the state of a texture sampler.
     fn set_sampler_state(&mut self, sampler: u32, ty: D3DSAMPLERSTATETYPE, value: u32) -> Error {
+        let _lock = self.lock();
+        if self.record(StateChange::SamplerState(sampler, ty, value)) {
+            return Error::Success;
+        }
+
         self.istate.set_sampler_state(sampler, ty, value);
 
         Error::Success
@@ -1057,6 +2579,7 @@ impl Device {
 
     /// Gets the state of a texture sampler.
     fn get_sampler_state(&self, sampler: u32, ty: D3DSAMPLERSTATETYPE, ret: *mut u32) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         *ret = self.istate.get_sampler_state(sampler, ty);
@@ -1066,6 +2589,7 @@ impl Device {
 
     /// Create a pixel shader from its bytecode.
     fn create_pixel_shader(&self, func: *const u32, ret: *mut *mut PixelShader) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = if_error!(PixelShader::new(self, func)).into();
         Error::Success
@@ -1073,14 +2597,21 @@ impl Device {
 
     /// Sets the current pixel shader.
     fn set_pixel_shader(&mut self, ps: *const PixelShader) -> Error {
+        let _lock = self.lock();
+        if self.record(StateChange::PixelShader(Bound::new(ps))) {
+            return Error::Success;
+        }
+
         self.istate.set_pixel_shader(ps);
+        self.dirty.mark(DirtyFlags::PIXEL_SHADER);
         Error::Success
     }
 
     /// Gets the current pixel shader.
     fn get_pixel_shader(&self, ret: *mut *const PixelShader) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
-        *ret = self.istate.get_pixel_shader();
+        *ret = com_ref(self.istate.get_pixel_shader());
         Error::Success
     }
 
@@ -1096,6 +2627,7 @@ impl Device {
         constant_data: *const f32,
         vector4f_count: UINT,
     ) -> Error {
+        let _lock = self.lock();
         warn!(
             "TODO: Implement set_pixel_shader_constant_f({}, {:?}, {})",
             start_register, constant_data, vector4f_count
@@ -1114,14 +2646,21 @@ impl Device {
 
     /// Binds a texture to a stage.
     fn set_texture(&mut self, stage: u32, texture: *mut BaseTexture) -> Error {
+        let _lock = self.lock();
+        if self.record(StateChange::Texture(stage, Bound::new(texture))) {
+            return Error::Success;
+        }
+
         self.istate.set_texture(stage, texture);
+        self.dirty.mark(DirtyFlags::TEXTURES);
         Error::Success
     }
 
     /// Retrieves the bound texture of a certain stage.
     fn get_texture(&self, stage: u32, ret: *mut *mut BaseTexture) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
-        *ret = self.istate.get_texture(stage);
+        *ret = com_ref(self.istate.get_texture(stage));
         Error::Success
     }
 
@@ -1132,6 +2671,11 @@ impl Device {
         ty: D3DTEXTURESTAGESTATETYPE,
         value: u32,
     ) -> Error {
+        let _lock = self.lock();
+        if self.record(StateChange::TextureStageState(stage, ty, value)) {
+            return Error::Success;
+        }
+
         self.istate.set_texture_stage_state(stage, ty, value);
         Error::Success
     }
@@ -1143,6 +2687,7 @@ impl Device {
         ty: D3DTEXTURESTAGESTATETYPE,
         ret: *mut u32,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = self.istate.get_texture_stage_state(stage, ty);
         Error::Success
@@ -1152,13 +2697,21 @@ impl Device {
 
     /// Sets a device's viewport.
     fn set_viewport(&mut self, vp: *const D3DVIEWPORT9) -> Error {
+        let _lock = self.lock();
         let vp = if_error!(check_ref(vp));
+
+        if self.record(StateChange::Viewport(*vp)) {
+            return Error::Success;
+        }
+
         self.istate.set_viewport(vp);
+        self.dirty.mark(DirtyFlags::VIEWPORT);
         Error::Success
     }
 
     /// Retrieves the currently set viewport.
     fn get_viewport(&self, ret: *mut D3DVIEWPORT9) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = self.istate.get_viewport();
         Error::Success
@@ -1171,23 +2724,47 @@ impl Device {
         unimplemented!()
     }
 
-    // -- Query creation --
+    // -- Query creation --
+
+    /// Creates a new asynchronous query.
+    ///
+    /// If `ret` is null, this only checks if the query type is supported.
+    fn create_query(&self, ty: D3DQUERYTYPE, ret: *mut *mut Query) -> Error {
+        let _lock = self.lock();
+        if ret.is_null() {
+            return if d3d_query_to_d3d11(ty).is_some() {
+                Error::Success
+            } else {
+                Error::NotAvailable
+            };
+        }
+
+        let ret = if_error!(check_mut_ref(ret));
 
-    fn create_query() {
-        unimplemented!()
+        *ret = if_error!(Query::new(self, ty)).into();
+
+        Error::Success
     }
 
     // -- Fixed function pipeline --
 
     /// Sets the current material.
     fn set_material(&mut self, mat: *const D3DMATERIAL9) -> Error {
+        let _lock = self.lock();
         let mat = if_error!(check_ref(mat));
+
+        if self.record(StateChange::Material(*mat)) {
+            return Error::Success;
+        }
+
         self.istate.set_material(mat);
+        self.dirty.mark(DirtyFlags::FIXED_FUNCTION);
         Error::Success
     }
 
     /// Retrieves the currently set material.
     fn get_material(&self, ret: *mut D3DMATERIAL9) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = self.istate.get_material();
         Error::Success
@@ -1195,13 +2772,19 @@ impl Device {
 
     /// Sets a transformation matrix to a value.
     fn set_transform(&mut self, ty: D3DTRANSFORMSTATETYPE, mat: *const D3DMATRIX) -> Error {
+        let _lock = self.lock();
         if (D3DTS_VIEW <= ty && ty <= D3DTS_PROJECTION)
             || (D3DTS_TEXTURE0 <= ty && ty <= D3DTS_TEXTURE7)
             || (256 <= ty && ty <= 512)
         {
             let mat = if_error!(check_ref(mat));
-            self.istate
-                .set_transform(ty, unsafe { mem::transmute(*mat) });
+            let mat = unsafe { mem::transmute(*mat) };
+
+            if !self.record(StateChange::Transform(ty, mat)) {
+                self.istate.set_transform(ty, mat);
+                self.dirty.mark(DirtyFlags::FIXED_FUNCTION);
+            }
+
             Error::Success
         } else {
             Error::InvalidCall
@@ -1210,6 +2793,7 @@ impl Device {
 
     /// Retrieves a transformation matrix.
     fn get_transform(&self, ty: D3DTRANSFORMSTATETYPE, ret: *mut D3DMATRIX) -> Error {
+        let _lock = self.lock();
         if (D3DTS_VIEW <= ty && ty <= D3DTS_PROJECTION)
             || (D3DTS_TEXTURE0 <= ty && ty <= D3DTS_TEXTURE7)
             || (256 <= ty && ty <= 512)
@@ -1222,85 +2806,599 @@ impl Device {
         }
     }
 
-    fn delete_patch() {
-        unimplemented!()
-    }
-    fn draw_rect_patch() {
-        unimplemented!()
+    fn delete_patch(&self, _handle: u32) -> Error {
+        let _lock = self.lock();
+        unimplemented_method!("IDirect3DDevice9::DeletePatch")
     }
-    fn draw_tri_patch() {
-        unimplemented!()
+    fn draw_rect_patch(
+        &self,
+        _handle: u32,
+        _segs: *const f32,
+        _info: *const D3DRECTPATCH_INFO,
+    ) -> Error {
+        let _lock = self.lock();
+        unimplemented_method!("IDirect3DDevice9::DrawRectPatch")
     }
-    fn get_clip_plane() {
-        unimplemented!()
+    fn draw_tri_patch(
+        &self,
+        _handle: u32,
+        _segs: *const f32,
+        _info: *const D3DTRIPATCH_INFO,
+    ) -> Error {
+        let _lock = self.lock();
+        unimplemented_method!("IDirect3DDevice9::DrawTriPatch")
+    }
+    /// Retrieves the coefficients of a user clip plane.
+    fn get_clip_plane(&self, index: u32, plane: *mut f32) -> Error {
+        let _lock = self.lock();
+        let plane = if_error!(check_mut_ref(plane as *mut [f32; 4]));
+        *plane = if_error!(self.istate.get_clip_plane(index).ok_or(Error::InvalidCall));
+        Error::Success
     }
-    fn get_clip_status() {
-        unimplemented!()
+    /// Retrieves the clip status.
+    fn get_clip_status(&self, ret: *mut D3DCLIPSTATUS9) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = self.clip_status;
+        Error::Success
     }
-    fn get_current_texture_palette() {
-        unimplemented!()
+    /// Retrieves the palette used by paletted textures.
+    fn get_current_texture_palette(&self, ret: *mut u32) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = self.current_palette;
+        Error::Success
     }
-    fn get_f_v_f() {
-        unimplemented!()
+    /// Retrieves the flexible vertex format of the current vertex declaration.
+    ///
+    /// Returns 0 if the declaration was not created from a flexible vertex format.
+    fn get_f_v_f(&self, ret: *mut u32) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let decl = unsafe { self.istate.get_vertex_declaration().as_ref() };
+        *ret = decl.map_or(0, VertexDeclaration::fvf);
+        Error::Success
     }
-    fn get_indices() {
-        unimplemented!()
+    /// Retrieves the currently bound index buffer.
+    fn get_indices(&self, ret: *mut *mut IndexBuffer) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let indices = self.istate.get_indices();
+
+        *ret = if indices.is_null() {
+            ptr::null_mut()
+        } else {
+            com_ref(indices)
+        };
+
+        Error::Success
     }
-    fn get_light() {
-        unimplemented!()
+    /// Retrieves the properties of a light.
+    fn get_light(&self, index: u32, ret: *mut D3DLIGHT9) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = if_error!(self.istate.get_light(index).ok_or(Error::InvalidCall));
+        Error::Success
     }
-    fn get_light_enable() {
-        unimplemented!()
+    /// Checks if a light is enabled.
+    fn get_light_enable(&self, index: u32, ret: *mut BOOL) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let enabled = if_error!(self
+            .istate
+            .is_light_enabled(index)
+            .ok_or(Error::InvalidCall));
+
+        // Native D3D9 returns 128 instead of TRUE, and some apps depend on it.
+        *ret = if enabled { 128 } else { 0 };
+
+        Error::Success
     }
-    fn get_n_patch_mode() {
-        unimplemented!()
+    /// Retrieves the number of segments used to tessellate N-patches.
+    fn get_n_patch_mode(&self) -> f32 {
+        let _lock = self.lock();
+        self.n_patch_segments
     }
-    fn get_palette_entries() {
-        unimplemented!()
+    /// Retrieves the entries of a texture palette.
+    fn get_palette_entries(&self, number: u32, entries: *mut PALETTEENTRY) -> Error {
+        let _lock = self.lock();
+        let entries = if_error!(check_mut_ref(entries));
+
+        let palette = match self.palettes.get(&number) {
+            Some(palette) => palette,
+            None => return Error::InvalidCall,
+        };
+
+        unsafe {
+            ptr::copy_nonoverlapping(palette.as_ptr(), entries, PALETTE_SIZE);
+        }
+
+        Error::Success
     }
-    fn get_software_vertex_processing() {
-        unimplemented!()
+    /// Checks if vertices are currently processed in software.
+    fn get_software_vertex_processing(&self) -> BOOL {
+        let _lock = self.lock();
+        self.software_vertex_processing as BOOL
     }
-    fn light_enable() {
-        unimplemented!()
+    /// Enables or disables a light.
+    fn light_enable(&mut self, index: u32, enable: BOOL) -> Error {
+        let _lock = self.lock();
+        if self.record(StateChange::LightEnable(index, enable != 0)) {
+            return Error::Success;
+        }
+
+        if !self.istate.light_enable(index, enable != 0) {
+            error!("Cannot enable more than {} lights", MAX_ACTIVE_LIGHTS);
+            return Error::InvalidCall;
+        }
+
+        self.dirty.mark(DirtyFlags::FIXED_FUNCTION);
+
+        Error::Success
     }
     fn multiply_transform() {
         unimplemented!()
     }
-    fn process_vertices() {
-        unimplemented!()
+    /// Transforms vertices on the CPU, writing the results to a vertex buffer.
+    ///
+    /// Only the fixed function transform is applied.
+    fn process_vertices(
+        &self,
+        src_start: u32,
+        dest_index: u32,
+        count: u32,
+        dest: *mut VertexBuffer,
+        decl: *const VertexDeclaration,
+        flags: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        let dest = if_error!(check_ref(dest));
+
+        if !self.istate.get_vertex_shader().is_null() {
+            // Vertex shaders can only be run on the CPU in software vertex processing mode.
+            if !self.software_vertex_processing {
+                return Error::InvalidCall;
+            }
+
+            return unimplemented_method!(
+                "ProcessVertices with a vertex shader",
+                Error::InvalidCall
+            );
+        }
+
+        let src_elems = match unsafe { self.istate.get_vertex_declaration().as_ref() } {
+            Some(decl) => decl.elements(),
+            None => return Error::InvalidCall,
+        };
+
+        // Without an explicit declaration, the output layout is given by the buffer's FVF.
+        let dest_elems = match unsafe { decl.as_ref() } {
+            Some(decl) => decl.elements().to_vec(),
+            None => fvf_to_elements(dest.fvf()),
+        };
+
+        if !dest_elems
+            .iter()
+            .any(|e| e.Stream == 0 && e.Usage == D3DDECLUSAGE_POSITIONT as u8)
+        {
+            error!("ProcessVertices requires a pre-transformed position in the output");
+            return Error::InvalidCall;
+        }
+
+        let streams = if_error!(self.read_streams(src_elems));
+
+        let stride = vertex_size(&dest_elems, 0);
+        let offset = dest_index as usize * stride;
+        let len = count as usize * stride;
+
+        let copy_data = flags & D3DPV_DONOTCOPYDATA == 0;
+
+        // When not copying the vertex data, the existing contents must be preserved.
+        let mut output = if copy_data {
+            vec![0; len]
+        } else {
+            let data = if_error!(dest.get_dx11().read(&self.device, &self.ctx.immediate()));
+            match data.get(offset..offset + len) {
+                Some(data) => data.to_vec(),
+                None => return Error::InvalidCall,
+            }
+        };
+
+        let processor = VertexProcessor {
+            transform: self.istate.get_transform(D3DTS_PROJECTION)
+                * self.istate.get_transform(D3DTS_VIEW)
+                * self.istate.get_transform(D3DTS_WORLD),
+            viewport: self.istate.get_viewport(),
+            copy_data,
+        };
+
+        if_error!(processor.process(
+            src_elems,
+            &streams,
+            src_start as usize,
+            &dest_elems,
+            &mut output,
+        ));
+
+        if_error!(dest.get_dx11().write(
+            &self.device,
+            &self.ctx.immediate(),
+            offset as u32,
+            &output
+        ));
+        dest.mark_dirty(dest.get_dx11().as_resource());
+
+        Error::Success
     }
-    fn set_clip_plane() {
-        unimplemented!()
+    /// Sets the coefficients of a user clip plane.
+    fn set_clip_plane(&mut self, index: u32, plane: *const f32) -> Error {
+        let _lock = self.lock();
+        let plane = if_error!(check_ref(plane as *const [f32; 4]));
+
+        if index as usize >= MAX_CLIP_PLANES {
+            return Error::InvalidCall;
+        }
+
+        if self.record(StateChange::ClipPlane(index, *plane)) {
+            return Error::Success;
+        }
+
+        self.istate.set_clip_plane(index, *plane);
+        self.dirty.mark(DirtyFlags::FIXED_FUNCTION);
+
+        Error::Success
     }
-    fn set_clip_status() {
-        unimplemented!()
+    /// Sets the clip status.
+    fn set_clip_status(&mut self, status: *const D3DCLIPSTATUS9) -> Error {
+        let _lock = self.lock();
+        let status = if_error!(check_ref(status));
+        self.clip_status = *status;
+        Error::Success
     }
-    fn set_current_texture_palette() {
-        unimplemented!()
+    /// Sets the palette used by paletted textures.
+    fn set_current_texture_palette(&mut self, number: u32) -> Error {
+        let _lock = self.lock();
+        let palette = match self.palettes.get(&number) {
+            Some(palette) => palette,
+            None => return Error::InvalidCall,
+        };
+
+        self.ffp.borrow().update_palette(&self.ctx.lock(), palette);
+        self.current_palette = number;
+
+        Error::Success
     }
-    fn set_dialog_box_mode() {
-        unimplemented!()
+    /// Allows GDI dialogs to be shown on top of the app when it's in full screen mode.
+    fn set_dialog_box_mode(&self, enable: BOOL) -> Error {
+        let _lock = self.lock();
+        for sc in &self.swap_chains {
+            if let Err(err) = sc.set_dialog_box_mode(enable != 0) {
+                return err;
+            }
+        }
+
+        Error::Success
     }
-    fn set_f_v_f() {
-        unimplemented!()
+    /// Sets the current vertex declaration to one described by a flexible vertex format.
+    fn set_f_v_f(&mut self, fvf: u32) -> Error {
+        let _lock = self.lock();
+
+        // Games tend to use only a handful of formats, so we reuse their declarations.
+        let decl = match self.fvf_decls.get(&fvf) {
+            Some(decl) => decl.clone(),
+            None => {
+                let decl = VertexDeclaration::from_fvf(self, fvf);
+                self.fvf_decls.insert(fvf, decl.clone());
+                decl
+            }
+        };
+
+        self.set_vertex_declaration(decl.as_mut())
     }
-    fn set_indices(&mut self, idx: *mut IDirect3DIndexBuffer9) -> Error {
-        warn!("TODO: SetIndices on unsupported Fixed Function Pipeline!");
-        if_error!(check_ref(idx));
-        self.istate.set_index(idx);
+    /// Binds an index buffer.
+    fn set_indices(&mut self, idx: *mut IndexBuffer) -> Error {
+        let _lock = self.lock();
+        if self.record(StateChange::Indices(Bound::new(idx))) {
+            return Error::Success;
+        }
+
+        self.istate.set_indices(idx);
+        self.dirty.mark(DirtyFlags::INDICES);
         Error::Success
     }
-    fn set_light() {
-        unimplemented!()
+    /// Sets the properties of a light.
+    fn set_light(&mut self, index: u32, light: *const D3DLIGHT9) -> Error {
+        let _lock = self.lock();
+        let light = if_error!(check_ref(light));
+
+        match light.Type {
+            D3DLIGHT_POINT | D3DLIGHT_SPOT | D3DLIGHT_DIRECTIONAL => (),
+            _ => return Error::InvalidCall,
+        }
+
+        if self.record(StateChange::Light(index, *light)) {
+            return Error::Success;
+        }
+
+        self.istate.set_light(index, light);
+        self.dirty.mark(DirtyFlags::FIXED_FUNCTION);
+
+        Error::Success
     }
-    fn set_n_patch_mode() {
-        unimplemented!()
+    /// Sets the number of segments used to tessellate N-patches.
+    fn set_n_patch_mode(&mut self, segments: f32) -> Error {
+        let _lock = self.lock();
+        // Values below 1 disable N-patches.
+        if segments >= 1.0 {
+            run_once!(|| warn!("N-patch tessellation is not supported, drawing plain triangles"));
+        }
+
+        self.n_patch_segments = segments;
+
+        Error::Success
     }
-    fn set_palette_entries() {
-        unimplemented!()
+    /// Defines the entries of a texture palette.
+    fn set_palette_entries(&mut self, number: u32, entries: *const PALETTEENTRY) -> Error {
+        let _lock = self.lock();
+        let entries = if_error!(check_ref(entries));
+
+        if number >= MAX_PALETTES {
+            return Error::InvalidCall;
+        }
+
+        let entries = unsafe { std::slice::from_raw_parts(entries, PALETTE_SIZE) };
+
+        if number == self.current_palette {
+            self.ffp.borrow().update_palette(&self.ctx.lock(), entries);
+        }
+
+        self.palettes.insert(number, entries.into());
+
+        Error::Success
     }
-    fn set_software_vertex_processing() {
-        unimplemented!()
+    /// Switches between hardware and software vertex processing.
+    fn set_software_vertex_processing(&mut self, software: BOOL) -> Error {
+        let _lock = self.lock();
+        let software = software != 0;
+
+        // Only mixed mode devices are allowed to switch.
+        if self.creation_params.BehaviorFlags & D3DCREATE_MIXED_VERTEXPROCESSING == 0
+            && software != self.software_vertex_processing
+        {
+            return Error::InvalidCall;
+        }
+
+        self.software_vertex_processing = software;
+
+        Error::Success
+    }
+}
+
+#[implementation(IDirect3DDevice9Ex)]
+impl Device {
+    fn set_convolution_mono_kernel(
+        &self,
+        _width: u32,
+        _height: u32,
+        _rows: *mut f32,
+        _columns: *mut f32,
+    ) -> Error {
+        let _lock = self.lock();
+        unimplemented_method!("IDirect3DDevice9Ex::SetConvolutionMonoKernel")
+    }
+    fn compose_rects(
+        &self,
+        _src: *mut Surface,
+        _dest: *mut Surface,
+        _src_descs: usize,
+        _count: u32,
+        _dest_descs: usize,
+        _op: D3DCOMPOSERECTSOP,
+        _x: i32,
+        _y: i32,
+    ) -> Error {
+        let _lock = self.lock();
+        unimplemented_method!("IDirect3DDevice9Ex::ComposeRects", Error::InvalidCall)
+    }
+
+    /// Presents the back buffers of the implicit swap chains.
+    fn present_ex(&self, src: usize, dest: usize, wnd: HWND, dirty: usize, flags: u32) -> Error {
+        let _lock = self.lock();
+        if self.lost.get() || self.is_window_minimized() {
+            self.lost.set(true);
+            return Error::DeviceLost;
+        }
+
+        if let Some(ref limiter) = self.frame_limiter {
+            limiter.wait();
+        }
+
+        let state_changes = self.dirty.stats().changes;
+
+        self.profiler.present(&self.ctx, state_changes, || {
+            for sc in &self.swap_chains {
+                match sc.present(src, dest, wnd, dirty, flags) {
+                    Error::Success => (),
+                    // DXGI doesn't lose the device, but apps expect D3D9's behaviour.
+                    Error::PresentOccluded => {
+                        self.lost.set(true);
+                        return Error::DeviceLost;
+                    }
+                    err => return err,
+                }
+            }
+            Error::Success
+        })
+    }
+
+    /// Retrieves the priority of the GPU thread which processes this device's commands.
+    fn get_g_p_u_thread_priority(&self, ret: *mut i32) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let dxgi = if_error!(self.device.dxgi_device());
+
+        let result = unsafe { dxgi.GetGPUThreadPriority(ret) };
+        check_hresult(result, "Failed to get GPU thread priority")
+    }
+
+    /// Sets the priority of the GPU thread which processes this device's commands.
+    fn set_g_p_u_thread_priority(&self, priority: i32) -> Error {
+        let _lock = self.lock();
+        // Both D3D9Ex and DXGI use the [-7; 7] range.
+        if priority < -7 || priority > 7 {
+            return Error::InvalidCall;
+        }
+
+        let dxgi = if_error!(self.device.dxgi_device());
+
+        let result = unsafe { dxgi.SetGPUThreadPriority(priority) };
+        check_hresult(result, "Failed to set GPU thread priority")
+    }
+
+    /// Waits for the next vertical blank of a swap chain's monitor.
+    fn wait_for_v_blank(&self, sc: u32) -> Error {
+        let _lock = self.lock();
+        let sc = if_error!(self.check_swap_chain(sc));
+        to_error_success!(sc.wait_for_vblank())
+    }
+
+    /// Checks if resources are in video memory.
+    fn check_resource_residency(&self, _resources: usize, _count: u32) -> Error {
+        let _lock = self.lock();
+        // D3D11 keeps all resources resident for us.
+        Error::Success
+    }
+
+    /// Sets the number of frames the CPU is allowed to queue up.
+    fn set_maximum_frame_latency(&self, latency: u32) -> Error {
+        let _lock = self.lock();
+        let dxgi = if_error!(self.device.dxgi_device());
+
+        // 0 means the default, which is 3 for both APIs.
+        let result = unsafe { dxgi.SetMaximumFrameLatency(latency) };
+        check_hresult(result, "Failed to set maximum frame latency")
+    }
+
+    /// Retrieves the number of frames the CPU is allowed to queue up.
+    fn get_maximum_frame_latency(&self, ret: *mut u32) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let dxgi = if_error!(self.device.dxgi_device());
+
+        let result = unsafe { dxgi.GetMaximumFrameLatency(ret) };
+        check_hresult(result, "Failed to get maximum frame latency")
+    }
+
+    /// Checks if the device is able to present to a window.
+    fn check_device_state(&self, _wnd: HWND) -> Error {
+        let _lock = self.lock();
+        if self.lost.get() {
+            Error::DeviceLost
+        } else if self.is_window_minimized() {
+            Error::PresentOccluded
+        } else {
+            Error::Success
+        }
+    }
+
+    /// Creates a render target with extra usage flags.
+    fn create_render_target_ex(
+        &mut self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
+        lockable: u32,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+        _usage: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        // The extra usage flags only concern restricted / protected content.
+        self.create_render_target(
+            width,
+            height,
+            fmt,
+            ms_ty,
+            ms_qlt,
+            lockable,
+            ret,
+            shared_handle,
+        )
+    }
+
+    /// Creates an off-screen surface with extra usage flags.
+    fn create_offscreen_plain_surface_ex(
+        &self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        pool: MemoryPool,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+        _usage: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        self.create_offscreen_plain_surface(width, height, fmt, pool, ret, shared_handle)
+    }
+
+    /// Creates a depth / stencil buffer with extra usage flags.
+    fn create_depth_stencil_surface_ex(
+        &self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
+        discard: u32,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+        _usage: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        self.create_depth_stencil_surface(
+            width,
+            height,
+            fmt,
+            ms_ty,
+            ms_qlt,
+            discard,
+            ret,
+            shared_handle,
+        )
+    }
+
+    /// Resets the device, optionally switching to a full screen display mode.
+    fn reset_ex(&mut self, pp: *mut D3DPRESENT_PARAMETERS, _mode: *mut D3DDISPLAYMODEEX) -> Error {
+        let _lock = self.lock();
+        // The display mode is described by the presentation parameters as well.
+        self.reset(pp)
+    }
+
+    /// Retrieves the display mode and rotation of a swap chain.
+    fn get_display_mode_ex(
+        &self,
+        sc: u32,
+        mode: *mut D3DDISPLAYMODEEX,
+        rotation: *mut D3DDISPLAYROTATION,
+    ) -> Error {
+        let _lock = self.lock();
+        let mode_ex = if_error!(check_mut_ref(mode));
+
+        let mut mode = unsafe { mem::zeroed() };
+        if_not_success!(self.get_display_mode(sc, &mut mode));
+
+        mode_ex.Width = mode.Width;
+        mode_ex.Height = mode.Height;
+        mode_ex.RefreshRate = mode.RefreshRate;
+        mode_ex.Format = mode.Format;
+        mode_ex.ScanLineOrdering = D3DSCANLINEORDERING_PROGRESSIVE;
+
+        if let Ok(rotation) = check_mut_ref(rotation) {
+            *rotation = D3DDISPLAYROTATION_IDENTITY;
+        }
+
+        Error::Success
     }
 }
@@ -0,0 +1,97 @@
+//! Tracking of the state which has to be bound again before the next draw.
+//!
+//! Games tend to set the same state over and over again, so instead of calling
+//! into D3D11 every time something changes, the state setters only mark what changed,
+//! and the device binds it all at once when drawing.
+
+use bitflags::*;
+
+use super::state::MAX_STREAMS;
+
+bitflags! {
+    /// Parts of the pipeline state which changed since they were last bound.
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(transparent)]
+    pub struct DirtyFlags: u32 {
+        /// The blend state, blend factor or sample mask.
+        const BLEND = 1;
+        /// The rasterizer state.
+        const RASTERIZER = 1 << 1;
+        /// The vertex shader.
+        const VERTEX_SHADER = 1 << 2;
+        /// The index buffer.
+        const INDICES = 1 << 3;
+        /// The textures bound to the samplers.
+        const TEXTURES = 1 << 4;
+        /// The input layout, which depends on both the vertex declaration and the vertex shader.
+        const INPUT_LAYOUT = 1 << 5;
+        /// The viewport.
+        const VIEWPORT = 1 << 6;
+        /// The pixel shader.
+        const PIXEL_SHADER = 1 << 7;
+        /// The fixed function state which the generated shaders read as constants.
+        const FIXED_FUNCTION = 1 << 8;
+        /// The geometry shader which expands points.
+        const GEOMETRY_SHADER = 1 << 9;
+    }
+}
+
+/// Counters which measure how many state changes were batched together.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FlushStats {
+    /// Number of state changes made by the app.
+    pub changes: u64,
+    /// Number of times the changed state was bound.
+    pub flushes: u64,
+    /// Number of states which were actually bound.
+    pub binds: u64,
+}
+
+/// Keeps track of the state which changed since the last draw.
+pub struct DirtyState {
+    flags: DirtyFlags,
+    // Vertex streams whose buffers changed, one bit per stream.
+    streams: u32,
+    stats: FlushStats,
+}
+
+impl DirtyState {
+    /// Creates a new tracker, with everything marked as changed.
+    pub fn new() -> Self {
+        Self {
+            flags: DirtyFlags::all(),
+            streams: (1 << MAX_STREAMS) - 1,
+            stats: FlushStats::default(),
+        }
+    }
+
+    /// Marks some state as changed.
+    pub fn mark(&mut self, flags: DirtyFlags) {
+        self.flags |= flags;
+        self.stats.changes += 1;
+    }
+
+    /// Marks the vertex buffer of an input stream as changed.
+    pub fn mark_stream(&mut self, stream: u32) {
+        self.streams |= 1 << stream;
+        self.stats.changes += 1;
+    }
+
+    /// Retrieves the changed state and vertex streams, and marks them as bound.
+    pub fn take(&mut self) -> (DirtyFlags, u32) {
+        let flags = std::mem::replace(&mut self.flags, DirtyFlags::empty());
+        let streams = std::mem::replace(&mut self.streams, 0);
+
+        if !flags.is_empty() || streams != 0 {
+            self.stats.flushes += 1;
+            self.stats.binds += u64::from(flags.bits().count_ones() + streams.count_ones());
+        }
+
+        (flags, streams)
+    }
+
+    /// Retrieves the counters of this tracker.
+    pub fn stats(&self) -> FlushStats {
+        self.stats
+    }
+}
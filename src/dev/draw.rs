@@ -0,0 +1,46 @@
+//! Helpers for issuing draw calls.
+
+use winapi::shared::d3d9types::*;
+use winapi::um::d3d11::D3D11_PRIMITIVE_TOPOLOGY;
+use winapi::um::d3dcommon::*;
+
+/// Converts a D3D9 primitive type to the D3D11 topology used to draw it,
+/// and computes the number of vertices making up a certain number of primitives.
+///
+/// Triangle fans are not supported by D3D11, and have to be converted to lists
+/// with `triangulate_fan` first.
+///
+/// Returns `None` if the primitive type is invalid, or the count is too large.
+pub fn primitive_topology(
+    ty: D3DPRIMITIVETYPE,
+    count: u32,
+) -> Option<(D3D11_PRIMITIVE_TOPOLOGY, u32)> {
+    let (topology, vertices) = match ty {
+        D3DPT_POINTLIST => (D3D11_PRIMITIVE_TOPOLOGY_POINTLIST, Some(count)),
+        D3DPT_LINELIST => (D3D11_PRIMITIVE_TOPOLOGY_LINELIST, count.checked_mul(2)),
+        D3DPT_LINESTRIP => (D3D11_PRIMITIVE_TOPOLOGY_LINESTRIP, count.checked_add(1)),
+        D3DPT_TRIANGLELIST => (D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST, count.checked_mul(3)),
+        D3DPT_TRIANGLESTRIP => (D3D11_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP, count.checked_add(2)),
+        D3DPT_TRIANGLEFAN => (D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST, count.checked_add(2)),
+        _ => return None,
+    };
+
+    Some((topology, vertices?))
+}
+
+/// Converts the vertices or indices of a triangle fan to a triangle list.
+///
+/// `elems` contains `count + 2` elements of `elem_size` bytes each.
+pub fn triangulate_fan(elems: &[u8], elem_size: usize, count: u32) -> Vec<u8> {
+    let elem = |index: usize| &elems[index * elem_size..(index + 1) * elem_size];
+
+    let mut list = Vec::with_capacity(count as usize * 3 * elem_size);
+
+    for i in 0..count as usize {
+        list.extend_from_slice(elem(0));
+        list.extend_from_slice(elem(i + 1));
+        list.extend_from_slice(elem(i + 2));
+    }
+
+    list
+}
@@ -0,0 +1,268 @@
+//! Fixed function state which the generated shaders read from a constant buffer.
+//!
+//! Only the state which would otherwise require a new shader every time it changes,
+//! such as the transforms, is uploaded as constants.
+
+use std::{mem, slice};
+
+use winapi::shared::d3d9types::*;
+use winapi::um::d3d11::D3D11_COMMONSHADER_CONSTANT_BUFFER_API_SLOT_COUNT;
+
+use nalgebra::{self as na, Matrix4, Vector4};
+
+use crate::dev::state::{DeviceState, MAX_ACTIVE_LIGHTS, MAX_CLIP_PLANES};
+
+/// Constant buffer slot of the fixed function state.
+///
+/// This is the last slot, in order to not conflict with the buffers used by the app's shaders.
+pub const CONSTANTS_SLOT: u32 = D3D11_COMMONSHADER_CONSTANT_BUFFER_API_SLOT_COUNT - 1;
+
+/// Largest size of a point, in pixels.
+///
+/// Must match the `MaxPointSize` reported in the device's caps.
+pub const MAX_POINT_SIZE: f32 = 2048.0;
+
+/// Converts a packed D3D9 color to a float vector.
+pub fn d3dcolor_to_vec(c: D3DCOLOR) -> [f32; 4] {
+    let channel = |shift: u32| ((c >> shift) & 0xff) as f32 / 255.0;
+    [channel(16), channel(8), channel(0), channel(24)]
+}
+
+fn color_value_to_vec(c: D3DCOLORVALUE) -> [f32; 4] {
+    [c.r, c.g, c.b, c.a]
+}
+
+/// A single light, as seen by the vertex shader.
+///
+/// The position and direction are in view space, where the lighting is computed.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct LightConstants {
+    pub diffuse: [f32; 4],
+    pub specular: [f32; 4],
+    pub ambient: [f32; 4],
+    // Ignored for directional lights.
+    pub position: [f32; 4],
+    // Ignored for point lights.
+    pub direction: [f32; 4],
+    // Range, falloff, and the cosines of half the inner and outer cone angles.
+    pub spot: [f32; 4],
+    // Constant, linear and quadratic attenuation, followed by the light type.
+    pub attenuation: [f32; 4],
+}
+
+impl LightConstants {
+    fn new(light: &D3DLIGHT9, view: &Matrix4<f32>) -> Self {
+        let position =
+            view * Vector4::new(light.Position.x, light.Position.y, light.Position.z, 1.0);
+        let direction =
+            view * Vector4::new(light.Direction.x, light.Direction.y, light.Direction.z, 0.0);
+        let direction = direction.try_normalize(0.0).unwrap_or_else(na::zero);
+
+        Self {
+            diffuse: color_value_to_vec(light.Diffuse),
+            specular: color_value_to_vec(light.Specular),
+            ambient: color_value_to_vec(light.Ambient),
+            position: position.into(),
+            direction: direction.into(),
+            spot: [
+                light.Range,
+                light.Falloff,
+                (light.Theta / 2.0).cos(),
+                (light.Phi / 2.0).cos(),
+            ],
+            attenuation: [
+                light.Attenuation0,
+                light.Attenuation1,
+                light.Attenuation2,
+                light.Type as f32,
+            ],
+        }
+    }
+}
+
+/// The fixed function state, as seen by the generated shaders.
+///
+/// Its layout must match the declaration returned by `constants_hlsl`.
+/// Matrices are stored column by column, like `nalgebra` does.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FixedFunctionConstants {
+    pub world: [[f32; 4]; 4],
+    pub view: [[f32; 4]; 4],
+    pub projection: [[f32; 4]; 4],
+    // Transforms normals to view space.
+    pub normal_matrix: [[f32; 4]; 4],
+    // Converts pre-transformed positions from screen space back to normalized device coordinates.
+    pub viewport_scale: [f32; 4],
+    pub viewport_offset: [f32; 4],
+    pub texture_factor: [f32; 4],
+    pub fog_color: [f32; 4],
+    // The fog's start, end and density.
+    pub fog_params: [f32; 4],
+    // The reference value of the alpha test, in the range [0, 255], padded to a full register.
+    pub alpha_ref: [f32; 4],
+    // The point size, its minimum and maximum, followed by the viewport's height.
+    pub point_params: [f32; 4],
+    // The A, B and C factors which scale points with their distance from the camera.
+    pub point_scale: [f32; 4],
+    // The user clip planes, in world space.
+    pub clip_planes: [[f32; 4]; MAX_CLIP_PLANES],
+    // Bit mask of the enabled clip planes, padded to a full register.
+    pub clip_plane_mask: [u32; 4],
+    pub material_diffuse: [f32; 4],
+    pub material_ambient: [f32; 4],
+    pub material_specular: [f32; 4],
+    pub material_emissive: [f32; 4],
+    // The specular power, padded to a full register.
+    pub material_power: [f32; 4],
+    pub global_ambient: [f32; 4],
+    // Only the first lights are used, depending on how many are enabled.
+    pub lights: [LightConstants; MAX_ACTIVE_LIGHTS],
+}
+
+impl FixedFunctionConstants {
+    /// Gathers the constants from the device's state.
+    ///
+    /// D3D9 puts pixel centers on integer coordinates, while D3D11 puts them halfway between.
+    /// Unless `half_pixel_offset` is false, pre-transformed vertices are shifted to match.
+    pub fn new(state: &DeviceState, half_pixel_offset: bool) -> Self {
+        let vp = state.get_viewport();
+        let half_pixel = if half_pixel_offset { 0.5 } else { 0.0 };
+
+        let width = vp.Width.max(1) as f32;
+        let height = vp.Height.max(1) as f32;
+
+        let depth_range = vp.MaxZ - vp.MinZ;
+        let depth_scale = if depth_range != 0.0 {
+            1.0 / depth_range
+        } else {
+            1.0
+        };
+
+        let world = state.get_transform(D3DTS_WORLD);
+        let view = state.get_transform(D3DTS_VIEW);
+
+        // Normals are transformed by the inverse transpose, to stay perpendicular to the surface.
+        let normal_matrix = (view * world)
+            .try_inverse()
+            .unwrap_or_else(na::one)
+            .transpose();
+
+        let material = state.get_material();
+
+        // Floating point states are stored as their bit patterns.
+        let float_state = |ty| f32::from_bits(state.get_render_state(ty));
+
+        let mut constants = Self {
+            world: world.into(),
+            view: view.into(),
+            projection: state.get_transform(D3DTS_PROJECTION).into(),
+            normal_matrix: normal_matrix.into(),
+            viewport_scale: [2.0 / width, -2.0 / height, depth_scale, 0.0],
+            viewport_offset: [
+                (half_pixel - vp.X as f32) * 2.0 / width - 1.0,
+                1.0 - (half_pixel - vp.Y as f32) * 2.0 / height,
+                -vp.MinZ * depth_scale,
+                0.0,
+            ],
+            texture_factor: d3dcolor_to_vec(state.get_render_state(D3DRS_TEXTUREFACTOR)),
+            fog_color: d3dcolor_to_vec(state.get_render_state(D3DRS_FOGCOLOR)),
+            fog_params: [
+                float_state(D3DRS_FOGSTART),
+                float_state(D3DRS_FOGEND),
+                float_state(D3DRS_FOGDENSITY),
+                0.0,
+            ],
+            alpha_ref: [
+                (state.get_render_state(D3DRS_ALPHAREF) & 0xff) as f32,
+                0.0,
+                0.0,
+                0.0,
+            ],
+            point_params: [
+                float_state(D3DRS_POINTSIZE),
+                float_state(D3DRS_POINTSIZE_MIN),
+                float_state(D3DRS_POINTSIZE_MAX).min(MAX_POINT_SIZE),
+                height,
+            ],
+            point_scale: [
+                float_state(D3DRS_POINTSCALE_A),
+                float_state(D3DRS_POINTSCALE_B),
+                float_state(D3DRS_POINTSCALE_C),
+                0.0,
+            ],
+            material_diffuse: color_value_to_vec(material.Diffuse),
+            material_ambient: color_value_to_vec(material.Ambient),
+            material_specular: color_value_to_vec(material.Specular),
+            material_emissive: color_value_to_vec(material.Emissive),
+            material_power: [material.Power, 0.0, 0.0, 0.0],
+            global_ambient: d3dcolor_to_vec(state.get_render_state(D3DRS_AMBIENT)),
+            ..Self::default()
+        };
+
+        for (c, light) in constants.lights.iter_mut().zip(state.active_lights()) {
+            *c = LightConstants::new(light, &view);
+        }
+
+        for (i, plane) in constants.clip_planes.iter_mut().enumerate() {
+            *plane = state.get_clip_plane(i as u32).unwrap_or_default();
+        }
+
+        // Turning off clipping also turns off the user clip planes.
+        if state.get_render_state(D3DRS_CLIPPING) != 0 {
+            constants.clip_plane_mask[0] =
+                state.get_render_state(D3DRS_CLIPPLANEENABLE) & ((1 << MAX_CLIP_PLANES) - 1);
+        }
+
+        constants
+    }
+
+    /// Returns the bytes which are uploaded to the constant buffer.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>()) }
+    }
+}
+
+/// Returns the HLSL declaration of the constant buffer.
+pub fn constants_hlsl() -> String {
+    format!(
+        r"struct Light {{
+    float4 diffuse;
+    float4 specular;
+    float4 ambient;
+    float4 position;
+    float4 direction;
+    float4 spot;
+    float4 attenuation;
+}};
+
+cbuffer FixedFunction : register(b{slot}) {{
+    float4x4 world;
+    float4x4 view;
+    float4x4 projection;
+    float4x4 normal_matrix;
+    float4 viewport_scale;
+    float4 viewport_offset;
+    float4 texture_factor;
+    float4 fog_color;
+    float4 fog_params;
+    float alpha_ref;
+    float4 point_params;
+    float4 point_scale;
+    float4 clip_planes[{clip_planes}];
+    uint clip_plane_mask;
+    float4 material_diffuse;
+    float4 material_ambient;
+    float4 material_specular;
+    float4 material_emissive;
+    float material_power;
+    float4 global_ambient;
+    Light lights[{lights}];
+}};
+",
+        slot = CONSTANTS_SLOT,
+        clip_planes = MAX_CLIP_PLANES,
+        lights = MAX_ACTIVE_LIGHTS,
+    )
+}
@@ -0,0 +1,78 @@
+//! Generation of the geometry shaders which expand points into quads.
+//!
+//! D3D11 always rasterizes points as single pixels, while D3D9 can draw them
+//! as squares of any size, optionally covered by a whole texture (point sprites).
+
+use std::fmt::Write;
+
+use winapi::shared::d3d9types::*;
+
+use crate::dev::state::DeviceState;
+
+use super::{common_hlsl, VertexShaderKey, MAX_TEX_COORDS};
+
+/// State which is baked into the generated geometry shader.
+///
+/// Whenever this changes, a different shader has to be used.
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct GeometryShaderKey {
+    // Replace the texture coordinates with ones covering the whole quad.
+    pub sprite: bool,
+}
+
+impl GeometryShaderKey {
+    /// Determines if points drawn with a generated vertex shader have to be expanded.
+    ///
+    /// Returns `None` if the points are single pixels, which D3D11 can draw by itself.
+    pub fn new(state: &DeviceState, vs_key: &VertexShaderKey) -> Option<Self> {
+        let sprite = state.get_render_state(D3DRS_POINTSPRITEENABLE) != 0;
+        let point_size = f32::from_bits(state.get_render_state(D3DRS_POINTSIZE));
+
+        if sprite || vs_key.point_size || vs_key.point_scale || point_size != 1.0 {
+            Some(Self { sprite })
+        } else {
+            None
+        }
+    }
+
+    /// Generates the HLSL source of the shader.
+    pub fn generate(&self) -> String {
+        let mut src = common_hlsl();
+
+        src += r"[maxvertexcount(4)]
+void main(point VertexOutput input[1], inout TriangleStream<VertexOutput> stream) {
+    // The corners of the quad, in triangle strip order.
+    float2 corners[4] = {
+        float2(-1.0, 1.0), float2(1.0, 1.0), float2(-1.0, -1.0), float2(1.0, -1.0)
+    };
+
+    // Half of the point's size, in clip space.
+    float2 extent = input[0].point_size * abs(viewport_scale.xy) * 0.5 * input[0].position.w;
+
+    VertexOutput output = input[0];
+
+    for (uint i = 0; i < 4; ++i) {
+        output.position.xy = input[0].position.xy + corners[i] * extent;
+";
+
+        // Sprites are covered by the whole texture, with (0, 0) in the top left corner.
+        if self.sprite {
+            writeln!(
+                src,
+                r"        float2 uv = float2(corners[i].x + 1.0, 1.0 - corners[i].y) * 0.5;
+        for (uint j = 0; j < {}; ++j) {{
+            output.tex[j] = float4(uv, 0.0, 1.0);
+        }}",
+                MAX_TEX_COORDS
+            )
+            .unwrap();
+        }
+
+        src += r"        stream.Append(output);
+    }
+}
+";
+
+        src
+    }
+}
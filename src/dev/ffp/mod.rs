@@ -0,0 +1,329 @@
+//! Emulation of D3D9's fixed function pipeline.
+//!
+//! D3D11 only has programmable shaders, so we generate HLSL shaders implementing
+//! the fixed function state the app set, and compile them at runtime.
+//! Every shader is cached, keyed by the state which was baked into it.
+
+use std::ptr;
+use std::sync::Arc;
+
+use winapi::shared::d3d9types::*;
+use winapi::um::{d3d11::*, wingdi::PALETTEENTRY};
+
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::d3d11;
+use crate::Error;
+
+use super::state::DeviceState;
+use super::{DirtyFlags, LruCache};
+
+mod constants;
+pub use self::constants::*;
+
+mod vertex;
+pub use self::vertex::{MaterialSource, VertexShaderKey};
+
+mod pixel;
+pub use self::pixel::{PixelShaderKey, StageKey, TextureKind};
+
+mod geometry;
+pub use self::geometry::GeometryShaderKey;
+
+/// Number of texture coordinate sets a vertex can have.
+pub const MAX_TEX_COORDS: usize = 8;
+
+/// Shader resource slot of the current texture palette.
+pub const PALETTE_SLOT: u32 = D3D11_COMMONSHADER_INPUT_RESOURCE_SLOT_COUNT - 1;
+
+/// Number of entries in a texture palette.
+pub const PALETTE_SIZE: usize = 256;
+
+/// Environment variable which overrides the capacity of each shader cache.
+const CAPACITY_VAR: &str = "D3D9_SHADER_CACHE_SIZE";
+
+/// Number of generated shaders of each kind kept by default.
+const DEFAULT_CAPACITY: usize = 256;
+
+/// Returns the HLSL declarations shared by every generated shader.
+///
+/// All vertex shaders have the same output, so that any of them can be used with any pixel shader.
+fn common_hlsl() -> String {
+    constants_hlsl()
+        + r"
+struct VertexOutput {
+    float4 position : SV_Position;
+    float4 diffuse : COLOR0;
+    float4 specular : COLOR1;
+    float4 tex[8] : TEXCOORD0;
+    float4 clip0 : SV_ClipDistance0;
+    float4 clip1 : SV_ClipDistance1;
+    // The per-vertex fog factor, followed by the depth used for per-pixel fog.
+    float2 fog : FOG0;
+    float point_size : PSIZE0;
+};
+
+"
+}
+
+/// Returns the fog modes which are currently in use.
+///
+/// The first one is the per-vertex fog mode, the second is the per-pixel (table) one.
+/// Table fog takes precedence if both are set.
+fn fog_modes(state: &DeviceState) -> (D3DFOGMODE, D3DFOGMODE) {
+    if state.get_render_state(D3DRS_FOGENABLE) == 0 {
+        return (D3DFOG_NONE, D3DFOG_NONE);
+    }
+
+    match state.get_render_state(D3DRS_FOGTABLEMODE) {
+        D3DFOG_NONE => (state.get_render_state(D3DRS_FOGVERTEXMODE), D3DFOG_NONE),
+        table_mode => (D3DFOG_NONE, table_mode),
+    }
+}
+
+/// Returns the HLSL expression of the fog factor at a certain distance from the camera.
+///
+/// A factor of 1 means no fog, and 0 means the fog color is used.
+fn fog_factor_hlsl(mode: D3DFOGMODE, distance: &str) -> String {
+    match mode {
+        D3DFOG_EXP => format!("exp(-{} * fog_params.z)", distance),
+        D3DFOG_EXP2 => format!(
+            "exp(-({0} * fog_params.z) * ({0} * fog_params.z))",
+            distance
+        ),
+        D3DFOG_LINEAR => format!(
+            "(fog_params.y - {}) / (fog_params.y - fog_params.x)",
+            distance
+        ),
+        _ => "1.0".to_owned(),
+    }
+}
+
+/// A vertex shader generated for the fixed function state.
+#[derive(Clone)]
+pub struct GeneratedVertexShader {
+    pub shader: ComPtr<ID3D11VertexShader>,
+    // Needed to create input layouts for the shader.
+    pub code: Arc<[u32]>,
+}
+
+/// Compiles a vertex shader.
+fn create_vertex_shader(
+    device: &ID3D11Device,
+    key: &VertexShaderKey,
+) -> Result<GeneratedVertexShader, Error> {
+    let code = d3d11::compile_shader(&key.generate(), "vs_4_0")?;
+
+    let shader = unsafe {
+        let mut ptr = ptr::null_mut();
+        let result = device.CreateVertexShader(
+            code.as_ptr() as *const _,
+            code.len() * 4,
+            ptr::null_mut(),
+            &mut ptr,
+        );
+        if_not_success_err!(check_hresult(result, "Failed to create vertex shader"));
+        ComPtr::new(ptr)
+    };
+
+    Ok(GeneratedVertexShader { shader, code })
+}
+
+/// Compiles a geometry shader.
+fn create_geometry_shader(
+    device: &ID3D11Device,
+    key: &GeometryShaderKey,
+) -> Result<ComPtr<ID3D11GeometryShader>, Error> {
+    let code = d3d11::compile_shader(&key.generate(), "gs_4_0")?;
+
+    let shader = unsafe {
+        let mut ptr = ptr::null_mut();
+        let result = device.CreateGeometryShader(
+            code.as_ptr() as *const _,
+            code.len() * 4,
+            ptr::null_mut(),
+            &mut ptr,
+        );
+        if_not_success_err!(check_hresult(result, "Failed to create geometry shader"));
+        ComPtr::new(ptr)
+    };
+
+    Ok(shader)
+}
+
+/// Compiles a pixel shader.
+fn create_pixel_shader(
+    device: &ID3D11Device,
+    key: &PixelShaderKey,
+) -> Result<ComPtr<ID3D11PixelShader>, Error> {
+    let code = d3d11::compile_shader(&key.generate(), "ps_4_0")?;
+
+    let shader = unsafe {
+        let mut ptr = ptr::null_mut();
+        let result = device.CreatePixelShader(
+            code.as_ptr() as *const _,
+            code.len() * 4,
+            ptr::null_mut(),
+            &mut ptr,
+        );
+        if_not_success_err!(check_hresult(result, "Failed to create pixel shader"));
+        ComPtr::new(ptr)
+    };
+
+    Ok(shader)
+}
+
+/// The shaders generated for the fixed function state, and the constants they read.
+pub struct FixedFunction {
+    // Ring buffer the constants of every draw are uploaded to.
+    constants: d3d11::DynamicBuffer,
+    vertex_shaders: LruCache<VertexShaderKey, GeneratedVertexShader>,
+    geometry_shaders: LruCache<GeometryShaderKey, ComPtr<ID3D11GeometryShader>>,
+    pixel_shaders: LruCache<PixelShaderKey, ComPtr<ID3D11PixelShader>>,
+    // The state the next draw's shaders are generated for.
+    // There is no vertex shader key if the app uses its own vertex shader.
+    vs_key: Option<VertexShaderKey>,
+    // Only points which aren't single pixels need a geometry shader.
+    gs_key: Option<GeometryShaderKey>,
+    ps_key: Option<PixelShaderKey>,
+    // Paletted textures are expanded in the pixel shader, using this 256 x 1 texture.
+    palette: d3d11::Texture2D,
+    palette_view: ComPtr<ID3D11ShaderResourceView>,
+    // Whether pre-transformed vertices are corrected for D3D9's pixel centers.
+    half_pixel_offset: bool,
+}
+
+impl FixedFunction {
+    /// Creates the buffer the constants are uploaded to, and the palette texture.
+    ///
+    /// The capacity of the shader caches can be set with the `D3D9_SHADER_CACHE_SIZE` environment variable,
+    /// and the half pixel offset of pre-transformed vertices disabled with `D3D9_NO_HALF_PIXEL_OFFSET`.
+    pub fn new(device: &d3d11::Device) -> Result<Self, Error> {
+        // This format has the same memory layout as a palette entry.
+        let palette = d3d11::Texture2D::new(
+            device,
+            (PALETTE_SIZE as u32, 1),
+            1,
+            UsageFlags::empty(),
+            D3DFMT_A8B8G8R8,
+            MemoryPool::Default,
+        )?;
+
+        let palette_view = palette
+            .create_shader_view(device)?
+            .ok_or(Error::DriverInternalError)?;
+
+        Ok(Self {
+            constants: d3d11::DynamicBuffer::new_constants(device)?,
+            vertex_shaders: LruCache::from_env(CAPACITY_VAR, DEFAULT_CAPACITY),
+            geometry_shaders: LruCache::from_env(CAPACITY_VAR, DEFAULT_CAPACITY),
+            pixel_shaders: LruCache::from_env(CAPACITY_VAR, DEFAULT_CAPACITY),
+            vs_key: None,
+            gs_key: None,
+            ps_key: None,
+            palette,
+            palette_view,
+            half_pixel_offset: !config::flag("D3D9_NO_HALF_PIXEL_OFFSET"),
+        })
+    }
+
+    /// Retrieves the view of the palette texture.
+    pub fn palette_view(&self) -> &ComPtr<ID3D11ShaderResourceView> {
+        &self.palette_view
+    }
+
+    /// Uploads the palette used to expand paletted textures.
+    pub fn update_palette(&self, ctx: &ID3D11DeviceContext, entries: &[PALETTEENTRY]) {
+        unsafe {
+            ctx.UpdateSubresource(
+                self.palette.as_resource(),
+                0,
+                ptr::null(),
+                entries.as_ptr() as *const _,
+                (entries.len() * 4) as u32,
+                0,
+            );
+        }
+    }
+
+    /// Records the state the next draw needs shaders for.
+    ///
+    /// Returns the parts of the pipeline which have to be bound again,
+    /// because they use a different shader than the previous draw.
+    pub fn update_keys(
+        &mut self,
+        vs_key: Option<VertexShaderKey>,
+        gs_key: Option<GeometryShaderKey>,
+        ps_key: PixelShaderKey,
+    ) -> DirtyFlags {
+        let mut dirty = DirtyFlags::empty();
+
+        if vs_key != self.vs_key {
+            self.vs_key = vs_key;
+            dirty |= DirtyFlags::VERTEX_SHADER | DirtyFlags::INPUT_LAYOUT;
+        }
+
+        if gs_key != self.gs_key {
+            self.gs_key = gs_key;
+            dirty |= DirtyFlags::GEOMETRY_SHADER;
+        }
+
+        if Some(ps_key) != self.ps_key {
+            self.ps_key = Some(ps_key);
+            dirty |= DirtyFlags::PIXEL_SHADER;
+        }
+
+        dirty
+    }
+
+    /// Retrieves the vertex shader for the next draw, if it uses a generated one.
+    pub fn vertex_shader(
+        &mut self,
+        device: &ID3D11Device,
+    ) -> Result<Option<GeneratedVertexShader>, Error> {
+        match self.vs_key {
+            Some(key) => self
+                .vertex_shaders
+                .get_or_insert_with(key, || create_vertex_shader(device, &key))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieves the geometry shader for the next draw, if it needs one.
+    pub fn geometry_shader(
+        &mut self,
+        device: &ID3D11Device,
+    ) -> Result<Option<ComPtr<ID3D11GeometryShader>>, Error> {
+        match self.gs_key {
+            Some(key) => self
+                .geometry_shaders
+                .get_or_insert_with(key, || create_geometry_shader(device, &key))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieves the pixel shader for the next draw.
+    pub fn pixel_shader(
+        &mut self,
+        device: &ID3D11Device,
+    ) -> Result<ComPtr<ID3D11PixelShader>, Error> {
+        let key = self.ps_key.ok_or(Error::InvalidCall)?;
+
+        self.pixel_shaders
+            .get_or_insert_with(key, || create_pixel_shader(device, &key))
+    }
+
+    /// Uploads the constants read by the generated shaders.
+    pub fn upload_constants(
+        &self,
+        ctx: &d3d11::DeviceContext,
+        state: &DeviceState,
+    ) -> Result<d3d11::ConstantRange, Error> {
+        let constants = FixedFunctionConstants::new(state, self.half_pixel_offset);
+
+        self.constants.upload_constants(ctx, constants.as_bytes())
+    }
+}
@@ -0,0 +1,338 @@
+//! Generation of the pixel shaders which implement the texture stages.
+
+use std::fmt::Write;
+
+use winapi::shared::d3d9types::*;
+
+use crate::core::fmt::{Swizzle, IDENTITY_SWIZZLE, SWIZZLE_ONE, SWIZZLE_PALETTE};
+use crate::core::ResourceType;
+use crate::dev::state::{DeviceState, MAX_TEXTURE_STAGES};
+use crate::dev::BaseTexture;
+
+use super::{common_hlsl, fog_factor_hlsl, fog_modes, MAX_TEX_COORDS, PALETTE_SLOT};
+
+/// The kind of texture bound to a texture stage.
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum TextureKind {
+    #[default]
+    None,
+    Texture2D,
+    Cube,
+    Volume,
+}
+
+impl TextureKind {
+    /// Returns the HLSL type of the texture, and the components of its coordinates.
+    fn hlsl(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            TextureKind::None => None,
+            TextureKind::Texture2D => Some(("Texture2D", "xy")),
+            TextureKind::Cube => Some(("TextureCube", "xyz")),
+            TextureKind::Volume => Some(("Texture3D", "xyz")),
+        }
+    }
+}
+
+/// The state of a texture stage which is baked into the generated pixel shader.
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct StageKey {
+    pub color_op: D3DTEXTUREOP,
+    // The arguments, in the order arg0, arg1, arg2.
+    pub color_args: [u32; 3],
+    pub alpha_op: D3DTEXTUREOP,
+    pub alpha_args: [u32; 3],
+    // Write the result to the temporary register instead of the current one.
+    pub to_temp: bool,
+    pub texture: TextureKind,
+    // Reorders the channels of the texture, for formats DXGI doesn't have.
+    pub swizzle: Swizzle,
+    pub tex_coord_index: u8,
+}
+
+impl StageKey {
+    fn new(state: &DeviceState, stage: u32) -> Self {
+        let ts = |ty| state.get_texture_stage_state(stage, ty);
+
+        let texture = unsafe { BaseTexture::from_com(state.get_texture(stage)) };
+        let swizzle = texture.map_or(IDENTITY_SWIZZLE, BaseTexture::swizzle);
+        let texture = match texture.map(|texture| texture.resource_type()) {
+            Some(ResourceType::Texture) => TextureKind::Texture2D,
+            Some(ResourceType::CubeTexture) => TextureKind::Cube,
+            Some(ResourceType::VolumeTexture) => TextureKind::Volume,
+            _ => TextureKind::None,
+        };
+
+        let tex_coord_index = ts(D3DTSS_TEXCOORDINDEX);
+        if tex_coord_index & !0xffff != 0 {
+            run_once!(|| warn!("Texture coordinate generation is not supported yet"));
+        }
+
+        Self {
+            color_op: ts(D3DTSS_COLOROP),
+            color_args: [
+                ts(D3DTSS_COLORARG0),
+                ts(D3DTSS_COLORARG1),
+                ts(D3DTSS_COLORARG2),
+            ],
+            alpha_op: ts(D3DTSS_ALPHAOP),
+            alpha_args: [
+                ts(D3DTSS_ALPHAARG0),
+                ts(D3DTSS_ALPHAARG1),
+                ts(D3DTSS_ALPHAARG2),
+            ],
+            to_temp: ts(D3DTSS_RESULTARG) & D3DTA_SELECTMASK == D3DTA_TEMP,
+            texture,
+            swizzle,
+            tex_coord_index: (tex_coord_index as usize % MAX_TEX_COORDS) as u8,
+        }
+    }
+
+    /// Checks if the stage samples a paletted texture.
+    fn is_paletted(&self) -> bool {
+        self.swizzle.contains(&SWIZZLE_PALETTE)
+    }
+}
+
+/// State which is baked into the generated pixel shader.
+///
+/// Whenever this changes, a different shader has to be used.
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct PixelShaderKey {
+    // Only the stages before the first disabled one are used.
+    pub stage_count: usize,
+    pub stages: [StageKey; MAX_TEXTURE_STAGES],
+    // Add the specular color to the result.
+    pub specular: bool,
+    pub fog: bool,
+    // Compute the fog factor per pixel, instead of using the per-vertex one.
+    pub fog_table_mode: D3DFOGMODE,
+    // Pixels failing this comparison against the reference alpha are discarded.
+    pub alpha_func: D3DCMPFUNC,
+}
+
+impl PixelShaderKey {
+    /// Determines the shader to use for the current texture stages.
+    pub fn new(state: &DeviceState) -> Self {
+        let (_, fog_table_mode) = fog_modes(state);
+
+        let alpha_func = if state.get_render_state(D3DRS_ALPHATESTENABLE) != 0 {
+            state.get_render_state(D3DRS_ALPHAFUNC)
+        } else {
+            D3DCMP_ALWAYS
+        };
+
+        let mut key = Self {
+            specular: state.get_render_state(D3DRS_SPECULARENABLE) != 0,
+            fog: state.get_render_state(D3DRS_FOGENABLE) != 0,
+            fog_table_mode,
+            alpha_func,
+            ..Self::default()
+        };
+
+        for stage in 0..MAX_TEXTURE_STAGES {
+            if state.get_texture_stage_state(stage as u32, D3DTSS_COLOROP) == D3DTOP_DISABLE {
+                break;
+            }
+
+            key.stages[stage] = StageKey::new(state, stage as u32);
+            key.stage_count += 1;
+        }
+
+        key
+    }
+
+    /// Generates the HLSL source of the shader.
+    pub fn generate(&self) -> String {
+        let mut src = common_hlsl();
+
+        let stages = &self.stages[..self.stage_count];
+
+        // Paletted textures are expanded using the current palette.
+        if stages.iter().any(StageKey::is_paletted) {
+            writeln!(src, "Texture2D palette : register(t{});", PALETTE_SLOT).unwrap();
+        }
+
+        for (i, stage) in stages.iter().enumerate() {
+            if let Some((ty, _)) = stage.texture.hlsl() {
+                writeln!(src, "{} stage{1}_texture : register(t{1});", ty, i).unwrap();
+                writeln!(src, "SamplerState stage{0}_sampler : register(s{0});", i).unwrap();
+            }
+        }
+
+        src += r"
+float4 main(VertexOutput input) : SV_Target {
+    float4 diffuse = input.diffuse;
+    float4 specular = input.specular;
+    float4 current = diffuse;
+    float4 temp = float4(0.0, 0.0, 0.0, 0.0);
+    float4 tex;
+    float4 entry;
+    float4 color;
+    float4 alpha;
+";
+
+        for (i, stage) in stages.iter().enumerate() {
+            writeln!(src, "\n    // Stage {}", i).unwrap();
+
+            // Stages without a texture read opaque white instead.
+            match stage.texture.hlsl() {
+                Some((_, coords)) => writeln!(
+                    src,
+                    "    tex = stage{0}_texture.Sample(stage{0}_sampler, input.tex[{1}].{2});",
+                    i, stage.tex_coord_index, coords
+                )
+                .unwrap(),
+                None => src += "    tex = float4(1.0, 1.0, 1.0, 1.0);\n",
+            }
+
+            if stage.texture != TextureKind::None && stage.swizzle != IDENTITY_SWIZZLE {
+                if stage.is_paletted() {
+                    src += "    entry = palette.Load(int3(tex.r * 255.0 + 0.5, 0, 0));\n";
+                }
+                writeln!(src, "    tex = {};", swizzle_hlsl(stage.swizzle)).unwrap();
+            }
+
+            writeln!(
+                src,
+                "    color = saturate({});",
+                op_hlsl(stage.color_op, stage.color_args)
+            )
+            .unwrap();
+
+            // With alpha blending disabled, the alpha of the previous stage is kept.
+            let alpha = match stage.alpha_op {
+                D3DTOP_DISABLE => "current".to_owned(),
+                op => op_hlsl(op, stage.alpha_args),
+            };
+            writeln!(src, "    alpha = saturate({});", alpha).unwrap();
+
+            let dest = if stage.to_temp { "temp" } else { "current" };
+            writeln!(src, "    {} = float4(color.rgb, alpha.a);", dest).unwrap();
+        }
+
+        if self.specular {
+            src += "\n    current.rgb = saturate(current.rgb + specular.rgb);\n";
+        }
+
+        if self.fog {
+            let factor = match self.fog_table_mode {
+                D3DFOG_NONE => "input.fog.x".to_owned(),
+                mode => fog_factor_hlsl(mode, "abs(input.fog.y)"),
+            };
+            writeln!(
+                src,
+                "    current.rgb = lerp(fog_color.rgb, current.rgb, saturate({}));",
+                factor
+            )
+            .unwrap();
+        }
+
+        if let Some(test) = alpha_test_hlsl(self.alpha_func) {
+            writeln!(src, "    clip({} ? 1.0 : -1.0);", test).unwrap();
+        }
+
+        src += "    return current;\n}\n";
+
+        src
+    }
+}
+
+/// Returns the HLSL expression which reorders the channels of the sampled texture.
+fn swizzle_hlsl(swizzle: Swizzle) -> String {
+    const COMPONENTS: [&str; 4] = ["r", "g", "b", "a"];
+
+    let channels: Vec<String> = swizzle
+        .iter()
+        .enumerate()
+        .map(|(i, &channel)| match channel {
+            0..=3 => format!("tex.{}", COMPONENTS[channel as usize]),
+            SWIZZLE_ONE => "1.0".to_owned(),
+            SWIZZLE_PALETTE => format!("entry.{}", COMPONENTS[i]),
+            // `SWIZZLE_ZERO`
+            _ => "0.0".to_owned(),
+        })
+        .collect();
+
+    format!("float4({})", channels.join(", "))
+}
+
+/// Returns the HLSL condition a pixel has to pass the alpha test,
+/// or `None` if every pixel passes.
+///
+/// Like D3D9, the alpha is compared at 8-bit precision.
+fn alpha_test_hlsl(func: D3DCMPFUNC) -> Option<&'static str> {
+    let test = match func {
+        D3DCMP_NEVER => "false",
+        D3DCMP_LESS => "round(current.a * 255.0) < alpha_ref",
+        D3DCMP_EQUAL => "round(current.a * 255.0) == alpha_ref",
+        D3DCMP_LESSEQUAL => "round(current.a * 255.0) <= alpha_ref",
+        D3DCMP_GREATER => "round(current.a * 255.0) > alpha_ref",
+        D3DCMP_NOTEQUAL => "round(current.a * 255.0) != alpha_ref",
+        D3DCMP_GREATEREQUAL => "round(current.a * 255.0) >= alpha_ref",
+        _ => return None,
+    };
+
+    Some(test)
+}
+
+/// Returns the HLSL expression of a texture stage argument.
+fn arg_hlsl(arg: u32) -> String {
+    let value = match arg & D3DTA_SELECTMASK {
+        D3DTA_DIFFUSE => "diffuse",
+        D3DTA_CURRENT => "current",
+        D3DTA_TEXTURE => "tex",
+        D3DTA_TFACTOR => "texture_factor",
+        D3DTA_SPECULAR => "specular",
+        D3DTA_TEMP => "temp",
+        _ => {
+            run_once!(|| warn!("Texture stage argument {:#x} is not supported", arg));
+            "current"
+        }
+    };
+
+    let value = if arg & D3DTA_ALPHAREPLICATE != 0 {
+        format!("{}.aaaa", value)
+    } else {
+        value.to_owned()
+    };
+
+    if arg & D3DTA_COMPLEMENT != 0 {
+        format!("(1.0 - {})", value)
+    } else {
+        value
+    }
+}
+
+/// Returns the HLSL expression computing the result of a texture operation.
+fn op_hlsl(op: D3DTEXTUREOP, args: [u32; 3]) -> String {
+    let [a0, a1, a2] = [arg_hlsl(args[0]), arg_hlsl(args[1]), arg_hlsl(args[2])];
+
+    match op {
+        D3DTOP_SELECTARG1 => a1,
+        D3DTOP_SELECTARG2 => a2,
+        D3DTOP_MODULATE => format!("{} * {}", a1, a2),
+        D3DTOP_MODULATE2X => format!("{} * {} * 2.0", a1, a2),
+        D3DTOP_MODULATE4X => format!("{} * {} * 4.0", a1, a2),
+        D3DTOP_ADD => format!("{} + {}", a1, a2),
+        D3DTOP_ADDSIGNED => format!("{} + {} - 0.5", a1, a2),
+        D3DTOP_ADDSIGNED2X => format!("({} + {} - 0.5) * 2.0", a1, a2),
+        D3DTOP_SUBTRACT => format!("{} - {}", a1, a2),
+        D3DTOP_ADDSMOOTH => format!("{0} + {1} - {0} * {1}", a1, a2),
+        D3DTOP_BLENDDIFFUSEALPHA => format!("lerp({}, {}, diffuse.a)", a2, a1),
+        D3DTOP_BLENDTEXTUREALPHA => format!("lerp({}, {}, tex.a)", a2, a1),
+        D3DTOP_BLENDFACTORALPHA => format!("lerp({}, {}, texture_factor.a)", a2, a1),
+        D3DTOP_BLENDTEXTUREALPHAPM => format!("{} + {} * (1.0 - tex.a)", a1, a2),
+        D3DTOP_BLENDCURRENTALPHA => format!("lerp({}, {}, current.a)", a2, a1),
+        D3DTOP_MODULATEALPHA_ADDCOLOR => format!("{0} + {0}.a * {1}", a1, a2),
+        D3DTOP_MODULATECOLOR_ADDALPHA => format!("{0} * {1} + {0}.a", a1, a2),
+        D3DTOP_MODULATEINVALPHA_ADDCOLOR => format!("(1.0 - {0}.a) * {1} + {0}", a1, a2),
+        D3DTOP_MODULATEINVCOLOR_ADDALPHA => format!("(1.0 - {0}) * {1} + {0}.a", a1, a2),
+        D3DTOP_DOTPRODUCT3 => format!("dot({}.rgb - 0.5, {}.rgb - 0.5) * 4.0", a1, a2),
+        D3DTOP_MULTIPLYADD => format!("{} + {} * {}", a0, a1, a2),
+        D3DTOP_LERP => format!("lerp({}, {}, {})", a2, a1, a0),
+        _ => {
+            run_once!(|| warn!("Texture operation {} is not supported", op));
+            "current".to_owned()
+        }
+    }
+}
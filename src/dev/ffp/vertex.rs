@@ -0,0 +1,359 @@
+//! Generation of the vertex shaders which implement the fixed function transform.
+
+use std::fmt::Write;
+
+use winapi::shared::d3d9types::*;
+
+use crate::dev::state::{DeviceState, MAX_ACTIVE_LIGHTS};
+
+use super::{common_hlsl, fog_factor_hlsl, fog_modes, MAX_TEX_COORDS};
+
+/// Where the lighting equation takes one of the material's colors from.
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum MaterialSource {
+    #[default]
+    Material,
+    Diffuse,
+    Specular,
+}
+
+impl MaterialSource {
+    fn hlsl(self, material: &str) -> &str {
+        match self {
+            MaterialSource::Material => material,
+            MaterialSource::Diffuse => "diffuse",
+            MaterialSource::Specular => "specular",
+        }
+    }
+}
+
+/// State which is baked into the generated vertex shader.
+///
+/// Whenever this changes, a different shader has to be used.
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct VertexShaderKey {
+    // The positions were already transformed to screen space by the app.
+    pub transformed: bool,
+    pub diffuse: bool,
+    pub specular: bool,
+    pub normal: bool,
+    // The vertices have their own point size.
+    pub point_size: bool,
+    // Bit mask of the texture coordinate sets the vertices contain.
+    pub tex_coords: u8,
+    pub lighting: bool,
+    // Number of enabled lights, or zero if lighting is disabled.
+    pub light_count: u8,
+    pub normalize_normals: bool,
+    pub local_viewer: bool,
+    pub diffuse_source: MaterialSource,
+    pub ambient_source: MaterialSource,
+    pub specular_source: MaterialSource,
+    pub emissive_source: MaterialSource,
+    pub fog_mode: D3DFOGMODE,
+    // Use the distance to the camera instead of the depth for vertex fog.
+    pub range_fog: bool,
+    // Scale points with their distance from the camera.
+    pub point_scale: bool,
+}
+
+impl VertexShaderKey {
+    /// Determines the shader to use for vertices with a certain declaration.
+    pub fn new(elems: &[D3DVERTEXELEMENT9], state: &DeviceState) -> Self {
+        let has = |usage: D3DDECLUSAGE, index: usize| {
+            elems
+                .iter()
+                .any(|e| e.Usage == usage as u8 && e.UsageIndex as usize == index)
+        };
+
+        let tex_coords = (0..MAX_TEX_COORDS)
+            .filter(|&i| has(D3DDECLUSAGE_TEXCOORD, i))
+            .fold(0, |mask, i| mask | (1 << i));
+
+        let transformed = has(D3DDECLUSAGE_POSITIONT, 0);
+        let diffuse = has(D3DDECLUSAGE_COLOR, 0);
+        let specular = has(D3DDECLUSAGE_COLOR, 1);
+
+        // Pre-transformed vertices are never lit.
+        let lighting = !transformed && state.get_render_state(D3DRS_LIGHTING) != 0;
+
+        // The material's own colors are used if the vertices don't have the requested one.
+        let color_vertex = state.get_render_state(D3DRS_COLORVERTEX) != 0;
+        let source = |rs| {
+            if !lighting || !color_vertex {
+                return MaterialSource::Material;
+            }
+
+            match state.get_render_state(rs) {
+                D3DMCS_COLOR1 if diffuse => MaterialSource::Diffuse,
+                D3DMCS_COLOR2 if specular => MaterialSource::Specular,
+                _ => MaterialSource::Material,
+            }
+        };
+
+        // Pre-transformed vertices bring their own fog factor.
+        let (fog_mode, _) = fog_modes(state);
+        let fog_mode = if transformed { D3DFOG_NONE } else { fog_mode };
+
+        let light_count = if lighting {
+            state.active_lights().take(MAX_ACTIVE_LIGHTS).count() as u8
+        } else {
+            0
+        };
+
+        Self {
+            transformed,
+            diffuse,
+            specular,
+            normal: has(D3DDECLUSAGE_NORMAL, 0),
+            point_size: has(D3DDECLUSAGE_PSIZE, 0),
+            tex_coords,
+            lighting,
+            light_count,
+            normalize_normals: lighting && state.get_render_state(D3DRS_NORMALIZENORMALS) != 0,
+            local_viewer: lighting && state.get_render_state(D3DRS_LOCALVIEWER) != 0,
+            diffuse_source: source(D3DRS_DIFFUSEMATERIALSOURCE),
+            ambient_source: source(D3DRS_AMBIENTMATERIALSOURCE),
+            specular_source: source(D3DRS_SPECULARMATERIALSOURCE),
+            emissive_source: source(D3DRS_EMISSIVEMATERIALSOURCE),
+            fog_mode,
+            range_fog: fog_mode != D3DFOG_NONE && state.get_render_state(D3DRS_RANGEFOGENABLE) != 0,
+            // Pre-transformed points are already sized in screen space.
+            point_scale: !transformed && state.get_render_state(D3DRS_POINTSCALEENABLE) != 0,
+        }
+    }
+
+    /// Generates the HLSL source of the shader.
+    pub fn generate(&self) -> String {
+        let mut src = common_hlsl();
+
+        src += "struct VertexInput {\n";
+        if self.transformed {
+            src += "    float4 position : POSITIONT0;\n";
+        } else {
+            src += "    float4 position : POSITION0;\n";
+        }
+        if self.diffuse {
+            src += "    float4 diffuse : COLOR0;\n";
+        }
+        if self.specular {
+            src += "    float4 specular : COLOR1;\n";
+        }
+        if self.normal {
+            src += "    float3 normal : NORMAL0;\n";
+        }
+        if self.point_size {
+            src += "    float point_size : PSIZE0;\n";
+        }
+        for i in self.tex_coord_sets() {
+            writeln!(src, "    float4 tex{0} : TEXCOORD{0};", i).unwrap();
+        }
+        src += "};\n\n";
+
+        src += r"float clip_distance(float4 position, uint plane) {
+    return (clip_plane_mask & (1u << plane)) != 0 ? dot(position, clip_planes[plane]) : 0.0;
+}
+";
+
+        if self.lighting {
+            src += LIGHTING_HLSL;
+        }
+
+        src += r"
+
+VertexOutput main(VertexInput input) {
+    VertexOutput output;
+";
+
+        if self.transformed {
+            // User clip planes don't apply to pre-transformed vertices.
+            src += r"    float w = input.position.w != 0.0 ? 1.0 / input.position.w : 1.0;
+    float3 ndc = input.position.xyz * viewport_scale.xyz + viewport_offset.xyz;
+    output.position = float4(ndc, 1.0) * w;
+    output.clip0 = 0.0;
+    output.clip1 = 0.0;
+    float depth = w;
+";
+        } else {
+            src += r"    float4 world_position = mul(world, input.position);
+    float4 view_position = mul(view, world_position);
+    output.position = mul(projection, view_position);
+    output.clip0 = float4(clip_distance(world_position, 0), clip_distance(world_position, 1),
+        clip_distance(world_position, 2), clip_distance(world_position, 3));
+    output.clip1 = float4(clip_distance(world_position, 4), clip_distance(world_position, 5),
+        clip_distance(world_position, 6), clip_distance(world_position, 7));
+    float depth = view_position.z;
+";
+        }
+
+        // Vertices without colors are opaque white, and have no specular highlight.
+        if self.diffuse {
+            src += "    float4 diffuse = input.diffuse;\n";
+        } else {
+            src += "    float4 diffuse = float4(1.0, 1.0, 1.0, 1.0);\n";
+        }
+        if self.specular {
+            src += "    float4 specular = input.specular;\n";
+        } else {
+            src += "    float4 specular = float4(0.0, 0.0, 0.0, 0.0);\n";
+        }
+
+        if self.lighting {
+            self.generate_lighting(&mut src);
+        } else {
+            src += "    output.diffuse = diffuse;\n";
+            src += "    output.specular = specular;\n";
+        }
+
+        // Without vertex fog, the factor is taken from the specular alpha.
+        if self.fog_mode != D3DFOG_NONE {
+            let distance = if self.range_fog {
+                "length(view_position.xyz)"
+            } else {
+                "abs(depth)"
+            };
+            writeln!(
+                src,
+                "    output.fog = float2(saturate({}), depth);",
+                fog_factor_hlsl(self.fog_mode, distance)
+            )
+            .unwrap();
+        } else {
+            src += "    output.fog = float2(specular.a, depth);\n";
+        }
+
+        // Points are only expanded to this size if a geometry shader is used.
+        if self.point_size {
+            src += "    float point_size = input.point_size;\n";
+        } else {
+            src += "    float point_size = point_params.x;\n";
+        }
+        if self.point_scale {
+            src += r"    float eye_distance = length(view_position.xyz);
+    point_size *= point_params.w * sqrt(1.0 / (point_scale.x + point_scale.y * eye_distance
+        + point_scale.z * eye_distance * eye_distance));
+";
+        }
+        src += "    output.point_size = clamp(point_size, point_params.y, point_params.z);\n";
+
+        for i in 0..MAX_TEX_COORDS {
+            if self.tex_coords & (1 << i) != 0 {
+                writeln!(src, "    output.tex[{0}] = input.tex{0};", i).unwrap();
+            } else {
+                writeln!(src, "    output.tex[{}] = float4(0.0, 0.0, 0.0, 1.0);", i).unwrap();
+            }
+        }
+
+        src += "    return output;\n}\n";
+
+        src
+    }
+
+    // Computes the vertex colors from the lights and the material.
+    fn generate_lighting(&self, src: &mut String) {
+        // Vertices without normals only receive the ambient and emissive light.
+        if self.normal {
+            *src += "    float3 normal = mul((float3x3)normal_matrix, input.normal);\n";
+            if self.normalize_normals {
+                *src += "    normal = normalize(normal);\n";
+            }
+        } else {
+            *src += "    float3 normal = float3(0.0, 0.0, 0.0);\n";
+        }
+
+        if self.local_viewer {
+            *src += "    float3 eye = normalize(-view_position.xyz);\n";
+        } else {
+            *src += "    float3 eye = float3(0.0, 0.0, 1.0);\n";
+        }
+
+        *src += r"    float3 ambient_light = float3(0.0, 0.0, 0.0);
+    float3 diffuse_light = float3(0.0, 0.0, 0.0);
+    float3 specular_light = float3(0.0, 0.0, 0.0);
+";
+
+        for i in 0..self.light_count {
+            writeln!(
+                src,
+                "    add_light(lights[{}], view_position.xyz, normal, eye, ambient_light, diffuse_light, specular_light);",
+                i
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            src,
+            r"    float4 mat_diffuse = {};
+    float4 mat_ambient = {};
+    float4 mat_specular = {};
+    float4 mat_emissive = {};
+    output.diffuse.rgb = saturate(mat_emissive.rgb + mat_ambient.rgb * (global_ambient.rgb + ambient_light)
+        + mat_diffuse.rgb * diffuse_light);
+    output.diffuse.a = mat_diffuse.a;
+    output.specular = float4(saturate(mat_specular.rgb * specular_light), mat_specular.a);",
+            self.diffuse_source.hlsl("material_diffuse"),
+            self.ambient_source.hlsl("material_ambient"),
+            self.specular_source.hlsl("material_specular"),
+            self.emissive_source.hlsl("material_emissive"),
+        )
+        .unwrap();
+    }
+
+    // Returns the indices of the texture coordinate sets the vertices contain.
+    fn tex_coord_sets(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_TEX_COORDS).filter(move |&i| self.tex_coords & (1 << i) != 0)
+    }
+}
+
+/// Accumulates the contribution of a single light, following D3D9's lighting equations.
+///
+/// The light's type is read from the constants, so every combination of lights uses the same shader.
+const LIGHTING_HLSL: &str = r"
+void add_light(Light light, float3 position, float3 normal, float3 eye,
+    inout float3 ambient, inout float3 diffuse, inout float3 specular) {
+    uint light_type = (uint)light.attenuation.w;
+
+    float3 dir;
+    float attenuation = 1.0;
+
+    if (light_type == 3) {
+        // Directional lights shine from infinitely far away.
+        dir = -light.direction.xyz;
+    } else {
+        float3 to_light = light.position.xyz - position;
+        float dist = length(to_light);
+        if (dist > light.spot.x) {
+            return;
+        }
+
+        dir = dist > 0.0 ? to_light / dist : float3(0.0, 0.0, 0.0);
+
+        float falloff = light.attenuation.x + light.attenuation.y * dist + light.attenuation.z * dist * dist;
+        attenuation = falloff > 0.0 ? 1.0 / falloff : 1.0;
+
+        if (light_type == 2) {
+            // Spot lights are brightest inside the inner cone, and fade out until the outer cone.
+            float rho = dot(-dir, light.direction.xyz);
+            if (rho <= light.spot.w) {
+                return;
+            }
+            if (rho < light.spot.z) {
+                attenuation *= pow(saturate((rho - light.spot.w) / (light.spot.z - light.spot.w)), light.spot.y);
+            }
+        }
+    }
+
+    ambient += attenuation * light.ambient.rgb;
+
+    float n_dot_l = dot(normal, dir);
+    if (n_dot_l > 0.0) {
+        diffuse += attenuation * n_dot_l * light.diffuse.rgb;
+
+        float3 halfway = normalize(dir + eye);
+        float n_dot_h = max(dot(normal, halfway), 0.0);
+        if (material_power > 0.0) {
+            specular += attenuation * pow(n_dot_h, material_power) * light.specular.rgb;
+        }
+    }
+}
+";
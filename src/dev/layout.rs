@@ -0,0 +1,162 @@
+//! Translation of vertex declarations to D3D11 input layouts.
+
+use std::ptr;
+use std::sync::Arc;
+
+use winapi::shared::{d3d9types::*, dxgiformat::*};
+use winapi::um::d3d11::*;
+
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::Error;
+
+use super::{CacheStats, LruCache};
+
+/// Number of input layouts we keep around by default.
+const DEFAULT_CAPACITY: usize = 256;
+
+/// Environment variable which can be used to override the capacity of the cache.
+const CAPACITY_VAR: &str = "D3D9_INPUT_LAYOUT_CACHE_SIZE";
+
+/// Converts a vertex element's usage to a (null-terminated) HLSL semantic name.
+fn decl_usage_to_semantic(usage: u8) -> &'static [u8] {
+    match usage as D3DDECLUSAGE {
+        D3DDECLUSAGE_POSITION => b"POSITION\0",
+        D3DDECLUSAGE_BLENDWEIGHT => b"BLENDWEIGHT\0",
+        D3DDECLUSAGE_BLENDINDICES => b"BLENDINDICES\0",
+        D3DDECLUSAGE_NORMAL => b"NORMAL\0",
+        D3DDECLUSAGE_PSIZE => b"PSIZE\0",
+        D3DDECLUSAGE_TEXCOORD => b"TEXCOORD\0",
+        D3DDECLUSAGE_TANGENT => b"TANGENT\0",
+        D3DDECLUSAGE_BINORMAL => b"BINORMAL\0",
+        D3DDECLUSAGE_TESSFACTOR => b"TESSFACTOR\0",
+        D3DDECLUSAGE_POSITIONT => b"POSITIONT\0",
+        D3DDECLUSAGE_COLOR => b"COLOR\0",
+        D3DDECLUSAGE_FOG => b"FOG\0",
+        D3DDECLUSAGE_DEPTH => b"DEPTH\0",
+        D3DDECLUSAGE_SAMPLE => b"SAMPLE\0",
+        _ => {
+            error!("Unknown vertex element usage: {}", usage);
+            b"UNKNOWN\0"
+        }
+    }
+}
+
+/// Converts a vertex element's type to the equivalent DXGI format.
+fn decl_type_to_dxgi(ty: u8) -> DXGI_FORMAT {
+    match ty as D3DDECLTYPE {
+        D3DDECLTYPE_FLOAT1 => DXGI_FORMAT_R32_FLOAT,
+        D3DDECLTYPE_FLOAT2 => DXGI_FORMAT_R32G32_FLOAT,
+        D3DDECLTYPE_FLOAT3 => DXGI_FORMAT_R32G32B32_FLOAT,
+        D3DDECLTYPE_FLOAT4 => DXGI_FORMAT_R32G32B32A32_FLOAT,
+        D3DDECLTYPE_D3DCOLOR => DXGI_FORMAT_B8G8R8A8_UNORM,
+        D3DDECLTYPE_UBYTE4 => DXGI_FORMAT_R8G8B8A8_UINT,
+        D3DDECLTYPE_SHORT2 => DXGI_FORMAT_R16G16_SINT,
+        D3DDECLTYPE_SHORT4 => DXGI_FORMAT_R16G16B16A16_SINT,
+        D3DDECLTYPE_UBYTE4N => DXGI_FORMAT_R8G8B8A8_UNORM,
+        D3DDECLTYPE_SHORT2N => DXGI_FORMAT_R16G16_SNORM,
+        D3DDECLTYPE_SHORT4N => DXGI_FORMAT_R16G16B16A16_SNORM,
+        D3DDECLTYPE_USHORT2N => DXGI_FORMAT_R16G16_UNORM,
+        D3DDECLTYPE_USHORT4N => DXGI_FORMAT_R16G16B16A16_UNORM,
+        D3DDECLTYPE_UDEC3 => DXGI_FORMAT_R10G10B10A2_UINT,
+        // There is no signed equivalent of this format in DXGI.
+        D3DDECLTYPE_DEC3N => DXGI_FORMAT_R10G10B10A2_UNORM,
+        D3DDECLTYPE_FLOAT16_2 => DXGI_FORMAT_R16G16_FLOAT,
+        D3DDECLTYPE_FLOAT16_4 => DXGI_FORMAT_R16G16B16A16_FLOAT,
+        _ => DXGI_FORMAT_UNKNOWN,
+    }
+}
+
+/// The fields of a vertex element, in a form which can be compared and hashed.
+type ElementKey = (u16, u16, u8, u8, u8, u8);
+
+/// Identifies an input layout by the full vertex declaration and the shader's byte code.
+///
+/// Two different pairs never share a key, even if their hashes collide.
+type LayoutKey = (Box<[ElementKey]>, Arc<[u32]>);
+
+/// Builds the cache key of a declaration / shader pair.
+fn layout_key(elems: &[D3DVERTEXELEMENT9], code: &Arc<[u32]>) -> LayoutKey {
+    let elems = elems
+        .iter()
+        .map(|e| (e.Stream, e.Offset, e.Type, e.Method, e.Usage, e.UsageIndex))
+        .collect();
+
+    // The byte code is shared with the shader, so it doesn't have to be copied.
+    (elems, Arc::clone(code))
+}
+
+/// Creates a new input layout from a vertex declaration and the shader which will use it.
+fn create_input_layout(
+    device: &ID3D11Device,
+    elems: &[D3DVERTEXELEMENT9],
+    code: &[u32],
+) -> Result<ComPtr<ID3D11InputLayout>, Error> {
+    let descs: Vec<_> = elems
+        .iter()
+        .map(|e| D3D11_INPUT_ELEMENT_DESC {
+            SemanticName: decl_usage_to_semantic(e.Usage).as_ptr() as *const _,
+            SemanticIndex: u32::from(e.UsageIndex),
+            Format: decl_type_to_dxgi(e.Type),
+            InputSlot: u32::from(e.Stream),
+            AlignedByteOffset: u32::from(e.Offset),
+            InputSlotClass: D3D11_INPUT_PER_VERTEX_DATA,
+            InstanceDataStepRate: 0,
+        })
+        .collect();
+
+    let layout = unsafe {
+        let mut ptr = ptr::null_mut();
+
+        let result = device.CreateInputLayout(
+            descs.as_ptr(),
+            descs.len() as u32,
+            code.as_ptr() as *const _,
+            code.len() * 4,
+            &mut ptr,
+        );
+        if_not_success_err!(check_hresult(result, "Failed to create input layout"));
+
+        ComPtr::new(ptr)
+    };
+
+    Ok(layout)
+}
+
+/// Cache of input layouts, keyed by vertex declaration and vertex shader signature.
+///
+/// The cache is bounded, in order to avoid growing indefinitely in games
+/// which generate a lot of declarations or shaders.
+pub struct InputLayoutCache {
+    cache: LruCache<LayoutKey, ComPtr<ID3D11InputLayout>>,
+}
+
+impl InputLayoutCache {
+    /// Creates a new, empty cache.
+    ///
+    /// Its capacity can be set with the `D3D9_INPUT_LAYOUT_CACHE_SIZE` environment variable.
+    pub fn new() -> Self {
+        Self {
+            cache: LruCache::from_env(CAPACITY_VAR, DEFAULT_CAPACITY),
+        }
+    }
+
+    /// Retrieves the input layout for a declaration / shader pair, creating it if necessary.
+    pub fn get(
+        &mut self,
+        device: &ID3D11Device,
+        elems: &[D3DVERTEXELEMENT9],
+        code: &Arc<[u32]>,
+    ) -> Result<ComPtr<ID3D11InputLayout>, Error> {
+        let key = layout_key(elems, code);
+
+        self.cache
+            .get_or_insert_with(key, || create_input_layout(device, elems, code))
+    }
+
+    /// Returns the usage statistics of this cache.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
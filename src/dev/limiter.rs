@@ -0,0 +1,60 @@
+//! Frame rate limiting.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use std::{cmp, thread};
+
+use crate::core::config;
+
+/// Sleeping isn't very precise, so we spin for the last part of the wait.
+const SPIN_TIME: Duration = Duration::from_millis(2);
+
+/// Limits the rate at which frames are presented.
+///
+/// This plays nicely with vsync: the limiter only waits when a frame finished early,
+/// so whichever of the two is stricter ends up determining the frame rate.
+pub struct FrameLimiter {
+    frame_time: Duration,
+    // The earliest time at which the next frame can be presented.
+    next_frame: Cell<Option<Instant>>,
+}
+
+impl FrameLimiter {
+    /// Creates a new limiter for a certain number of frames per second.
+    pub fn new(max_fps: u32) -> Self {
+        Self {
+            frame_time: Duration::from_secs(1) / max_fps,
+            next_frame: Cell::new(None),
+        }
+    }
+
+    /// Creates a limiter if the user set the `D3D9_MAX_FPS` environment variable.
+    pub fn from_env() -> Option<Self> {
+        config::value("D3D9_MAX_FPS")
+            .filter(|&fps| fps > 0)
+            .map(Self::new)
+    }
+
+    /// Waits until the next frame can be presented.
+    pub fn wait(&self) {
+        let now = Instant::now();
+        let target = self.next_frame.get().unwrap_or(now);
+
+        if target > now {
+            let remaining = target - now;
+
+            if remaining > SPIN_TIME {
+                thread::sleep(remaining - SPIN_TIME);
+            }
+
+            while Instant::now() < target {
+                thread::yield_now();
+            }
+        }
+
+        // If the game is running slower than the limit, don't let it catch up
+        // by presenting the next frames faster.
+        self.next_frame
+            .set(Some(cmp::max(target, now) + self.frame_time));
+    }
+}
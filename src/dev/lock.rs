@@ -0,0 +1,86 @@
+//! Serializes the calls made to a device from multiple threads.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, ThreadId};
+
+/// Lock which is held for the whole duration of a device's API calls.
+///
+/// Devices created with `D3DCREATE_MULTITHREADED` promise apps they can be
+/// called from any thread, so each call has to run on its own.
+/// The lock is reentrant, since API calls can end up calling other API functions.
+pub struct DeviceLock {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // The thread which holds the lock, and how many times it acquired it.
+    owner: Mutex<(Option<ThreadId>, u32)>,
+    released: Condvar,
+}
+
+/// Keeps a device locked for as long as it's alive.
+pub struct DeviceLockGuard {
+    inner: Arc<Inner>,
+}
+
+impl DeviceLock {
+    /// Creates a new lock, which isn't held by any thread.
+    pub fn new() -> Self {
+        let inner = Inner {
+            owner: Mutex::new((None, 0)),
+            released: Condvar::new(),
+        };
+
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Waits until no other thread holds the lock, then acquires it.
+    pub fn lock(&self) -> DeviceLockGuard {
+        let current = thread::current().id();
+
+        // A panic on another thread doesn't leave the lock in an invalid state.
+        let mut owner = self
+            .inner
+            .owner
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        loop {
+            match owner.0 {
+                Some(thread) if thread != current => {
+                    owner = self
+                        .inner
+                        .released
+                        .wait(owner)
+                        .unwrap_or_else(|err| err.into_inner());
+                }
+                _ => break,
+            }
+        }
+
+        owner.0 = Some(current);
+        owner.1 += 1;
+
+        DeviceLockGuard {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Drop for DeviceLockGuard {
+    fn drop(&mut self) {
+        let mut owner = self
+            .inner
+            .owner
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        owner.1 -= 1;
+        if owner.1 == 0 {
+            owner.0 = None;
+            self.inner.released.notify_one();
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! Residency management for resources in the managed pool.
+//!
+//! D3D9 keeps the authoritative copy of managed resources in system memory,
+//! and uploads them to VRAM when they're used. We store that copy in staging
+//! resources, and create GPU-only copies of them on demand. When VRAM is scarce,
+//! the copies of the least recently used resources are thrown out, and uploaded
+//! again the next time they're needed.
+
+use std::collections::HashMap;
+
+use winapi::um::d3d11::*;
+
+use comptr::ComPtr;
+
+use crate::core::{
+    fmt::{block_size, dxgi_format_to_d3d},
+    *,
+};
+use crate::d3d11;
+use crate::Error;
+
+/// Environment variable which can be used to limit the VRAM used by managed resources.
+const BUDGET_VAR: &str = "D3D9_MANAGED_BUDGET_MB";
+
+const MIB: u64 = 1024 * 1024;
+
+/// Identifies a resource registered with the residency manager.
+///
+/// Handles are derived from the system memory copy, so that surfaces and volumes
+/// which are part of a managed texture can refer to it too.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ManagedHandle(usize);
+
+impl ManagedHandle {
+    /// Returns the handle of the managed resource whose data is stored in `resource`.
+    pub fn from_resource(resource: *mut ID3D11Resource) -> Self {
+        ManagedHandle(resource as usize)
+    }
+}
+
+/// The system memory copy of a managed resource.
+pub enum ManagedStorage {
+    /// A vertex or index buffer, and the flags used to bind its GPU copy.
+    Buffer(d3d11::Buffer, D3D11_BIND_FLAG),
+    /// A 2D texture or a cube map.
+    Texture2D(d3d11::Texture2D),
+    /// A volume texture.
+    Texture3D(d3d11::VolumeTexture),
+}
+
+/// The VRAM copy of a managed resource.
+#[derive(Clone)]
+pub enum Resident {
+    Buffer(d3d11::Buffer),
+    Texture2D(d3d11::Texture2D, Option<ComPtr<ID3D11ShaderResourceView>>),
+    Texture3D(
+        d3d11::VolumeTexture,
+        Option<ComPtr<ID3D11ShaderResourceView>>,
+    ),
+}
+
+impl Resident {
+    /// Retrieves the resource data gets uploaded to.
+    fn as_resource(&self) -> *mut ID3D11Resource {
+        match self {
+            Resident::Buffer(buffer) => buffer.as_resource(),
+            Resident::Texture2D(texture, _) => texture.as_resource(),
+            Resident::Texture3D(texture, _) => texture.as_resource(),
+        }
+    }
+
+    /// Retrieves the view used to bind this resource to shaders, if it's a texture.
+    pub fn shader_view(&self) -> Option<&ComPtr<ID3D11ShaderResourceView>> {
+        match self {
+            Resident::Buffer(_) => None,
+            Resident::Texture2D(_, view) | Resident::Texture3D(_, view) => view.as_ref(),
+        }
+    }
+
+    /// (Re)creates the shader view of a texture, skipping the mip levels before `lod`.
+    fn create_view(&mut self, device: &ID3D11Device, lod: u32) -> Result<(), Error> {
+        match self {
+            Resident::Buffer(_) => (),
+            Resident::Texture2D(texture, view) => {
+                let lod = lod.min(texture.desc().MipLevels - 1);
+                *view = Some(
+                    if texture.desc().MiscFlags & D3D11_RESOURCE_MISC_TEXTURECUBE != 0 {
+                        d3d11::CubeTexture::create_cube_view(device, texture, lod)?
+                    } else {
+                        texture.create_mip_view(device, lod)?
+                    },
+                );
+            }
+            Resident::Texture3D(texture, view) => {
+                let lod = lod.min(texture.desc().MipLevels - 1);
+                *view = Some(texture.create_shader_view(device, lod)?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ManagedStorage {
+    /// Retrieves the resource holding the authoritative data.
+    fn as_resource(&self) -> *mut ID3D11Resource {
+        match self {
+            ManagedStorage::Buffer(buffer, _) => buffer.as_resource(),
+            ManagedStorage::Texture2D(texture) => texture.as_resource(),
+            ManagedStorage::Texture3D(texture) => texture.as_resource(),
+        }
+    }
+
+    /// Estimates how much VRAM the GPU copy of this resource takes up.
+    fn size(&self) -> u64 {
+        // Size of a mip chain, given the size of the top level.
+        let with_mips =
+            |top: u64, levels: u32| (0..levels).map(|level| top >> (2 * level)).sum::<u64>();
+
+        let pixel_size = |fmt| {
+            let (bytes, dim) = block_size(dxgi_format_to_d3d(fmt));
+            (u64::from(bytes), u64::from(dim * dim))
+        };
+
+        match self {
+            ManagedStorage::Buffer(buffer, _) => u64::from(buffer.desc().ByteWidth),
+            ManagedStorage::Texture2D(texture) => {
+                let desc = texture.desc();
+                let (bytes, pixels) = pixel_size(desc.Format);
+                let top = u64::from(desc.Width) * u64::from(desc.Height) * bytes / pixels;
+                with_mips(top, desc.MipLevels) * u64::from(desc.ArraySize)
+            }
+            ManagedStorage::Texture3D(texture) => {
+                let desc = texture.desc();
+                let (bytes, pixels) = pixel_size(desc.Format);
+                let top =
+                    u64::from(desc.Width) * u64::from(desc.Height) * u64::from(desc.Depth) * bytes
+                        / pixels;
+                with_mips(top, desc.MipLevels)
+            }
+        }
+    }
+
+    /// Creates a GPU-only copy of this resource, without uploading any data.
+    ///
+    /// Textures are viewed starting with the `lod` mip level.
+    fn create_resident(&self, device: &ID3D11Device, lod: u32) -> Result<Resident, Error> {
+        let mut resident = match self {
+            ManagedStorage::Buffer(buffer, bind_flags) => {
+                Resident::Buffer(buffer.new_gpu_copy(device, *bind_flags)?)
+            }
+            ManagedStorage::Texture2D(texture) => {
+                Resident::Texture2D(texture.new_gpu_copy(device)?, None)
+            }
+            ManagedStorage::Texture3D(texture) => {
+                Resident::Texture3D(texture.new_gpu_copy(device)?, None)
+            }
+        };
+
+        resident.create_view(device, lod)?;
+
+        Ok(resident)
+    }
+}
+
+/// A resource tracked by the residency manager.
+struct Entry {
+    storage: ManagedStorage,
+    resident: Option<Resident>,
+    // Estimated size of the GPU copy, in bytes.
+    size: u64,
+    // Resources with a lower priority are evicted first.
+    priority: u32,
+    // Most detailed mip level of a texture which gets used.
+    lod: u32,
+    // Value of the manager's clock the last time this resource was used.
+    last_used: u64,
+    // Set when the system memory copy was modified since the last upload.
+    dirty: bool,
+}
+
+/// Statistics about the managed resources of a device.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ResidencyStats {
+    /// Number of registered resources.
+    pub resources: usize,
+    /// Number of resources which currently have a copy in VRAM.
+    pub resident: usize,
+    /// Estimated amount of VRAM used by managed resources.
+    pub resident_bytes: u64,
+    /// Number of times data was copied to VRAM.
+    pub uploads: u64,
+    /// Number of times a resource was evicted from VRAM.
+    pub evictions: u64,
+}
+
+/// Keeps track of all the managed resources of a device,
+/// and decides which of them have a copy in VRAM.
+pub struct ResidencyManager {
+    entries: HashMap<ManagedHandle, Entry>,
+    // Monotonic counter, incremented every time a resource is used.
+    clock: u64,
+    // Maximum number of bytes managed resources are allowed to use in VRAM.
+    budget: u64,
+    stats: ResidencyStats,
+}
+
+impl ResidencyManager {
+    /// Creates a new manager, which allows up to `budget` bytes to be resident.
+    ///
+    /// The budget can be overridden with the `D3D9_MANAGED_BUDGET_MB` environment variable.
+    pub fn new(budget: u64) -> Self {
+        let budget = config::value::<u64>(BUDGET_VAR)
+            .map(|mb| mb.saturating_mul(MIB))
+            .unwrap_or(budget);
+
+        Self {
+            entries: HashMap::new(),
+            clock: 0,
+            budget,
+            stats: ResidencyStats::default(),
+        }
+    }
+
+    /// Starts tracking a new managed resource.
+    ///
+    /// Nothing is uploaded until the resource is first used.
+    pub fn register(&mut self, storage: ManagedStorage) -> ManagedHandle {
+        let handle = ManagedHandle::from_resource(storage.as_resource());
+
+        let entry = Entry {
+            size: storage.size(),
+            storage,
+            resident: None,
+            priority: 0,
+            lod: 0,
+            last_used: 0,
+            dirty: true,
+        };
+
+        self.entries.insert(handle, entry);
+
+        handle
+    }
+
+    /// Stops tracking a resource, once it is destroyed.
+    pub fn unregister(&mut self, handle: ManagedHandle) {
+        if let Some(entry) = self.entries.remove(&handle) {
+            if entry.resident.is_some() {
+                self.stats.resident_bytes -= entry.size;
+            }
+        }
+    }
+
+    /// Updates the eviction priority of a resource.
+    pub fn set_priority(&mut self, handle: ManagedHandle, priority: u32) {
+        if let Some(entry) = self.entries.get_mut(&handle) {
+            entry.priority = priority;
+        }
+    }
+
+    /// Changes the most detailed mip level used by a texture.
+    ///
+    /// If the texture is resident, its view is recreated.
+    pub fn set_lod(
+        &mut self,
+        device: &ID3D11Device,
+        handle: ManagedHandle,
+        lod: u32,
+    ) -> Result<(), Error> {
+        if let Some(entry) = self.entries.get_mut(&handle) {
+            entry.lod = lod;
+
+            if let Some(ref mut resident) = entry.resident {
+                resident.create_view(device, lod)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the manager that the system memory copy of a resource was modified.
+    pub fn mark_dirty(&mut self, handle: ManagedHandle) {
+        if let Some(entry) = self.entries.get_mut(&handle) {
+            entry.dirty = true;
+        }
+    }
+
+    /// Looks up a registered resource.
+    fn entry(&self, handle: ManagedHandle) -> Result<&Entry, Error> {
+        self.entries.get(&handle).ok_or(Error::InvalidCall)
+    }
+
+    /// Looks up a registered resource, in order to modify it.
+    fn entry_mut(&mut self, handle: ManagedHandle) -> Result<&mut Entry, Error> {
+        self.entries.get_mut(&handle).ok_or(Error::InvalidCall)
+    }
+
+    /// Retrieves the GPU copy of a resource, uploading it if necessary.
+    pub fn make_resident(
+        &mut self,
+        device: &ID3D11Device,
+        ctx: &ID3D11DeviceContext,
+        handle: ManagedHandle,
+    ) -> Result<Resident, Error> {
+        self.clock += 1;
+
+        let (size, lod, needs_copy) = {
+            let entry = self.entry(handle)?;
+            (entry.size, entry.lod, entry.resident.is_none())
+        };
+
+        if needs_copy {
+            self.make_room(size, handle);
+
+            let created = self.entry(handle)?.storage.create_resident(device, lod);
+
+            let resident = match created {
+                Ok(resident) => resident,
+                Err(_) => {
+                    // The driver might have run out of VRAM, so try again with nothing else resident.
+                    warn!("Evicting all managed resources to make room for a new one");
+                    self.evict_all_except(Some(handle));
+                    self.entry(handle)?.storage.create_resident(device, lod)?
+                }
+            };
+
+            let entry = self.entry_mut(handle)?;
+            entry.resident = Some(resident);
+            entry.dirty = true;
+            self.stats.resident_bytes += size;
+        }
+
+        let clock = self.clock;
+        let entry = self.entry_mut(handle)?;
+        entry.last_used = clock;
+
+        let resident = entry.resident.clone().ok_or(Error::NotAvailable)?;
+
+        if entry.dirty {
+            unsafe {
+                ctx.CopyResource(resident.as_resource(), entry.storage.as_resource());
+            }
+            entry.dirty = false;
+            self.stats.uploads += 1;
+        }
+
+        Ok(resident)
+    }
+
+    /// Throws out the GPU copies of all managed resources.
+    pub fn evict_all(&mut self) {
+        self.evict_all_except(None);
+    }
+
+    /// Returns statistics about the managed resources.
+    pub fn stats(&self) -> ResidencyStats {
+        ResidencyStats {
+            resources: self.entries.len(),
+            resident: self
+                .entries
+                .values()
+                .filter(|e| e.resident.is_some())
+                .count(),
+            ..self.stats
+        }
+    }
+
+    // Evicts resources until there is enough room in the budget for `size` more bytes.
+    fn make_room(&mut self, size: u64, keep: ManagedHandle) {
+        while self.stats.resident_bytes + size > self.budget {
+            // Lowest priority first, then least recently used.
+            let victim = self
+                .entries
+                .iter()
+                .filter(|(&handle, e)| handle != keep && e.resident.is_some())
+                .min_by_key(|(_, e)| (e.priority, e.last_used))
+                .map(|(&handle, _)| handle);
+
+            match victim {
+                Some(handle) => self.evict(handle),
+                None => break,
+            }
+        }
+    }
+
+    fn evict_all_except(&mut self, keep: Option<ManagedHandle>) {
+        let handles: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(&handle, e)| Some(handle) != keep && e.resident.is_some())
+            .map(|(&handle, _)| handle)
+            .collect();
+
+        for handle in handles {
+            self.evict(handle);
+        }
+    }
+
+    fn evict(&mut self, handle: ManagedHandle) {
+        if let Some(entry) = self.entries.get_mut(&handle) {
+            if entry.resident.take().is_some() {
+                self.stats.resident_bytes -= entry.size;
+                self.stats.evictions += 1;
+            }
+        }
+    }
+}
@@ -13,13 +13,54 @@ pub use self::resource::*;
 mod surface;
 pub use self::surface::*;
 
+mod volume;
+pub use self::volume::*;
+
 mod texture;
 pub use self::texture::*;
 
 pub mod state;
 
+pub mod ffp;
+
 mod shader;
 pub use self::shader::*;
 
 mod buffer;
 pub use self::buffer::*;
+
+mod query;
+pub use self::query::*;
+
+mod cache;
+pub use self::cache::*;
+
+mod layout;
+pub use self::layout::*;
+
+mod managed;
+pub use self::managed::*;
+
+mod pipeline;
+pub use self::pipeline::*;
+
+mod dirty;
+pub use self::dirty::*;
+
+mod process;
+pub use self::process::*;
+
+mod cursor;
+pub use self::cursor::*;
+
+mod draw;
+pub use self::draw::*;
+
+mod lock;
+pub use self::lock::*;
+
+mod limiter;
+pub use self::limiter::*;
+
+mod profiler;
+pub use self::profiler::*;
@@ -0,0 +1,445 @@
+//! Translation of D3D9's render states to D3D11's pipeline state objects.
+//!
+//! D3D11 groups related render states into immutable objects, which are
+//! expensive to create. They are cached, keyed by a packed description of
+//! the render states they were created from.
+
+use std::ptr;
+
+use winapi::shared::d3d9types::*;
+use winapi::um::d3d11::*;
+
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::Error;
+
+use super::state::DeviceState;
+use super::{CacheStats, StateCache, StateDesc};
+
+/// Environment variable which overrides the capacity of each state object cache.
+const CAPACITY_VAR: &str = "D3D9_STATE_CACHE_SIZE";
+
+/// Number of state objects of each kind kept by default.
+const DEFAULT_CAPACITY: usize = 4096;
+
+/// Number of render targets which have their own color write mask.
+const MAX_WRITE_MASKS: usize = 4;
+
+/// Checks if a render state is part of the blend state.
+pub fn is_blend_state(state: D3DRENDERSTATETYPE) -> bool {
+    match state {
+        D3DRS_ALPHABLENDENABLE
+        | D3DRS_SRCBLEND
+        | D3DRS_DESTBLEND
+        | D3DRS_BLENDOP
+        | D3DRS_SEPARATEALPHABLENDENABLE
+        | D3DRS_SRCBLENDALPHA
+        | D3DRS_DESTBLENDALPHA
+        | D3DRS_BLENDOPALPHA
+        | D3DRS_COLORWRITEENABLE
+        | D3DRS_COLORWRITEENABLE1
+        | D3DRS_COLORWRITEENABLE2
+        | D3DRS_COLORWRITEENABLE3 => true,
+        _ => false,
+    }
+}
+
+/// Checks if a render state is part of the rasterizer state.
+pub fn is_rasterizer_state(state: D3DRENDERSTATETYPE) -> bool {
+    match state {
+        D3DRS_FILLMODE
+        | D3DRS_CULLMODE
+        | D3DRS_MULTISAMPLEANTIALIAS
+        | D3DRS_ANTIALIASEDLINEENABLE
+        | D3DRS_DEPTHBIAS
+        | D3DRS_SLOPESCALEDEPTHBIAS => true,
+        _ => false,
+    }
+}
+
+/// Converts a D3D9 fill mode to the D3D11 equivalent.
+fn d3d_fill_mode_to_d3d11(mode: D3DFILLMODE) -> D3D11_FILL_MODE {
+    match mode {
+        D3DFILL_SOLID => D3D11_FILL_SOLID,
+        D3DFILL_WIREFRAME => D3D11_FILL_WIREFRAME,
+        // D3D11 cannot draw only the vertices of a triangle.
+        D3DFILL_POINT => {
+            run_once!(|| warn!("Point fill mode is not supported, drawing wireframe instead"));
+            D3D11_FILL_WIREFRAME
+        }
+        _ => {
+            error!("Unknown fill mode: {}", mode);
+            D3D11_FILL_SOLID
+        }
+    }
+}
+
+/// Converts a D3D9 cull mode to the D3D11 equivalent.
+///
+/// D3D9 specifies which winding order gets culled, while D3D11 culls either
+/// the front or the back faces. We always consider clockwise triangles
+/// to be front facing, like D3D9 does.
+fn d3d_cull_mode_to_d3d11(mode: D3DCULL) -> D3D11_CULL_MODE {
+    match mode {
+        D3DCULL_NONE => D3D11_CULL_NONE,
+        D3DCULL_CW => D3D11_CULL_FRONT,
+        D3DCULL_CCW => D3D11_CULL_BACK,
+        _ => {
+            error!("Unknown cull mode: {}", mode);
+            D3D11_CULL_NONE
+        }
+    }
+}
+
+/// Returns the factor D3D9's depth bias has to be scaled by
+/// to get D3D11's depth bias for a certain depth buffer format.
+///
+/// D3D9 specifies the bias in depth buffer units, while D3D11 specifies it
+/// as a multiple of the smallest representable depth value.
+fn depth_bias_scale(fmt: D3DFORMAT) -> f32 {
+    match fmt {
+        D3DFMT_D16 | D3DFMT_D16_LOCKABLE => (1 << 16) as f32,
+        D3DFMT_D15S1 => (1 << 15) as f32,
+        D3DFMT_D24S8 | D3DFMT_D24X8 | D3DFMT_D24X4S4 => (1 << 24) as f32,
+        // For floating point formats the unit depends on the depth of the primitive,
+        // assume it's close to the far plane, where precision matters the most.
+        _ => (1 << 23) as f32,
+    }
+}
+
+/// Converts a D3D9 blend factor to the D3D11 equivalent.
+///
+/// Factors used for the alpha channel cannot refer to colors in D3D11,
+/// so they're replaced with the matching alpha factor.
+fn d3d_blend_to_d3d11(blend: D3DBLEND, alpha: bool) -> D3D11_BLEND {
+    match blend {
+        D3DBLEND_ZERO => D3D11_BLEND_ZERO,
+        D3DBLEND_ONE => D3D11_BLEND_ONE,
+        D3DBLEND_SRCCOLOR if alpha => D3D11_BLEND_SRC_ALPHA,
+        D3DBLEND_SRCCOLOR => D3D11_BLEND_SRC_COLOR,
+        D3DBLEND_INVSRCCOLOR if alpha => D3D11_BLEND_INV_SRC_ALPHA,
+        D3DBLEND_INVSRCCOLOR => D3D11_BLEND_INV_SRC_COLOR,
+        D3DBLEND_SRCALPHA => D3D11_BLEND_SRC_ALPHA,
+        D3DBLEND_INVSRCALPHA => D3D11_BLEND_INV_SRC_ALPHA,
+        D3DBLEND_DESTALPHA => D3D11_BLEND_DEST_ALPHA,
+        D3DBLEND_INVDESTALPHA => D3D11_BLEND_INV_DEST_ALPHA,
+        D3DBLEND_DESTCOLOR if alpha => D3D11_BLEND_DEST_ALPHA,
+        D3DBLEND_DESTCOLOR => D3D11_BLEND_DEST_COLOR,
+        D3DBLEND_INVDESTCOLOR if alpha => D3D11_BLEND_INV_DEST_ALPHA,
+        D3DBLEND_INVDESTCOLOR => D3D11_BLEND_INV_DEST_COLOR,
+        D3DBLEND_SRCALPHASAT => D3D11_BLEND_SRC_ALPHA_SAT,
+        D3DBLEND_BLENDFACTOR => D3D11_BLEND_BLEND_FACTOR,
+        D3DBLEND_INVBLENDFACTOR => D3D11_BLEND_INV_BLEND_FACTOR,
+        D3DBLEND_SRCCOLOR2 if alpha => D3D11_BLEND_SRC1_ALPHA,
+        D3DBLEND_SRCCOLOR2 => D3D11_BLEND_SRC1_COLOR,
+        D3DBLEND_INVSRCCOLOR2 if alpha => D3D11_BLEND_INV_SRC1_ALPHA,
+        D3DBLEND_INVSRCCOLOR2 => D3D11_BLEND_INV_SRC1_COLOR,
+        _ => {
+            error!("Unsupported blend factor: {}", blend);
+            D3D11_BLEND_ONE
+        }
+    }
+}
+
+/// Converts a D3D9 blend operation to the D3D11 equivalent.
+fn d3d_blend_op_to_d3d11(op: D3DBLENDOP) -> D3D11_BLEND_OP {
+    match op {
+        D3DBLENDOP_ADD => D3D11_BLEND_OP_ADD,
+        D3DBLENDOP_SUBTRACT => D3D11_BLEND_OP_SUBTRACT,
+        D3DBLENDOP_REVSUBTRACT => D3D11_BLEND_OP_REV_SUBTRACT,
+        D3DBLENDOP_MIN => D3D11_BLEND_OP_MIN,
+        D3DBLENDOP_MAX => D3D11_BLEND_OP_MAX,
+        _ => {
+            error!("Unsupported blend operation: {}", op);
+            D3D11_BLEND_OP_ADD
+        }
+    }
+}
+
+/// Converts a D3D9 color write mask to the D3D11 equivalent.
+fn d3d_write_mask_to_d3d11(mask: u32) -> u8 {
+    let mut ret = 0;
+
+    if mask & D3DCOLORWRITEENABLE_RED != 0 {
+        ret |= D3D11_COLOR_WRITE_ENABLE_RED;
+    }
+    if mask & D3DCOLORWRITEENABLE_GREEN != 0 {
+        ret |= D3D11_COLOR_WRITE_ENABLE_GREEN;
+    }
+    if mask & D3DCOLORWRITEENABLE_BLUE != 0 {
+        ret |= D3D11_COLOR_WRITE_ENABLE_BLUE;
+    }
+    if mask & D3DCOLORWRITEENABLE_ALPHA != 0 {
+        ret |= D3D11_COLOR_WRITE_ENABLE_ALPHA;
+    }
+
+    ret as u8
+}
+
+/// Blend factors and operation of either the color or the alpha channel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct BlendEquation {
+    src: D3DBLEND,
+    dest: D3DBLEND,
+    op: D3DBLENDOP,
+}
+
+impl BlendEquation {
+    /// Returns the D3D11 source and destination blend factors.
+    fn factors(&self, alpha: bool) -> (D3D11_BLEND, D3D11_BLEND) {
+        match self.src {
+            // These set both the source and destination factors.
+            D3DBLEND_BOTHSRCALPHA => (D3D11_BLEND_SRC_ALPHA, D3D11_BLEND_INV_SRC_ALPHA),
+            D3DBLEND_BOTHINVSRCALPHA => (D3D11_BLEND_INV_SRC_ALPHA, D3D11_BLEND_SRC_ALPHA),
+            src => (
+                d3d_blend_to_d3d11(src, alpha),
+                d3d_blend_to_d3d11(self.dest, alpha),
+            ),
+        }
+    }
+}
+
+/// Packed description of a blend state.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BlendKey {
+    enable: bool,
+    color: BlendEquation,
+    alpha: BlendEquation,
+    // Write masks of the first render targets, already converted to D3D11.
+    write_masks: [u8; MAX_WRITE_MASKS],
+}
+
+impl BlendKey {
+    /// Builds the description of the blend state from the current render states.
+    pub fn new(state: &DeviceState) -> Self {
+        let rs = |ty| state.get_render_state(ty);
+
+        let color = BlendEquation {
+            src: rs(D3DRS_SRCBLEND),
+            dest: rs(D3DRS_DESTBLEND),
+            op: rs(D3DRS_BLENDOP),
+        };
+
+        // Without separate alpha blending, the color equation is used for both.
+        let alpha = if rs(D3DRS_SEPARATEALPHABLENDENABLE) != 0 {
+            BlendEquation {
+                src: rs(D3DRS_SRCBLENDALPHA),
+                dest: rs(D3DRS_DESTBLENDALPHA),
+                op: rs(D3DRS_BLENDOPALPHA),
+            }
+        } else {
+            color
+        };
+
+        let write_masks = [
+            d3d_write_mask_to_d3d11(rs(D3DRS_COLORWRITEENABLE)),
+            d3d_write_mask_to_d3d11(rs(D3DRS_COLORWRITEENABLE1)),
+            d3d_write_mask_to_d3d11(rs(D3DRS_COLORWRITEENABLE2)),
+            d3d_write_mask_to_d3d11(rs(D3DRS_COLORWRITEENABLE3)),
+        ];
+
+        Self {
+            enable: rs(D3DRS_ALPHABLENDENABLE) != 0,
+            color,
+            alpha,
+            write_masks,
+        }
+    }
+
+    /// Converts this key to a D3D11 blend state description.
+    fn desc(&self) -> D3D11_BLEND_DESC {
+        let (src_blend, dest_blend) = self.color.factors(false);
+        let (src_blend_alpha, dest_blend_alpha) = self.alpha.factors(true);
+
+        let rt = D3D11_RENDER_TARGET_BLEND_DESC {
+            BlendEnable: self.enable as i32,
+            SrcBlend: src_blend,
+            DestBlend: dest_blend,
+            BlendOp: d3d_blend_op_to_d3d11(self.color.op),
+            SrcBlendAlpha: src_blend_alpha,
+            DestBlendAlpha: dest_blend_alpha,
+            BlendOpAlpha: d3d_blend_op_to_d3d11(self.alpha.op),
+            RenderTargetWriteMask: self.write_masks[0],
+        };
+
+        let mut render_targets = [rt; 8];
+
+        // The remaining render targets use the same mask as the last one D3D9 can set.
+        for (i, target) in render_targets.iter_mut().enumerate() {
+            target.RenderTargetWriteMask = self.write_masks[i.min(MAX_WRITE_MASKS - 1)];
+        }
+
+        // Only needed if the targets have different masks.
+        let independent = self.write_masks.iter().any(|&m| m != self.write_masks[0]);
+
+        D3D11_BLEND_DESC {
+            AlphaToCoverageEnable: 0,
+            IndependentBlendEnable: independent as i32,
+            RenderTarget: render_targets,
+        }
+    }
+}
+
+/// Packed description of a rasterizer state.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RasterizerKey {
+    fill_mode: D3DFILLMODE,
+    cull_mode: D3DCULL,
+    multisample: bool,
+    antialiased_lines: bool,
+    depth_bias: i32,
+    // Stored as its bit pattern, so the key can be hashed.
+    slope_scaled_depth_bias: u32,
+}
+
+impl RasterizerKey {
+    /// Builds the description of the rasterizer state from the current render states.
+    ///
+    /// The format of the bound depth buffer is needed to convert the depth bias.
+    pub fn new(state: &DeviceState, depth_format: D3DFORMAT) -> Self {
+        let rs = |ty| state.get_render_state(ty);
+
+        let depth_bias = f32::from_bits(rs(D3DRS_DEPTHBIAS)) * depth_bias_scale(depth_format);
+
+        Self {
+            fill_mode: rs(D3DRS_FILLMODE),
+            cull_mode: rs(D3DRS_CULLMODE),
+            multisample: rs(D3DRS_MULTISAMPLEANTIALIAS) != 0,
+            antialiased_lines: rs(D3DRS_ANTIALIASEDLINEENABLE) != 0,
+            depth_bias: depth_bias as i32,
+            slope_scaled_depth_bias: rs(D3DRS_SLOPESCALEDEPTHBIAS),
+        }
+    }
+
+    /// Converts this key to a D3D11 rasterizer state description.
+    fn desc(&self) -> D3D11_RASTERIZER_DESC {
+        D3D11_RASTERIZER_DESC {
+            FillMode: d3d_fill_mode_to_d3d11(self.fill_mode),
+            CullMode: d3d_cull_mode_to_d3d11(self.cull_mode),
+            // Clockwise triangles are front facing in D3D9.
+            FrontCounterClockwise: 0,
+            DepthBias: self.depth_bias,
+            DepthBiasClamp: 0.0,
+            SlopeScaledDepthBias: f32::from_bits(self.slope_scaled_depth_bias),
+            DepthClipEnable: 1,
+            ScissorEnable: 0,
+            MultisampleEnable: self.multisample as i32,
+            AntialiasedLineEnable: self.antialiased_lines as i32,
+        }
+    }
+}
+
+impl StateDesc for BlendKey {
+    type State = ID3D11BlendState;
+
+    fn create(&self, device: &ID3D11Device) -> Result<ComPtr<ID3D11BlendState>, Error> {
+        unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateBlendState(&self.desc(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create blend state"));
+            Ok(ComPtr::new(ptr))
+        }
+    }
+}
+
+impl StateDesc for RasterizerKey {
+    type State = ID3D11RasterizerState;
+
+    fn create(&self, device: &ID3D11Device) -> Result<ComPtr<ID3D11RasterizerState>, Error> {
+        unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateRasterizerState(&self.desc(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create rasterizer state"));
+            Ok(ComPtr::new(ptr))
+        }
+    }
+}
+
+/// Statistics about the state object caches.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PipelineStats {
+    /// Usage of the blend state cache.
+    pub blend: CacheStats,
+    /// Usage of the rasterizer state cache.
+    pub rasterizer: CacheStats,
+}
+
+/// Cache of the state objects created for the render states.
+///
+/// Each kind of state object is kept in its own bounded cache, whose capacity
+/// can be set with the `D3D9_STATE_CACHE_SIZE` environment variable.
+pub struct PipelineStates {
+    blend: StateCache<BlendKey, ID3D11BlendState>,
+    rasterizer: StateCache<RasterizerKey, ID3D11RasterizerState>,
+}
+
+impl PipelineStates {
+    /// Creates a new, empty cache.
+    pub fn new() -> Self {
+        let capacity = config::value(CAPACITY_VAR).unwrap_or(DEFAULT_CAPACITY);
+
+        Self {
+            blend: StateCache::new(capacity),
+            rasterizer: StateCache::new(capacity),
+        }
+    }
+
+    /// Retrieves the blend state matching a description, creating it if necessary.
+    pub fn blend_state(
+        &mut self,
+        device: &ID3D11Device,
+        key: BlendKey,
+    ) -> Result<ComPtr<ID3D11BlendState>, Error> {
+        self.blend.get(device, key)
+    }
+
+    /// Retrieves the rasterizer state matching a description, creating it if necessary.
+    pub fn rasterizer_state(
+        &mut self,
+        device: &ID3D11Device,
+        key: RasterizerKey,
+    ) -> Result<ComPtr<ID3D11RasterizerState>, Error> {
+        self.rasterizer.get(device, key)
+    }
+
+    /// Returns the usage statistics of the caches.
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats {
+            blend: self.blend.stats(),
+            rasterizer: self.rasterizer.stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cull_modes_map_to_d3d11() {
+        let modes = [
+            (D3DCULL_NONE, D3D11_CULL_NONE),
+            (D3DCULL_CW, D3D11_CULL_FRONT),
+            (D3DCULL_CCW, D3D11_CULL_BACK),
+        ];
+
+        for &(mode, expected) in &modes {
+            assert_eq!(d3d_cull_mode_to_d3d11(mode), expected);
+        }
+    }
+
+    #[test]
+    fn clockwise_triangles_are_drawn_by_default() {
+        // D3D9 culls counter-clockwise triangles by default, and clockwise ones are front facing.
+        let desc = RasterizerKey::new(&DeviceState::default(), D3DFMT_D24S8).desc();
+
+        assert_eq!(desc.CullMode, D3D11_CULL_BACK);
+        assert_eq!(desc.FrontCounterClockwise, 0);
+    }
+
+    #[test]
+    fn point_fill_mode_falls_back_to_wireframe() {
+        assert_eq!(d3d_fill_mode_to_d3d11(D3DFILL_POINT), D3D11_FILL_WIREFRAME);
+    }
+}
@@ -0,0 +1,252 @@
+//! Software implementation of `ProcessVertices`.
+//!
+//! Only the fixed function transform is emulated: vertices are transformed
+//! into screen space, while lighting, fog and texture coordinate generation
+//! are not applied.
+
+use std::{mem, ptr};
+
+use nalgebra::{Matrix4, Vector4};
+use winapi::shared::d3d9types::*;
+
+use crate::Error;
+
+/// Returns the size in bytes of a vertex element's type.
+pub fn decl_type_size(ty: u8) -> usize {
+    match ty as D3DDECLTYPE {
+        D3DDECLTYPE_FLOAT1 => 4,
+        D3DDECLTYPE_FLOAT2 => 8,
+        D3DDECLTYPE_FLOAT3 => 12,
+        D3DDECLTYPE_FLOAT4 => 16,
+        D3DDECLTYPE_SHORT4 | D3DDECLTYPE_SHORT4N | D3DDECLTYPE_USHORT4N => 8,
+        D3DDECLTYPE_FLOAT16_4 => 8,
+        D3DDECLTYPE_UNUSED => 0,
+        // All the other types are packed in 32 bits.
+        _ => 4,
+    }
+}
+
+/// Builds the list of vertex elements described by a flexible vertex format code.
+pub fn fvf_to_elements(fvf: u32) -> Vec<D3DVERTEXELEMENT9> {
+    let mut elems = Vec::new();
+    let mut offset = 0;
+
+    let mut push = |ty: D3DDECLTYPE, usage: D3DDECLUSAGE, index: u8| {
+        elems.push(D3DVERTEXELEMENT9 {
+            Stream: 0,
+            Offset: offset,
+            Type: ty as u8,
+            Method: D3DDECLMETHOD_DEFAULT as u8,
+            Usage: usage as u8,
+            UsageIndex: index,
+        });
+        offset += decl_type_size(ty as u8) as u16;
+    };
+
+    const FLOATS: [D3DDECLTYPE; 4] = [
+        D3DDECLTYPE_FLOAT1,
+        D3DDECLTYPE_FLOAT2,
+        D3DDECLTYPE_FLOAT3,
+        D3DDECLTYPE_FLOAT4,
+    ];
+
+    match fvf & D3DFVF_POSITION_MASK {
+        D3DFVF_XYZ => push(D3DDECLTYPE_FLOAT3, D3DDECLUSAGE_POSITION, 0),
+        D3DFVF_XYZRHW => push(D3DDECLTYPE_FLOAT4, D3DDECLUSAGE_POSITIONT, 0),
+        D3DFVF_XYZW => push(D3DDECLTYPE_FLOAT4, D3DDECLUSAGE_POSITION, 0),
+        pos @ D3DFVF_XYZB1..=D3DFVF_XYZB5 => {
+            push(D3DDECLTYPE_FLOAT3, D3DDECLUSAGE_POSITION, 0);
+
+            let mut betas = ((pos - D3DFVF_XYZB1) / 2 + 1) as usize;
+
+            let indices = if fvf & D3DFVF_LASTBETA_UBYTE4 != 0 {
+                Some(D3DDECLTYPE_UBYTE4)
+            } else if fvf & D3DFVF_LASTBETA_D3DCOLOR != 0 {
+                Some(D3DDECLTYPE_D3DCOLOR)
+            } else {
+                None
+            };
+
+            if indices.is_some() {
+                betas -= 1;
+            }
+
+            if betas > 0 {
+                push(FLOATS[betas - 1], D3DDECLUSAGE_BLENDWEIGHT, 0);
+            }
+
+            if let Some(ty) = indices {
+                push(ty, D3DDECLUSAGE_BLENDINDICES, 0);
+            }
+        }
+        _ => (),
+    }
+
+    if fvf & D3DFVF_NORMAL != 0 {
+        push(D3DDECLTYPE_FLOAT3, D3DDECLUSAGE_NORMAL, 0);
+    }
+
+    if fvf & D3DFVF_PSIZE != 0 {
+        push(D3DDECLTYPE_FLOAT1, D3DDECLUSAGE_PSIZE, 0);
+    }
+
+    if fvf & D3DFVF_DIFFUSE != 0 {
+        push(D3DDECLTYPE_D3DCOLOR, D3DDECLUSAGE_COLOR, 0);
+    }
+
+    if fvf & D3DFVF_SPECULAR != 0 {
+        push(D3DDECLTYPE_D3DCOLOR, D3DDECLUSAGE_COLOR, 1);
+    }
+
+    let tex_count = (fvf & D3DFVF_TEXCOUNT_MASK) >> D3DFVF_TEXCOUNT_SHIFT;
+
+    for i in 0..tex_count.min(8) {
+        // Two bits per coordinate set, where 0 means two components.
+        let ty = match (fvf >> (16 + i * 2)) & 0b11 {
+            0 => D3DDECLTYPE_FLOAT2,
+            1 => D3DDECLTYPE_FLOAT3,
+            2 => D3DDECLTYPE_FLOAT4,
+            _ => D3DDECLTYPE_FLOAT1,
+        };
+
+        push(ty, D3DDECLUSAGE_TEXCOORD, i as u8);
+    }
+
+    elems
+}
+
+/// Computes the size of a vertex in a certain stream.
+pub fn vertex_size(elems: &[D3DVERTEXELEMENT9], stream: u8) -> usize {
+    elems
+        .iter()
+        .filter(|e| e.Stream == stream)
+        .map(|e| e.Offset as usize + decl_type_size(e.Type))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Vertex data read back from a stream source.
+pub struct StreamData {
+    pub data: Vec<u8>,
+    pub offset: usize,
+    pub stride: usize,
+}
+
+impl StreamData {
+    /// Retrieves the bytes of an element of a certain vertex.
+    pub fn element(&self, elem: &D3DVERTEXELEMENT9, vertex: usize) -> Option<&[u8]> {
+        let start = self.offset + vertex * self.stride + elem.Offset as usize;
+        self.data.get(start..start + decl_type_size(elem.Type))
+    }
+}
+
+/// Transforms vertices from the fixed function pipeline's input to screen space.
+pub struct VertexProcessor {
+    /// Combined world, view and projection matrix.
+    pub transform: Matrix4<f32>,
+    pub viewport: D3DVIEWPORT9,
+    /// Whether to copy the non-position elements from the source vertices.
+    pub copy_data: bool,
+}
+
+impl VertexProcessor {
+    /// Processes `count` vertices starting at `start`, writing them to `output`.
+    pub fn process(
+        &self,
+        src_elems: &[D3DVERTEXELEMENT9],
+        streams: &[Option<StreamData>],
+        start: usize,
+        dest_elems: &[D3DVERTEXELEMENT9],
+        output: &mut [u8],
+    ) -> Result<(), Error> {
+        let dest_stride = vertex_size(dest_elems, 0);
+
+        let find_src = |usage: u8, index: u8| {
+            src_elems
+                .iter()
+                .find(|e| e.Usage == usage && e.UsageIndex == index)
+                .and_then(|e| {
+                    let stream = streams.get(e.Stream as usize)?.as_ref()?;
+                    Some((e, stream))
+                })
+        };
+
+        let (pos_elem, pos_stream) =
+            find_src(D3DDECLUSAGE_POSITION as u8, 0).ok_or(Error::InvalidCall)?;
+
+        for (i, vertex) in output.chunks_exact_mut(dest_stride).enumerate() {
+            let src_index = start + i;
+
+            for elem in dest_elems.iter().filter(|e| e.Stream == 0) {
+                let dest = &mut vertex[elem.Offset as usize..][..decl_type_size(elem.Type)];
+
+                if elem.Usage == D3DDECLUSAGE_POSITIONT as u8 {
+                    let pos = pos_stream
+                        .element(pos_elem, src_index)
+                        .ok_or(Error::InvalidCall)?;
+                    let pos = self.transform_position(read_position(pos, pos_elem.Type));
+                    write_floats(dest, pos.as_slice());
+                } else if self.copy_data {
+                    match find_src(elem.Usage, elem.UsageIndex) {
+                        Some((src, stream)) if src.Type == elem.Type => {
+                            let data = stream.element(src, src_index).ok_or(Error::InvalidCall)?;
+                            dest.copy_from_slice(data);
+                        }
+                        // The default diffuse color is opaque white.
+                        None if elem.Usage == D3DDECLUSAGE_COLOR as u8 && elem.UsageIndex == 0 => {
+                            dest.copy_from_slice(&0xFFFF_FFFFu32.to_ne_bytes());
+                        }
+                        _ => dest.fill(0),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Transforms a position to screen space, returning `(x, y, z, 1/w)`.
+    pub fn transform_position(&self, pos: Vector4<f32>) -> Vector4<f32> {
+        let clip = self.transform * pos;
+        let vp = &self.viewport;
+
+        let rhw = if clip.w != 0.0 { 1.0 / clip.w } else { 1.0 };
+
+        let x = vp.X as f32 + (1.0 + clip.x * rhw) * vp.Width as f32 / 2.0;
+        let y = vp.Y as f32 + (1.0 - clip.y * rhw) * vp.Height as f32 / 2.0;
+        let z = vp.MinZ + clip.z * rhw * (vp.MaxZ - vp.MinZ);
+
+        Vector4::new(x, y, z, rhw)
+    }
+}
+
+/// Reads a position element, filling in the missing components.
+pub fn read_position(data: &[u8], ty: u8) -> Vector4<f32> {
+    let mut pos = [0.0, 0.0, 0.0, 1.0];
+
+    let count = match ty as D3DDECLTYPE {
+        D3DDECLTYPE_FLOAT1 => 1,
+        D3DDECLTYPE_FLOAT2 => 2,
+        D3DDECLTYPE_FLOAT3 => 3,
+        D3DDECLTYPE_FLOAT4 => 4,
+        _ => {
+            error!("Unsupported position type for vertex processing: {}", ty);
+            0
+        }
+    };
+
+    for (i, c) in pos.iter_mut().take(count).enumerate() {
+        *c = unsafe {
+            ptr::read_unaligned(data[i * mem::size_of::<f32>()..].as_ptr() as *const f32)
+        };
+    }
+
+    Vector4::from(pos)
+}
+
+/// Writes as many floats as fit into an element.
+pub fn write_floats(dest: &mut [u8], values: &[f32]) {
+    for (chunk, value) in dest.chunks_exact_mut(mem::size_of::<f32>()).zip(values) {
+        chunk.copy_from_slice(&value.to_ne_bytes());
+    }
+}
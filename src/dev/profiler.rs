@@ -0,0 +1,280 @@
+//! Per-frame performance counters.
+//!
+//! When the `profiling` feature is enabled, the device measures how long every frame takes
+//! on the CPU and on the GPU, and counts the draw calls and state changes made by the game.
+//! A summary is logged every `D3D9_PROFILE_INTERVAL` frames (300 by default).
+//!
+//! Without the feature, all of these functions do nothing.
+
+use winapi::um::d3d11::ID3D11Device;
+
+use crate::d3d11::DeviceContext;
+
+#[cfg(feature = "profiling")]
+mod counters {
+    use std::cell::{Cell, RefCell};
+    use std::cmp;
+    use std::time::{Duration, Instant};
+
+    use winapi::um::d3d11::*;
+
+    use crate::core::config;
+    use crate::d3d11::{DeviceContext, Query};
+    use crate::Error;
+
+    /// Number of frames the GPU can lag behind before its timings are read back.
+    const FRAMES_IN_FLIGHT: usize = 4;
+
+    /// Pair of timestamps measuring how long the GPU took to process a frame.
+    struct GpuTimer {
+        disjoint: Query,
+        start: Query,
+        end: Query,
+        // Whether the start timestamp was issued, but not the end one.
+        running: Cell<bool>,
+        // Whether both timestamps were issued, and the result was not read yet.
+        pending: Cell<bool>,
+    }
+
+    impl GpuTimer {
+        fn new(device: &ID3D11Device) -> Result<Self, Error> {
+            Ok(Self {
+                disjoint: Query::new(device, D3D11_QUERY_TIMESTAMP_DISJOINT)?,
+                start: Query::new(device, D3D11_QUERY_TIMESTAMP)?,
+                end: Query::new(device, D3D11_QUERY_TIMESTAMP)?,
+                running: Cell::new(false),
+                pending: Cell::new(false),
+            })
+        }
+
+        fn begin(&self, ctx: &ID3D11DeviceContext) {
+            unsafe {
+                ctx.Begin(self.disjoint.as_async());
+                ctx.End(self.start.as_async());
+            }
+
+            self.running.set(true);
+            self.pending.set(false);
+        }
+
+        fn end(&self, ctx: &ID3D11DeviceContext) {
+            if !self.running.replace(false) {
+                return;
+            }
+
+            unsafe {
+                ctx.End(self.end.as_async());
+                ctx.End(self.disjoint.as_async());
+            }
+
+            self.pending.set(true);
+        }
+
+        /// Retrieves the time the GPU spent on the frame, if it finished processing it.
+        ///
+        /// Results which are not available yet are dropped, to avoid stalling the pipeline.
+        fn read(&self, ctx: &ID3D11DeviceContext) -> Option<Duration> {
+            if !self.pending.replace(false) {
+                return None;
+            }
+
+            let flags = D3D11_ASYNC_GETDATA_DONOTFLUSH;
+
+            let disjoint: D3D11_QUERY_DATA_TIMESTAMP_DISJOINT =
+                self.disjoint.read(ctx, flags).ok()??;
+            let start: u64 = self.start.read(ctx, flags).ok()??;
+            let end: u64 = self.end.read(ctx, flags).ok()??;
+
+            // The timestamps are meaningless if the GPU's clock changed during the frame.
+            if disjoint.Disjoint != 0 || disjoint.Frequency == 0 {
+                return None;
+            }
+
+            let ticks = u128::from(end.saturating_sub(start));
+            let nanos = ticks * 1_000_000_000 / u128::from(disjoint.Frequency);
+
+            Some(Duration::from_nanos(nanos as u64))
+        }
+    }
+
+    /// Counters accumulated since the last summary.
+    #[derive(Default)]
+    struct Totals {
+        frames: u32,
+        frame_time: Duration,
+        max_frame_time: Duration,
+        present_time: Duration,
+        gpu_frames: u32,
+        gpu_time: Duration,
+        draws: u64,
+        state_changes: u64,
+    }
+
+    impl Totals {
+        fn log(&self) {
+            let frames = f64::from(self.frames);
+            let ms = |time: Duration, count: f64| time.as_secs_f64() * 1000.0 / count;
+
+            let gpu = if self.gpu_frames > 0 {
+                format!("{:.2} ms", ms(self.gpu_time, f64::from(self.gpu_frames)))
+            } else {
+                "unknown".to_owned()
+            };
+
+            info!(
+                "{} frames: {:.2} ms per frame (slowest {:.2} ms), {:.2} ms in Present, \
+                 {} on the GPU, {:.0} draws and {:.0} state changes per frame",
+                self.frames,
+                ms(self.frame_time, frames),
+                ms(self.max_frame_time, 1.0),
+                ms(self.present_time, frames),
+                gpu,
+                self.draws as f64 / frames,
+                self.state_changes as f64 / frames,
+            );
+        }
+    }
+
+    /// Measures the frames presented by a device.
+    pub struct FrameProfiler {
+        // Number of frames between summaries.
+        interval: u32,
+        // Empty if timestamp queries are not supported.
+        timers: Vec<GpuTimer>,
+        current: Cell<usize>,
+        last_present: Cell<Option<Instant>>,
+        draws: Cell<u64>,
+        // Value of the device's state change counter at the last present.
+        state_changes: Cell<u64>,
+        totals: RefCell<Totals>,
+    }
+
+    impl FrameProfiler {
+        pub fn new(device: &ID3D11Device) -> Self {
+            let timers = (0..FRAMES_IN_FLIGHT)
+                .map(|_| GpuTimer::new(device))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap_or_else(|_| {
+                    warn!("Timestamp queries are not available, GPU times will not be measured");
+                    Vec::new()
+                });
+
+            Self {
+                interval: config::value("D3D9_PROFILE_INTERVAL")
+                    .filter(|&frames| frames > 0)
+                    .unwrap_or(300),
+                timers,
+                current: Cell::new(0),
+                last_present: Cell::new(None),
+                draws: Cell::new(0),
+                state_changes: Cell::new(0),
+                totals: RefCell::new(Totals::default()),
+            }
+        }
+
+        pub fn record_draw(&self) {
+            self.draws.set(self.draws.get() + 1);
+        }
+
+        pub fn present<R>(
+            &self,
+            ctx: &DeviceContext,
+            state_changes: u64,
+            present: impl FnOnce() -> R,
+        ) -> R {
+            // The frame ends with the commands which are submitted by presenting.
+            if let Some(timer) = self.timers.get(self.current.get()) {
+                timer.end(&ctx.lock());
+            }
+
+            let start = Instant::now();
+            let result = present();
+            let now = Instant::now();
+
+            let mut totals = self.totals.borrow_mut();
+
+            // The first frame's start is unknown.
+            if let Some(last) = self.last_present.replace(Some(now)) {
+                let frame_time = now - last;
+                totals.frames += 1;
+                totals.frame_time += frame_time;
+                totals.max_frame_time = cmp::max(totals.max_frame_time, frame_time);
+            }
+
+            totals.present_time += now - start;
+            totals.draws += self.draws.replace(0);
+            totals.state_changes += state_changes - self.state_changes.replace(state_changes);
+
+            // Reuse the oldest timer for the next frame, once its result was collected.
+            if !self.timers.is_empty() {
+                let next = (self.current.get() + 1) % self.timers.len();
+                self.current.set(next);
+
+                let timer = &self.timers[next];
+
+                if let Some(gpu_time) = timer.read(&ctx.immediate()) {
+                    totals.gpu_frames += 1;
+                    totals.gpu_time += gpu_time;
+                }
+
+                timer.begin(&ctx.lock());
+            }
+
+            if totals.frames >= self.interval {
+                totals.log();
+                *totals = Totals::default();
+            }
+
+            result
+        }
+    }
+}
+
+/// Measures the performance of the frames presented by a device.
+pub struct Profiler {
+    #[cfg(feature = "profiling")]
+    counters: counters::FrameProfiler,
+}
+
+impl Profiler {
+    /// Creates a new profiler for a device.
+    pub fn new(_device: &ID3D11Device) -> Self {
+        Self {
+            #[cfg(feature = "profiling")]
+            counters: counters::FrameProfiler::new(_device),
+        }
+    }
+
+    /// Counts a draw call made by the game.
+    #[inline]
+    pub fn record_draw(&self) {
+        #[cfg(feature = "profiling")]
+        self.counters.record_draw();
+    }
+
+    /// Runs the given function to present a frame, and measures the frame
+    /// which just ended.
+    ///
+    /// `state_changes` is the total number of state changes made by the game so far.
+    #[cfg(feature = "profiling")]
+    pub fn present<R>(
+        &self,
+        ctx: &DeviceContext,
+        state_changes: u64,
+        present: impl FnOnce() -> R,
+    ) -> R {
+        self.counters.present(ctx, state_changes, present)
+    }
+
+    /// Runs the given function to present a frame.
+    #[cfg(not(feature = "profiling"))]
+    #[inline]
+    pub fn present<R>(
+        &self,
+        _ctx: &DeviceContext,
+        _state_changes: u64,
+        present: impl FnOnce() -> R,
+    ) -> R {
+        present()
+    }
+}
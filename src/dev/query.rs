@@ -0,0 +1,202 @@
+use std::sync::atomic::AtomicU32;
+use std::{mem, ptr};
+
+use winapi::ctypes::c_void;
+use winapi::shared::{d3d9::*, d3d9types::*, minwindef::BOOL};
+use winapi::um::d3d11::*;
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+
+use com_impl::{implementation, interface, ComInterface};
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::d3d11;
+use crate::Error;
+
+use super::Device;
+
+// These flags are missing from `winapi`.
+const D3DISSUE_END: u32 = 1 << 0;
+const D3DISSUE_BEGIN: u32 = 1 << 1;
+const D3DGETDATA_FLUSH: u32 = 1 << 0;
+
+/// Converts a D3D9 query type to the equivalent D3D11 query type.
+///
+/// Returns `None` if the query type is not supported.
+pub fn d3d_query_to_d3d11(ty: D3DQUERYTYPE) -> Option<D3D11_QUERY> {
+    match ty {
+        D3DQUERYTYPE_EVENT => Some(D3D11_QUERY_EVENT),
+        D3DQUERYTYPE_OCCLUSION => Some(D3D11_QUERY_OCCLUSION),
+        D3DQUERYTYPE_TIMESTAMP => Some(D3D11_QUERY_TIMESTAMP),
+        _ => None,
+    }
+}
+
+/// Asynchronous query for retrieving information from the GPU.
+#[interface(IDirect3DQuery9)]
+pub struct Query {
+    refs: AtomicU32,
+    device: *const Device,
+    ty: D3DQUERYTYPE,
+    query: d3d11::Query,
+    // Occlusion queries also track their result with a predicate,
+    // which can be used to skip draws on the GPU.
+    predicate: Option<d3d11::Query>,
+}
+
+impl Query {
+    /// Creates a new query.
+    pub fn new(device: &Device, ty: D3DQUERYTYPE) -> Result<ComPtr<Self>, Error> {
+        let d3d11_ty = d3d_query_to_d3d11(ty).ok_or(Error::NotAvailable)?;
+        let dx11_device = device.dx11_device();
+        let query = d3d11::Query::new(dx11_device, d3d11_ty)?;
+
+        let predicate = if ty == D3DQUERYTYPE_OCCLUSION && dx11_device.supports_predication() {
+            Some(d3d11::Query::new_predicate(dx11_device)?)
+        } else {
+            None
+        };
+
+        device.add_child();
+
+        let query = Self {
+            __vtable: Box::new(Self::create_vtable()),
+            refs: AtomicU32::new(1),
+            device,
+            ty,
+            query,
+            predicate,
+        };
+
+        Ok(unsafe { new_com_interface(query) })
+    }
+
+    fn device(&self) -> &Device {
+        unsafe { &*self.device }
+    }
+
+    /// Retrieves the predicate which tracks whether any pixels passed this occlusion query.
+    ///
+    /// Returns `None` for other query types, or if the GPU doesn't support predication.
+    pub fn predicate(&self) -> Option<&d3d11::Query> {
+        self.predicate.as_ref()
+    }
+
+    /// Tries to read the query's result.
+    ///
+    /// Returns `None` if the data is not yet available.
+    fn read<T>(&self, flags: u32) -> Result<Option<T>, Error> {
+        let ctx = self.device().device_context().immediate();
+        self.query.read(&ctx, flags)
+    }
+}
+
+impl Drop for Query {
+    fn drop(&mut self) {
+        // This might free the device.
+        self.device().remove_child();
+    }
+}
+
+impl_iunknown!(struct Query: IUnknown, IDirect3DQuery9);
+
+#[implementation(IDirect3DQuery9)]
+impl Query {
+    /// Retrieves the device which created this query.
+    fn get_device(&self, ret: *mut *mut Device) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = com_ref(self.device);
+        Error::Success
+    }
+
+    /// Returns the type of this query.
+    fn get_type(&self) -> D3DQUERYTYPE {
+        self.ty
+    }
+
+    /// Returns the size of the data returned by this query.
+    fn get_data_size(&self) -> u32 {
+        match self.ty {
+            D3DQUERYTYPE_EVENT => mem::size_of::<BOOL>() as u32,
+            D3DQUERYTYPE_OCCLUSION => mem::size_of::<u32>() as u32,
+            D3DQUERYTYPE_TIMESTAMP => mem::size_of::<u64>() as u32,
+            _ => 0,
+        }
+    }
+
+    /// Marks the beginning or the end of the commands this query applies to.
+    fn issue(&self, flags: u32) -> Error {
+        let ctx = self.device().device_context().lock();
+        let query = self.query.as_async();
+
+        // Only occlusion queries have a beginning, the rest can only be ended.
+        if flags & D3DISSUE_BEGIN != 0 {
+            if self.ty != D3DQUERYTYPE_OCCLUSION {
+                return Error::InvalidCall;
+            }
+
+            unsafe {
+                ctx.Begin(query);
+
+                if let Some(ref predicate) = self.predicate {
+                    ctx.Begin(predicate.as_async());
+                }
+            }
+        }
+
+        if flags & D3DISSUE_END != 0 {
+            unsafe {
+                ctx.End(query);
+
+                if let Some(ref predicate) = self.predicate {
+                    ctx.End(predicate.as_async());
+                }
+            }
+        }
+
+        Error::Success
+    }
+
+    /// Polls the query for its result.
+    fn get_data(&self, data: *mut c_void, size: u32, flags: u32) -> Error {
+        let gpu_flags = if flags & D3DGETDATA_FLUSH == 0 {
+            D3D11_ASYNC_GETDATA_DONOTFLUSH
+        } else {
+            0
+        };
+
+        if !data.is_null() && size < self.get_data_size() {
+            return Error::InvalidCall;
+        }
+
+        // Retrieves the result, and writes it to the app's buffer if it asked for it.
+        macro_rules! read_into {
+            ($ty:ty, $conv:expr) => {{
+                match if_error!(self.read::<$ty>(gpu_flags)) {
+                    Some(value) => {
+                        if !data.is_null() {
+                            unsafe {
+                                ptr::write_unaligned(data as *mut _, $conv(value));
+                            }
+                        }
+                        Error::Success
+                    }
+                    None => Error::False,
+                }
+            }};
+        }
+
+        match self.ty {
+            D3DQUERYTYPE_EVENT => read_into!(BOOL, |done: BOOL| done),
+            // D3D9 only has a 32-bit counter for the number of pixels drawn.
+            D3DQUERYTYPE_OCCLUSION => {
+                read_into!(
+                    u64,
+                    |pixels: u64| std::cmp::min(pixels, u64::from(u32::max_value())) as u32
+                )
+            }
+            D3DQUERYTYPE_TIMESTAMP => read_into!(u64, |ts: u64| ts),
+            _ => Error::InvalidCall,
+        }
+    }
+}
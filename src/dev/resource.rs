@@ -6,17 +6,34 @@ use winapi::{
 use com_impl::implementation;
 use std::sync::atomic::AtomicU32;
 
+use winapi::um::d3d11::ID3D11Resource;
+
 use crate::core::*;
 use crate::d3d11;
 use crate::Error;
 
-use super::Device;
+use super::{Device, ManagedHandle, ManagedStorage, Resident};
+
+/// Checks if a texture or surface with certain usage flags can be locked.
+///
+/// Buffers can always be locked, even if they are static and in the default pool.
+pub fn check_lockable(usage: UsageFlags, pool: MemoryPool, ty: ResourceType) -> Result<(), Error> {
+    if pool == MemoryPool::Default && !usage.intersects(UsageFlags::DYNAMIC) {
+        error!(
+            "Cannot lock a static {:?} in the default pool, it must be created with D3DUSAGE_DYNAMIC",
+            ty
+        );
+        return Err(Error::InvalidCall);
+    }
+
+    Ok(())
+}
 
 /// Structure used as the base for all the D3D9 device resources.
 /// Use the `impl_resource` macro to implement its functions in inherited classes.
 pub struct Resource {
     refs: AtomicU32,
-    /// Need to hold a reference back to the parent device.
+    /// The parent device, which stays alive as long as this resource does.
     device: *const Device,
     /// Usage flags of this resource.
     usage: UsageFlags,
@@ -27,6 +44,8 @@ pub struct Resource {
     /// Priority of this resource.
     /// Higher value indicates this resource should be evicted last from VRAM.
     priority: u32,
+    /// If this resource is managed, the handle it was registered with.
+    managed: Option<ManagedHandle>,
 }
 
 impl Resource {
@@ -37,6 +56,14 @@ impl Resource {
         pool: MemoryPool,
         ty: ResourceType,
     ) -> Self {
+        let parent = unsafe { &*device };
+        parent.add_child();
+
+        // The device needs to know about these resources when it's reset.
+        if pool == MemoryPool::Default {
+            parent.add_default_resource();
+        }
+
         Self {
             refs: AtomicU32::new(1),
             device,
@@ -44,6 +71,7 @@ impl Resource {
             pool,
             ty,
             priority: 0,
+            managed: None,
         }
     }
 
@@ -66,6 +94,79 @@ impl Resource {
     pub fn pool(&self) -> MemoryPool {
         self.pool
     }
+
+    /// Retrieves the kind of resource this is.
+    pub fn resource_type(&self) -> ResourceType {
+        self.ty
+    }
+
+    /// Hands the system memory copy of a managed resource over to the device,
+    /// which uploads it to VRAM when it's used.
+    pub fn manage(&mut self, storage: ManagedStorage) {
+        debug_assert_eq!(self.pool, MemoryPool::Managed);
+        self.managed = Some(self.device().register_managed(storage));
+    }
+
+    /// Retrieves the VRAM copy of this resource, uploading it if necessary.
+    ///
+    /// Returns `None` if this resource is not managed.
+    pub fn resident(&self) -> Result<Option<Resident>, Error> {
+        match self.managed {
+            Some(handle) => self.device().make_resident(handle).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Changes the most detailed mip level used when sampling a managed texture.
+    pub fn set_managed_lod(&self, lod: u32) -> Result<(), Error> {
+        match self.managed {
+            Some(handle) => self.device().set_managed_lod(handle, lod),
+            None => Ok(()),
+        }
+    }
+
+    /// Notifies the device that the data of a managed resource was modified.
+    ///
+    /// `resource` is the system memory copy, which might belong to a parent texture.
+    pub fn mark_dirty(&self, resource: *mut ID3D11Resource) {
+        if self.pool == MemoryPool::Managed {
+            self.device()
+                .mark_managed_dirty(ManagedHandle::from_resource(resource));
+        }
+    }
+
+    /// Checks if the app is allowed to lock this resource.
+    ///
+    /// Textures in the default pool live in VRAM, so they can only be locked if they are dynamic.
+    pub fn check_lockable(&self) -> Result<(), Error> {
+        check_lockable(self.usage, self.pool, self.ty)
+    }
+
+    /// Retrieves the usage flags to use when mapping this resource.
+    pub fn map_usage(&self) -> UsageFlags {
+        if self.pool == MemoryPool::Managed {
+            // The system memory copy cannot be discarded.
+            self.usage - UsageFlags::WRITE_ONLY
+        } else {
+            self.usage
+        }
+    }
+}
+
+impl Drop for Resource {
+    fn drop(&mut self) {
+        if self.pool == MemoryPool::Default {
+            self.device().remove_default_resource();
+        }
+
+        if let Some(handle) = self.managed {
+            let _lock = self.device().lock();
+            self.device().unregister_managed(handle);
+        }
+
+        // This might free the device.
+        self.device().remove_child();
+    }
 }
 
 /*impl ComInterface<IUnknownVtbl> for Resource {
@@ -122,12 +223,17 @@ impl Resource {
         unimplemented!()
     }
 
-    // TODO: the functions below could be used to improve performance.
-
     /// Updates this resource's priority.
     fn set_priority(self: &mut Thunk, priority: u32) -> u32 {
+        let _lock = self.device().lock();
         let old = self.priority;
         self.priority = priority;
+
+        // Only managed resources can be evicted.
+        if let Some(handle) = self.managed {
+            self.device().set_managed_priority(handle, priority);
+        }
+
         old
     }
 
@@ -138,6 +244,9 @@ impl Resource {
 
     /// Pre loads resource to VRAM.
     fn pre_load(self: &Thunk) {
-        info!("Resource pre-loading is not yet implemented");
+        let _lock = self.device().lock();
+        if let Err(err) = self.resident() {
+            warn!("Failed to pre-load resource: {:?}", err);
+        }
     }
 }
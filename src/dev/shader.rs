@@ -1,5 +1,6 @@
 use std::slice;
 use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
 
 use winapi::shared::{d3d9::*, d3d9types::*};
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
@@ -9,7 +10,7 @@ use comptr::ComPtr;
 
 use crate::{core::*, Error};
 
-use super::Device;
+use super::{fvf_to_elements, Device};
 use std::ffi::c_void;
 use winapi::um::d3d11::ID3D11VertexShader;
 
@@ -73,7 +74,8 @@ macro_rules! impl_shader {
 pub struct VertexShader {
     refs: AtomicU32,
     device: *const Device,
-    code: Box<[u32]>,
+    // Shared with the input layout cache, which uses it as part of its keys.
+    code: Arc<[u32]>,
     dx11: ComPtr<ID3D11VertexShader>,
 }
 
@@ -95,11 +97,13 @@ impl VertexShader {
             ComPtr::new(p_vs)
         };
 
+        device.add_child();
+
         let vs = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
             device,
-            code,
+            code: code.into(),
             dx11,
         };
 
@@ -109,6 +113,20 @@ impl VertexShader {
     pub fn get_dx11(&self) -> &ComPtr<ID3D11VertexShader> {
         &self.dx11
     }
+
+    /// Retrieves the byte code of this shader.
+    pub fn code(&self) -> &Arc<[u32]> {
+        &self.code
+    }
+}
+
+impl Drop for VertexShader {
+    fn drop(&mut self) {
+        let device = unsafe { &*self.device };
+
+        // This might free the device.
+        device.remove_child();
+    }
 }
 
 impl_iunknown!(struct VertexShader: IUnknown, IDirect3DVertexShader9);
@@ -128,6 +146,8 @@ impl PixelShader {
     pub fn new(device: &Device, func: *const u32) -> Result<ComPtr<Self>, Error> {
         let code = tokens_to_box(func);
 
+        device.add_child();
+
         let ps = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
@@ -139,6 +159,15 @@ impl PixelShader {
     }
 }
 
+impl Drop for PixelShader {
+    fn drop(&mut self) {
+        let device = unsafe { &*self.device };
+
+        // This might free the device.
+        device.remove_child();
+    }
+}
+
 impl_iunknown!(struct PixelShader: IUnknown, IDirect3DPixelShader9);
 
 impl_shader!(PixelShader, IDirect3DPixelShader9);
@@ -149,6 +178,8 @@ pub struct VertexDeclaration {
     refs: AtomicU32,
     device: *const Device,
     elems: Box<[D3DVERTEXELEMENT9]>,
+    // The flexible vertex format this declaration was created from, if any.
+    fvf: u32,
 }
 
 impl VertexDeclaration {
@@ -185,15 +216,48 @@ impl VertexDeclaration {
             Box::from(elems)
         };
 
+        Self::with_elements(device, elems, 0)
+    }
+
+    /// Creates the declaration matching a flexible vertex format.
+    pub fn from_fvf(device: &Device, fvf: u32) -> ComPtr<Self> {
+        let elems = fvf_to_elements(fvf).into_boxed_slice();
+
+        Self::with_elements(device, elems, fvf)
+    }
+
+    fn with_elements(device: &Device, elems: Box<[D3DVERTEXELEMENT9]>, fvf: u32) -> ComPtr<Self> {
+        device.add_child();
+
         let vd = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
             device,
             elems,
+            fvf,
         };
 
         unsafe { new_com_interface(vd) }
     }
+
+    /// Retrieves the elements which make up this declaration.
+    pub fn elements(&self) -> &[D3DVERTEXELEMENT9] {
+        &self.elems
+    }
+
+    /// Retrieves the flexible vertex format this declaration was created from, or 0.
+    pub fn fvf(&self) -> u32 {
+        self.fvf
+    }
+}
+
+impl Drop for VertexDeclaration {
+    fn drop(&mut self) {
+        let device = unsafe { &*self.device };
+
+        // This might free the device.
+        device.remove_child();
+    }
 }
 
 impl_iunknown!(struct VertexDeclaration: IUnknown, IDirect3DVertexDeclaration9);
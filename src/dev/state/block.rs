@@ -9,23 +9,70 @@ use comptr::ComPtr;
 use crate::dev::Device;
 use crate::{core::*, Error};
 
+use super::{DeviceState, StateChange};
+
+/// The state saved in a state block.
+enum Contents {
+    /// A copy of the device's state.
+    /// Only the parts determined by the type are used.
+    Captured(D3DSTATEBLOCKTYPE, DeviceState),
+    /// The state changes made while the block was being recorded.
+    Recorded(Vec<StateChange>),
+}
+
 /// Object which records some portions of a device's state.
 #[interface(IDirect3DStateBlock9)]
 pub struct StateBlock {
     refs: AtomicU32,
     device: *mut Device,
+    contents: Contents,
 }
 
 impl StateBlock {
-    /// Creates a new empty state block.
-    pub fn new(device: &mut Device, _ty: D3DSTATEBLOCKTYPE) -> Result<ComPtr<Self>, Error> {
-        let _sb = Self {
+    /// Creates a new state block, containing the current values of a certain type of state.
+    pub fn new(device: &mut Device, ty: D3DSTATEBLOCKTYPE) -> Result<ComPtr<Self>, Error> {
+        match ty {
+            D3DSBT_ALL | D3DSBT_PIXELSTATE | D3DSBT_VERTEXSTATE => (),
+            _ => return Err(Error::InvalidCall),
+        }
+
+        let state = device.state().clone();
+
+        device.add_child();
+
+        let sb = Self {
+            __vtable: Box::new(Self::create_vtable()),
+            refs: AtomicU32::new(1),
+            device,
+            contents: Contents::Captured(ty, state),
+        };
+
+        Ok(unsafe { new_com_interface(sb) })
+    }
+
+    /// Creates a new state block from the state changes recorded by a device.
+    pub fn recorded(device: &mut Device, changes: Vec<StateChange>) -> ComPtr<Self> {
+        device.add_child();
+
+        let sb = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
             device,
+            contents: Contents::Recorded(changes),
         };
 
-        unimplemented!();
+        unsafe { new_com_interface(sb) }
+    }
+
+    fn device(&self) -> &Device {
+        unsafe { &*self.device }
+    }
+}
+
+impl Drop for StateBlock {
+    fn drop(&mut self) {
+        // This might free the device.
+        self.device().remove_child();
     }
 }
 
@@ -42,11 +89,42 @@ impl StateBlock {
 
     /// Captures the current values for the state which is already in this block.
     fn capture(&mut self) -> Error {
-        unimplemented!()
+        let device = unsafe { &*self.device };
+        let _lock = device.lock();
+
+        match &mut self.contents {
+            Contents::Captured(ty, state) => state.copy_from(device.state(), *ty),
+            Contents::Recorded(changes) => {
+                for change in changes {
+                    change.capture(device.state());
+                }
+            }
+        }
+
+        Error::Success
     }
 
     /// Applies the contained state to the parent device.
     fn apply(&self) -> Error {
-        unimplemented!()
+        let _lock = self.device().lock();
+
+        // The pointer was created from the device's own mutable reference,
+        // and the lock keeps other threads out of the device while its state is replaced.
+        let device = unsafe { &mut *self.device };
+
+        match &self.contents {
+            Contents::Captured(ty, state) => device.restore_state(state, *ty),
+            Contents::Recorded(changes) => {
+                let mut state = device.state().clone();
+
+                for change in changes {
+                    change.apply(&mut state);
+                }
+
+                device.restore_state(&state, D3DSBT_ALL);
+            }
+        }
+
+        Error::Success
     }
 }
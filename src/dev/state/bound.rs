@@ -0,0 +1,64 @@
+use std::{fmt, ptr};
+
+use winapi::um::unknwnbase::IUnknown;
+
+use crate::core::com_ref;
+
+/// Reference to a COM object which is bound to the pipeline.
+///
+/// D3D9 keeps bound objects alive until they are unbound,
+/// even if the app already released all of its references to them.
+pub struct Bound<T>(*mut T);
+
+impl<T> Bound<T> {
+    /// Takes a new reference to an object, which can be null.
+    pub fn new(object: *const T) -> Self {
+        Bound(com_ref(object))
+    }
+
+    /// Returns a reference to no object.
+    pub fn null() -> Self {
+        Bound(ptr::null_mut())
+    }
+
+    /// Returns the bound object, without taking a new reference to it.
+    pub fn as_ptr(&self) -> *mut T {
+        self.0
+    }
+}
+
+impl<T> Clone for Bound<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.0)
+    }
+}
+
+impl<T> Drop for Bound<T> {
+    fn drop(&mut self) {
+        if let Some(unknwn) = unsafe { (self.0 as *const IUnknown).as_ref() } {
+            unsafe {
+                unknwn.Release();
+            }
+        }
+    }
+}
+
+impl<T> Default for Bound<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> PartialEq for Bound<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for Bound<T> {}
+
+impl<T> fmt::Debug for Bound<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:p}", self.0)
+    }
+}
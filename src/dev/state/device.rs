@@ -10,30 +10,116 @@ use crate::dev::*;
 
 use super::*;
 
+/// Maximum number of vertex streams a device supports.
+pub const MAX_STREAMS: usize = 16;
+
+/// Maximum number of user clip planes a device supports.
+pub const MAX_CLIP_PLANES: usize = 8;
+
+/// Maximum number of lights which can be enabled at the same time.
+pub const MAX_ACTIVE_LIGHTS: usize = 8;
+
+/// Maximum number of texture blending stages a device supports.
+pub const MAX_TEXTURE_STAGES: usize = 8;
+
+/// Returns the light which is used when enabling a light which was never set.
+fn default_light() -> D3DLIGHT9 {
+    let mut light: D3DLIGHT9 = unsafe { mem::zeroed() };
+
+    light.Type = D3DLIGHT_DIRECTIONAL;
+    light.Diffuse = D3DCOLORVALUE {
+        r: 1.0,
+        g: 1.0,
+        b: 1.0,
+        a: 0.0,
+    };
+    light.Direction = D3DVECTOR {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    };
+
+    light
+}
+
+/// A vertex buffer bound to one of the input streams.
+#[derive(Debug, Clone, Default)]
+pub struct StreamSource {
+    pub buffer: Bound<VertexBuffer>,
+    pub offset: u32,
+    pub stride: u32,
+}
+
 /// Structure containing all render state.
 /// This includes pixel and vertex state.
 ///
 /// For a list of all state we must keep track of, see:
 /// https://docs.microsoft.com/en-us/windows/desktop/direct3d9/saving-vertex-states-with-a-stateblock
+#[derive(Clone)]
 pub struct DeviceState {
     vertex: VertexState,
     pixel: PixelState,
-    textures: [*mut BaseTexture; 20],
+    textures: [Bound<BaseTexture>; 20],
     viewport: D3DVIEWPORT9,
     transforms: HashMap<D3DTRANSFORMSTATETYPE, Matrix4<f32>>,
     material: D3DMATERIAL9,
+    // The app can use any index for a light, not only the first few.
+    lights: HashMap<u32, D3DLIGHT9>,
+    // Indices of the enabled lights, in the order they were enabled.
+    active_lights: Vec<u32>,
+    streams: [StreamSource; MAX_STREAMS],
+    indices: Bound<IndexBuffer>,
+    clip_planes: [[f32; 4]; MAX_CLIP_PLANES],
+    // Render states we do not know about, such as the ones used by vendor hacks.
+    // We store them so that the app can read back what it wrote.
+    unknown_rs: HashMap<D3DRENDERSTATETYPE, u32>,
 }
 
 impl DeviceState {
+    /// Returns the state a device starts out with, or is reset to.
+    ///
+    /// Depth testing is only enabled by default if the device
+    /// was created with an automatic depth / stencil buffer.
+    pub fn default_state(pp: &D3DPRESENT_PARAMETERS) -> Self {
+        let mut state = Self::default();
+
+        if pp.EnableAutoDepthStencil != 0 {
+            state.pixel.z_enable = D3DZB_TRUE;
+        }
+
+        state
+    }
+
+    /// Copies the state which is part of a certain state block type from another state object.
+    pub fn copy_from(&mut self, other: &DeviceState, ty: D3DSTATEBLOCKTYPE) {
+        match ty {
+            D3DSBT_PIXELSTATE => self.pixel = other.pixel.clone(),
+            D3DSBT_VERTEXSTATE => {
+                self.vertex = other.vertex.clone();
+                self.lights = other.lights.clone();
+                self.active_lights = other.active_lights.clone();
+            }
+            // Everything else is only part of the `ALL` state block.
+            _ => *self = other.clone(),
+        }
+    }
+
     pub fn set_render_state(&mut self, state: D3DRENDERSTATETYPE, value: u32) {
-        self.vertex.set_render_state(state, value);
-        self.pixel.set_render_state(state, value);
+        // Some states are shared by both the vertex and the pixel state.
+        let vs_known = self.vertex.set_render_state(state, value);
+        let ps_known = self.pixel.set_render_state(state, value);
+
+        // Unknown states are treated as no-ops, but we still remember their value.
+        if !vs_known && !ps_known && self.unknown_rs.insert(state, value).is_none() {
+            trace!("Ignoring unknown render state {}", state);
+        }
     }
 
     pub fn get_render_state(&self, state: D3DRENDERSTATETYPE) -> u32 {
         self.vertex
             .get_render_state(state)
             .or_else(|| self.pixel.get_render_state(state))
+            .or_else(|| self.unknown_rs.get(&state).cloned())
             .unwrap_or_default()
     }
 
@@ -86,7 +172,7 @@ impl DeviceState {
         }
 
         if let Some(tx) = self.textures.get_mut(stage as usize) {
-            *tx = texture;
+            *tx = Bound::new(texture);
         }
     }
 
@@ -97,32 +183,31 @@ impl DeviceState {
 
         self.textures
             .get(stage as usize)
-            .cloned()
-            .unwrap_or(ptr::null_mut())
+            .map_or(ptr::null_mut(), Bound::as_ptr)
     }
 
     pub fn set_vertex_shader(&mut self, shader: *const VertexShader) {
-        self.vertex.vertex_shader = shader;
+        self.vertex.vertex_shader = Bound::new(shader);
     }
 
     pub fn get_vertex_shader(&self) -> *const VertexShader {
-        self.vertex.vertex_shader
+        self.vertex.vertex_shader.as_ptr()
     }
 
     pub fn set_pixel_shader(&mut self, shader: *const PixelShader) {
-        self.pixel.pixel_shader = shader;
+        self.pixel.pixel_shader = Bound::new(shader);
     }
 
     pub fn get_pixel_shader(&self) -> *const PixelShader {
-        self.pixel.pixel_shader
+        self.pixel.pixel_shader.as_ptr()
     }
 
     pub fn set_vertex_declaration(&mut self, decl: *const VertexDeclaration) {
-        self.vertex.vertex_decl = decl;
+        self.vertex.vertex_decl = Bound::new(decl);
     }
 
     pub fn get_vertex_declaration(&self) -> *const VertexDeclaration {
-        self.vertex.vertex_decl
+        self.vertex.vertex_decl.as_ptr()
     }
 
     pub fn set_viewport(&mut self, vp: &D3DVIEWPORT9) {
@@ -141,6 +226,34 @@ impl DeviceState {
         self.transforms.get(&ty).cloned().unwrap_or_else(na::one)
     }
 
+    pub fn set_stream_source(&mut self, stream: u32, source: StreamSource) {
+        if let Some(s) = self.streams.get_mut(stream as usize) {
+            *s = source;
+        }
+    }
+
+    pub fn get_stream_source(&self, stream: u32) -> Option<StreamSource> {
+        self.streams.get(stream as usize).cloned()
+    }
+
+    pub fn set_indices(&mut self, indices: *mut IndexBuffer) {
+        self.indices = Bound::new(indices);
+    }
+
+    pub fn get_indices(&self) -> *mut IndexBuffer {
+        self.indices.as_ptr()
+    }
+
+    pub fn set_clip_plane(&mut self, index: u32, plane: [f32; 4]) {
+        if let Some(p) = self.clip_planes.get_mut(index as usize) {
+            *p = plane;
+        }
+    }
+
+    pub fn get_clip_plane(&self, index: u32) -> Option<[f32; 4]> {
+        self.clip_planes.get(index as usize).cloned()
+    }
+
     pub fn set_material(&mut self, mat: &D3DMATERIAL9) {
         self.material = *mat;
     }
@@ -148,6 +261,52 @@ impl DeviceState {
     pub fn get_material(&self) -> D3DMATERIAL9 {
         self.material
     }
+
+    pub fn set_light(&mut self, index: u32, light: &D3DLIGHT9) {
+        self.lights.insert(index, *light);
+    }
+
+    pub fn get_light(&self, index: u32) -> Option<D3DLIGHT9> {
+        self.lights.get(&index).cloned()
+    }
+
+    /// Enables or disables a light, creating it if it doesn't exist yet.
+    ///
+    /// Returns `false` if too many lights would be enabled.
+    pub fn light_enable(&mut self, index: u32, enable: bool) -> bool {
+        self.lights.entry(index).or_insert_with(default_light);
+
+        let active = self.active_lights.iter().position(|&i| i == index);
+
+        match (enable, active) {
+            (true, None) => {
+                if self.active_lights.len() >= MAX_ACTIVE_LIGHTS {
+                    return false;
+                }
+                self.active_lights.push(index);
+            }
+            (false, Some(pos)) => {
+                self.active_lights.remove(pos);
+            }
+            _ => (),
+        }
+
+        true
+    }
+
+    /// Checks if a light is enabled. Returns `None` if the light was never set.
+    pub fn is_light_enabled(&self, index: u32) -> Option<bool> {
+        if self.lights.contains_key(&index) {
+            Some(self.active_lights.contains(&index))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the currently enabled lights, in the order they were enabled.
+    pub fn active_lights(&self) -> impl Iterator<Item = &D3DLIGHT9> + '_ {
+        self.active_lights.iter().map(move |i| &self.lights[i])
+    }
 }
 
 impl Default for DeviceState {
@@ -155,17 +314,28 @@ impl Default for DeviceState {
         let mut state = Self {
             vertex: VertexState::default(),
             pixel: PixelState::default(),
-            textures: [ptr::null_mut(); 20],
+            textures: Default::default(),
             // The default viewport depends on the default render target's size.
             viewport: unsafe { mem::zeroed() },
             transforms: HashMap::with_capacity(4),
             material: unsafe { mem::zeroed() },
+            lights: HashMap::new(),
+            active_lights: Vec::with_capacity(MAX_ACTIVE_LIGHTS),
+            streams: Default::default(),
+            indices: Bound::null(),
+            clip_planes: [[0.0; 4]; MAX_CLIP_PLANES],
+            unknown_rs: HashMap::new(),
         };
 
         // The first texture stage has a different default state.
         state.pixel.ts[0].color_op = D3DTOP_MODULATE;
         state.pixel.ts[0].alpha_op = D3DTOP_SELECTARG1;
 
+        // Every stage uses its own set of texture coordinates.
+        for (i, ts) in state.pixel.ts.iter_mut().enumerate() {
+            ts.tex_coord_index = i as u32;
+        }
+
         state
     }
 }
@@ -10,7 +10,7 @@ macro_rules! impl_state {
         }
     } => {
         $(#[$attr])*
-        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        #[derive(Debug, Clone, Eq, PartialEq)]
         pub struct $sname {
             $(pub $rs_name: u32,)*
             pub ss: [SamplerState; $maxn],
@@ -33,11 +33,15 @@ macro_rules! impl_state {
         #[allow(single_match)]
         impl $sname {
             /// Sets a render state variable.
-            pub fn set_render_state(&mut self, state: D3DRENDERSTATETYPE, value: u32) {
+            ///
+            /// Returns `false` if this state doesn't keep track of the given variable.
+            pub fn set_render_state(&mut self, state: D3DRENDERSTATETYPE, value: u32) -> bool {
                 match state {
                     $($rs_enum => self.$rs_name = value,)*
-                    _ => (),
+                    _ => return false,
                 }
+
+                true
             }
 
             /// Retrieves the value of a render state variable.
@@ -3,6 +3,9 @@
 #[macro_use]
 mod macros;
 
+mod bound;
+pub use self::bound::Bound;
+
 mod pixel;
 pub(self) use self::pixel::PixelState;
 
@@ -10,7 +13,12 @@ mod vertex;
 pub(self) use self::vertex::VertexState;
 
 mod device;
-pub use self::device::DeviceState;
+pub use self::device::{
+    DeviceState, StreamSource, MAX_ACTIVE_LIGHTS, MAX_CLIP_PLANES, MAX_STREAMS, MAX_TEXTURE_STAGES,
+};
+
+mod record;
+pub use self::record::StateChange;
 
 mod block;
 pub use self::block::StateBlock;
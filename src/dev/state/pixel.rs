@@ -1,9 +1,9 @@
-use std::ptr;
-
 use winapi::shared::d3d9types::*;
 
 use crate::dev::shader::*;
 
+use super::Bound;
+
 impl_state! {
     /// Structure containing all state related to pixel processing.
     ///
@@ -24,9 +24,10 @@ impl_state! {
         alpha_ref: D3DRS_ALPHAREF = 0,
         alpha_func: D3DRS_ALPHAFUNC = D3DCMP_ALWAYS,
         dither_enable: D3DRS_DITHERENABLE = 0,
+        // Floating point states are stored as their bit patterns.
         fog_start: D3DRS_FOGSTART = 0,
-        fog_end: D3DRS_FOGEND = 1,
-        fog_density: D3DRS_FOGDENSITY = 1,
+        fog_end: D3DRS_FOGEND = 0x3f80_0000,
+        fog_density: D3DRS_FOGDENSITY = 0x3f80_0000,
         alpha_blend_enable: D3DRS_ALPHABLENDENABLE = 0,
         depth_bias: D3DRS_DEPTHBIAS = 0,
         stencil_enable: D3DRS_STENCILENABLE = 0,
@@ -111,6 +112,6 @@ impl_state! {
         alpha_arg0: D3DTSS_ALPHAARG0 = D3DTA_CURRENT,
         result_arg: D3DTSS_RESULTARG = D3DTA_CURRENT;
         // Extra state variables
-        pixel_shader: *const PixelShader = ptr::null(),
+        pixel_shader: Bound<PixelShader> = Bound::null(),
     }
 }
@@ -0,0 +1,108 @@
+use winapi::shared::d3d9types::*;
+
+use nalgebra::Matrix4;
+
+use crate::dev::shader::VertexDeclaration;
+use crate::dev::*;
+
+use super::{Bound, DeviceState, StreamSource, MAX_ACTIVE_LIGHTS};
+
+/// A single state change, recorded between `BeginStateBlock` and `EndStateBlock`.
+#[derive(Clone)]
+pub enum StateChange {
+    RenderState(D3DRENDERSTATETYPE, u32),
+    SamplerState(u32, D3DSAMPLERSTATETYPE, u32),
+    TextureStageState(u32, D3DTEXTURESTAGESTATETYPE, u32),
+    Texture(u32, Bound<BaseTexture>),
+    VertexShader(Bound<VertexShader>),
+    PixelShader(Bound<PixelShader>),
+    VertexDeclaration(Bound<VertexDeclaration>),
+    Viewport(D3DVIEWPORT9),
+    Transform(D3DTRANSFORMSTATETYPE, Matrix4<f32>),
+    Material(D3DMATERIAL9),
+    Light(u32, D3DLIGHT9),
+    LightEnable(u32, bool),
+    ClipPlane(u32, [f32; 4]),
+    StreamSource(u32, StreamSource),
+    Indices(Bound<IndexBuffer>),
+}
+
+impl StateChange {
+    /// Applies this change to a device's state.
+    pub fn apply(&self, state: &mut DeviceState) {
+        match *self {
+            StateChange::RenderState(ty, value) => state.set_render_state(ty, value),
+            StateChange::SamplerState(sampler, ty, value) => {
+                state.set_sampler_state(sampler, ty, value)
+            }
+            StateChange::TextureStageState(stage, ty, value) => {
+                state.set_texture_stage_state(stage, ty, value)
+            }
+            StateChange::Texture(stage, ref texture) => state.set_texture(stage, texture.as_ptr()),
+            StateChange::VertexShader(ref vs) => state.set_vertex_shader(vs.as_ptr()),
+            StateChange::PixelShader(ref ps) => state.set_pixel_shader(ps.as_ptr()),
+            StateChange::VertexDeclaration(ref decl) => state.set_vertex_declaration(decl.as_ptr()),
+            StateChange::Viewport(ref vp) => state.set_viewport(vp),
+            StateChange::Transform(ty, mat) => state.set_transform(ty, mat),
+            StateChange::Material(ref mat) => state.set_material(mat),
+            StateChange::Light(index, ref light) => state.set_light(index, light),
+            StateChange::LightEnable(index, enable) => {
+                // The limit depends on which lights are enabled when the block is applied.
+                if !state.light_enable(index, enable) {
+                    warn!(
+                        "Cannot enable more than {} lights, ignoring light {}",
+                        MAX_ACTIVE_LIGHTS, index
+                    );
+                }
+            }
+            StateChange::ClipPlane(index, plane) => state.set_clip_plane(index, plane),
+            StateChange::StreamSource(stream, ref source) => {
+                state.set_stream_source(stream, source.clone())
+            }
+            StateChange::Indices(ref indices) => state.set_indices(indices.as_ptr()),
+        }
+    }
+
+    /// Replaces the recorded value with the one currently set in a device's state.
+    pub fn capture(&mut self, state: &DeviceState) {
+        match self {
+            StateChange::RenderState(ty, value) => *value = state.get_render_state(*ty),
+            StateChange::SamplerState(sampler, ty, value) => {
+                *value = state.get_sampler_state(*sampler, *ty)
+            }
+            StateChange::TextureStageState(stage, ty, value) => {
+                *value = state.get_texture_stage_state(*stage, *ty)
+            }
+            StateChange::Texture(stage, texture) => {
+                *texture = Bound::new(state.get_texture(*stage))
+            }
+            StateChange::VertexShader(vs) => *vs = Bound::new(state.get_vertex_shader()),
+            StateChange::PixelShader(ps) => *ps = Bound::new(state.get_pixel_shader()),
+            StateChange::VertexDeclaration(decl) => {
+                *decl = Bound::new(state.get_vertex_declaration())
+            }
+            StateChange::Viewport(vp) => *vp = state.get_viewport(),
+            StateChange::Transform(ty, mat) => *mat = state.get_transform(*ty),
+            StateChange::Material(mat) => *mat = state.get_material(),
+            StateChange::Light(index, light) => {
+                if let Some(current) = state.get_light(*index) {
+                    *light = current;
+                }
+            }
+            StateChange::LightEnable(index, enable) => {
+                *enable = state.is_light_enabled(*index).unwrap_or(false)
+            }
+            StateChange::ClipPlane(index, plane) => {
+                if let Some(current) = state.get_clip_plane(*index) {
+                    *plane = current;
+                }
+            }
+            StateChange::StreamSource(stream, source) => {
+                if let Some(current) = state.get_stream_source(*stream) {
+                    *source = current;
+                }
+            }
+            StateChange::Indices(indices) => *indices = Bound::new(state.get_indices()),
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::ptr;
 use std::sync::atomic::{AtomicU32, Ordering};
 
 use winapi::shared::{d3d9::*, d3d9types::*, guiddef::GUID, windef::RECT};
@@ -11,7 +12,7 @@ use crate::core::{fmt::dxgi_format_to_d3d, msample::dxgi_samples_to_d3d9, *};
 use crate::d3d11;
 use crate::Error;
 
-use super::{Device, Resource};
+use super::{Device, Resource, SwapChain};
 
 /// Represents a 2D contiguous array of pixels.
 #[interface(IDirect3DSurface9)]
@@ -22,6 +23,30 @@ pub struct Surface {
     texture: d3d11::Texture2D,
     // Extra data required for this surface type.
     data: SurfaceData,
+    // CPU-accessible copy of the texture, used for locking GPU-only surfaces.
+    staging: Option<d3d11::Texture2D>,
+    // The object returned by `GetContainer`.
+    container: Container,
+}
+
+/// The object which contains a surface.
+pub enum Container {
+    /// Standalone surfaces are contained by the device.
+    Device,
+    /// This surface is a level of a texture, which it keeps alive.
+    Texture(ComPtr<IUnknown>),
+    /// This surface is the back buffer of a swap chain.
+    ///
+    /// The swap chain owns its back buffer, so this is a weak pointer to avoid a reference cycle.
+    /// The swap chain resets it once it releases the back buffer.
+    SwapChain(*const SwapChain),
+}
+
+impl Container {
+    /// Creates a new reference to the texture containing a surface.
+    pub fn texture<T>(texture: &T) -> Self {
+        Container::Texture(ComPtr::new(com_ref(texture) as *mut IUnknown))
+    }
 }
 
 /// Extra information required to fully describe a surface.
@@ -33,10 +58,17 @@ pub enum SurfaceData {
     None,
     /// This surface is owning a render target.
     RenderTarget(ComPtr<ID3D11RenderTargetView>),
-    /// This surface is owning a depth / stencil buffer.
-    DepthStencil(ComPtr<ID3D11DepthStencilView>),
+    /// This surface is owning a depth / stencil buffer,
+    /// which might also be sampled by shaders.
+    DepthStencil(
+        ComPtr<ID3D11DepthStencilView>,
+        Option<ComPtr<ID3D11ShaderResourceView>>,
+    ),
     /// This surface is part of a bigger texture.
     SubResource(u32),
+    /// This surface is a `NULL` render target of a certain size.
+    /// Nothing is bound when it's set, so only the depth buffer gets written.
+    NullRenderTarget(u32, u32),
 }
 
 impl Surface {
@@ -47,6 +79,18 @@ impl Surface {
         usage: UsageFlags,
         pool: MemoryPool,
         data: SurfaceData,
+    ) -> ComPtr<Self> {
+        Self::with_staging(device, texture, None, usage, pool, data)
+    }
+
+    /// Creates a new surface which can be locked through a staging texture.
+    pub fn with_staging(
+        device: *const Device,
+        texture: d3d11::Texture2D,
+        staging: Option<d3d11::Texture2D>,
+        usage: UsageFlags,
+        pool: MemoryPool,
+        data: SurfaceData,
     ) -> ComPtr<Self> {
         let surface = Self {
             __vtable: Box::new(Self::create_vtable()),
@@ -54,11 +98,30 @@ impl Surface {
             refs: AtomicU32::new(1),
             texture,
             data,
+            staging,
+            container: Container::Device,
         };
 
         unsafe { new_com_interface(surface) }
     }
 
+    /// Returns the width and height of this surface.
+    ///
+    /// Null render targets have the size they were created with, not the one of their placeholder.
+    pub fn size(&self) -> (u32, u32) {
+        if let SurfaceData::NullRenderTarget(width, height) = self.data {
+            (width, height)
+        } else {
+            let desc = self.texture.desc();
+            (desc.Width, desc.Height)
+        }
+    }
+
+    /// Changes the object this surface is a part of.
+    pub fn set_container(&mut self, container: Container) {
+        self.container = container;
+    }
+
     /// Retrieves a reference to the subresource this surface represents.
     pub fn subresource(&self) -> (*mut ID3D11Resource, u32) {
         let resource = self.texture.as_resource();
@@ -71,6 +134,28 @@ impl Surface {
         (resource, subresource)
     }
 
+    /// Reads back the pixels of this surface, which must have 32 bits per pixel.
+    ///
+    /// Returns the surface's width and height, and its pixels one row after the other.
+    pub fn read_pixels(&self) -> Result<(u32, u32, Vec<u32>), Error> {
+        let (_, subres) = self.subresource();
+        let device = self.device();
+
+        self.texture.read_pixels(
+            device.dx11_device(),
+            &device.device_context().immediate(),
+            subres,
+        )
+    }
+
+    /// Checks if this surface can be set as a render target.
+    pub fn is_render_target(&self) -> bool {
+        match self.data {
+            SurfaceData::RenderTarget(_) | SurfaceData::NullRenderTarget(..) => true,
+            _ => false,
+        }
+    }
+
     /// If this surface is a render target, retrieves the associated RT view.
     pub fn render_target_view(&self) -> Option<&mut ID3D11RenderTargetView> {
         if let SurfaceData::RenderTarget(ref view) = self.data {
@@ -82,7 +167,16 @@ impl Surface {
 
     /// If this surface is a depth / stencil buffer, retrieves the associated DS view.
     pub fn depth_stencil_view(&self) -> Option<&mut ID3D11DepthStencilView> {
-        if let SurfaceData::DepthStencil(ref view) = self.data {
+        if let SurfaceData::DepthStencil(ref view, _) = self.data {
+            Some(view.as_mut())
+        } else {
+            None
+        }
+    }
+
+    /// If this surface is a depth / stencil buffer, retrieves the view used to sample its depth.
+    pub fn depth_shader_view(&self) -> Option<&mut ID3D11ShaderResourceView> {
+        if let SurfaceData::DepthStencil(_, Some(ref view)) = self.data {
             Some(view.as_mut())
         } else {
             None
@@ -110,8 +204,14 @@ impl ComInterface<IDirect3DResource9Vtbl> for Surface {
 #[implementation(IDirect3DSurface9)]
 impl Surface {
     /// Gets the container of this resource.
-    fn get_container(&self, _riid: &GUID, _ret: *mut usize) -> Error {
-        unimplemented!()
+    fn get_container(&self, riid: &GUID, ret: *mut usize) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+
+        match self.container {
+            Container::Device => query_interface(self.device(), riid, ret),
+            Container::Texture(ref texture) => query_interface(texture.as_mut(), riid, ret),
+            Container::SwapChain(swap_chain) => query_interface(swap_chain, riid, ret),
+        }
     }
 
     /// Retrieves a description of this surface.
@@ -120,10 +220,17 @@ impl Surface {
 
         let desc = self.texture.desc();
 
-        ret.Width = desc.Width;
-        ret.Height = desc.Height;
+        let (width, height) = self.size();
+        ret.Width = width;
+        ret.Height = height;
+
+        ret.Format = if let SurfaceData::NullRenderTarget(..) = self.data {
+            // The texture is just a placeholder.
+            fmt::NULL
+        } else {
+            dxgi_format_to_d3d(desc.Format)
+        };
 
-        ret.Format = dxgi_format_to_d3d(desc.Format);
         ret.Type = D3DRTYPE_SURFACE;
 
         ret.Usage = self.usage().bits();
@@ -139,15 +246,56 @@ impl Surface {
     // -- Memory mapping functions --
 
     fn lock_rect(&mut self, ret: *mut D3DLOCKED_RECT, _r: *const RECT, flags: LockFlags) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
         let (res, subres) = self.subresource();
-        *ret = if_error!(self.device_context().map(res, subres, flags, self.usage()));
+        let ctx = self.device_context();
+
+        if let Some(ref staging) = self.staging {
+            let staging = staging.as_resource();
+
+            // Download the current contents, unless the app wants to overwrite them.
+            if !flags.intersects(LockFlags::DISCARD) {
+                unsafe {
+                    ctx.lock()
+                        .CopySubresourceRegion(staging, 0, 0, 0, 0, res, subres, ptr::null());
+                }
+            }
+
+            *ret = if_error!(ctx.map(staging, 0, flags, self.usage()));
+        } else {
+            // Offscreen plain surfaces are always kept in system memory.
+            if !matches!(self.data, SurfaceData::None) {
+                if_error!(self.check_lockable());
+            }
+
+            *ret = if_error!(ctx.map(res, subres, flags, self.map_usage()));
+        }
+
         Error::Success
     }
 
     fn unlock_rect(&self) -> Error {
+        let _lock = self.device().lock();
         let (res, subres) = self.subresource();
-        self.device_context().unmap(res, subres);
+        let ctx = self.device_context();
+
+        if let Some(ref staging) = self.staging {
+            let staging = staging.as_resource();
+
+            ctx.unmap(staging, 0);
+
+            // Upload the new contents back to the GPU.
+            unsafe {
+                ctx.lock()
+                    .CopySubresourceRegion(res, subres, 0, 0, 0, staging, 0, ptr::null());
+            }
+        } else {
+            ctx.unmap(res, subres);
+            // Surfaces of managed textures share their system memory copy.
+            self.mark_dirty(res);
+        }
+
         Error::Success
     }
 
@@ -1,19 +1,31 @@
-use std::{cmp, mem, ptr, sync::atomic::AtomicU32};
-
-use winapi::shared::{d3d9::*, d3d9types::*, dxgi::*, dxgitype::*, windef::HWND, winerror};
+use std::{
+    cell::{Cell, RefCell},
+    cmp, mem, ptr,
+    sync::atomic::AtomicU32,
+    time::Instant,
+};
+
+use winapi::shared::{
+    d3d9::*,
+    d3d9types::*,
+    dxgi::*,
+    dxgiformat::*,
+    dxgitype::*,
+    windef::{HWND, POINT, RECT},
+    winerror,
+};
 use winapi::um::d3d11::*;
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
 use winapi::um::winuser;
-use winapi::Interface;
 
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
 
-use crate::core::{fmt::d3d_display_format_to_dxgi, msample::d3d9_to_dxgi_samples, *};
+use crate::core::{fmt::DESKTOP_FORMAT, *};
 use crate::d3d11;
 use crate::Error;
 
-use super::{Device, Surface, SurfaceData};
+use super::{Container, Device, Surface, SurfaceData};
 
 /// Represents a swap chain, which is a queue of buffers
 /// on which the app can draw.
@@ -24,16 +36,93 @@ use super::{Device, Surface, SurfaceData};
 #[interface(IDirect3DSwapChain9)]
 pub struct SwapChain {
     refs: AtomicU32,
-    // Parent device of this interface.
+    // Parent device of this interface, which stays alive as long as we do.
     parent: *const Device,
     // The equivalent DXGI interface.
-    swap_chain: ComPtr<IDXGISwapChain>,
+    swap_chain: d3d11::SwapChain,
+    // Surface representing the back buffer, shared by every call to `GetBackBuffer`.
+    // DXGI only allows accessing the first buffer of a discarding swap chain.
+    back_buffer: Option<ComPtr<Surface>>,
     // Store these for retrieving them later.
     pp: D3DPRESENT_PARAMETERS,
     // Determines how many vblanks to wait before presenting:
     // 0 -> no vsync
     // 1 through 4 -> vsync, with `refresh rate = (monitor Hz / sync_interval)`.
     sync_interval: u32,
+    // The gamma ramp last set by the app.
+    gamma_ramp: Cell<D3DGAMMARAMP>,
+    // When the last frame was presented, used to estimate the raster position.
+    last_present: Cell<Instant>,
+    // Copy of the last presented frame.
+    // Only kept up to date once the app starts reading back the front buffer.
+    front_buffer: RefCell<Option<d3d11::Texture2D>>,
+    // Set while a full screen swap chain is presented to a borderless window instead,
+    // so that GDI dialogs can be shown on top of it.
+    dialog_box_mode: Cell<bool>,
+    // Whether this swap chain was created by the app, rather than being an implicit one.
+    additional: bool,
+}
+
+/// Refresh rate assumed when the output doesn't report one.
+const DEFAULT_REFRESH_RATE: u32 = 60;
+
+/// Ratio between the total number of lines in a refresh and the visible ones.
+///
+/// DXGI doesn't expose the display timings, so this is an approximation
+/// based on common video modes, where the vertical blank is roughly 4% of a refresh.
+const TOTAL_LINES_RATIO: f64 = 1.04;
+
+/// Returns a gamma ramp which leaves the colors unchanged.
+fn identity_gamma_ramp() -> D3DGAMMARAMP {
+    let mut ramp: D3DGAMMARAMP = unsafe { mem::zeroed() };
+
+    for i in 0..256 {
+        // Map [0; 255] to [0; 65535].
+        let value = (i * 257) as u16;
+        ramp.red[i] = value;
+        ramp.green[i] = value;
+        ramp.blue[i] = value;
+    }
+
+    ramp
+}
+
+/// Samples a 256-entry gamma ramp at a position in the [0; 1] range.
+fn sample_gamma_ramp(ramp: &[u16; 256], pos: f32) -> f32 {
+    let pos = pos.max(0.0).min(1.0) * 255.0;
+
+    let lo = pos.floor() as usize;
+    let hi = cmp::min(lo + 1, 255);
+    let t = pos - lo as f32;
+
+    let lo = f32::from(ramp[lo]) / 65535.0;
+    let hi = f32::from(ramp[hi]) / 65535.0;
+
+    lo + (hi - lo) * t
+}
+
+/// Converts a pixel from a back buffer format to `D3DFMT_A8R8G8B8`.
+fn convert_to_argb(fmt: DXGI_FORMAT, pixel: u32) -> Option<u32> {
+    let (r, g, b) = match fmt {
+        DXGI_FORMAT_B8G8R8A8_UNORM
+        | DXGI_FORMAT_B8G8R8A8_UNORM_SRGB
+        | DXGI_FORMAT_B8G8R8X8_UNORM
+        | DXGI_FORMAT_B8G8R8X8_UNORM_SRGB => {
+            ((pixel >> 16) & 0xFF, (pixel >> 8) & 0xFF, pixel & 0xFF)
+        }
+        DXGI_FORMAT_R8G8B8A8_UNORM | DXGI_FORMAT_R8G8B8A8_UNORM_SRGB => {
+            (pixel & 0xFF, (pixel >> 8) & 0xFF, (pixel >> 16) & 0xFF)
+        }
+        DXGI_FORMAT_R10G10B10A2_UNORM => (
+            (pixel & 0x3FF) >> 2,
+            ((pixel >> 10) & 0x3FF) >> 2,
+            ((pixel >> 20) & 0x3FF) >> 2,
+        ),
+        _ => return None,
+    };
+
+    // The front buffer is always opaque.
+    Some(0xFF00_0000 | (r << 16) | (g << 8) | b)
 }
 
 impl SwapChain {
@@ -45,212 +134,357 @@ impl SwapChain {
         pp: &mut D3DPRESENT_PARAMETERS,
         window: HWND,
     ) -> Result<ComPtr<Self>, Error> {
-        // First we need to set up the description of this swap chain.
-        let mut sc_desc = {
-            // Fill in the description of the back buffer.
-            let buffer_desc = {
-                // Determine the dimensions of the back buffer.
-                let (width, height) = {
-                    if pp.BackBufferWidth * pp.BackBufferHeight == 0 {
-                        // In full-screen modes, we need to be told the exact resolution.
-                        if pp.Windowed == 0 {
-                            return Err(Error::InvalidCall);
-                        }
-
-                        // If either of these was 0, the app requested us to use the window's size.
-                        unsafe {
-                            let mut r = mem::zeroed();
-
-                            winuser::GetClientRect(window, &mut r);
-
-                            let width = (r.right - r.left) as u32;
-                            let height = (r.bottom - r.top) as u32;
-
-                            // We have to update these elements of the structure.
-                            pp.BackBufferWidth = width;
-                            pp.BackBufferHeight = height;
-
-                            (width, height)
-                        }
-                    } else {
-                        (pp.BackBufferWidth, pp.BackBufferHeight)
-                    }
-                };
+        let swap_chain = d3d11::SwapChain::new(device, factory, pp, window)?;
+        Self::apply_fullscreen_state(parent, &swap_chain, pp)?;
+        let back_buffer = Self::create_back_buffer(parent, &swap_chain)?;
 
-                let refresh_rate = DXGI_RATIONAL {
-                    Numerator: pp.FullScreen_RefreshRateInHz,
-                    Denominator: 1,
-                };
+        let pp = *pp;
 
-                // Determine the back buffer format.
-                // We take a mut reference because we have to update the format
-                // with the actual format we will be using.
-                let fmt = &mut pp.BackBufferFormat;
+        // Clamp this to 4.
+        let sync_interval = cmp::min(pp.PresentationInterval, 4);
 
-                // If it's unknown, use the display's one.
-                if *fmt == D3DFMT_UNKNOWN {
-                    *fmt = D3DFMT_A8R8G8B8;
-                }
+        parent.add_child();
 
-                DXGI_MODE_DESC {
-                    Width: width,
-                    Height: height,
-                    RefreshRate: refresh_rate,
-                    Format: d3d_display_format_to_dxgi(*fmt),
-                    ScanlineOrdering: DXGI_MODE_SCANLINE_ORDER_UNSPECIFIED,
-                    Scaling: DXGI_MODE_SCALING_UNSPECIFIED,
-                }
-            };
+        let swap_chain = Self {
+            __vtable: Box::new(Self::create_vtable()),
+            refs: AtomicU32::new(1),
+            parent,
+            swap_chain,
+            back_buffer: Some(back_buffer),
+            pp,
+            sync_interval,
+            gamma_ramp: Cell::new(identity_gamma_ramp()),
+            last_present: Cell::new(Instant::now()),
+            front_buffer: RefCell::new(None),
+            dialog_box_mode: Cell::new(false),
+            additional: false,
+        };
 
-            let sample_desc = {
-                let count = if pp.SwapEffect != D3DSWAPEFFECT_DISCARD {
-                    error!("Multisampling is only supported with the DISCARD swap effect");
-                    error!("Disabling multisample antialiasing");
-                    1
-                } else {
-                    pp.MultiSampleType
-                };
+        let swap_chain: ComPtr<Self> = unsafe { new_com_interface(swap_chain) };
 
-                d3d9_to_dxgi_samples(count, pp.MultiSampleQuality)
-            };
+        // The back buffer can only point back to us once we have been allocated.
+        swap_chain.link_back_buffer();
 
-            let buffer_usage = DXGI_USAGE_BACK_BUFFER | DXGI_USAGE_RENDER_TARGET_OUTPUT;
+        Ok(swap_chain)
+    }
 
-            let buffer_count = {
-                if pp.SwapEffect == D3DSWAPEFFECT_COPY {
-                    error!("Application requested multiple back buffers, even though swap effect is COPY");
-                    error!("Limiting to one back buffer");
-                    pp.BackBufferCount = 1;
-                }
+    /// Enters or leaves full screen mode, as requested by the presentation parameters.
+    ///
+    /// If the output cannot be used exclusively, we fall back to a borderless window covering it.
+    fn apply_fullscreen_state(
+        parent: &Device,
+        swap_chain: &d3d11::SwapChain,
+        pp: &D3DPRESENT_PARAMETERS,
+    ) -> Result<(), Error> {
+        if pp.Windowed != 0 {
+            return swap_chain.set_windowed();
+        }
 
-                // We have to clamp this to be between 1 and 3.
-                // TODO: with D3D9Ex the limit goes up to 30.
-                std::cmp::min(std::cmp::max(pp.BackBufferCount, 1), 3)
-            };
+        let adapter = parent.adapter();
+        let output = adapter.active_output().ok_or(Error::NotAvailable)?;
+
+        let mode = adapter
+            .find_mode(
+                pp.BackBufferFormat,
+                pp.BackBufferWidth,
+                pp.BackBufferHeight,
+                pp.FullScreen_RefreshRateInHz,
+            )
+            .ok_or_else(|| {
+                error!(
+                    "Unsupported full screen mode: {}x{} @ {} Hz",
+                    pp.BackBufferWidth, pp.BackBufferHeight, pp.FullScreen_RefreshRateInHz
+                );
+                Error::NotAvailable
+            })?;
+
+        if !swap_chain.set_fullscreen(&output, &mode)? {
+            warn!("Full screen mode is not available, falling back to a borderless window");
+            Self::cover_output(swap_chain, &output)?;
+        }
 
-            let swap_effect = match pp.SwapEffect {
-                D3DSWAPEFFECT_DISCARD => DXGI_SWAP_EFFECT_DISCARD,
-                se => {
-                    error!("Unsupported swap effect: {}", se);
-                    error!("Falling back to DISCARD");
-                    DXGI_SWAP_EFFECT_DISCARD
-                }
-            };
+        Ok(())
+    }
 
-            // TODO: we currently ignore the pp.Flags
-            if pp.Flags != 0 {
-                warn!("Unsupported presentation flags: {}", pp.Flags);
-            }
+    /// Turns the swap chain's window into a borderless window covering a whole output.
+    fn cover_output(swap_chain: &d3d11::SwapChain, output: &IDXGIOutput) -> Result<(), Error> {
+        let window = swap_chain.desc()?.OutputWindow;
 
-            DXGI_SWAP_CHAIN_DESC {
-                BufferDesc: buffer_desc,
-                SampleDesc: sample_desc,
-                BufferUsage: buffer_usage,
-                BufferCount: buffer_count,
-                OutputWindow: window,
-                Windowed: pp.Windowed,
-                SwapEffect: swap_effect,
-                // We don't need any special flags.
-                Flags: 0,
-            }
+        let desc = unsafe {
+            let mut desc = mem::zeroed();
+            let result = output.GetDesc(&mut desc);
+            if_not_success_err!(check_hresult(result, "Failed to get output description"));
+            desc
         };
 
-        let swap_chain = unsafe {
-            let mut ptr = ptr::null_mut();
+        let rc = desc.DesktopCoordinates;
 
-            let result = factory.CreateSwapChain(
-                device as *const _ as *mut IUnknown,
-                &mut sc_desc,
-                &mut ptr,
+        unsafe {
+            winuser::SetWindowLongPtrW(
+                window,
+                winuser::GWL_STYLE,
+                (winuser::WS_POPUP | winuser::WS_VISIBLE) as isize,
+            );
+            winuser::SetWindowPos(
+                window,
+                winuser::HWND_TOP,
+                rc.left,
+                rc.top,
+                rc.right - rc.left,
+                rc.bottom - rc.top,
+                winuser::SWP_FRAMECHANGED,
             );
-            if_not_success_err!(check_hresult(result, "Failed to create swap chain"));
+        }
 
-            ComPtr::new(ptr)
-        };
+        Ok(())
+    }
 
-        let pp = *pp;
+    /// Allows GDI dialogs to be shown on top of a full screen swap chain, or stops allowing it.
+    ///
+    /// Exclusive full screen mode doesn't let anything else draw on the output,
+    /// so while dialogs are allowed, the swap chain presents to a borderless window.
+    pub fn set_dialog_box_mode(&self, enable: bool) -> Result<(), Error> {
+        // Windowed swap chains can always be drawn over.
+        if self.pp.Windowed != 0 || self.dialog_box_mode.get() == enable {
+            return Ok(());
+        }
 
-        // Clamp this to 4.
-        let sync_interval = cmp::min(pp.PresentationInterval, 4);
+        if !self.swap_chain.is_gdi_compatible()? {
+            error!("Dialog box mode requires a lockable, single-sampled back buffer");
+            return Err(Error::InvalidCall);
+        }
 
-        let swap_chain = Self {
-            __vtable: Box::new(Self::create_vtable()),
-            refs: AtomicU32::new(1),
+        if enable {
+            let output = self.output()?;
+            self.swap_chain.set_windowed()?;
+            Self::cover_output(&self.swap_chain, &output)?;
+        } else {
+            let parent = unsafe { &*self.parent };
+            Self::apply_fullscreen_state(parent, &self.swap_chain, &self.pp)?;
+        }
+
+        self.dialog_box_mode.set(enable);
+
+        Ok(())
+    }
+
+    /// Creates the surface through which the app renders to the back buffer.
+    fn create_back_buffer(
+        parent: &Device,
+        swap_chain: &d3d11::SwapChain,
+    ) -> Result<ComPtr<Surface>, Error> {
+        let buffer = swap_chain.buffer(0)?;
+
+        // Back buffers can be rendered to, so they need a view.
+        let rt_view = buffer.create_rt_view(parent.dx11_device())?;
+
+        Ok(Surface::new(
             parent,
-            swap_chain,
-            pp,
-            sync_interval,
-        };
+            buffer,
+            UsageFlags::RENDER_TARGET,
+            MemoryPool::Default,
+            SurfaceData::RenderTarget(rt_view),
+        ))
+    }
+
+    /// Makes the back buffer return this swap chain as its container.
+    fn link_back_buffer(&self) {
+        let this = self as *const Self;
 
-        Ok(unsafe { new_com_interface(swap_chain) })
+        if let Some(ref back_buffer) = self.back_buffer {
+            back_buffer
+                .as_mut()
+                .set_container(Container::SwapChain(this));
+        }
     }
 
-    /// Retrieves a buffer in this swap chain.
-    pub fn buffer(&self, id: u32) -> Result<d3d11::Texture2D, Error> {
-        let mut ptr: *mut ID3D11Texture2D = ptr::null_mut();
-        let uuid = ID3D11Texture2D::uuidof();
+    /// Stops the back buffer from pointing back to us, since the app might keep it alive longer.
+    fn unlink_back_buffer(&mut self) {
+        if let Some(back_buffer) = self.back_buffer.take() {
+            back_buffer.as_mut().set_container(Container::Device);
+        }
+    }
+
+    /// Marks this swap chain as having been created by the app.
+    pub fn set_additional(&mut self) {
+        self.additional = true;
+    }
+
+    /// Retrieves the surface representing the back buffer.
+    pub fn back_buffer(&self) -> Option<&ComPtr<Surface>> {
+        self.back_buffer.as_ref()
+    }
 
-        let ret = &mut ptr as *mut _ as *mut *mut _;
+    /// Releases our reference to the back buffer, so that the swap chain can be reset.
+    pub fn release_back_buffer(&mut self) {
+        self.unlink_back_buffer();
+        *self.front_buffer.borrow_mut() = None;
+    }
+
+    /// Changes the presentation parameters of this swap chain.
+    ///
+    /// The back buffer must have been released, by both the app and us.
+    pub fn reset(
+        &mut self,
+        factory: &IDXGIFactory,
+        pp: &mut D3DPRESENT_PARAMETERS,
+        window: HWND,
+    ) -> Result<(), Error> {
+        let parent = unsafe { &*self.parent };
+
+        // DXGI can only resize the buffers, anything else requires a new swap chain.
+        let can_resize = self.pp.hDeviceWindow == pp.hDeviceWindow
+            && self.pp.SwapEffect == pp.SwapEffect
+            && self.pp.MultiSampleType == pp.MultiSampleType
+            && self.pp.MultiSampleQuality == pp.MultiSampleQuality;
+
+        if can_resize {
+            self.swap_chain.resize(pp)?;
+        } else {
+            self.swap_chain = d3d11::SwapChain::new(parent.dx11_device(), factory, pp, window)?;
+        }
 
-        let result = unsafe { self.swap_chain.GetBuffer(id, &uuid, ret) };
+        Self::apply_fullscreen_state(parent, &self.swap_chain, pp)?;
+        self.dialog_box_mode.set(false);
 
-        if_not_success_err!(check_hresult(
-            result,
-            "Failed to retrieve swap chain buffer"
-        ));
+        self.back_buffer = Some(Self::create_back_buffer(parent, &self.swap_chain)?);
+        self.link_back_buffer();
+        self.pp = *pp;
+        self.sync_interval = cmp::min(pp.PresentationInterval, 4);
 
-        Ok(ComPtr::new(ptr).into())
+        Ok(())
+    }
+
+    /// Retrieves a buffer in this swap chain.
+    pub fn buffer(&self, id: u32) -> Result<d3d11::Texture2D, Error> {
+        self.swap_chain.buffer(id)
     }
 
     // Retrieves this swap chain's containing output.
     fn output(&self) -> Result<ComPtr<IDXGIOutput>, Error> {
-        let output = unsafe {
-            let mut ptr = ptr::null_mut();
-            let result = self.swap_chain.GetContainingOutput(&mut ptr);
-            if_not_success_err!(check_hresult(result, "Failed to get swap chain's output"));
-            ComPtr::new(ptr)
+        self.swap_chain.output()
+    }
+
+    /// Presents part of the back buffer, or presents it to a different window than the default.
+    ///
+    /// DXGI can only present the whole buffer to its own window, so these are done with GDI.
+    fn present_gdi(&self, src: *const RECT, dest: *const RECT, wnd: HWND) -> Error {
+        // The back buffer is read through GDI, so the recorded commands have to be replayed first.
+        let parent = unsafe { &*self.parent };
+        if_error!(parent.device_context().submit());
+
+        let window = if wnd.is_null() {
+            if_error!(self.swap_chain.desc()).OutputWindow
+        } else {
+            wnd
+        };
+
+        let src = unsafe { src.as_ref() }.cloned().unwrap_or(RECT {
+            left: 0,
+            top: 0,
+            right: self.pp.BackBufferWidth as i32,
+            bottom: self.pp.BackBufferHeight as i32,
+        });
+
+        // By default, the back buffer is stretched to cover the whole client area.
+        let dest = match unsafe { dest.as_ref() } {
+            Some(dest) => *dest,
+            None => unsafe {
+                let mut rc = mem::zeroed();
+                winuser::GetClientRect(window, &mut rc);
+                rc
+            },
         };
 
-        Ok(output)
+        if let Err(err) = self.swap_chain.blit_to_window(&src, window, &dest) {
+            return err;
+        }
+
+        self.last_present.set(Instant::now());
+
+        Error::Success
+    }
+
+    /// Blocks until the next vertical blank of the output this swap chain is presenting to.
+    pub fn wait_for_vblank(&self) -> Result<(), Error> {
+        let output = self.output()?;
+
+        let result = unsafe { output.WaitForVBlank() };
+        if_not_success_err!(check_hresult(result, "Failed to wait for vertical blank"));
+
+        Ok(())
     }
 
     /// Sets the associated output's gamma ramp.
-    pub fn set_gamma_ramp(&self, flags: u32, _ramp: &D3DGAMMARAMP) -> Result<(), Error> {
-        if self.pp.Windowed == 1 {
-            return Err(Error::InvalidCall);
+    ///
+    /// The D3DSGR_CALIBRATE flag is ignored: the ramp is always applied as-is.
+    pub fn set_gamma_ramp(&self, _flags: u32, ramp: &D3DGAMMARAMP) -> Result<(), Error> {
+        self.gamma_ramp.set(*ramp);
+
+        // DXGI only allows changing the gamma of an output we own.
+        if self.pp.Windowed != 0 {
+            run_once!(|| warn!("Gamma ramps are not applied in windowed mode"));
+            return Ok(());
         }
 
-        // winapi is missing this constant.
-        const D3DSGR_CALIBRATE: u32 = 1;
+        let output = self.output()?;
 
-        if flags & D3DSGR_CALIBRATE != 0 {
-            warn!("Gamma calibration isn't implemented");
-        }
+        let caps = unsafe {
+            let mut caps = mem::zeroed();
+            let result = output.GetGammaControlCapabilities(&mut caps);
+            if_not_success_err!(check_hresult(result, "Failed to get gamma capabilities"));
+            caps
+        };
 
-        let _output = self.output()?;
+        let mut control: DXGI_GAMMA_CONTROL = unsafe { mem::zeroed() };
+
+        control.Scale = DXGI_RGB {
+            Red: 1.0,
+            Green: 1.0,
+            Blue: 1.0,
+        };
+
+        // DXGI's curve has a variable number of control points, placed at arbitrary positions.
+        let num_points = cmp::min(
+            caps.NumGammaControlPoints as usize,
+            control.GammaCurve.len(),
+        );
+
+        for i in 0..num_points {
+            let pos = caps.ControlPointPositions[i];
+
+            control.GammaCurve[i] = DXGI_RGB {
+                Red: sample_gamma_ramp(&ramp.red, pos),
+                Green: sample_gamma_ramp(&ramp.green, pos),
+                Blue: sample_gamma_ramp(&ramp.blue, pos),
+            };
+        }
 
-        error!("Setting gamma ramp is not supported");
+        let result = unsafe { output.SetGammaControl(&control) };
+        if_not_success_err!(check_hresult(result, "Failed to set gamma ramp"));
 
         Ok(())
     }
 
     /// Gets the associated output's gamma ramp.
-    pub fn get_gamma_ramp(&self, _ramp: &mut D3DGAMMARAMP) -> Result<(), Error> {
-        let _output = self.output()?;
-
-        error!("Getting gamma ramp is not supported");
-
+    pub fn get_gamma_ramp(&self, ramp: &mut D3DGAMMARAMP) -> Result<(), Error> {
+        *ramp = self.gamma_ramp.get();
         Ok(())
     }
 }
 
 impl Drop for SwapChain {
     fn drop(&mut self) {
-        unsafe {
-            // According to the DXGI docs, if the swap chain was created as full-screen
-            // we need to make it windowed right before destroying it.
-            self.swap_chain.SetFullscreenState(0, ptr::null_mut());
+        let parent = unsafe { &*self.parent };
+
+        if self.additional {
+            parent.forget_swap_chain(self);
         }
+
+        self.unlink_back_buffer();
+
+        // This might free the device.
+        parent.remove_child();
     }
 }
 
@@ -259,18 +493,18 @@ impl_iunknown!(struct SwapChain: IUnknown, IDirect3DSwapChain9);
 #[implementation(IDirect3DSwapChain9)]
 impl SwapChain {
     /// Presents the back buffer to the screen, and moves to the next buffer in the chain.
-    pub fn present(&self, src: usize, dest: usize, wnd: HWND, dirty: usize, flags: u32) -> Error {
-        if src != 0 || dest != 0 || dirty != 0 {
+    ///
+    /// The dirty region is only a hint, so the whole rectangle is always presented.
+    pub fn present(&self, src: usize, dest: usize, wnd: HWND, _dirty: usize, flags: u32) -> Error {
+        if src != 0 || dest != 0 {
             // Check if the app is even allowed to partially present.
             if self.pp.SwapEffect != D3DSWAPEFFECT_COPY {
                 return Error::InvalidCall;
             }
-
-            unimplemented!("Partial present is not yet supported");
         }
 
-        if !wnd.is_null() {
-            unimplemented!("Presenting to a different window is not supported");
+        if src != 0 || dest != 0 || !wnd.is_null() {
+            return self.present_gdi(src as *const RECT, dest as *const RECT, wnd);
         }
 
         let mut fl = 0;
@@ -288,22 +522,143 @@ impl SwapChain {
             warn!("sRGB / gamma correction not yet supported");
         }
 
+        let parent = unsafe { &*self.parent };
+
+        // Keep a copy of the frame, since DXGI doesn't allow reading the front buffer.
+        if let Some(ref front_buffer) = *self.front_buffer.borrow() {
+            if_error!(self.buffer(0)).copy_to(&parent.device_context().lock(), front_buffer);
+        }
+
+        // Replay the commands recorded during this frame.
+        if_error!(parent.device_context().submit());
+
         // Try to present.
         let result = unsafe { self.swap_chain.Present(self.sync_interval, fl) };
 
         match result {
-            0 => Error::Success,
+            0 => {
+                self.last_present.set(Instant::now());
+                Error::Success
+            }
             winerror::DXGI_ERROR_WAS_STILL_DRAWING => Error::WasStillDrawing,
+            // The window is not visible, e.g. because it was minimized.
+            winerror::DXGI_STATUS_OCCLUDED => Error::PresentOccluded,
             hr => check_hresult(hr, "Failed to present to screen"),
         }
     }
 
     /// Copies data from the front buffer into a surface.
+    ///
+    /// The destination must be a system memory surface in the `A8R8G8B8` format.
+    /// In windowed mode it represents the whole desktop of the output.
     pub fn get_front_buffer_data(&self, fb: *mut Surface) -> Error {
-        let _fb = check_mut_ref(fb);
-        // TODO: we need to get the front buffer, then copy its data into the passed-in surface.
-        // We also need to ensure the format is converted to a format D3D9 supports.
-        unimplemented!()
+        let fb = if_error!(check_mut_ref(fb));
+
+        let mut fb_desc = unsafe { mem::zeroed() };
+        if_not_success!(fb.get_desc(&mut fb_desc));
+
+        if fb.pool() != MemoryPool::SystemMem || fb_desc.Format != D3DFMT_A8R8G8B8 {
+            return Error::InvalidCall;
+        }
+
+        let parent = unsafe { &*self.parent };
+        let device = parent.dx11_device();
+        let ctx = parent.device_context().immediate();
+
+        // Start keeping copies of the presented frames.
+        // Until the next present, the current back buffer is the best approximation we have.
+        let mut front_buffer = self.front_buffer.borrow_mut();
+        if front_buffer.is_none() {
+            let buffer = if_error!(self.buffer(0));
+            let copy = if_error!(buffer.new_resolve_target(device));
+            buffer.copy_to(&ctx, &copy);
+            *front_buffer = Some(copy);
+        }
+
+        let front_buffer = if_error!(front_buffer.as_ref().ok_or(Error::InvalidCall));
+        let desc = front_buffer.desc();
+
+        let staging = if_error!(front_buffer.new_staging(device));
+        front_buffer.copy_to(&ctx, &staging);
+
+        // In windowed mode, the frame is placed wherever the window is on the desktop.
+        let (x, y) = if self.pp.Windowed != 0 {
+            let output = if_error!(self.output());
+
+            let sc_desc = if_error!(self.swap_chain.desc());
+
+            unsafe {
+                let mut output_desc = mem::zeroed();
+                let result = output.GetDesc(&mut output_desc);
+                if_not_success!(check_hresult(result, "Failed to get output description"));
+
+                let mut origin = POINT { x: 0, y: 0 };
+                winuser::ClientToScreen(sc_desc.OutputWindow, &mut origin);
+
+                let desktop = output_desc.DesktopCoordinates;
+                (origin.x - desktop.left, origin.y - desktop.top)
+            }
+        } else {
+            (0, 0)
+        };
+
+        let (src_res, dest) = (staging.as_resource(), fb.subresource());
+
+        unsafe {
+            let mut src = mem::zeroed();
+            let result = ctx.Map(src_res, 0, D3D11_MAP_READ, 0, &mut src);
+            if_not_success!(check_hresult(result, "Failed to map front buffer"));
+
+            let mut dst = mem::zeroed();
+            let result = ctx.Map(dest.0, dest.1, D3D11_MAP_WRITE, 0, &mut dst);
+            if result != 0 {
+                ctx.Unmap(src_res, 0);
+                return check_hresult(result, "Failed to map destination surface");
+            }
+
+            let mut supported = true;
+
+            for row in 0..desc.Height as i32 {
+                let dest_row = row + y;
+                if dest_row < 0 || dest_row >= fb_desc.Height as i32 {
+                    continue;
+                }
+
+                let src_row = (src.pData as *const u8).offset((row * src.RowPitch as i32) as isize)
+                    as *const u32;
+                let dest_row = (dst.pData as *mut u8)
+                    .offset((dest_row * dst.RowPitch as i32) as isize)
+                    as *mut u32;
+
+                for col in 0..desc.Width as i32 {
+                    let dest_col = col + x;
+                    if dest_col < 0 || dest_col >= fb_desc.Width as i32 {
+                        continue;
+                    }
+
+                    let pixel = ptr::read_unaligned(src_row.offset(col as isize));
+
+                    match convert_to_argb(desc.Format, pixel) {
+                        Some(pixel) => *dest_row.offset(dest_col as isize) = pixel,
+                        None => supported = false,
+                    }
+                }
+
+                if !supported {
+                    break;
+                }
+            }
+
+            ctx.Unmap(dest.0, dest.1);
+            ctx.Unmap(src_res, 0);
+
+            if supported {
+                Error::Success
+            } else {
+                error!("Cannot read back front buffer in format {}", desc.Format);
+                Error::NotAvailable
+            }
+        }
     }
 
     /// Retrieves the the back buffer's surface.
@@ -325,41 +680,90 @@ impl SwapChain {
             return Error::InvalidCall;
         }
 
-        // Retrieve the 2D texture representing this back buffer.
-        let buffer = if_error!(self.buffer(idx));
-
-        // Create and return a pointer to the surface.
-        *surf = Surface::new(
-            self.parent,
-            buffer,
-            UsageFlags::RENDER_TARGET,
-            MemoryPool::Default,
-            SurfaceData::None,
-        )
-        .into();
+        *surf = match self.back_buffer {
+            Some(ref back_buffer) => back_buffer.clone().into(),
+            None => return Error::InvalidCall,
+        };
 
         Error::Success
     }
 
     /// Gets the status of the current scanline the rasterizer is processing.
+    ///
+    /// DXGI doesn't report the raster position, so it is estimated from the time
+    /// passed since the last present, which returns right after a vertical blank.
     pub fn get_raster_status(&self, rs: *mut D3DRASTER_STATUS) -> Error {
-        if_error!(check_mut_ref(rs));
+        let rs = if_error!(check_mut_ref(rs));
 
-        // We reported in the device caps that we don't support this.
-        Error::NotAvailable
+        let mut mode = unsafe { mem::zeroed() };
+        if_not_success!(self.get_display_mode(&mut mode));
+
+        let refresh_rate = match mode.RefreshRate {
+            0 => DEFAULT_REFRESH_RATE,
+            rate => rate,
+        };
+
+        let period = 1.0 / f64::from(refresh_rate);
+        let elapsed = self.last_present.get().elapsed().as_secs_f64();
+        let phase = (elapsed % period) / period;
+
+        // The vertical blank comes at the end of every refresh.
+        let line = phase * f64::from(mode.Height) * TOTAL_LINES_RATIO;
+
+        if line >= f64::from(mode.Height) {
+            rs.InVBlank = 1;
+            rs.ScanLine = 0;
+        } else {
+            rs.InVBlank = 0;
+            rs.ScanLine = line as u32;
+        }
+
+        Error::Success
     }
 
-    /// Retrieves the swap chain's display mode.
+    /// Retrieves the display mode of the output this swap chain is presenting to.
+    ///
+    /// In windowed mode this is the desktop's mode, otherwise it's the full screen mode we set.
     pub fn get_display_mode(&self, dm: *mut D3DDISPLAYMODE) -> Error {
         let dm = if_error!(check_mut_ref(dm));
-        let pp = &self.pp;
 
-        *dm = D3DDISPLAYMODE {
-            Width: pp.BackBufferWidth,
-            Height: pp.BackBufferHeight,
-            Format: pp.BackBufferFormat,
-            RefreshRate: pp.FullScreen_RefreshRateInHz,
-        };
+        if self.pp.Windowed != 0 {
+            let output = if_error!(self.output());
+
+            let desc = unsafe {
+                let mut desc = mem::zeroed();
+                let result = output.GetDesc(&mut desc);
+                if_not_success!(check_hresult(result, "Failed to get output description"));
+                desc
+            };
+
+            let rc = desc.DesktopCoordinates;
+
+            *dm = D3DDISPLAYMODE {
+                Width: (rc.right - rc.left) as u32,
+                Height: (rc.bottom - rc.top) as u32,
+                // 0 indicates an adapter-default rate.
+                RefreshRate: 0,
+                Format: DESKTOP_FORMAT,
+            };
+        } else {
+            // The buffers might have been resized since the swap chain was created.
+            let desc = if_error!(self.swap_chain.desc());
+
+            let mode = desc.BufferDesc;
+            let rate = mode.RefreshRate;
+
+            *dm = D3DDISPLAYMODE {
+                Width: mode.Width,
+                Height: mode.Height,
+                RefreshRate: if rate.Denominator != 0 {
+                    rate.Numerator / rate.Denominator
+                } else {
+                    self.pp.FullScreen_RefreshRateInHz
+                },
+                Format: self.pp.BackBufferFormat,
+            };
+        }
 
         Error::Success
     }
@@ -372,6 +776,9 @@ impl SwapChain {
     }
 
     /// Retrieves the presentation parameters this swap chain was created with.
+    ///
+    /// These include the adjustments made when creating it, like the actual back buffer size,
+    /// format and count.
     pub fn get_present_parameters(&self, pp: *mut D3DPRESENT_PARAMETERS) -> Error {
         let pp = if_error!(check_mut_ref(pp));
         *pp = self.pp;
@@ -1,9 +1,13 @@
+use std::cmp;
+
 use winapi::shared::{d3d9::*, d3d9types::*};
+use winapi::um::d3d11::ID3D11ShaderResourceView;
 
 use com_impl::{implementation, ComInterface};
+use comptr::ComPtr;
 
-use crate::core::*;
-use crate::dev::{Device, Resource};
+use crate::core::{fmt::format_swizzle, fmt::Swizzle, *};
+use crate::dev::{Device, ManagedStorage, Resource};
 use crate::Error;
 
 /// The common interface for all texture interfaces.
@@ -12,6 +16,15 @@ pub struct BaseTexture {
     resource: Resource,
     // Number of subresource levels in this textures.
     levels: u32,
+    // Most detailed mip level which gets sampled. Only used by managed textures.
+    lod: u32,
+    // View used to bind this texture to shaders, if it can be bound.
+    view: Option<ComPtr<ID3D11ShaderResourceView>>,
+    // Filter the app would like to be used when generating mip maps.
+    autogen_filter: D3DTEXTUREFILTERTYPE,
+    // D3D11 views cannot reorder channels, so formats without a DXGI equivalent
+    // are stored in other channels, and the shaders swizzle them back when sampling.
+    swizzle: Swizzle,
 }
 
 impl BaseTexture {
@@ -21,18 +34,71 @@ impl BaseTexture {
         usage: UsageFlags,
         pool: MemoryPool,
         rtype: ResourceType,
+        fmt: D3DFORMAT,
         levels: u32,
+        view: Option<ComPtr<ID3D11ShaderResourceView>>,
     ) -> Self {
         Self {
             resource: Resource::new(device, usage, pool, rtype),
             levels,
+            lod: 0,
+            view,
+            autogen_filter: D3DTEXF_LINEAR,
+            swizzle: format_swizzle(fmt),
         }
     }
 
+    /// Retrieves the texture behind a pointer received from the app.
+    ///
+    /// # Safety
+    /// The pointer must be null or point to one of our texture interfaces.
+    pub unsafe fn from_com<'a>(ptr: *mut BaseTexture) -> Option<&'a BaseTexture> {
+        (ptr as *const Thunk).as_ref().map(|thunk| &thunk.txt)
+    }
+
     /// Retrieves the number of mip map levels in this texture.
     pub fn level_count(&self) -> u32 {
         self.levels
     }
+
+    /// Retrieves the swizzle to apply when sampling this texture.
+    pub fn swizzle(&self) -> Swizzle {
+        self.swizzle
+    }
+
+    /// Retrieves the view used to bind this texture to shaders.
+    pub fn shader_view(&self) -> Option<&ComPtr<ID3D11ShaderResourceView>> {
+        self.view.as_ref()
+    }
+
+    /// Registers the system memory copy of a managed texture with the device.
+    pub fn manage(&mut self, storage: ManagedStorage) {
+        self.resource.manage(storage);
+    }
+
+    /// Retrieves the view to bind when this texture is used for drawing.
+    ///
+    /// Managed textures are uploaded to VRAM first.
+    pub fn bind_view(&self) -> Result<Option<ComPtr<ID3D11ShaderResourceView>>, Error> {
+        match self.resident()? {
+            Some(resident) => Ok(resident.shader_view().cloned()),
+            None => Ok(self.view.clone()),
+        }
+    }
+
+    /// Regenerates the mip sub-levels from the top level, if this texture uses automatic mip-mapping.
+    pub fn generate_mips(&self) {
+        if !self.usage().intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+            return;
+        }
+
+        if let Some(ref view) = self.view {
+            // D3D11 always uses a (driver-chosen) linear filter.
+            unsafe {
+                self.device_context().lock().GenerateMips(view.as_mut());
+            }
+        }
+    }
 }
 
 impl std::ops::Deref for BaseTexture {
@@ -70,24 +136,61 @@ impl std::ops::DerefMut for Thunk {
 
 #[implementation(IDirect3DBaseTexture9)]
 impl BaseTexture {
-    fn set_l_o_d(self: &mut Thunk, _lod: u32) -> u32 {
-        unimplemented!()
+    /// Sets the most detailed mip level to use, returning the previous one.
+    fn set_l_o_d(self: &mut Thunk, lod: u32) -> u32 {
+        let _lock = self.device().lock();
+        // Only managed textures support level of detail control.
+        if self.pool() != MemoryPool::Managed {
+            return 0;
+        }
+
+        let old = self.lod;
+        self.lod = cmp::min(lod, self.levels - 1);
+
+        if let Err(err) = self.set_managed_lod(self.lod) {
+            error!("Failed to change texture LOD: {:?}", err);
+        }
+
+        old
     }
+
+    /// Retrieves the most detailed mip level which gets used.
     fn get_l_o_d(self: &Thunk) -> u32 {
-        unimplemented!()
+        self.lod
     }
 
+    /// Retrieves the number of mip levels the app can access.
     fn get_level_count(self: &Thunk) -> u32 {
-        self.level_count()
+        // Automatically generated mip levels are hidden from the app.
+        if self.usage().intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+            1
+        } else {
+            self.level_count()
+        }
     }
 
-    fn set_auto_gen_filter_type(self: &mut Thunk, _filter: D3DTEXTUREFILTERTYPE) -> Error {
-        unimplemented!()
+    /// Sets the filter used for generating mip maps.
+    fn set_auto_gen_filter_type(self: &mut Thunk, filter: D3DTEXTUREFILTERTYPE) -> Error {
+        match filter {
+            D3DTEXF_NONE => return Error::InvalidCall,
+            D3DTEXF_POINT | D3DTEXF_LINEAR => (),
+            _ => warn!("Mip map generation filter {} is not supported", filter),
+        }
+
+        // We can't actually choose the filter in D3D11, but we remember it for the app.
+        self.autogen_filter = filter;
+
+        Error::Success
     }
+
+    /// Retrieves the filter used for generating mip maps.
     fn get_auto_gen_filter_type(self: &Thunk) -> D3DTEXTUREFILTERTYPE {
-        unimplemented!()
+        self.autogen_filter
     }
+
+    /// Generates the mip sub-levels of this texture.
     fn generate_mip_sub_levels(self: &mut Thunk) {
-        unimplemented!()
+        let _lock = self.device().lock();
+        self.generate_mips();
     }
 }
@@ -20,27 +20,48 @@ use super::BaseTexture;
 pub struct CubeTexture {
     base: BaseTexture,
     refs: AtomicU32,
-    texture: d3d11::Texture2D,
+    texture: d3d11::CubeTexture,
 }
 
 impl CubeTexture {
     /// Creates a new cube texture.
     pub fn new(
         device: *const Device,
-        texture: d3d11::Texture2D,
+        texture: d3d11::CubeTexture,
+        fmt: D3DFORMAT,
         levels: u32,
         usage: UsageFlags,
         pool: MemoryPool,
     ) -> ComPtr<Self> {
+        let mut base = BaseTexture::new(
+            device,
+            usage,
+            pool,
+            ResourceType::CubeTexture,
+            fmt,
+            levels,
+            texture.shader_view().cloned(),
+        );
+
+        // Cube maps with automatically generated mip maps always live in VRAM.
+        if pool == MemoryPool::Managed && !usage.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+            base.manage(ManagedStorage::Texture2D(texture.texture().clone()));
+        }
+
         let tc = Self {
             __vtable: Box::new(Self::create_vtable()),
-            base: BaseTexture::new(device, usage, pool, ResourceType::CubeTexture, levels),
+            base,
             refs: AtomicU32::new(1),
             texture,
         };
 
         unsafe { new_com_interface(tc) }
     }
+
+    /// Retrieves the D3D11 cube texture wrapped by this interface.
+    pub fn get_dx11(&self) -> &d3d11::CubeTexture {
+        &self.texture
+    }
 }
 
 impl std::ops::Deref for CubeTexture {
@@ -79,25 +100,28 @@ impl CubeTexture {
 
     /// Retrieves a face of this cube map.
     fn get_cube_map_surface(&self, face: u32, level: u32, ret: *mut *mut Surface) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
         let levels = self.level_count();
 
-        if face >= 6 {
-            return Error::InvalidCall;
-        }
-
         if level >= levels {
             return Error::InvalidCall;
         }
 
+        let subres = if_error!(self
+            .texture
+            .calc_subresource(face, level, levels)
+            .ok_or(Error::InvalidCall));
+
         let device = self.device();
-        let texture = self.texture.clone();
+        let texture = self.texture.texture().clone();
         let usage = self.usage();
         let pool = self.pool();
-        let subres = self.texture.calc_subresource(level, face, levels);
         let data = SurfaceData::SubResource(subres);
 
-        *ret = Surface::new(device, texture, usage, pool, data).into();
+        let surface = Surface::new(device, texture, usage, pool, data);
+        surface.as_mut().set_container(Container::texture(self));
+        *ret = surface.into();
 
         Error::Success
     }
@@ -111,26 +135,36 @@ impl CubeTexture {
         _r: *const RECT,
         flags: LockFlags,
     ) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
         let resource = self.texture.as_resource();
         let levels = self.level_count();
-        let subres = self.texture.calc_subresource(level, face, levels);
+        let subres = if_error!(self
+            .texture
+            .calc_subresource(face, level, levels)
+            .ok_or(Error::InvalidCall));
         let ctx = self.device_context();
 
-        *ret = if_error!(ctx.map(resource, subres, flags, self.usage()));
+        if_error!(self.check_lockable());
+        *ret = if_error!(ctx.map(resource, subres, flags, self.map_usage()));
 
         Error::Success
     }
 
     /// Unmaps a face of this cube map.
     fn unlock_rect(&self, face: u32, level: u32) -> Error {
+        let _lock = self.device().lock();
         let resource = self.texture.as_resource();
         let levels = self.level_count();
-        let subres = self.texture.calc_subresource(level, face, levels);
+        let subres = if_error!(self
+            .texture
+            .calc_subresource(face, level, levels)
+            .ok_or(Error::InvalidCall));
         let ctx = self.device_context();
 
         ctx.unmap(resource, subres);
+        self.mark_dirty(resource);
 
         Error::Success
     }
@@ -2,13 +2,18 @@ use std::ptr;
 use std::sync::atomic::AtomicU32;
 
 use winapi::shared::{d3d9::*, d3d9types::*, windef::RECT};
+use winapi::um::d3d11::ID3D11ShaderResourceView;
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
 
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
 
+use crate::core::{
+    fmt::{block_size, dxgi_format_to_d3d},
+    *,
+};
 use crate::dev::*;
-use crate::{core::*, d3d11, Error};
+use crate::{d3d11, Error};
 
 use super::BaseTexture;
 
@@ -20,6 +25,10 @@ pub struct Texture {
     base: BaseTexture,
     refs: AtomicU32,
     texture: d3d11::Texture2D,
+    // Textures which live only in VRAM are locked through this copy.
+    staging: Option<d3d11::Texture2D>,
+    // D3D11 formats do not map one-to-one to D3D9 formats, so we store the original one.
+    fmt: D3DFORMAT,
 }
 
 impl Texture {
@@ -28,14 +37,34 @@ impl Texture {
         device: *const Device,
         pool: MemoryPool,
         texture: d3d11::Texture2D,
+        staging: Option<d3d11::Texture2D>,
+        view: Option<ComPtr<ID3D11ShaderResourceView>>,
+        fmt: D3DFORMAT,
         levels: u32,
         usage: UsageFlags,
     ) -> ComPtr<Self> {
+        let mut base = BaseTexture::new(
+            device,
+            usage,
+            pool,
+            ResourceType::Texture,
+            fmt,
+            levels,
+            view,
+        );
+
+        // Textures with automatically generated mip maps always live in VRAM.
+        if pool == MemoryPool::Managed && staging.is_none() {
+            base.manage(ManagedStorage::Texture2D(texture.clone()));
+        }
+
         let texture = Self {
             __vtable: Box::new(Self::create_vtable()),
-            base: BaseTexture::new(device, usage, pool, ResourceType::Texture, levels),
+            base,
             refs: AtomicU32::new(1),
             texture,
+            staging,
+            fmt,
         };
 
         unsafe { new_com_interface(texture) }
@@ -77,6 +106,7 @@ impl Texture {
 
     /// Retrieves a surface representing a mip level of this texture.
     fn get_surface_level(&self, level: u32, ret: *mut *mut Surface) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if level >= self.level_count() {
@@ -87,9 +117,19 @@ impl Texture {
         let texture = self.texture.clone();
         let usage = self.usage();
         let pool = self.pool();
-        let data = SurfaceData::SubResource(level);
 
-        *ret = Surface::new(device, texture, usage, pool, data).into();
+        let data = if usage.intersects(UsageFlags::DEPTH_STENCIL) {
+            // Depth textures only have one level, which can be bound as a depth buffer.
+            let fmt = dxgi_format_to_d3d(texture.desc().Format);
+            let ds_view = if_error!(texture.create_ds_view(device.dx11_device(), fmt));
+            SurfaceData::DepthStencil(ds_view, self.shader_view().cloned())
+        } else {
+            SurfaceData::SubResource(level)
+        };
+
+        let surface = Surface::new(device, texture, usage, pool, data);
+        surface.as_mut().set_container(Container::texture(self));
+        *ret = surface.into();
 
         Error::Success
     }
@@ -100,25 +140,91 @@ impl Texture {
         level: u32,
         ret: *mut D3DLOCKED_RECT,
         // TODO: maybe track dirty regions for efficiency.
-        _r: *const RECT,
+        r: *const RECT,
         flags: LockFlags,
     ) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
+        if level >= self.level_count() {
+            return Error::InvalidCall;
+        }
+
         let resource = self.texture.as_resource();
         let ctx = self.device_context();
 
-        *ret = if_error!(ctx.map(resource, level, flags, self.usage()));
+        if let Some(ref staging) = self.staging {
+            let staging = staging.as_resource();
+
+            // Download the current contents, unless the app wants to overwrite them.
+            if !flags.intersects(LockFlags::DISCARD) {
+                unsafe {
+                    ctx.lock().CopySubresourceRegion(
+                        staging,
+                        level,
+                        0,
+                        0,
+                        0,
+                        resource,
+                        level,
+                        ptr::null(),
+                    );
+                }
+            }
+
+            *ret = if_error!(ctx.map(staging, level, flags, self.usage()));
+        } else {
+            if_error!(self.check_lockable());
+            *ret = if_error!(ctx.map(resource, level, flags, self.map_usage()));
+        }
+
+        // D3D11 always maps the whole subresource, so we need to offset the pointer ourselves.
+        if let Some(r) = unsafe { r.as_ref() } {
+            // For compressed formats, the pitch is the size of a row of blocks.
+            let (block_bytes, block_dim) = block_size(self.fmt);
+
+            let offset = (r.top as u32 / block_dim) as usize * ret.Pitch as usize
+                + (r.left as u32 / block_dim * block_bytes) as usize;
+
+            ret.pBits = unsafe { (ret.pBits as *mut u8).add(offset) as *mut _ };
+        }
 
         Error::Success
     }
 
     /// Unlocks the locked rectangle of memory.
     pub fn unlock_rect(&self, level: u32) -> Error {
+        let _lock = self.device().lock();
         let resource = self.texture.as_resource();
         let ctx = self.device_context();
 
-        ctx.unmap(resource, level);
+        if let Some(ref staging) = self.staging {
+            let staging = staging.as_resource();
+
+            ctx.unmap(staging, level);
+
+            // Upload the new contents back to the GPU.
+            unsafe {
+                ctx.lock().CopySubresourceRegion(
+                    resource,
+                    level,
+                    0,
+                    0,
+                    0,
+                    staging,
+                    level,
+                    ptr::null(),
+                );
+            }
+        } else {
+            ctx.unmap(resource, level);
+            self.mark_dirty(resource);
+        }
+
+        // Writing to the top level invalidates the generated mip maps.
+        if level == 0 {
+            self.generate_mips();
+        }
 
         Error::Success
     }
@@ -0,0 +1,164 @@
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+
+use winapi::shared::{d3d9::*, d3d9types::*};
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+
+use com_impl::{implementation, interface, ComInterface};
+use comptr::ComPtr;
+
+use crate::dev::*;
+use crate::{core::*, d3d11, Error};
+
+use super::BaseTexture;
+
+/// Texture with three dimensions, and its mip sub-levels.
+///
+/// Closely matches the `ID3D11Texture3D` interface.
+#[interface(IDirect3DVolumeTexture9)]
+pub struct VolumeTexture {
+    base: BaseTexture,
+    refs: AtomicU32,
+    texture: d3d11::VolumeTexture,
+    // D3D11 formats do not map one-to-one to D3D9 formats, so we store the original one.
+    fmt: D3DFORMAT,
+}
+
+impl VolumeTexture {
+    /// Creates a new volume texture object.
+    pub fn new(
+        device: *const Device,
+        pool: MemoryPool,
+        texture: d3d11::VolumeTexture,
+        fmt: D3DFORMAT,
+        levels: u32,
+        usage: UsageFlags,
+    ) -> ComPtr<Self> {
+        let mut base = BaseTexture::new(
+            device,
+            usage,
+            pool,
+            ResourceType::VolumeTexture,
+            fmt,
+            levels,
+            None,
+        );
+
+        if pool == MemoryPool::Managed {
+            base.manage(ManagedStorage::Texture3D(texture.clone()));
+        }
+
+        let texture = Self {
+            __vtable: Box::new(Self::create_vtable()),
+            base,
+            refs: AtomicU32::new(1),
+            texture,
+            fmt,
+        };
+
+        unsafe { new_com_interface(texture) }
+    }
+}
+
+impl std::ops::Deref for VolumeTexture {
+    type Target = BaseTexture;
+    fn deref(&self) -> &BaseTexture {
+        &self.base
+    }
+}
+
+impl_iunknown!(struct VolumeTexture: IUnknown, IDirect3DResource9, IDirect3DBaseTexture9, IDirect3DVolumeTexture9);
+
+impl ComInterface<IDirect3DBaseTexture9Vtbl> for VolumeTexture {
+    fn create_vtable() -> IDirect3DBaseTexture9Vtbl {
+        let mut vtbl: IDirect3DBaseTexture9Vtbl = BaseTexture::create_vtable();
+        vtbl.parent.parent = Self::create_vtable();
+        vtbl
+    }
+}
+
+#[implementation(IDirect3DVolumeTexture9)]
+impl VolumeTexture {
+    /// Retrieves the description of a certain mip level.
+    fn get_level_desc(&self, level: u32, desc: *mut D3DVOLUME_DESC) -> Error {
+        let volume = {
+            let mut ptr = ptr::null_mut();
+            match self.get_volume_level(level, &mut ptr) {
+                Error::Success => (),
+                err => return err,
+            }
+            ComPtr::new(ptr)
+        };
+
+        volume.get_desc(desc)
+    }
+
+    /// Retrieves a volume representing a mip level of this texture.
+    fn get_volume_level(&self, level: u32, ret: *mut *mut Volume) -> Error {
+        let _lock = self.device().lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if level >= self.level_count() {
+            return Error::InvalidCall;
+        }
+
+        let device = self.device();
+        let container = ComPtr::new(com_ref(self) as *mut IUnknown);
+        let texture = self.texture.clone();
+
+        *ret = Volume::new(
+            device,
+            container,
+            texture,
+            level,
+            self.fmt,
+            self.usage(),
+            self.pool(),
+        )
+        .into();
+
+        Error::Success
+    }
+
+    /// Locks a box of a mip level and maps its memory.
+    fn lock_box(
+        &self,
+        level: u32,
+        ret: *mut D3DLOCKED_BOX,
+        bx: *const D3DBOX,
+        flags: LockFlags,
+    ) -> Error {
+        let _lock = self.device().lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if level >= self.level_count() {
+            return Error::InvalidCall;
+        }
+
+        *ret = if_error!(lock_volume_level(
+            self.device(),
+            &self.texture,
+            self.fmt,
+            level,
+            bx,
+            flags,
+            self.usage(),
+            self.pool()
+        ));
+
+        Error::Success
+    }
+
+    /// Unlocks a mip level.
+    fn unlock_box(&self, level: u32) -> Error {
+        let _lock = self.device().lock();
+        unlock_volume_level(self.device(), &self.texture, level, self.pool());
+        Error::Success
+    }
+
+    fn add_dirty_box(&mut self, bx: *const D3DBOX) -> Error {
+        let _bx = if_error!(check_ref(bx));
+        warn!("AddDirtyBox is not implemented");
+        Error::Success
+    }
+}
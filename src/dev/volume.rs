@@ -0,0 +1,215 @@
+use std::sync::atomic::AtomicU32;
+
+use winapi::ctypes::c_void;
+use winapi::shared::{d3d9::*, d3d9types::*, guiddef::GUID};
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+
+use com_impl::{implementation, interface, ComInterface};
+use comptr::ComPtr;
+
+use crate::core::{fmt::block_size, *};
+use crate::d3d11;
+use crate::Error;
+
+use super::{check_lockable, Device, ManagedHandle};
+
+/// Maps a mip level of a volume texture, and returns a pointer to the requested box.
+pub fn lock_volume_level(
+    device: &Device,
+    texture: &d3d11::VolumeTexture,
+    fmt: D3DFORMAT,
+    level: u32,
+    bx: *const D3DBOX,
+    flags: LockFlags,
+    usage: UsageFlags,
+    pool: MemoryPool,
+) -> Result<D3DLOCKED_BOX, Error> {
+    let (width, height, depth) = texture.level_size(level);
+
+    let bx = unsafe { bx.as_ref() };
+
+    // Make sure the box is within the bounds of the level.
+    if let Some(bx) = bx {
+        if bx.Left >= bx.Right
+            || bx.Top >= bx.Bottom
+            || bx.Front >= bx.Back
+            || bx.Right > width
+            || bx.Bottom > height
+            || bx.Back > depth
+        {
+            return Err(Error::InvalidCall);
+        }
+    }
+
+    check_lockable(usage, pool, ResourceType::VolumeTexture)?;
+
+    // Managed textures are locked through their system memory copy, which cannot be discarded.
+    let usage = if pool == MemoryPool::Managed {
+        usage - UsageFlags::WRITE_ONLY
+    } else {
+        usage
+    };
+
+    let ctx = device.device_context();
+    let mut mapped = ctx.map_box(texture.as_resource(), level, flags, usage)?;
+
+    // D3D11 always maps the whole subresource, so we need to offset the pointer ourselves.
+    if let Some(bx) = bx {
+        // For compressed formats, the pitch is the size of a row of blocks.
+        let (block_bytes, block_dim) = block_size(fmt);
+
+        let offset = bx.Front as usize * mapped.SlicePitch as usize
+            + (bx.Top / block_dim) as usize * mapped.RowPitch as usize
+            + (bx.Left / block_dim * block_bytes) as usize;
+
+        mapped.pBits = unsafe { (mapped.pBits as *mut u8).add(offset) as *mut _ };
+    }
+
+    Ok(mapped)
+}
+
+/// Unmaps a mip level of a volume texture.
+pub fn unlock_volume_level(
+    device: &Device,
+    texture: &d3d11::VolumeTexture,
+    level: u32,
+    pool: MemoryPool,
+) {
+    let resource = texture.as_resource();
+
+    device.device_context().unmap(resource, level);
+
+    // The new data has to be uploaded to VRAM again.
+    if pool == MemoryPool::Managed {
+        device.mark_managed_dirty(ManagedHandle::from_resource(resource));
+    }
+}
+
+/// Represents a mip level of a volume texture.
+#[interface(IDirect3DVolume9)]
+pub struct Volume {
+    refs: AtomicU32,
+    device: *const Device,
+    // The texture which contains this volume.
+    texture: d3d11::VolumeTexture,
+    // The mip level of the texture this volume represents.
+    level: u32,
+    fmt: D3DFORMAT,
+    usage: UsageFlags,
+    pool: MemoryPool,
+    // Reference to the volume texture, which is returned by `GetContainer`.
+    container: ComPtr<IUnknown>,
+}
+
+impl Volume {
+    /// Creates a new volume representing a mip level of a texture.
+    pub fn new(
+        device: *const Device,
+        container: ComPtr<IUnknown>,
+        texture: d3d11::VolumeTexture,
+        level: u32,
+        fmt: D3DFORMAT,
+        usage: UsageFlags,
+        pool: MemoryPool,
+    ) -> ComPtr<Self> {
+        let volume = Self {
+            __vtable: Box::new(Self::create_vtable()),
+            refs: AtomicU32::new(1),
+            device,
+            texture,
+            level,
+            fmt,
+            usage,
+            pool,
+            container,
+        };
+
+        unsafe { new_com_interface(volume) }
+    }
+
+    fn device(&self) -> &Device {
+        unsafe { &*self.device }
+    }
+}
+
+impl_iunknown!(struct Volume: IUnknown, IDirect3DVolume9);
+
+#[implementation(IDirect3DVolume9)]
+impl Volume {
+    /// Returns the parent device.
+    fn get_device(&self, ret: *mut *mut Device) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = com_ref(self.device);
+        Error::Success
+    }
+
+    fn set_private_data(
+        &self,
+        _guid: &GUID,
+        _data: *const c_void,
+        _size: u32,
+        _flags: u32,
+    ) -> Error {
+        unimplemented_method!("IDirect3DVolume9::SetPrivateData", Error::InvalidCall)
+    }
+
+    /// Since private data cannot be set, there is never any to retrieve.
+    fn get_private_data(&self, _guid: &GUID, _data: *mut c_void, _size: *mut u32) -> Error {
+        Error::NotFound
+    }
+
+    fn free_private_data(&self, _guid: &GUID) -> Error {
+        Error::NotFound
+    }
+
+    /// Gets the container of this volume.
+    fn get_container(&self, riid: &GUID, ret: *mut usize) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+        query_interface(self.container.as_mut(), riid, ret)
+    }
+
+    /// Retrieves a description of this volume.
+    pub fn get_desc(&self, ret: *mut D3DVOLUME_DESC) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+
+        let (width, height, depth) = self.texture.level_size(self.level);
+
+        *ret = D3DVOLUME_DESC {
+            Format: self.fmt,
+            Type: D3DRTYPE_VOLUME,
+            Usage: self.usage.bits(),
+            Pool: self.pool as u32,
+            Width: width,
+            Height: height,
+            Depth: depth,
+        };
+
+        Error::Success
+    }
+
+    /// Maps a box of this volume to memory.
+    fn lock_box(&self, ret: *mut D3DLOCKED_BOX, bx: *const D3DBOX, flags: LockFlags) -> Error {
+        let _lock = self.device().lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = if_error!(lock_volume_level(
+            self.device(),
+            &self.texture,
+            self.fmt,
+            self.level,
+            bx,
+            flags,
+            self.usage,
+            self.pool
+        ));
+
+        Error::Success
+    }
+
+    /// Unmaps this volume.
+    fn unlock_box(&self) -> Error {
+        let _lock = self.device().lock();
+        unlock_volume_level(self.device(), &self.texture, self.level, self.pool);
+        Error::Success
+    }
+}
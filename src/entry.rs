@@ -1,4 +1,4 @@
-use crate::core::Context;
+use crate::core::{check_mut_ref, Context};
 use crate::Error;
 use comptr::ComPtr;
 use std::sync::Once;
@@ -44,11 +44,21 @@ pub unsafe extern "stdcall" fn Direct3DCreate9(sdk_version: u32) -> Option<ComPt
 /// This is unsafe because it's exported as DLL
 #[no_mangle]
 pub unsafe extern "stdcall" fn Direct3DCreate9Ex(
-    _sdk_version: u32,
-    _ptr: *mut *mut IDirect3D9Ex,
+    sdk_version: u32,
+    ptr: *mut *mut IDirect3D9Ex,
 ) -> Error {
-    error!("D3D9Ex is not yet supported");
-    Error::NotAvailable
+    let ptr = if_error!(check_mut_ref(ptr));
+
+    // The context implements both the normal and the extended interface.
+    let ctx = match Direct3DCreate9(sdk_version) {
+        Some(ctx) => ctx,
+        None => return Error::NotAvailable,
+    };
+
+    let ctx: *mut Context = ctx.into();
+    *ptr = ctx as *mut IDirect3D9Ex;
+
+    Error::Success
 }
 
 /// # Safety
@@ -1,5 +1,7 @@
 //! Error type definition.
 
+use winapi::shared::winerror::*;
+
 const D3D9_FACILITY: u32 = 0x876;
 
 const fn make_hresult(sev: u32, fac: u32, code: u32) -> u32 {
@@ -18,6 +20,8 @@ const fn make_status(code: u32) -> u32 {
 #[repr(u32)]
 pub enum Error {
     Success = 0,
+    // Equivalent to S_FALSE: the call succeeded, but the operation is not yet complete.
+    False = 1,
     NotFound = make_result(2150),
     MoreData = make_result(2151),
     NotAvailable = make_result(2154),
@@ -66,4 +70,90 @@ pub enum Error {
     PresentModeChanged = make_status(2167),
     PresentOccluded = make_status(2168),
     PresentStatisticsDisjoint = make_status(2180),
+
+    // COM errors
+    NoInterface = E_NOINTERFACE as u32,
+}
+
+impl Error {
+    /// Converts a D3D11 / DXGI result code to the closest D3D9 error.
+    pub fn from_hresult(hr: HRESULT) -> Self {
+        match hr {
+            S_OK => Error::Success,
+            S_FALSE => Error::False,
+            E_OUTOFMEMORY => Error::OutOfVideoMemory,
+            E_NOINTERFACE => Error::NoInterface,
+            E_INVALIDARG | DXGI_ERROR_INVALID_CALL => Error::InvalidCall,
+            E_NOTIMPL | DXGI_ERROR_UNSUPPORTED => Error::NotAvailable,
+            DXGI_ERROR_NOT_FOUND => Error::NotFound,
+            DXGI_ERROR_MORE_DATA => Error::MoreData,
+            DXGI_ERROR_WAS_STILL_DRAWING => Error::WasStillDrawing,
+            DXGI_ERROR_DEVICE_HUNG => Error::DeviceHung,
+            DXGI_ERROR_DEVICE_REMOVED | DXGI_ERROR_DEVICE_RESET => Error::DeviceLost,
+            DXGI_ERROR_DRIVER_INTERNAL_ERROR => Error::DriverInternalError,
+            DXGI_STATUS_OCCLUDED => Error::PresentOccluded,
+            _ => Error::DriverInternalError,
+        }
+    }
+}
+
+/// Every error which can be returned by this library.
+const ALL_ERRORS: &[Error] = &[
+    Error::Success,
+    Error::False,
+    Error::NotFound,
+    Error::MoreData,
+    Error::NotAvailable,
+    Error::InvalidCall,
+    Error::InvalidDevice,
+    Error::DeviceHung,
+    Error::DeviceLost,
+    Error::DeviceNotReset,
+    Error::DeviceRemoved,
+    Error::DriverInvalidCall,
+    Error::DriverInternalError,
+    Error::OutOfVideoMemory,
+    Error::TooManyOperations,
+    Error::WasStillDrawing,
+    Error::WrongTextureFormat,
+    Error::UnsupportedTextureFilter,
+    Error::UnsupportedColorOperation,
+    Error::UnsupportedColorArg,
+    Error::UnsupportedAlphaOperation,
+    Error::UnsupportedAlphaArg,
+    Error::UnsupportedFactorValue,
+    Error::UnsupportedOverlay,
+    Error::UnsupportedOverlayFormat,
+    Error::NoAutoGen,
+    Error::ConflictingTextureFilter,
+    Error::ConflictingTexturePalette,
+    Error::ConflictingRenderState,
+    Error::CanNotProtectContent,
+    Error::UnsupportedCrypto,
+    Error::NotResident,
+    Error::ResidentInSharedMemory,
+    Error::PresentModeChanged,
+    Error::PresentOccluded,
+    Error::PresentStatisticsDisjoint,
+    Error::NoInterface,
+];
+
+impl Error {
+    /// Converts the result code returned by a D3D9 method back to an error.
+    ///
+    /// Codes which are not D3D9 errors are converted like D3D11 / DXGI ones.
+    pub fn from_d3d9_result(hr: HRESULT) -> Self {
+        ALL_ERRORS
+            .iter()
+            .copied()
+            .find(|&err| HRESULT::from(err) == hr)
+            .unwrap_or_else(|| Self::from_hresult(hr))
+    }
+}
+
+impl From<Error> for HRESULT {
+    fn from(err: Error) -> HRESULT {
+        // The enum's values are the D3D9 result codes.
+        err as u32 as HRESULT
+    }
 }
@@ -27,3 +27,6 @@ mod d3d11;
 
 mod entry;
 pub use self::entry::*;
+
+#[cfg(feature = "testing")]
+pub mod testing;
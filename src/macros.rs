@@ -9,13 +9,35 @@ macro_rules! run_once {
     }};
 }
 
+/// Macro which logs a warning the first time an unimplemented method is called,
+/// then evaluates to the given error (`Error::Success` if omitted).
+///
+/// Games tend to call the same methods every frame, so we only log once per call site.
+macro_rules! unimplemented_method {
+    ($name: expr) => {
+        unimplemented_method!($name, Error::Success)
+    };
+    ($name: expr, $ret: expr) => {{
+        use std::sync::atomic::{AtomicBool, Ordering};
+        static LOGGED: AtomicBool = AtomicBool::new(false);
+        if !LOGGED.swap(true, Ordering::Relaxed) {
+            warn!("{} is not implemented", $name);
+        }
+        $ret
+    }};
+}
+
+/// Implements `IUnknown` for a COM object, which can be queried for any of the listed interfaces.
+///
+/// Extended interfaces (like `IDirect3D9Ex`) have vtables which start with the base interface's
+/// methods, so the same pointer is handed out for all of them.
 macro_rules! impl_iunknown {
     (struct $struct_name:ty : $($ifaces:ident),*) => {
         #[implementation(IUnknown)]
         impl $struct_name {
             fn query_interface(&mut self, riid: &winapi::shared::guiddef::GUID, obj: &mut usize) -> i32 {
                 use winapi::Interface;
-                use winapi::shared::{guiddef::IsEqualGUID, winerror::{S_OK, E_NOTIMPL}};
+                use winapi::shared::{guiddef::IsEqualGUID, winerror::{S_OK, E_NOINTERFACE}};
 
                 *obj = 0;
 
@@ -24,7 +46,8 @@ macro_rules! impl_iunknown {
                     self.add_ref();
                     S_OK
                 } else {
-                    E_NOTIMPL
+                    // Apps probe for interfaces this way, so this is not an error.
+                    E_NOINTERFACE
                 }
             }
 
@@ -38,6 +61,7 @@ macro_rules! impl_iunknown {
                 use std::sync::atomic::Ordering;
                 let prev = self.refs.fetch_sub(1, Ordering::SeqCst);
                 if prev == 1 {
+                    $crate::core::leaks::unregister(self as *const _);
                     let _box = unsafe { Box::from_raw(self as *mut _) };
                 }
                 prev - 1
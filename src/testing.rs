@@ -0,0 +1,413 @@
+//! Safe wrappers for driving the library from Rust code.
+//!
+//! Everything else in this library is only meant to be called through the D3D9 COM interfaces.
+//! These types wrap the interfaces' unsafe vtable calls in methods returning `Result`,
+//! which makes it easier to write tests and to reproduce bugs.
+//!
+//! Only available with the `testing` feature.
+
+use std::{mem, ptr};
+
+use comptr::ComPtr;
+use widestring::U16CString;
+use winapi::shared::d3d9::*;
+use winapi::shared::d3d9caps::D3DCAPS9;
+use winapi::shared::d3d9types::*;
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::HRESULT;
+use winapi::um::winuser;
+
+use crate::core;
+use crate::dev;
+use crate::Error;
+
+/// Converts the result of a D3D9 method.
+fn check(hr: HRESULT) -> Result<(), Error> {
+    match Error::from_d3d9_result(hr) {
+        Error::Success => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Hidden window which devices can render to.
+pub struct Window {
+    handle: HWND,
+}
+
+impl Window {
+    /// Creates a new window, with a client area of a certain size.
+    pub fn new(width: u32, height: u32) -> Result<Self, Error> {
+        // Built-in window classes don't need to be registered.
+        let class = U16CString::from_str("STATIC").unwrap();
+        let title = U16CString::from_str("d3d9-to-11").unwrap();
+
+        let handle = unsafe {
+            winuser::CreateWindowExW(
+                0,
+                class.as_ptr(),
+                title.as_ptr(),
+                winuser::WS_OVERLAPPEDWINDOW,
+                0,
+                0,
+                width as i32,
+                height as i32,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+
+        if handle.is_null() {
+            error!("Failed to create a {}x{} window", width, height);
+            return Err(Error::NotAvailable);
+        }
+
+        Ok(Self { handle })
+    }
+
+    /// Returns the window's handle.
+    pub fn handle(&self) -> HWND {
+        self.handle
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        unsafe {
+            winuser::DestroyWindow(self.handle);
+        }
+    }
+}
+
+/// D3D9 context, from which devices are created.
+pub struct Context {
+    ctx: ComPtr<IDirect3D9Ex>,
+}
+
+impl Context {
+    /// Creates a context which uses the system's graphics adapters.
+    pub fn new() -> Result<Self, Error> {
+        core::Context::new().map(Self::from_context)
+    }
+
+    /// Creates a context which uses the WARP software rasterizer.
+    pub fn new_warp() -> Result<Self, Error> {
+        core::Context::new_warp().map(Self::from_context)
+    }
+
+    fn from_context(ctx: ComPtr<core::Context>) -> Self {
+        let ctx: *mut core::Context = ctx.into();
+
+        Self {
+            ctx: ComPtr::new(ctx as *mut IDirect3D9Ex),
+        }
+    }
+
+    /// Returns the underlying COM interface.
+    pub fn as_raw(&self) -> &IDirect3D9Ex {
+        &self.ctx
+    }
+
+    /// Returns one of the context's adapters.
+    pub fn adapter(&self, adapter: u32) -> Option<&core::Adapter> {
+        // The interface is implemented by our own context.
+        let ctx = unsafe { &*(&*self.ctx as *const IDirect3D9Ex as *const core::Context) };
+        ctx.adapter(adapter)
+    }
+
+    /// Returns the number of adapters.
+    pub fn adapter_count(&self) -> u32 {
+        unsafe { self.ctx.GetAdapterCount() }
+    }
+
+    /// Retrieves the description of an adapter.
+    pub fn adapter_identifier(&self, adapter: u32) -> Result<D3DADAPTER_IDENTIFIER9, Error> {
+        unsafe {
+            let mut id = mem::zeroed();
+            check(self.ctx.GetAdapterIdentifier(adapter, 0, &mut id))?;
+            Ok(id)
+        }
+    }
+
+    /// Retrieves the current display mode of an adapter.
+    pub fn display_mode(&self, adapter: u32) -> Result<D3DDISPLAYMODE, Error> {
+        unsafe {
+            let mut mode = mem::zeroed();
+            check(self.ctx.GetAdapterDisplayMode(adapter, &mut mode))?;
+            Ok(mode)
+        }
+    }
+
+    /// Retrieves the capabilities of an adapter.
+    pub fn caps(&self, adapter: u32, ty: D3DDEVTYPE) -> Result<D3DCAPS9, Error> {
+        unsafe {
+            let mut caps = mem::zeroed();
+            check(self.ctx.GetDeviceCaps(adapter, ty, &mut caps))?;
+            Ok(caps)
+        }
+    }
+
+    /// Checks if a resource format is supported for a certain usage.
+    pub fn check_device_format(
+        &self,
+        adapter: u32,
+        adapter_fmt: D3DFORMAT,
+        usage: DWORD,
+        rtype: D3DRESOURCETYPE,
+        fmt: D3DFORMAT,
+    ) -> Result<(), Error> {
+        unsafe {
+            check(self.ctx.CheckDeviceFormat(
+                adapter,
+                D3DDEVTYPE_HAL,
+                adapter_fmt,
+                usage,
+                rtype,
+                fmt,
+            ))
+        }
+    }
+
+    /// Creates a hardware device which renders to a window.
+    ///
+    /// The present parameters are updated with the values the device actually uses.
+    pub fn create_device(
+        &self,
+        adapter: u32,
+        window: &Window,
+        flags: DWORD,
+        pp: &mut D3DPRESENT_PARAMETERS,
+    ) -> Result<Device, Error> {
+        unsafe {
+            let mut device = ptr::null_mut();
+            check(self.ctx.CreateDevice(
+                adapter,
+                D3DDEVTYPE_HAL,
+                window.handle(),
+                flags,
+                pp,
+                &mut device,
+            ))?;
+
+            // Devices always implement the extended interface.
+            Ok(Device {
+                device: ComPtr::new(device as *mut IDirect3DDevice9Ex),
+            })
+        }
+    }
+}
+
+/// D3D9 device, which creates resources and renders.
+pub struct Device {
+    device: ComPtr<IDirect3DDevice9Ex>,
+}
+
+impl Device {
+    /// Returns the underlying COM interface.
+    pub fn as_raw(&self) -> &IDirect3DDevice9Ex {
+        &self.device
+    }
+
+    /// Checks if the device was lost.
+    pub fn test_cooperative_level(&self) -> Result<(), Error> {
+        unsafe { check(self.device.TestCooperativeLevel()) }
+    }
+
+    /// Resets the device, recreating its swap chain.
+    pub fn reset(&self, pp: &mut D3DPRESENT_PARAMETERS) -> Result<(), Error> {
+        unsafe { check(self.device.Reset(pp)) }
+    }
+
+    /// Presents the back buffer to the device's window.
+    pub fn present(&self) -> Result<(), Error> {
+        unsafe {
+            check(
+                self.device
+                    .Present(ptr::null(), ptr::null(), ptr::null_mut(), ptr::null()),
+            )
+        }
+    }
+
+    pub fn begin_scene(&self) -> Result<(), Error> {
+        unsafe { check(self.device.BeginScene()) }
+    }
+
+    pub fn end_scene(&self) -> Result<(), Error> {
+        unsafe { check(self.device.EndScene()) }
+    }
+
+    /// Clears the whole render target, depth and / or stencil buffer.
+    pub fn clear(
+        &self,
+        flags: DWORD,
+        color: D3DCOLOR,
+        z: f32,
+        stencil: DWORD,
+    ) -> Result<(), Error> {
+        unsafe { check(self.device.Clear(0, ptr::null(), flags, color, z, stencil)) }
+    }
+
+    pub fn set_render_state(&self, state: D3DRENDERSTATETYPE, value: DWORD) -> Result<(), Error> {
+        unsafe { check(self.device.SetRenderState(state, value)) }
+    }
+
+    pub fn render_state(&self, state: D3DRENDERSTATETYPE) -> Result<DWORD, Error> {
+        unsafe {
+            let mut value = 0;
+            check(self.device.GetRenderState(state, &mut value))?;
+            Ok(value)
+        }
+    }
+
+    pub fn set_fvf(&self, fvf: DWORD) -> Result<(), Error> {
+        unsafe { check(self.device.SetFVF(fvf)) }
+    }
+
+    pub fn fvf(&self) -> Result<DWORD, Error> {
+        unsafe {
+            let mut fvf = 0;
+            check(self.device.GetFVF(&mut fvf))?;
+            Ok(fvf)
+        }
+    }
+
+    /// Retrieves one of the back buffers of a swap chain.
+    pub fn back_buffer(
+        &self,
+        swap_chain: u32,
+        index: u32,
+    ) -> Result<ComPtr<IDirect3DSurface9>, Error> {
+        unsafe {
+            let mut surface = ptr::null_mut();
+            check(self.device.GetBackBuffer(
+                swap_chain,
+                index,
+                D3DBACKBUFFER_TYPE_MONO,
+                &mut surface,
+            ))?;
+            Ok(ComPtr::new(surface))
+        }
+    }
+
+    /// Creates a render target which is not multisampled.
+    pub fn create_render_target(
+        &self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        lockable: bool,
+    ) -> Result<ComPtr<IDirect3DSurface9>, Error> {
+        unsafe {
+            let mut surface = ptr::null_mut();
+            check(self.device.CreateRenderTarget(
+                width,
+                height,
+                fmt,
+                D3DMULTISAMPLE_NONE,
+                0,
+                lockable as i32,
+                &mut surface,
+                ptr::null_mut(),
+            ))?;
+            Ok(ComPtr::new(surface))
+        }
+    }
+
+    pub fn create_texture(
+        &self,
+        width: u32,
+        height: u32,
+        levels: u32,
+        usage: DWORD,
+        fmt: D3DFORMAT,
+        pool: D3DPOOL,
+    ) -> Result<ComPtr<IDirect3DTexture9>, Error> {
+        unsafe {
+            let mut texture = ptr::null_mut();
+            check(self.device.CreateTexture(
+                width,
+                height,
+                levels,
+                usage,
+                fmt,
+                pool,
+                &mut texture,
+                ptr::null_mut(),
+            ))?;
+            Ok(ComPtr::new(texture))
+        }
+    }
+
+    pub fn create_vertex_buffer(
+        &self,
+        len: u32,
+        usage: DWORD,
+        fvf: DWORD,
+        pool: D3DPOOL,
+    ) -> Result<ComPtr<IDirect3DVertexBuffer9>, Error> {
+        unsafe {
+            let mut buffer = ptr::null_mut();
+            check(self.device.CreateVertexBuffer(
+                len,
+                usage,
+                fvf,
+                pool,
+                &mut buffer,
+                ptr::null_mut(),
+            ))?;
+            Ok(ComPtr::new(buffer))
+        }
+    }
+
+    pub fn create_query(&self, ty: D3DQUERYTYPE) -> Result<ComPtr<IDirect3DQuery9>, Error> {
+        unsafe {
+            let mut query = ptr::null_mut();
+            check(self.device.CreateQuery(ty, &mut query))?;
+            Ok(ComPtr::new(query))
+        }
+    }
+
+    /// Skips the following draws on the GPU, depending on the result of an occlusion query.
+    ///
+    /// Passing `None` draws everything again.
+    pub fn set_predication(
+        &self,
+        query: Option<&IDirect3DQuery9>,
+        value: bool,
+    ) -> Result<(), Error> {
+        // The interfaces are implemented by our own types.
+        let device =
+            unsafe { &*(&*self.device as *const IDirect3DDevice9Ex as *const dev::Device) };
+        let query =
+            query.map(|query| unsafe { &*(query as *const IDirect3DQuery9 as *const dev::Query) });
+
+        device.set_predication(query, value)
+    }
+
+    /// Draws primitives from vertices stored in a slice.
+    ///
+    /// The stride is the size of `T`.
+    pub fn draw_primitive_up<T: Copy>(
+        &self,
+        ty: D3DPRIMITIVETYPE,
+        count: u32,
+        vertices: &[T],
+    ) -> Result<(), Error> {
+        // The device cannot check the vertices are all there.
+        let (_, needed) = crate::dev::primitive_topology(ty, count).ok_or(Error::InvalidCall)?;
+        if vertices.len() < needed as usize {
+            return Err(Error::InvalidCall);
+        }
+
+        unsafe {
+            check(self.device.DrawPrimitiveUP(
+                ty,
+                count,
+                vertices.as_ptr() as *const _,
+                mem::size_of::<T>() as u32,
+            ))
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Tests for the D3D9 context and its adapters.
+
+use std::ptr;
+
+use winapi::shared::d3d9caps::*;
+use winapi::shared::d3d9types::*;
+
+use d3d9::core::fmt::d3d_format_to_dxgi;
+use d3d9::testing::Context;
+use d3d9::Error;
+
+#[test]
+fn warp_adapter_has_no_outputs() {
+    let ctx = Context::new_warp().unwrap();
+    let adapter = ctx.adapter(0).unwrap();
+
+    assert_eq!(adapter.outputs().count(), 0);
+    assert_eq!(adapter.mode_count(D3DFMT_X8R8G8B8), 0);
+    assert!(matches!(
+        adapter.set_active_output(0),
+        Err(Error::InvalidCall)
+    ));
+}
+
+#[test]
+fn adapters_without_outputs_report_a_display_mode() {
+    let ctx = Context::new_warp().unwrap();
+    let mode = ctx.display_mode(0).unwrap();
+
+    assert_eq!((mode.Width, mode.Height), (1920, 1080));
+    assert_eq!(mode.Format, D3DFMT_X8R8G8B8);
+    assert_eq!(mode.RefreshRate, 0);
+}
+
+#[test]
+fn switching_outputs_switches_modes() {
+    // Only machines with a GPU have outputs to switch between.
+    let ctx = match Context::new() {
+        Ok(ctx) => ctx,
+        Err(_) => return,
+    };
+
+    for index in 0..ctx.adapter_count() {
+        let adapter = ctx.adapter(index).unwrap();
+        let outputs: Vec<_> = adapter.outputs().cloned().collect();
+
+        // Go through the outputs twice, to check the cached modes are not mixed up.
+        for (i, output) in outputs.iter().enumerate().chain(outputs.iter().enumerate()) {
+            adapter.set_active_output(i).unwrap();
+
+            let expected = unsafe {
+                let mut num = 0;
+                output.GetDisplayModeList(
+                    d3d_format_to_dxgi(D3DFMT_X8R8G8B8),
+                    0,
+                    &mut num,
+                    ptr::null_mut(),
+                );
+                num
+            };
+
+            assert_eq!(adapter.mode_count(D3DFMT_X8R8G8B8), expected);
+
+            adapter.refresh_modes();
+            assert_eq!(adapter.mode_count(D3DFMT_X8R8G8B8), expected);
+        }
+    }
+}
+
+#[test]
+fn caps_are_within_d3d9_limits() {
+    let ctx = Context::new_warp().unwrap();
+    let caps = ctx.caps(0, D3DDEVTYPE_HAL).unwrap();
+
+    let vs = caps.VS20Caps;
+    assert!((D3DVS20_MIN_NUMTEMPS..=D3DVS20_MAX_NUMTEMPS).contains(&(vs.NumTemps as u32)));
+    assert!(vs.DynamicFlowControlDepth as u32 <= D3DVS20_MAX_DYNAMICFLOWCONTROLDEPTH);
+    assert!(vs.StaticFlowControlDepth as u32 <= D3DVS20_MAX_STATICFLOWCONTROLDEPTH);
+
+    let ps = caps.PS20Caps;
+    assert!((D3DPS20_MIN_NUMTEMPS..=D3DPS20_MAX_NUMTEMPS).contains(&(ps.NumTemps as u32)));
+    assert!(ps.DynamicFlowControlDepth as u32 <= D3DPS20_MAX_DYNAMICFLOWCONTROLDEPTH);
+    assert!(ps.StaticFlowControlDepth as u32 <= D3DPS20_MAX_STATICFLOWCONTROLDEPTH);
+    assert!(
+        (D3DPS20_MIN_NUMINSTRUCTIONSLOTS..=D3DPS20_MAX_NUMINSTRUCTIONSLOTS)
+            .contains(&(ps.NumInstructionSlots as u32))
+    );
+
+    for &slots in &[
+        caps.MaxVertexShader30InstructionSlots,
+        caps.MaxPixelShader30InstructionSlots,
+    ] {
+        assert!((D3DMIN30SHADERINSTRUCTIONS..=D3DMAX30SHADERINSTRUCTIONS).contains(&slots));
+    }
+}
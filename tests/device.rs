@@ -0,0 +1,466 @@
+//! Tests for the D3D9 device.
+
+use std::{mem, ptr};
+
+use winapi::shared::d3d9::*;
+use winapi::shared::d3d9types::*;
+use winapi::um::wingdi::PALETTEENTRY;
+
+use d3d9::testing::{Context, Device, Window};
+use d3d9::Error;
+
+/// Describes a windowed swap chain with a back buffer of a certain size.
+fn present_parameters(window: &Window, width: u32, height: u32) -> D3DPRESENT_PARAMETERS {
+    let mut pp: D3DPRESENT_PARAMETERS = unsafe { mem::zeroed() };
+    pp.BackBufferWidth = width;
+    pp.BackBufferHeight = height;
+    pp.BackBufferFormat = D3DFMT_X8R8G8B8;
+    pp.BackBufferCount = 1;
+    pp.SwapEffect = D3DSWAPEFFECT_DISCARD;
+    pp.hDeviceWindow = window.handle();
+    pp.Windowed = 1;
+    pp
+}
+
+/// Creates a device which renders to a small window, using WARP.
+///
+/// The window and the context must be kept alive for as long as the device.
+fn create_device() -> (Device, Window, Context) {
+    let ctx = Context::new_warp().unwrap();
+    let window = Window::new(64, 64).unwrap();
+
+    let mut pp = present_parameters(&window, 64, 64);
+    let device = ctx
+        .create_device(0, &window, D3DCREATE_HARDWARE_VERTEXPROCESSING, &mut pp)
+        .unwrap();
+
+    (device, window, ctx)
+}
+
+/// Retrieves the size of a surface.
+fn surface_size(surface: &IDirect3DSurface9) -> (u32, u32) {
+    let mut desc: D3DSURFACE_DESC = unsafe { mem::zeroed() };
+    unsafe {
+        assert_eq!(surface.GetDesc(&mut desc), 0);
+    }
+    (desc.Width, desc.Height)
+}
+
+#[test]
+fn render_states_are_kept() {
+    let (device, _window, _ctx) = create_device();
+
+    // Without an automatic depth buffer, depth testing starts out disabled.
+    assert_eq!(device.render_state(D3DRS_ZENABLE).unwrap(), D3DZB_FALSE);
+
+    device.set_render_state(D3DRS_ZENABLE, D3DZB_TRUE).unwrap();
+    assert_eq!(device.render_state(D3DRS_ZENABLE).unwrap(), D3DZB_TRUE);
+}
+
+#[test]
+fn frames_can_be_drawn_and_presented() {
+    let (device, _window, _ctx) = create_device();
+
+    let triangle = [
+        [0.0f32, 0.0, 0.5, 1.0],
+        [64.0, 0.0, 0.5, 1.0],
+        [0.0, 64.0, 0.5, 1.0],
+    ];
+
+    device.test_cooperative_level().unwrap();
+    device.set_fvf(D3DFVF_XYZRHW).unwrap();
+
+    device.begin_scene().unwrap();
+    device.clear(D3DCLEAR_TARGET, 0xff00_ff00, 1.0, 0).unwrap();
+    device
+        .draw_primitive_up(D3DPT_TRIANGLELIST, 1, &triangle)
+        .unwrap();
+    device.end_scene().unwrap();
+
+    device.present().unwrap();
+    device.test_cooperative_level().unwrap();
+}
+
+#[test]
+fn user_clip_planes_can_be_enabled() {
+    let (device, _window, _ctx) = create_device();
+
+    let triangle = [[-1.0f32, -1.0, 0.5], [-1.0, 1.0, 0.5], [1.0, -1.0, 0.5]];
+
+    device.set_fvf(D3DFVF_XYZ).unwrap();
+    assert_eq!(device.fvf().unwrap(), D3DFVF_XYZ);
+
+    // Keep only the left half of the screen.
+    let plane = [-1.0f32, 0.0, 0.0, 0.0];
+    unsafe {
+        assert_eq!(device.as_raw().SetClipPlane(0, plane.as_ptr()), 0);
+    }
+    device
+        .set_render_state(D3DRS_CLIPPLANEENABLE, D3DCLIPPLANE0)
+        .unwrap();
+
+    device.begin_scene().unwrap();
+    device
+        .draw_primitive_up(D3DPT_TRIANGLELIST, 1, &triangle)
+        .unwrap();
+    device.end_scene().unwrap();
+}
+
+#[test]
+fn lit_geometry_can_be_drawn() {
+    let (device, _window, _ctx) = create_device();
+
+    // Positions followed by normals.
+    let triangle = [
+        [-1.0f32, -1.0, 0.5, 0.0, 0.0, -1.0],
+        [-1.0, 1.0, 0.5, 0.0, 0.0, -1.0],
+        [1.0, -1.0, 0.5, 0.0, 0.0, -1.0],
+    ];
+
+    device.set_fvf(D3DFVF_XYZ | D3DFVF_NORMAL).unwrap();
+
+    let mut light: D3DLIGHT9 = unsafe { mem::zeroed() };
+    light.Type = D3DLIGHT_SPOT;
+    light.Diffuse = D3DCOLORVALUE {
+        r: 1.0,
+        g: 1.0,
+        b: 1.0,
+        a: 1.0,
+    };
+    light.Position.z = -1.0;
+    light.Direction.z = 1.0;
+    light.Range = 10.0;
+    light.Attenuation0 = 1.0;
+    light.Theta = 0.5;
+    light.Phi = 1.0;
+    light.Falloff = 1.0;
+
+    unsafe {
+        assert_eq!(device.as_raw().SetLight(0, &light), 0);
+        assert_eq!(device.as_raw().LightEnable(0, 1), 0);
+    }
+    device.set_render_state(D3DRS_LIGHTING, 1).unwrap();
+
+    device.begin_scene().unwrap();
+    device
+        .draw_primitive_up(D3DPT_TRIANGLELIST, 1, &triangle)
+        .unwrap();
+    device.end_scene().unwrap();
+}
+
+#[test]
+fn fogged_geometry_can_be_drawn() {
+    let (device, _window, _ctx) = create_device();
+
+    let triangle = [[-1.0f32, -1.0, 0.5], [-1.0, 1.0, 0.5], [1.0, -1.0, 0.5]];
+
+    device.set_fvf(D3DFVF_XYZ).unwrap();
+    device.set_render_state(D3DRS_FOGENABLE, 1).unwrap();
+    device
+        .set_render_state(D3DRS_FOGCOLOR, 0xff80_8080)
+        .unwrap();
+    device
+        .set_render_state(D3DRS_FOGSTART, 0.25f32.to_bits())
+        .unwrap();
+    device
+        .set_render_state(D3DRS_FOGEND, 0.75f32.to_bits())
+        .unwrap();
+
+    device.begin_scene().unwrap();
+
+    // Per-vertex fog.
+    device
+        .set_render_state(D3DRS_FOGVERTEXMODE, D3DFOG_LINEAR)
+        .unwrap();
+    device
+        .draw_primitive_up(D3DPT_TRIANGLELIST, 1, &triangle)
+        .unwrap();
+
+    // Per-pixel fog takes precedence.
+    device
+        .set_render_state(D3DRS_FOGTABLEMODE, D3DFOG_EXP2)
+        .unwrap();
+    device
+        .draw_primitive_up(D3DPT_TRIANGLELIST, 1, &triangle)
+        .unwrap();
+
+    device.end_scene().unwrap();
+}
+
+#[test]
+fn alpha_tested_geometry_can_be_drawn() {
+    let (device, _window, _ctx) = create_device();
+
+    // Positions followed by a half transparent diffuse color.
+    let triangle = [
+        [-1.0f32, -1.0, 0.5, f32::from_bits(0x80ff_ffff)],
+        [-1.0, 1.0, 0.5, f32::from_bits(0x80ff_ffff)],
+        [1.0, -1.0, 0.5, f32::from_bits(0x80ff_ffff)],
+    ];
+
+    device.set_fvf(D3DFVF_XYZ | D3DFVF_DIFFUSE).unwrap();
+    device.set_render_state(D3DRS_ALPHATESTENABLE, 1).unwrap();
+    device
+        .set_render_state(D3DRS_ALPHAFUNC, D3DCMP_GREATER)
+        .unwrap();
+    device.set_render_state(D3DRS_ALPHAREF, 0x7f).unwrap();
+
+    device.begin_scene().unwrap();
+    device
+        .draw_primitive_up(D3DPT_TRIANGLELIST, 1, &triangle)
+        .unwrap();
+    device.end_scene().unwrap();
+}
+
+#[test]
+fn paletted_textures_can_be_drawn() {
+    let (device, _window, _ctx) = create_device();
+
+    // Positions followed by texture coordinates.
+    let triangle = [
+        [-1.0f32, -1.0, 0.5, 0.0, 1.0],
+        [-1.0, 1.0, 0.5, 0.0, 0.0],
+        [1.0, -1.0, 0.5, 1.0, 1.0],
+    ];
+
+    let texture = device
+        .create_texture(16, 16, 1, 0, D3DFMT_P8, D3DPOOL_MANAGED)
+        .unwrap();
+
+    let mut palette: [PALETTEENTRY; 256] = unsafe { mem::zeroed() };
+    palette[1].peRed = 0xff;
+
+    unsafe {
+        let device = device.as_raw();
+        assert_eq!(device.SetPaletteEntries(3, palette.as_ptr()), 0);
+        assert_eq!(device.SetCurrentTexturePalette(3), 0);
+        assert_eq!(device.SetTexture(0, texture.as_mut() as *mut _), 0);
+    }
+
+    device.set_fvf(D3DFVF_XYZ | D3DFVF_TEX1).unwrap();
+
+    device.begin_scene().unwrap();
+    device
+        .draw_primitive_up(D3DPT_TRIANGLELIST, 1, &triangle)
+        .unwrap();
+    device.end_scene().unwrap();
+}
+
+#[test]
+fn point_sprites_can_be_drawn() {
+    let (device, _window, _ctx) = create_device();
+
+    let points = [[0.0f32, 0.0, 0.5], [0.5, 0.5, 0.5]];
+
+    device.set_fvf(D3DFVF_XYZ).unwrap();
+    device.set_render_state(D3DRS_POINTSPRITEENABLE, 1).unwrap();
+    device
+        .set_render_state(D3DRS_POINTSIZE, 16.0f32.to_bits())
+        .unwrap();
+    device.set_render_state(D3DRS_POINTSCALEENABLE, 1).unwrap();
+
+    device.begin_scene().unwrap();
+    device
+        .draw_primitive_up(D3DPT_POINTLIST, 2, &points)
+        .unwrap();
+    device.end_scene().unwrap();
+}
+
+#[test]
+fn draws_need_enough_vertices() {
+    let (device, _window, _ctx) = create_device();
+
+    let line = [[0.0f32, 0.0, 0.5, 1.0], [64.0, 64.0, 0.5, 1.0]];
+
+    device.begin_scene().unwrap();
+    assert!(matches!(
+        device.draw_primitive_up(D3DPT_TRIANGLELIST, 1, &line),
+        Err(Error::InvalidCall)
+    ));
+    device.end_scene().unwrap();
+}
+
+#[test]
+fn reset_resizes_the_back_buffer() {
+    let (device, window, _ctx) = create_device();
+
+    assert_eq!(surface_size(&device.back_buffer(0, 0).unwrap()), (64, 64));
+
+    let mut pp = present_parameters(&window, 32, 16);
+    device.reset(&mut pp).unwrap();
+
+    assert_eq!(surface_size(&device.back_buffer(0, 0).unwrap()), (32, 16));
+    device.test_cooperative_level().unwrap();
+}
+
+#[test]
+fn unknown_back_buffer_formats_default_to_the_desktop_format() {
+    let (device, window, ctx) = create_device();
+    let desktop = ctx.display_mode(0).unwrap().Format;
+
+    let mut pp = present_parameters(&window, 64, 64);
+    pp.BackBufferFormat = D3DFMT_UNKNOWN;
+    device.reset(&mut pp).unwrap();
+
+    assert_eq!(pp.BackBufferFormat, desktop);
+}
+
+#[test]
+fn render_targets_must_be_in_the_default_pool() {
+    let (device, _window, _ctx) = create_device();
+
+    let surface = device
+        .create_render_target(16, 16, D3DFMT_A8R8G8B8, true)
+        .unwrap();
+    assert_eq!(surface_size(&surface), (16, 16));
+
+    device
+        .create_texture(
+            16,
+            16,
+            1,
+            D3DUSAGE_RENDERTARGET,
+            D3DFMT_A8R8G8B8,
+            D3DPOOL_DEFAULT,
+        )
+        .unwrap();
+
+    assert!(matches!(
+        device.create_texture(
+            16,
+            16,
+            1,
+            D3DUSAGE_RENDERTARGET,
+            D3DFMT_A8R8G8B8,
+            D3DPOOL_MANAGED
+        ),
+        Err(Error::InvalidCall)
+    ));
+}
+
+#[test]
+fn occlusion_queries_predicate_draws() {
+    let (device, _window, _ctx) = create_device();
+
+    let query = device.create_query(D3DQUERYTYPE_OCCLUSION).unwrap();
+    device.set_predication(Some(&*query), false).unwrap();
+    device.set_predication(None, false).unwrap();
+
+    // Other queries don't track whether any pixels were drawn.
+    let event = device.create_query(D3DQUERYTYPE_EVENT).unwrap();
+    assert!(matches!(
+        device.set_predication(Some(&*event), false),
+        Err(Error::NotAvailable)
+    ));
+}
+
+#[test]
+fn queries_keep_their_device_alive() {
+    let (device, _window, _ctx) = create_device();
+
+    let query = device.create_query(D3DQUERYTYPE_EVENT).unwrap();
+    drop(device);
+
+    // The query still points back to the device the app released.
+    unsafe {
+        let mut parent = ptr::null_mut();
+        assert_eq!(query.GetDevice(&mut parent), 0);
+        assert!(!parent.is_null());
+        (*parent).Release();
+    }
+}
+
+#[test]
+fn static_buffers_in_the_default_pool_can_be_locked() {
+    let (device, _window, _ctx) = create_device();
+
+    let buffer = device
+        .create_vertex_buffer(64, D3DUSAGE_WRITEONLY, 0, D3DPOOL_DEFAULT)
+        .unwrap();
+
+    unsafe {
+        let mut data = ptr::null_mut();
+        assert_eq!(buffer.Lock(0, 0, &mut data, 0), 0);
+        ptr::write_bytes(data as *mut u8, 0xff, 64);
+        assert_eq!(buffer.Unlock(), 0);
+    }
+}
+
+#[test]
+fn buffers_in_the_default_pool_keep_their_contents() {
+    let (device, _window, _ctx) = create_device();
+
+    for &usage in &[0, D3DUSAGE_DYNAMIC] {
+        let buffer = device
+            .create_vertex_buffer(64, usage, 0, D3DPOOL_DEFAULT)
+            .unwrap();
+
+        unsafe {
+            let mut data = ptr::null_mut();
+            assert_eq!(buffer.Lock(0, 0, &mut data, 0), 0);
+            for i in 0..64 {
+                *(data as *mut u8).add(i) = i as u8;
+            }
+            assert_eq!(buffer.Unlock(), 0);
+
+            // Locks which don't discard only change the range they lock.
+            assert_eq!(buffer.Lock(16, 16, &mut data, 0), 0);
+            ptr::write_bytes(data as *mut u8, 0xff, 16);
+            assert_eq!(buffer.Unlock(), 0);
+
+            assert_eq!(buffer.Lock(0, 0, &mut data, D3DLOCK_READONLY), 0);
+            let contents = std::slice::from_raw_parts(data as *const u8, 64);
+            for (i, &byte) in contents.iter().enumerate() {
+                let expected = if (16..32).contains(&i) { 0xff } else { i as u8 };
+                assert_eq!(byte, expected, "usage {:#x}, byte {}", usage, i);
+            }
+            assert_eq!(buffer.Unlock(), 0);
+        }
+    }
+}
+
+#[test]
+fn static_textures_in_the_default_pool_cannot_be_locked() {
+    let (device, _window, _ctx) = create_device();
+
+    let texture = device
+        .create_texture(16, 16, 1, 0, D3DFMT_A8R8G8B8, D3DPOOL_DEFAULT)
+        .unwrap();
+
+    let result = unsafe {
+        let mut rect = mem::zeroed();
+        texture.LockRect(0, &mut rect, ptr::null(), 0)
+    };
+    assert!(matches!(
+        Error::from_d3d9_result(result),
+        Error::InvalidCall
+    ));
+}
+
+#[test]
+fn buffers_describe_themselves() {
+    let (device, _window, _ctx) = create_device();
+
+    let fvf = D3DFVF_XYZRHW | D3DFVF_DIFFUSE;
+    let buffer = device
+        .create_vertex_buffer(
+            96,
+            D3DUSAGE_DYNAMIC | D3DUSAGE_WRITEONLY,
+            fvf,
+            D3DPOOL_DEFAULT,
+        )
+        .unwrap();
+
+    let desc = unsafe {
+        let mut desc: D3DVERTEXBUFFER_DESC = mem::zeroed();
+        assert_eq!(buffer.GetDesc(&mut desc), 0);
+        desc
+    };
+
+    assert_eq!(desc.Format, D3DFMT_VERTEXDATA);
+    assert_eq!(desc.Type, D3DRTYPE_VERTEXBUFFER);
+    assert_eq!(desc.Usage, D3DUSAGE_DYNAMIC | D3DUSAGE_WRITEONLY);
+    assert_eq!(desc.Pool, D3DPOOL_DEFAULT);
+    assert_eq!(desc.Size, 96);
+    assert_eq!(desc.FVF, fvf);
+}